@@ -0,0 +1,303 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use clap::{Parser, ValueEnum};
+use crunch_client::Client;
+use crunch_engine::engine::Engine;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::Rng;
+
+/// Workload generator for measuring CrunchKV's throughput and latency
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// What to run the workload against
+    #[arg(long, value_enum, default_value_t = Target::Engine)]
+    target: Target,
+
+    /// The data directory for the engine target. A temporary one is used, and
+    /// removed afterwards, if this isn't given
+    #[arg(long)]
+    path: Option<PathBuf>,
+
+    /// The port of the server target
+    #[arg(short, long, default_value_t = 6210)]
+    port: u16,
+
+    /// The password to authenticate with, if the server requires one
+    #[arg(long)]
+    password: Option<String>,
+
+    /// The total number of operations to run
+    #[arg(long, default_value_t = 100_000)]
+    operations: usize,
+
+    /// The number of distinct keys, which are all written before the workload
+    /// starts
+    #[arg(long, default_value_t = 10_000)]
+    keys: usize,
+
+    /// The size of each value, in bytes
+    #[arg(long, default_value_t = 100)]
+    value_size: usize,
+
+    /// The percentage of operations that are reads, with the rest being writes
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=100))]
+    reads: u8,
+
+    /// The number of threads running operations at once
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+
+    /// How keys are picked for each operation
+    #[arg(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    /// The skew of the zipfian distribution. Higher values concentrate more of
+    /// the operations on the hottest keys
+    #[arg(long, default_value_t = 0.99)]
+    zipf_exponent: f64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Target {
+    /// An engine embedded in this process.
+    Engine,
+
+    /// A CrunchKV server, over TCP.
+    Server,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Distribution {
+    /// Every key is equally likely.
+    Uniform,
+
+    /// A few keys get most of the operations, like a typical cache workload.
+    Zipfian,
+}
+
+/// One thread's way of running operations against the target.
+enum Connection {
+    Engine(Arc<Engine>),
+    Server(Client),
+}
+
+impl Connection {
+    fn get(&mut self, key: &str) -> anyhow::Result<()> {
+        match self {
+            Self::Engine(engine) => _ = engine.get(key)?,
+            Self::Server(client) => _ = client.get(key.as_bytes())?,
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match self {
+            Self::Engine(engine) => engine.set(key, value)?,
+            Self::Server(client) => client.set(key.as_bytes(), value.as_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+/// Picks the key for each operation, as an index into the key space.
+enum KeySampler {
+    Uniform(usize),
+
+    /// The cumulative probability of each key, with the lowest indices being
+    /// the most likely.
+    Zipfian(Vec<f64>),
+}
+
+impl KeySampler {
+    fn new(distribution: Distribution, keys: usize, exponent: f64) -> Self {
+        match distribution {
+            Distribution::Uniform => Self::Uniform(keys),
+            Distribution::Zipfian => {
+                let weights: Vec<_> =
+                    (1..=keys).map(|rank| 1.0 / (rank as f64).powf(exponent)).collect();
+                let total: f64 = weights.iter().sum();
+                let mut cumulative = 0.0;
+                Self::Zipfian(
+                    weights
+                        .into_iter()
+                        .map(|weight| {
+                            cumulative += weight / total;
+                            cumulative
+                        })
+                        .collect(),
+                )
+            },
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            Self::Uniform(keys) => rng.gen_range(0..*keys),
+            Self::Zipfian(cdf) => {
+                let point: f64 = rng.gen();
+                // Rounding can leave the last cumulative probability just under 1.
+                cdf.partition_point(|&probability| probability < point).min(cdf.len() - 1)
+            },
+        }
+    }
+}
+
+fn key(index: usize) -> String {
+    format!("key{index:010}")
+}
+
+/// The latencies of each kind of operation run by a thread.
+#[derive(Default)]
+struct Latencies {
+    reads: Vec<Duration>,
+    writes: Vec<Duration>,
+}
+
+fn run(
+    mut connection: Connection,
+    sampler: &KeySampler,
+    operations: usize,
+    reads: u8,
+    value: &str,
+) -> anyhow::Result<Latencies> {
+    let mut rng = rand::thread_rng();
+    let mut latencies = Latencies::default();
+    for _ in 0..operations {
+        let key = key(sampler.sample(&mut rng));
+        let is_read = rng.gen_range(0..100) < reads;
+        let start = Instant::now();
+        if is_read {
+            connection.get(&key)?;
+            latencies.reads.push(start.elapsed());
+        } else {
+            connection.set(&key, value)?;
+            latencies.writes.push(start.elapsed());
+        }
+    }
+    Ok(latencies)
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort_unstable();
+    let percentile = |percentile: f64| {
+        let index = ((latencies.len() as f64 * percentile / 100.0).ceil() as usize).max(1) - 1;
+        latencies[index]
+    };
+    println!(
+        "{name:6}  {:>9}  {:>10.1?}  {:>10.1?}  {:>10.1?}  {:>10.1?}  {:>10.1?}",
+        latencies.len(),
+        percentile(50.0),
+        percentile(90.0),
+        percentile(99.0),
+        percentile(99.9),
+        latencies[latencies.len() - 1],
+    );
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = Cli::parse();
+    if args.keys == 0 || args.concurrency == 0 {
+        return Err(anyhow!("--keys and --concurrency must be at least 1"));
+    }
+
+    let temporary = args.path.is_none();
+    let path = args.path.clone().unwrap_or_else(|| {
+        std::env::temp_dir().join(format!("crunch-bench-{}", std::process::id()))
+    });
+    let engine = match args.target {
+        Target::Engine => {
+            std::fs::create_dir_all(&path)?;
+            Some(Arc::new(Engine::new(path.clone())?))
+        },
+        Target::Server => None,
+    };
+    let connect = || -> anyhow::Result<Connection> {
+        match &engine {
+            Some(engine) => Ok(Connection::Engine(engine.clone())),
+            None => {
+                let mut client = Client::connect(("127.0.0.1", args.port))?;
+                if let Some(password) = &args.password {
+                    client.auth(password.as_bytes())?;
+                }
+                Ok(Connection::Server(client))
+            },
+        }
+    };
+
+    let value = Alphanumeric.sample_string(&mut rand::thread_rng(), args.value_size);
+    println!("loading {} keys", args.keys);
+    let mut connection = connect()?;
+    for index in 0..args.keys {
+        connection.set(&key(index), &value)?;
+    }
+    drop(connection);
+
+    let sampler = KeySampler::new(args.distribution, args.keys, args.zipf_exponent);
+    println!("running {} operations on {} threads", args.operations, args.concurrency);
+    let start = Instant::now();
+    let results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..args.concurrency)
+            .map(|thread| {
+                // Spread the remainder over the first few threads.
+                let operations = args.operations / args.concurrency
+                    + usize::from(thread < args.operations % args.concurrency);
+                let connection = connect();
+                let (sampler, value) = (&sampler, &value);
+                scope.spawn(move || run(connection?, sampler, operations, args.reads, value))
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    let elapsed = start.elapsed();
+
+    let mut latencies = Latencies::default();
+    for result in results {
+        let result = result?;
+        latencies.reads.extend(result.reads);
+        latencies.writes.extend(result.writes);
+    }
+    println!(
+        "{:.0} operations/s over {elapsed:.2?}",
+        args.operations as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "{:6}  {:>9}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "", "count", "p50", "p90", "p99", "p99.9", "max"
+    );
+    report("reads", latencies.reads);
+    report("writes", latencies.writes);
+
+    if let Some(engine) = engine {
+        if let Ok(engine) = Arc::try_unwrap(engine) {
+            engine.stop().map_err(|_| anyhow!("engine panicked while stopping"))?;
+        }
+        if temporary {
+            std::fs::remove_dir_all(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn zipfian_favours_low_indices() {
+        let sampler = KeySampler::new(Distribution::Zipfian, 1000, 0.99);
+        let mut rng = rand::thread_rng();
+        let samples: Vec<_> = (0..10_000).map(|_| sampler.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|&index| index < 1000));
+        // The hottest 1% of keys get roughly 40% of the operations.
+        assert!(samples.iter().filter(|&&index| index < 10).count() > 2500);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use crunch_common::slot::key_slot;
+
+use crate::{Client, ClientArgs, Error, Result, SlotRange};
+
+/// How many redirects a command follows before giving up, in case the nodes
+/// disagree about who owns a slot.
+const MAX_REDIRECTS: usize = 5;
+
+/// A client for a slot cluster, which sends each command to the node that
+/// owns its key's slot.
+///
+/// The slots are learnt from the node that the client connects to. When a
+/// node answers that another one owns a key, the slots are learnt again from
+/// that node, and the command is sent on to it. Connections to each node are
+/// opened as they are first needed, and kept.
+pub struct ClusterClient {
+    args: ClientArgs,
+    slots: Vec<SlotRange>,
+    connections: HashMap<String, Client>,
+
+    /// The password to authenticate every connection with, from
+    /// [`Self::auth`].
+    password: Option<Vec<u8>>,
+}
+
+impl ClusterClient {
+    /// Connect to the node at `address`, and fetch the slots from it. If the
+    /// cluster needs a password, they are fetched once [`Self::auth`] is
+    /// called instead.
+    pub fn connect(address: &str) -> Result<Self> {
+        Self::connect_with(address, &ClientArgs::default())
+    }
+
+    pub fn connect_with(address: &str, args: &ClientArgs) -> Result<Self> {
+        let mut client = Self {
+            args: args.clone(),
+            slots: Vec::new(),
+            connections: HashMap::new(),
+            password: None,
+        };
+        match client.refresh(address) {
+            Ok(()) | Err(Error::Unauthenticated) => Ok(client),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Authenticate with the cluster's `password`, which every connection
+    /// opened after this is also authenticated with.
+    pub fn auth(&mut self, password: &[u8]) -> Result<()> {
+        self.password = Some(password.to_owned());
+        for client in self.connections.values_mut() {
+            client.auth(password)?;
+        }
+        // Fetching the slots was refused if it was tried before authenticating.
+        if self.slots.is_empty() {
+            let address = self.connections.keys().next().cloned();
+            if let Some(address) = address {
+                self.refresh(&address)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The slots, and the nodes that own them, as last learnt.
+    pub fn slots(&self) -> &[SlotRange] {
+        &self.slots
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.run(key, |client| client.get(key))
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.run(key, |client| client.set(key, value))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.run(key, |client| client.delete(key))
+    }
+
+    pub fn exists(&mut self, key: &[u8]) -> Result<bool> {
+        self.run(key, |client| client.exists(key))
+    }
+
+    pub fn getset(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.run(key, |client| client.getset(key, value))
+    }
+
+    pub fn getdel(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.run(key, |client| client.getdel(key))
+    }
+
+    pub fn setnx(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.run(key, |client| client.setnx(key, value))
+    }
+
+    pub fn append(&mut self, key: &[u8], suffix: &[u8]) -> Result<u64> {
+        self.run(key, |client| client.append(key, suffix))
+    }
+
+    /// Run `command` on the node that owns `key`, following any redirects.
+    fn run<T>(&mut self, key: &[u8], command: impl Fn(&mut Client) -> Result<T>) -> Result<T> {
+        let slot = key_slot(key);
+        let mut address = self.owner(slot).ok_or_else(|| {
+            Error::InvalidResponse(format!("the cluster has no node for slot {slot}"))
+        })?;
+        for _ in 0..MAX_REDIRECTS {
+            match command(self.connection(&address)?) {
+                Err(Error::Moved { address: owner, .. }) => {
+                    self.refresh(&owner)?;
+                    address = owner;
+                },
+                Err(error @ (Error::Io(_) | Error::TimedOut)) => {
+                    self.connections.remove(&address);
+                    return Err(error);
+                },
+                result => return result,
+            }
+        }
+        Err(Error::InvalidResponse(format!("slot {slot} was redirected too many times")))
+    }
+
+    fn owner(&self, slot: u16) -> Option<String> {
+        let index = self.slots.partition_point(|range| range.end < slot);
+        let range = self.slots.get(index)?;
+        (range.start <= slot).then(|| range.address.clone())
+    }
+
+    /// Learn the slots from the node at `address`.
+    fn refresh(&mut self, address: &str) -> Result<()> {
+        self.slots = self.connection(address)?.cluster_slots()?;
+        Ok(())
+    }
+
+    /// The connection to the node at `address`, opening it if it isn't open.
+    /// Connections that fail with an I/O error or a timeout are dropped, so
+    /// that the next command to the node reconnects.
+    fn connection(&mut self, address: &str) -> Result<&mut Client> {
+        if !self.connections.contains_key(address) {
+            let mut client = Client::connect_with(address, &self.args)?;
+            if let Some(password) = &self.password {
+                client.auth(password)?;
+            }
+            self.connections.insert(address.to_owned(), client);
+        }
+        Ok(self.connections.get_mut(address).unwrap())
+    }
+}
@@ -0,0 +1,957 @@
+//! A client for CrunchKV servers.
+//!
+//! ```no_run
+//! let mut client = crunch_client::Client::connect(("127.0.0.1", 6210))?;
+//! client.set(b"key", b"value")?;
+//! assert_eq!(client.get(b"key")?.as_deref(), Some(&b"value"[..]));
+//! # Ok::<(), crunch_client::Error>(())
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+pub use cluster::ClusterClient;
+
+mod cluster;
+
+/// The length prefix of chunked data, in place of its size. The data follows
+/// as length prefixed chunks, ending with an empty one.
+const CHUNKED: u32 = u32::MAX;
+
+/// The size of the chunks that [`Client::set_from`] sends.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[repr(u8)]
+enum Command {
+    Get = 1,
+    Set,
+    Delete,
+    Ping,
+    Auth,
+    Scan = 6,
+    Exists,
+    Info = 10,
+    DbSize,
+    Watch,
+    Config,
+    Select,
+    GetSet,
+    Append,
+    SetNx,
+    GetDel,
+    Subscribe,
+    Eval,
+    AuthUser,
+    Cluster,
+    Acks,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(io::Error),
+
+    /// The server took longer than the timeout to connect or respond. The
+    /// connection is closed after this, since a late response would be read
+    /// as the answer to the next command.
+    #[error("timed out waiting for the server")]
+    TimedOut,
+    #[error("operation failed")]
+    Failed,
+    #[error("authentication required")]
+    Unauthenticated,
+    #[error("invalid password")]
+    InvalidPassword,
+
+    /// The server couldn't carry out the command, for the given reason.
+    #[error("{0}")]
+    Server(String),
+
+    /// A key or value was larger than the server accepts.
+    #[error("{0}")]
+    TooLarge(String),
+
+    /// The server found data on disk that it can't read while carrying out
+    /// the command.
+    #[error("{0}")]
+    Corruption(String),
+
+    /// The server couldn't make sense of the command's arguments.
+    #[error("{0}")]
+    InvalidRequest(String),
+
+    /// The server won't run the command in its current role, such as a write
+    /// sent to a follower.
+    #[error("{0}")]
+    Refused(String),
+
+    /// The user that the connection authenticated as isn't allowed to run the
+    /// command on the keys or database that it names.
+    #[error("{0}")]
+    Forbidden(String),
+
+    /// The key belongs to a slot that another node of the slot cluster owns,
+    /// at `address`. A [`ClusterClient`] follows these itself.
+    #[error("slot {slot} is owned by {address}")]
+    Moved { slot: u16, address: String },
+
+    /// The write was applied, but too few followers acknowledged it in time
+    /// for the level asked for with [`Client::acks`]. It isn't undone.
+    #[error("{0}")]
+    Unreplicated(String),
+
+    /// A watcher fell too far behind, and missed some changes.
+    #[error("fell too far behind the server's changes")]
+    Lagged,
+
+    #[error("invalid response from server: {0}")]
+    InvalidResponse(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        // A timed out read or write is reported as `WouldBlock` on some
+        // platforms and `TimedOut` on others.
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Self::TimedOut,
+            _ => Self::Io(error),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Default)]
+pub struct ClientArgs {
+    /// How long to wait for the connection to be made, or forever if `None`.
+    pub connect_timeout: Option<Duration>,
+
+    /// How long to wait for each request to be sent, and for its response to
+    /// arrive, or forever if `None`.
+    pub request_timeout: Option<Duration>,
+}
+
+/// How many servers must have applied a write before the server that it was
+/// sent to acknowledges it, from [`Client::acks`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AckLevel {
+    /// The server that the write was sent to.
+    Local = 1,
+
+    /// That server and at least one of its followers.
+    LeaderPlusOne,
+
+    /// A majority of that server and its followers.
+    Majority,
+}
+
+/// A change to a watched key, pushed by the server.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Change {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+/// A keyspace event, pushed by the server to a subscriber for a change to a
+/// key that matches its pattern.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Event {
+    /// What happened to the key, such as `set` or `delete`.
+    pub name: String,
+    pub key: Vec<u8>,
+
+    /// The key's new value, for a `set`.
+    pub value: Option<Vec<u8>>,
+}
+
+/// The response to a command sent in a [`Pipeline`].
+#[derive(Debug, Eq, PartialEq)]
+pub enum Reply {
+    /// The command succeeded, and has nothing to return.
+    Ok,
+
+    /// The value from a `get`, or the one replaced by a `getset` or removed by
+    /// a `getdel`, or the result of an `eval`, or `None` if there is none.
+    Value(Option<Vec<u8>>),
+
+    /// The answer to an `exists`.
+    Bool(bool),
+}
+
+/// The kind of reply that a command gets.
+#[derive(Clone, Copy)]
+enum ReplyKind {
+    Ok,
+    Value,
+    Bool,
+}
+
+/// A page of results from [`Client::scan`].
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ScanPage {
+    /// The keys in the page, in order, along with their values if they were
+    /// asked for.
+    pub entries: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+
+    /// The cursor that the next page starts from, or `None` if this is the
+    /// last page.
+    pub next: Option<Vec<u8>>,
+}
+
+/// A run of slots, from `start` to `end` inclusive, that belong to the node
+/// of a slot cluster at `address`, from [`Client::cluster_slots`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SlotRange {
+    pub start: u16,
+    pub end: u16,
+    pub address: String,
+}
+
+/// A connection to a CrunchKV server.
+pub struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    pub fn connect(address: impl ToSocketAddrs) -> Result<Self> {
+        Self::connect_with(address, &ClientArgs::default())
+    }
+
+    pub fn connect_with(address: impl ToSocketAddrs, args: &ClientArgs) -> Result<Self> {
+        let stream = match args.connect_timeout {
+            Some(timeout) => connect_timeout(address, timeout)?,
+            None => TcpStream::connect(address)?,
+        };
+        stream.set_read_timeout(args.request_timeout)?;
+        stream.set_write_timeout(args.request_timeout)?;
+        Ok(Self { stream })
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.send(Command::Get, &[key])?;
+        match self.read_reply(ReplyKind::Value)? {
+            Reply::Value(value) => Ok(value),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.send(Command::Set, &[key, value])?;
+        self.assert_success()
+    }
+
+    /// Get the value of `key`, and copy it to `writer` as it arrives, rather
+    /// than holding all of it at once. Returns whether the key exists.
+    pub fn get_into(&mut self, key: &[u8], writer: &mut impl Write) -> Result<bool> {
+        self.send(Command::Get, &[key])?;
+        match self.read_outcome()? {
+            1 => {
+                self.read_data_into(writer)?;
+                Ok(true)
+            },
+            2 => Ok(false),
+            _ => Err(Error::Failed),
+        }
+    }
+
+    /// Set `key` to the value read from `reader`, which is sent in chunks as it
+    /// is read, so neither its size nor all of it has to be known up front.
+    ///
+    /// If reading fails partway through, the connection is closed, since the
+    /// command can't be finished or taken back.
+    pub fn set_from(&mut self, key: &[u8], reader: &mut impl Read) -> Result<()> {
+        let mut buffer = Vec::with_capacity(1 + 4 + key.len() + 4);
+        encode(&mut buffer, Command::Set, &[key]);
+        buffer.extend(CHUNKED.to_be_bytes());
+        self.write_all(&buffer)?;
+        let mut chunk = vec![0; 4 + CHUNK_SIZE];
+        loop {
+            let size = match reader.read(&mut chunk[4..]) {
+                Ok(size) => size,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    _ = self.stream.shutdown(Shutdown::Both);
+                    return Err(Error::Io(error));
+                },
+            };
+            chunk[..4].copy_from_slice(&(size as u32).to_be_bytes());
+            self.write_all(&chunk[..4 + size])?;
+            if size == 0 {
+                break;
+            }
+        }
+        self.assert_success()
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.send(Command::Delete, &[key])?;
+        self.assert_success()
+    }
+
+    /// Delete `key`, returning the value that it had, if any. When several
+    /// clients race to take the same key, only one of them gets its value.
+    pub fn getdel(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.send(Command::GetDel, &[key])?;
+        match self.read_reply(ReplyKind::Value)? {
+            Reply::Value(value) => Ok(value),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Set `key` to `value`, returning the value that it replaced, if it had
+    /// one.
+    pub fn getset(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.send(Command::GetSet, &[key, value])?;
+        match self.read_reply(ReplyKind::Value)? {
+            Reply::Value(value) => Ok(value),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Set `key` to `value` only if it has no value, returning whether it was
+    /// set.
+    pub fn setnx(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.send(Command::SetNx, &[key, value])?;
+        match self.read_outcome()? {
+            1 => Ok(true),
+            0 => Ok(false),
+            outcome => Err(Error::InvalidResponse(format!("unexpected status {outcome}"))),
+        }
+    }
+
+    /// Add `suffix` to the end of the value of `key`, which counts as empty if
+    /// it has none, returning the length of the new value in bytes.
+    pub fn append(&mut self, key: &[u8], suffix: &[u8]) -> Result<u64> {
+        self.send(Command::Append, &[key, suffix])?;
+        self.assert_success()?;
+        let mut len = [0; 8];
+        self.read_exact(&mut len)?;
+        Ok(u64::from_be_bytes(len))
+    }
+
+    pub fn exists(&mut self, key: &[u8]) -> Result<bool> {
+        self.send(Command::Exists, &[key])?;
+        match self.read_reply(ReplyKind::Bool)? {
+            Reply::Bool(exists) => Ok(exists),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        self.send(Command::Ping, &[])?;
+        self.assert_success()
+    }
+
+    /// Start queueing up commands, to send to the server all at once.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline { client: self, buffer: Vec::new(), kinds: Vec::new() }
+    }
+
+    /// Fetch up to `count` keys, in order, starting from the first key that is
+    /// at least `cursor`. Pass an empty `cursor` to start from the beginning,
+    /// and [`ScanPage::next`] to carry on from where a page left off.
+    pub fn scan(&mut self, cursor: &[u8], count: u32, with_values: bool) -> Result<ScanPage> {
+        self.send(Command::Scan, &[cursor, &count.to_be_bytes(), &[with_values as u8]])?;
+        self.assert_success()?;
+        let mut page = ScanPage::default();
+        for _ in 0..self.read_u32()? {
+            let key = self.read_data()?;
+            let value = if with_values { Some(self.read_data()?) } else { None };
+            page.entries.push((key, value));
+        }
+        let next = self.read_data()?;
+        page.next = (!next.is_empty()).then_some(next);
+        Ok(page)
+    }
+
+    /// Fetch the server's statistics, as (name, value) pairs.
+    pub fn info(&mut self) -> Result<Vec<(String, String)>> {
+        self.send(Command::Info, &[])?;
+        self.assert_success()?;
+        (0..self.read_u32()?).map(|_| Ok((self.read_string()?, self.read_string()?))).collect()
+    }
+
+    /// The name and effective value of each of the server's settings that is
+    /// set, such as `engine.store.wal_sync`. Secret values are redacted.
+    pub fn config(&mut self) -> Result<Vec<(String, String)>> {
+        self.send(Command::Config, &[])?;
+        self.assert_success()?;
+        (0..self.read_u32()?).map(|_| Ok((self.read_string()?, self.read_string()?))).collect()
+    }
+
+    /// Which node owns each slot of the slot cluster that the server is part
+    /// of, in order.
+    pub fn cluster_slots(&mut self) -> Result<Vec<SlotRange>> {
+        self.send(Command::Cluster, &[])?;
+        self.assert_success()?;
+        (0..self.read_u32()?)
+            .map(|_| {
+                let (start, end) = (self.read_u32()?, self.read_u32()?);
+                let address = self.read_string()?;
+                match (u16::try_from(start), u16::try_from(end)) {
+                    (Ok(start), Ok(end)) => Ok(SlotRange { start, end, address }),
+                    _ => Err(Error::InvalidResponse(format!("invalid slots {start}-{end}"))),
+                }
+            })
+            .collect()
+    }
+
+    /// The approximate number of keys in the database.
+    pub fn dbsize(&mut self) -> Result<u64> {
+        self.send(Command::DbSize, &[])?;
+        self.assert_success()?;
+        let mut len = [0; 8];
+        self.read_exact(&mut len)?;
+        Ok(u64::from_be_bytes(len))
+    }
+
+    /// Switch the connection to the server's database at `index`. Every
+    /// connection starts out using database 0.
+    pub fn select(&mut self, index: u32) -> Result<()> {
+        self.send(Command::Select, &[&index.to_be_bytes()])?;
+        self.assert_success()
+    }
+
+    /// Have the connection's writes wait for as many servers as `level` asks
+    /// for to apply them, or for as many as the server is configured to wait
+    /// for if `None`. A write that isn't acknowledged in time fails with
+    /// [`Error::Unreplicated`].
+    pub fn acks(&mut self, level: Option<AckLevel>) -> Result<()> {
+        self.send(Command::Acks, &[&[level.map_or(0, |level| level as u8)]])?;
+        self.assert_success()
+    }
+
+    /// Start watching `keys`. From here on, the connection only carries the
+    /// changes to them, which are read with [`Self::next_change`].
+    ///
+    /// The request timeout doesn't apply to waiting for changes, since there
+    /// may not be any for a long time.
+    pub fn watch<'a>(&mut self, keys: impl IntoIterator<Item = &'a [u8]>) -> Result<()> {
+        let mut data = Vec::new();
+        for key in keys {
+            data.extend((key.len() as u32).to_be_bytes());
+            data.extend(key);
+        }
+        self.send(Command::Watch, &[&data])?;
+        self.assert_success()?;
+        self.stream.set_read_timeout(None)?;
+        Ok(())
+    }
+
+    /// Wait for the next change to a watched key.
+    pub fn next_change(&mut self) -> Result<Change> {
+        match self.read_outcome()? {
+            1 => Ok(Change::Set { key: self.read_data()?, value: self.read_data()? }),
+            2 => Ok(Change::Delete { key: self.read_data()? }),
+            _ => Err(Error::Lagged),
+        }
+    }
+
+    /// Run the Lua `script` on the server, with `args` in its `ARGV` table,
+    /// and return its result. No other write comes between the script's
+    /// reads and writes.
+    ///
+    /// The script reads and writes through `crunch.get`, `crunch.exists`,
+    /// `crunch.set` and `crunch.delete`, and returns `nil` for no value, or a
+    /// string, number or boolean.
+    pub fn eval(&mut self, script: &[u8], args: &[&[u8]]) -> Result<Option<Vec<u8>>> {
+        let mut data = Vec::new();
+        for arg in args {
+            data.extend((arg.len() as u32).to_be_bytes());
+            data.extend(*arg);
+        }
+        self.send(Command::Eval, &[script, &data])?;
+        match self.read_reply(ReplyKind::Value)? {
+            Reply::Value(value) => Ok(value),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Subscribe to the keyspace events for every key that matches `pattern`,
+    /// in which `*` matches any run of characters, `?` matches any single
+    /// character, and `\` matches the character after it literally. From
+    /// here on, the connection only carries events, which are read with
+    /// [`Self::next_event`].
+    ///
+    /// The request timeout doesn't apply to waiting for events, since there
+    /// may not be any for a long time.
+    pub fn subscribe(&mut self, pattern: &[u8]) -> Result<()> {
+        self.send(Command::Subscribe, &[pattern])?;
+        self.assert_success()?;
+        self.stream.set_read_timeout(None)?;
+        Ok(())
+    }
+
+    /// Wait for the next keyspace event.
+    pub fn next_event(&mut self) -> Result<Event> {
+        if self.read_outcome()? != 1 {
+            return Err(Error::Lagged);
+        }
+        let name = self.read_string()?;
+        let key = self.read_data()?;
+        let value = if name == "set" { Some(self.read_data()?) } else { None };
+        Ok(Event { name, key, value })
+    }
+
+    /// Authenticate the connection with the server's `password`.
+    pub fn auth(&mut self, password: &[u8]) -> Result<()> {
+        self.send(Command::Auth, &[password])?;
+        match self.read_outcome()? {
+            1 => Ok(()),
+            _ => Err(Error::InvalidPassword),
+        }
+    }
+
+    /// Authenticate the connection as the user `name`, with their `password`.
+    /// The connection can then only run what the user is allowed to.
+    pub fn auth_user(&mut self, name: &[u8], password: &[u8]) -> Result<()> {
+        self.send(Command::AuthUser, &[name, password])?;
+        match self.read_outcome()? {
+            1 => Ok(()),
+            _ => Err(Error::InvalidPassword),
+        }
+    }
+
+    fn assert_success(&mut self) -> Result<()> {
+        self.read_reply(ReplyKind::Ok).map(|_| ())
+    }
+
+    /// Send a `command` with its `args`, in a single write.
+    fn send(&mut self, command: Command, args: &[&[u8]]) -> Result<()> {
+        let mut buffer = Vec::new();
+        encode(&mut buffer, command, args);
+        self.write_all(&buffer)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        let result = self.stream.write_all(data);
+        self.check(result)
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let result = self.stream.read_exact(buffer);
+        self.check(result)
+    }
+
+    /// Convert the result of some I/O on the stream. After a timeout, the
+    /// stream is shut down, so that a late response can't be mistaken for the
+    /// answer to a later command.
+    fn check(&mut self, result: io::Result<()>) -> Result<()> {
+        match result.map_err(Error::from) {
+            Err(Error::TimedOut) => {
+                _ = self.stream.shutdown(Shutdown::Both);
+                Err(Error::TimedOut)
+            },
+            result => result,
+        }
+    }
+
+    fn read_reply(&mut self, kind: ReplyKind) -> Result<Reply> {
+        match (kind, self.read_outcome()?) {
+            (ReplyKind::Ok, 1) => Ok(Reply::Ok),
+            (ReplyKind::Value, 1) => Ok(Reply::Value(Some(self.read_data()?))),
+            (ReplyKind::Value, 2) => Ok(Reply::Value(None)),
+            (ReplyKind::Bool, 1) => Ok(Reply::Bool(true)),
+            (ReplyKind::Bool, 2) => Ok(Reply::Bool(false)),
+            _ => Err(Error::Failed),
+        }
+    }
+
+    /// Read the status that starts a response. Error statuses, from 4 up, are
+    /// followed by a message, and are returned as an [`Error`].
+    fn read_outcome(&mut self) -> Result<u8> {
+        let mut outcome = [0; 1];
+        self.read_exact(&mut outcome)?;
+        let status = match outcome[0] {
+            3 => return Err(Error::Unauthenticated),
+            status @ 4.. => status,
+            outcome => return Ok(outcome),
+        };
+        let message = String::from_utf8_lossy(&self.read_data()?).into_owned();
+        Err(match status {
+            5 => Error::TooLarge(message),
+            6 => Error::Corruption(message),
+            7 => Error::InvalidRequest(message),
+            8 => Error::Refused(message),
+            9 => Error::Forbidden(message),
+            10 => match message.split_once(' ').map(|(slot, address)| (slot.parse(), address)) {
+                Some((Ok(slot), address)) => Error::Moved { slot, address: address.to_owned() },
+                _ => Error::InvalidResponse(format!("malformed redirect {message:?}")),
+            },
+            11 => Error::Unreplicated(message),
+            // Statuses added after this client was written are still errors.
+            _ => Error::Server(message),
+        })
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut value = [0; 4];
+        self.read_exact(&mut value)?;
+        Ok(u32::from_be_bytes(value))
+    }
+
+    fn read_data(&mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_data_into(&mut data)?;
+        Ok(data)
+    }
+
+    /// Read length prefixed or chunked data, and copy it to `writer` in pieces
+    /// of at most [`CHUNK_SIZE`] bytes.
+    fn read_data_into(&mut self, writer: &mut impl Write) -> Result<()> {
+        let size = self.read_u32()?;
+        if size != CHUNKED {
+            return self.copy_to(size, writer);
+        }
+        loop {
+            match self.read_u32()? {
+                0 => return Ok(()),
+                size => self.copy_to(size, writer)?,
+            }
+        }
+    }
+
+    /// Copy the next `size` bytes from the stream to `writer`.
+    fn copy_to(&mut self, size: u32, writer: &mut impl Write) -> Result<()> {
+        let mut buffer = vec![0; (size as usize).min(CHUNK_SIZE)];
+        let mut remaining = size as usize;
+        while remaining > 0 {
+            let piece = &mut buffer[..remaining.min(CHUNK_SIZE)];
+            self.read_exact(piece)?;
+            if let Err(error) = writer.write_all(piece) {
+                // The rest of the data is left unread, which unframes the stream.
+                _ = self.stream.shutdown(Shutdown::Both);
+                return Err(Error::Io(error));
+            }
+            remaining -= piece.len();
+        }
+        Ok(())
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        String::from_utf8(self.read_data()?)
+            .map_err(|error| Error::InvalidResponse(error.to_string()))
+    }
+}
+
+/// Commands queued up to be sent to the server in one write, from
+/// [`Client::pipeline`].
+///
+/// This saves a round trip per command, but unlike a batch, the commands
+/// aren't applied atomically.
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    buffer: Vec<u8>,
+    kinds: Vec<ReplyKind>,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: &[u8]) -> &mut Self {
+        self.push(Command::Get, &[key], ReplyKind::Value)
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.push(Command::Set, &[key, value], ReplyKind::Ok)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.push(Command::Delete, &[key], ReplyKind::Ok)
+    }
+
+    pub fn getdel(&mut self, key: &[u8]) -> &mut Self {
+        self.push(Command::GetDel, &[key], ReplyKind::Value)
+    }
+
+    pub fn getset(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.push(Command::GetSet, &[key, value], ReplyKind::Value)
+    }
+
+    pub fn exists(&mut self, key: &[u8]) -> &mut Self {
+        self.push(Command::Exists, &[key], ReplyKind::Bool)
+    }
+
+    pub fn ping(&mut self) -> &mut Self {
+        self.push(Command::Ping, &[], ReplyKind::Ok)
+    }
+
+    /// The number of commands queued so far.
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    /// Send every queued command, and return their replies in order.
+    ///
+    /// A command that fails doesn't stop the ones after it, so each reply is
+    /// a result of its own. Only an I/O error or a timeout fails the whole
+    /// pipeline, since the replies after it can't be read.
+    pub fn exec(self) -> Result<Vec<Result<Reply>>> {
+        self.client.write_all(&self.buffer)?;
+        let mut replies = Vec::with_capacity(self.kinds.len());
+        for kind in self.kinds {
+            match self.client.read_reply(kind) {
+                Err(error @ (Error::Io(_) | Error::TimedOut)) => return Err(error),
+                reply => replies.push(reply),
+            }
+        }
+        Ok(replies)
+    }
+
+    fn push(&mut self, command: Command, args: &[&[u8]], kind: ReplyKind) -> &mut Self {
+        encode(&mut self.buffer, command, args);
+        self.kinds.push(kind);
+        self
+    }
+}
+
+/// Connect like [`TcpStream::connect`], trying each of the addresses in turn,
+/// but give up on each one after `timeout`.
+fn connect_timeout(address: impl ToSocketAddrs, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error =
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses");
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+fn encode(buffer: &mut Vec<u8>, command: Command, args: &[&[u8]]) {
+    buffer.push(command as u8);
+    for arg in args {
+        buffer.extend((arg.len() as u32).to_be_bytes());
+        buffer.extend(*arg);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    /// Start a fake server that reads a request of `request_size` bytes, and
+    /// writes back `response`. Joining the thread returns the request.
+    fn serve_once(request_size: usize, response: Vec<u8>) -> (Client, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; request_size];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&response).unwrap();
+            request
+        });
+        (Client::connect(address).unwrap(), server)
+    }
+
+    #[test]
+    fn pipeline() {
+        // The value of "a", a failed delete, and "b" not existing.
+        let mut response = vec![1, 0, 0, 0, 1, b'1'];
+        response.extend([4, 0, 0, 0, 4]);
+        response.extend(b"oops");
+        response.push(2);
+        let (mut client, server) = serve_once(3 * (1 + 4 + 1), response);
+
+        let mut pipeline = client.pipeline();
+        pipeline.get(b"a").delete(b"a").exists(b"b");
+        assert_eq!(pipeline.len(), 3);
+        let replies = pipeline.exec().unwrap();
+        assert!(matches!(replies[0], Ok(Reply::Value(Some(ref value))) if value == b"1"));
+        assert!(matches!(replies[1], Err(Error::Server(ref message)) if message == "oops"));
+        assert!(matches!(replies[2], Ok(Reply::Bool(false))));
+
+        let request = server.join().unwrap();
+        assert_eq!(&request[..6], [Command::Get as u8, 0, 0, 0, 1, b'a']);
+        assert_eq!(request[6], Command::Delete as u8);
+        assert_eq!(request[12], Command::Exists as u8);
+    }
+
+    #[test]
+    fn cluster_redirect() {
+        let slots = |address: &str| {
+            let mut response = vec![1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x3F, 0xFF];
+            response.extend((address.len() as u32).to_be_bytes());
+            response.extend(address.as_bytes());
+            response
+        };
+        // Each node says that it owns every slot, but the first one redirects
+        // "foo", which is in slot 12182, to the second once it is asked for it.
+        let nodes = [(); 2].map(|_| TcpListener::bind("127.0.0.1:0").unwrap());
+        let addresses = nodes.each_ref().map(|node| node.local_addr().unwrap().to_string());
+        let mut moved = format!("12182 {}", addresses[1]).into_bytes();
+        moved.splice(0..0, [10, 0, 0, 0, moved.len() as u8]);
+        let mut found = slots(&addresses[1]);
+        found.extend([1, 0, 0, 0, 1, b'1']);
+        let responses = [[slots(&addresses[0]), moved], [found, Vec::new()]];
+        let nodes: Vec<_> = nodes
+            .into_iter()
+            .zip(responses)
+            .map(|(node, [first, second])| {
+                thread::spawn(move || {
+                    let (mut stream, _) = node.accept().unwrap();
+                    let mut request = [0; 1 + 8];
+                    stream.read_exact(&mut request[..1]).unwrap();
+                    stream.write_all(&first).unwrap();
+                    stream.read_exact(&mut request[1..]).unwrap();
+                    stream.write_all(&second).unwrap();
+                    request
+                })
+            })
+            .collect();
+
+        let mut client = ClusterClient::connect(&addresses[0]).unwrap();
+        assert_eq!(client.slots()[0].address, addresses[0]);
+        assert_eq!(client.get(b"foo").unwrap().as_deref(), Some(&b"1"[..]));
+        let owner = addresses[1].clone();
+        assert_eq!(client.slots(), [SlotRange { start: 0, end: 16383, address: owner }]);
+        for node in nodes {
+            let request = node.join().unwrap();
+            assert_eq!(request[0], Command::Cluster as u8);
+            assert_eq!(request[1..], [Command::Get as u8, 0, 0, 0, 3, b'f', b'o', b'o']);
+        }
+    }
+
+    #[test]
+    fn chunked_values() {
+        let value: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|n| b'a' + (n % 26) as u8).collect();
+        let chunks = [CHUNK_SIZE, CHUNK_SIZE, 10];
+        let request_size = 1 + 4 + 1 + 4 + chunks.len() * 4 + value.len() + 4;
+        let (mut client, server) = serve_once(request_size, vec![1]);
+        client.set_from(b"k", &mut value.as_slice()).unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(&request[..10], [Command::Set as u8, 0, 0, 0, 1, b'k', 255, 255, 255, 255]);
+        let mut rest = &request[10..];
+        for size in chunks {
+            assert_eq!(rest[..4], (size as u32).to_be_bytes());
+            rest = &rest[4 + size..];
+        }
+        assert_eq!(rest, [0, 0, 0, 0]);
+
+        // The reply is chunked differently to how the value was sent.
+        let mut response = vec![1];
+        response.extend(CHUNKED.to_be_bytes());
+        for chunk in value.chunks(100_000) {
+            response.extend((chunk.len() as u32).to_be_bytes());
+            response.extend(chunk);
+        }
+        response.extend([0; 4]);
+        let (mut client, _) = serve_once(1 + 4 + 1, response);
+        let mut read = Vec::new();
+        assert!(client.get_into(b"k", &mut read).unwrap());
+        assert!(read == value);
+    }
+
+    #[test]
+    fn error_statuses() {
+        let mut response = Vec::new();
+        let statuses =
+            [(4, b"a"), (5, b"b"), (6, b"c"), (7, b"d"), (8, b"e"), (9, b"f"), (12, b"g")];
+        for (status, message) in statuses {
+            response.extend([status, 0, 0, 0, 1]);
+            response.extend(message);
+        }
+        response.push(3);
+        let (mut client, _) = serve_once(8, response);
+
+        let mut pipeline = client.pipeline();
+        for _ in 0..8 {
+            pipeline.ping();
+        }
+        let replies: Vec<_> =
+            pipeline.exec().unwrap().into_iter().map(Result::unwrap_err).collect();
+        assert!(matches!(&replies[0], Error::Server(message) if message == "a"));
+        assert!(matches!(&replies[1], Error::TooLarge(message) if message == "b"));
+        assert!(matches!(&replies[2], Error::Corruption(message) if message == "c"));
+        assert!(matches!(&replies[3], Error::InvalidRequest(message) if message == "d"));
+        assert!(matches!(&replies[4], Error::Refused(message) if message == "e"));
+        assert!(matches!(&replies[5], Error::Forbidden(message) if message == "f"));
+        assert!(matches!(&replies[6], Error::Server(message) if message == "g"));
+        assert!(matches!(&replies[7], Error::Unauthenticated));
+    }
+
+    #[test]
+    fn request_timeout() {
+        // The listener is never accepted from, so the server never responds.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let args =
+            ClientArgs { request_timeout: Some(Duration::from_millis(50)), ..Default::default() };
+        let mut client = Client::connect_with(listener.local_addr().unwrap(), &args).unwrap();
+        assert!(matches!(client.ping(), Err(Error::TimedOut)));
+        assert!(matches!(client.ping(), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn select() {
+        let mut response = vec![7, 0, 0, 0, 2];
+        response.extend(b"no");
+        let (mut client, server) = serve_once(1 + 4 + 4, response);
+
+        assert!(matches!(client.select(9), Err(Error::InvalidRequest(message)) if message == "no"));
+        assert_eq!(server.join().unwrap(), [14, 0, 0, 0, 4, 0, 0, 0, 9]);
+    }
+
+    #[test]
+    fn acks() {
+        let (mut client, server) = serve_once(1 + 4 + 1, vec![1]);
+        client.acks(Some(AckLevel::Majority)).unwrap();
+        assert_eq!(server.join().unwrap(), [23, 0, 0, 0, 1, 3]);
+
+        let mut response = vec![11, 0, 0, 0, 2];
+        response.extend(b"no");
+        let (mut client, _) = serve_once(1 + 4 + 1, response);
+        assert!(
+            matches!(client.delete(b"a"), Err(Error::Unreplicated(message)) if message == "no")
+        );
+    }
+
+    #[test]
+    fn events() {
+        let mut response = vec![1, 1, 0, 0, 0, 3];
+        response.extend(b"set");
+        response.extend([0, 0, 0, 1, b'a', 0, 0, 0, 1, b'1', 1, 0, 0, 0, 6]);
+        response.extend(b"delete");
+        response.extend([0, 0, 0, 1, b'b', 0]);
+        let (mut client, server) = serve_once(1 + 4 + 1, response);
+
+        client.subscribe(b"*").unwrap();
+        assert_eq!(client.next_event().unwrap(), Event {
+            name: "set".to_owned(),
+            key: b"a".to_vec(),
+            value: Some(b"1".to_vec())
+        });
+        assert_eq!(client.next_event().unwrap(), Event {
+            name: "delete".to_owned(),
+            key: b"b".to_vec(),
+            value: None
+        });
+        assert!(matches!(client.next_event(), Err(Error::Lagged)));
+        assert_eq!(server.join().unwrap(), [19, 0, 0, 0, 1, b'*']);
+    }
+
+    #[test]
+    fn scan() {
+        // Two keys with their values, and the cursor for the next page.
+        let mut response = vec![1, 0, 0, 0, 2];
+        response.extend([0, 0, 0, 1, b'a', 0, 0, 0, 1, b'1']);
+        response.extend([0, 0, 0, 1, b'b', 0, 0, 0, 1, b'2']);
+        response.extend([0, 0, 0, 1, b'c']);
+        let (mut client, server) = serve_once(1 + 4 + 4 + 4 + 4 + 1, response);
+
+        let page = client.scan(b"", 2, true).unwrap();
+        assert_eq!(page.entries, [
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"b".to_vec(), Some(b"2".to_vec()))
+        ]);
+        assert_eq!(page.next.as_deref(), Some(&b"c"[..]));
+        assert_eq!(server.join().unwrap(), [6, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 1, 1]);
+    }
+}
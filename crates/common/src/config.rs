@@ -0,0 +1,250 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::abort;
+use crate::env::{variable_name, FromEnv};
+
+/// Settings gathered from several sources. When a setting is read, the first
+/// of these that sets it wins:
+///
+/// 1. Overrides, such as those passed on the command line.
+/// 2. Environment variables, named as described in [`variable_name`].
+/// 3. A TOML file.
+/// 4. The default given by the caller.
+///
+/// Settings are named by their component, an optional namespace, and the
+/// name of the setting itself. In the TOML file these are nested tables, so
+/// `CRUNCH_ENGINE_STORE__WAL_SYNC` can also be set by:
+///
+/// ```toml
+/// [engine.store]
+/// wal_sync = false
+/// ```
+///
+/// while an override names it as `engine.store.wal_sync`. A [`Config`] with
+/// no file or overrides reads only from the environment.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Values from the TOML file, by their dotted name.
+    file: HashMap<String, String>,
+
+    /// Overriding values, by their dotted name.
+    overrides: HashMap<String, String>,
+}
+
+impl Config {
+    /// Read settings from the TOML file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| anyhow!("failed to read {}: {error}", path.display()))?;
+        Self::from_toml(&text).map_err(|error| anyhow!("in {}: {error}", path.display()))
+    }
+
+    /// Read settings from TOML `text`.
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let table: toml::Table = text.parse()?;
+        let mut file = HashMap::new();
+        flatten(&mut file, "", table)?;
+        Ok(Self { file, overrides: HashMap::new() })
+    }
+
+    /// Override a setting with an `assignment` like
+    /// `engine.store.wal_sync=false`.
+    pub fn set_override(&mut self, assignment: &str) -> anyhow::Result<()> {
+        let (name, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected name=value, not {assignment}"))?;
+        self.overrides.insert(name.trim().to_lowercase(), value.trim().to_owned());
+        Ok(())
+    }
+
+    /// Read a setting and parse it to the given type, or return the given
+    /// `default` if no source sets it.
+    ///
+    /// Panics if the value that is found can't be parsed, like
+    /// [`parse_env`](crate::env::parse_env).
+    pub fn get<T: FromEnv>(
+        &self,
+        component: &str,
+        namespace: Option<&str>,
+        name: &str,
+        default: T,
+    ) -> T {
+        let Some((source, value)) = self.lookup(component, namespace, name) else {
+            return default;
+        };
+        T::from_env(&value).unwrap_or_else(|error| {
+            let source = match source {
+                Source::Environment => variable_name(component, namespace, name),
+                _ => dotted_name(component, namespace, name),
+            };
+            let typename = std::any::type_name::<T>();
+            abort!("failed to parse setting", source, value, typename, error);
+        })
+    }
+
+    /// Find the raw value of a setting, along with where it came from, or
+    /// `None` if no source sets it.
+    pub fn lookup(
+        &self,
+        component: &str,
+        namespace: Option<&str>,
+        name: &str,
+    ) -> Option<(Source, String)> {
+        let key = dotted_name(component, namespace, name);
+        if let Some(value) = self.overrides.get(&key) {
+            Some((Source::Override, value.clone()))
+        } else if let Ok(value) = std::env::var(variable_name(component, namespace, name)) {
+            Some((Source::Environment, value))
+        } else {
+            self.file.get(&key).map(|value| (Source::File, value.clone()))
+        }
+    }
+
+    /// The names of the tables directly under the dotted name `table`, from
+    /// the file and overrides, for settings that are grouped under names of
+    /// the user's choosing. The tables under `kv.users` in:
+    ///
+    /// ```toml
+    /// [kv.users.reports]
+    /// password = "secret"
+    /// ```
+    ///
+    /// are `reports`, and the same name then comes from an override like
+    /// `kv.users.reports.password=secret`.
+    pub fn tables(&self, table: &str) -> BTreeSet<String> {
+        let prefix = format!("{}.", table.to_lowercase());
+        self.file
+            .keys()
+            .chain(self.overrides.keys())
+            .filter_map(|key| key.strip_prefix(&prefix)?.split_once('.'))
+            .map(|(name, _)| name.to_owned())
+            .collect()
+    }
+}
+
+/// Where the value of a setting came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    Override,
+    Environment,
+    File,
+
+    /// Nothing set the setting, so the default was used.
+    Default,
+}
+
+impl Source {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Override => "override",
+            Self::Environment => "environment",
+            Self::File => "file",
+            Self::Default => "default",
+        }
+    }
+}
+
+/// The name of a setting in a TOML file or override, such as
+/// `engine.store.wal_sync`.
+pub(crate) fn dotted_name(component: &str, namespace: Option<&str>, name: &str) -> String {
+    let mut key = component.to_lowercase();
+    if let Some(namespace) = namespace {
+        key.push('.');
+        key.push_str(&namespace.to_lowercase());
+    }
+    key.push('.');
+    key.push_str(&name.to_lowercase());
+    key
+}
+
+/// Add every value in `table` to `values`, under its dotted name after
+/// `prefix`.
+fn flatten(
+    values: &mut HashMap<String, String>,
+    prefix: &str,
+    table: toml::Table,
+) -> anyhow::Result<()> {
+    for (name, value) in table {
+        let name = format!("{prefix}{}", name.to_lowercase());
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten(values, &format!("{name}."), table)?;
+                continue;
+            },
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Float(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            toml::Value::Datetime(value) => value.to_string(),
+            toml::Value::Array(_) => {
+                return Err(anyhow!("{name} is an array, which isn't supported"))
+            },
+        };
+        values.insert(name, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        let mut config = Config::from_toml(
+            r#"
+            [test_config]
+            from_file = 1
+            from_env = 1
+            overridden = 1
+
+            [test_config.nested]
+            flag = true
+            name = "file"
+            "#,
+        )
+        .unwrap();
+        config.set_override("test_config.overridden = 3").unwrap();
+        std::env::set_var("CRUNCH_TEST_CONFIG__FROM_ENV", "2");
+        std::env::set_var("CRUNCH_TEST_CONFIG__OVERRIDDEN", "2");
+
+        assert_eq!(config.get("test_config", None, "from_file", 0u64), 1);
+        assert_eq!(config.get("test_config", None, "from_env", 0u64), 2);
+        assert_eq!(config.get("test_config", None, "overridden", 0u64), 3);
+        assert_eq!(config.get("test_config", None, "unset", 4u64), 4);
+        assert!(config.get("test_config", Some("nested"), "flag", false));
+        assert_eq!(config.get("test_config", Some("nested"), "name", String::new()), "file");
+    }
+
+    #[test]
+    fn tables() {
+        let mut config = Config::from_toml(
+            r#"
+            [test_tables.a]
+            x = 1
+
+            [test_tables.b.nested]
+            x = 1
+
+            [test_tables]
+            not_a_table = 1
+            "#,
+        )
+        .unwrap();
+        config.set_override("test_tables.c.x=1").unwrap();
+        let tables: Vec<_> = config.tables("test_tables").into_iter().collect();
+        assert_eq!(tables, ["a", "b", "c"]);
+        assert!(config.tables("missing").is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(Config::from_toml("[a]\nb = [1, 2]").is_err());
+        assert!(Config::from_toml("a = ").is_err());
+        assert!(Config::default().set_override("a.b").is_err());
+    }
+}
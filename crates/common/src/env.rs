@@ -1,7 +1,10 @@
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
-use crate::abort;
+use anyhow::anyhow;
+
+use crate::config::Config;
 
 pub trait FromEnv: Sized {
     fn from_env(value: &str) -> anyhow::Result<Self>;
@@ -37,26 +40,126 @@ impl FromEnv for PathBuf {
     }
 }
 
+impl FromEnv for String {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        Ok(value.to_owned())
+    }
+}
+
+/// A whole number followed by a unit of `ms`, `s`, `m`, `h` or `d`, such as
+/// `30s` or `5m`. A number without a unit is a count of seconds.
+impl FromEnv for Duration {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        let split = value.find(|char: char| !char.is_ascii_digit()).unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number: u64 = number.parse()?;
+        let seconds = match unit.trim() {
+            "ms" => return Ok(Duration::from_millis(number)),
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            unit => return Err(anyhow!("unknown unit of time {unit:?}")),
+        };
+        number
+            .checked_mul(seconds)
+            .map(Duration::from_secs)
+            .ok_or_else(|| anyhow!("{value} is too long"))
+    }
+}
+
+/// A number of bytes, read from a whole number followed by an optional unit of
+/// `B`, `KB`, `MB`, `GB` or `TB`, such as `64KB`. Units are powers of 1024, and
+/// are case-insensitive, so `16mb` and `16MiB` are the same size.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ByteSize(pub u64);
+
+impl FromEnv for ByteSize {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        let split = value.find(|char: char| !char.is_ascii_digit()).unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number: u64 = number.parse()?;
+        let power = match unit.trim().to_lowercase().as_str() {
+            "" | "b" => 0,
+            "k" | "kb" | "kib" => 1,
+            "m" | "mb" | "mib" => 2,
+            "g" | "gb" | "gib" => 3,
+            "t" | "tb" | "tib" => 4,
+            unit => return Err(anyhow!("unknown unit of size {unit:?}")),
+        };
+        number
+            .checked_mul(1024u64.pow(power))
+            .map(ByteSize)
+            .ok_or_else(|| anyhow!("{value} is too large"))
+    }
+}
+
+impl<T: FromEnv> FromEnv for Option<T> {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        T::from_env(value).map(Some)
+    }
+}
+
+/// The name of the environment variable for a setting, such as
+/// `CRUNCH_ENGINE_STORE__WAL_SYNC` for the `wal_sync` setting in the `store`
+/// namespace of the `engine` component.
+pub fn variable_name(component: &str, namespace: Option<&str>, name: &str) -> String {
+    let mut prefix = format!("CRUNCH_{}", component.to_uppercase());
+    if let Some(namespace) = namespace {
+        prefix.push('_');
+        prefix.push_str(&namespace.to_uppercase());
+    }
+    format!("{prefix}__{}", name.to_uppercase())
+}
+
 /// Read the value of an environment variable and parse it to the given type, or
 /// return the given `default`.
+///
+/// This is a shorthand for reading from a [`Config`] without a file or any
+/// overrides.
 pub fn parse_env<T: FromEnv>(
     component: &str,
     namespace: Option<&str>,
     variable_name: &str,
     default: T,
 ) -> T {
-    let mut prefix = format!("CRUNCH_{}", component.to_uppercase());
-    if let Some(namespace) = namespace {
-        prefix.push('_');
-        prefix.push_str(&namespace.to_uppercase());
+    Config::default().get(component, namespace, variable_name, default)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn duration() {
+        let parse = |value| Duration::from_env(value).ok();
+        assert_eq!(parse("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse("5m"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(parse(" 1 h"), Some(Duration::from_secs(60 * 60)));
+        assert_eq!(parse("2d"), Some(Duration::from_secs(2 * 24 * 60 * 60)));
+        assert_eq!(parse("5w"), None);
+        assert_eq!(parse("s"), None);
+        assert_eq!(parse("1.5h"), None);
+        assert_eq!(parse("18446744073709551615d"), None);
+    }
+
+    #[test]
+    fn byte_size() {
+        let parse = |value| ByteSize::from_env(value).ok().map(|size| size.0);
+        assert_eq!(parse("100"), Some(100));
+        assert_eq!(parse("100B"), Some(100));
+        assert_eq!(parse("64KB"), Some(64 * 1024));
+        assert_eq!(parse("16mb"), Some(16 * 1024 * 1024));
+        assert_eq!(parse("16MiB"), Some(16 * 1024 * 1024));
+        assert_eq!(parse(" 2 G"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse("1TB"), Some(1024 * 1024 * 1024 * 1024));
+        assert_eq!(parse("1PB"), None);
+        assert_eq!(parse("1.5MB"), None);
+        assert_eq!(parse("MB"), None);
+        assert_eq!(parse("18446744073709551615KB"), None);
     }
-    let name = format!("{prefix}__{}", variable_name.to_uppercase());
-    std::env::var(&name)
-        .map(|value| {
-            T::from_env(value.as_str()).unwrap_or_else(|error| {
-                let typename = std::any::type_name::<T>();
-                abort!("failed to parse environment variable", name, value, typename, error);
-            })
-        })
-        .unwrap_or(default)
 }
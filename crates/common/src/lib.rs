@@ -1,4 +1,7 @@
+pub mod config;
 pub mod env;
+pub mod registry;
+pub mod slot;
 
 macro_rules! format_variable {
     ($variable:ident, $value:expr) => {
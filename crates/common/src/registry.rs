@@ -0,0 +1,513 @@
+//! Every setting that CrunchKV reads, so that tools can list them along with
+//! their effective values.
+//!
+//! The code that reads each setting gives its own default, so a setting's
+//! entry here must be kept in line with it.
+
+use crate::config::{dotted_name, Config, Source};
+
+/// A setting that is read through a [`Config`].
+#[derive(Clone, Copy, Debug)]
+pub struct Setting {
+    pub component: &'static str,
+    pub namespace: Option<&'static str>,
+    pub name: &'static str,
+
+    /// The placeholder for the values that the setting accepts, as used in
+    /// the README.
+    pub kind: &'static str,
+
+    /// The value used when nothing sets the setting, or `None` if it is unset
+    /// by default.
+    pub default: Option<&'static str>,
+    pub description: &'static str,
+
+    /// Whether the value should be hidden when the setting is listed.
+    pub secret: bool,
+}
+
+impl Setting {
+    const fn new(
+        component: &'static str,
+        namespace: Option<&'static str>,
+        name: &'static str,
+        kind: &'static str,
+        default: Option<&'static str>,
+        description: &'static str,
+    ) -> Self {
+        Self { component, namespace, name, kind, default, description, secret: false }
+    }
+
+    /// The dotted name of the setting, such as `engine.store.wal_sync`.
+    pub fn dotted_name(&self) -> String {
+        dotted_name(self.component, self.namespace, self.name)
+    }
+}
+
+/// Every known setting, in the order of the README's table.
+pub const SETTINGS: &[Setting] = &[
+    Setting::new(
+        "engine",
+        Some("memtable"),
+        "capacity",
+        "uint",
+        Some("1024"),
+        "The number of key-value pairs that the memtable can hold before it flushes to disk.",
+    ),
+    Setting::new(
+        "engine",
+        Some("row_cache"),
+        "capacity",
+        "size",
+        Some("0"),
+        "The most bytes of recently read keys and values to cache in memory. 0 turns the \
+         cache off.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "compaction_enabled",
+        "bool",
+        Some("true"),
+        "Whether the background thread to perform compaction should run.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "compaction_interval",
+        "duration",
+        Some("10m"),
+        "The time between compaction runs.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "compaction_interval_seconds",
+        "duration",
+        None,
+        "The time between compaction runs, when compaction_interval isn't set. Deprecated.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "compaction_max_inputs",
+        "uint",
+        Some("8"),
+        "The most segment files that a single compaction will merge together.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "compaction_trigger_segment_count",
+        "uint",
+        Some("8"),
+        "Once there are at least this many segment files, a flush wakes the compaction loop \
+         early.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "compaction_trigger_bytes",
+        "size",
+        Some("64MB"),
+        "Once the segment files hold at least this many bytes combined, a flush wakes the \
+         compaction loop early.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "compaction_bytes_per_second",
+        "size",
+        Some("0"),
+        "The most bytes per second that compaction will read or write, combined. 0 means \
+         unlimited.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "compression_threshold",
+        "size",
+        Some("0"),
+        "Values of at least this many bytes are compressed as they are written. 0 turns \
+         compression off.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "direct_io",
+        "bool",
+        Some("false"),
+        "Whether segment files are read with O_DIRECT, around the page cache. Linux only.",
+    ),
+    Setting {
+        secret: true,
+        ..Setting::new(
+            "engine",
+            Some("store"),
+            "encryption_key",
+            "hex",
+            None,
+            "The AES-256 key, as 64 hex digits, that segment files and the WAL are encrypted \
+             with.",
+        )
+    },
+    Setting::new(
+        "engine",
+        Some("store"),
+        "io_backend",
+        "std|io_uring",
+        Some("std"),
+        "How segment files are read and the WAL is appended to. io_uring needs a Linux build \
+         with the io-uring feature.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "open_threads",
+        "uint",
+        Some("1"),
+        "The number of threads that segment files are read with when the store is opened.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "prefix_bloom_length",
+        "uint",
+        Some("0"),
+        "The number of leading key bytes that each segment's prefix bloom filter covers. 0 \
+         turns prefix filters off.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "quarantine_corrupt_segments",
+        "bool",
+        Some("false"),
+        "Whether a corrupt segment file is moved into the quarantine directory and dropped from \
+         the store, rather than failing the reads that reach it.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "read_ahead",
+        "size",
+        Some("256KB"),
+        "The most bytes at a time that scans read ahead of themselves in each segment file. 0 \
+         turns read ahead off.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "retained_versions",
+        "uint",
+        Some("1"),
+        "The most versions of each key to keep, counting the current one.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "wal_max_bytes",
+        "size",
+        Some("4MB"),
+        "The size past which the active WAL file is closed off and a new one is started.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "wal_recovery_mode",
+        "strict|salvage",
+        Some("strict"),
+        "What replaying the WAL does with a corrupt record: refuse to open the store, or \
+         truncate the WAL at the record and replay what comes before it.",
+    ),
+    Setting::new(
+        "engine",
+        Some("store"),
+        "wal_sync",
+        "bool",
+        Some("true"),
+        "Whether every write to the WAL is fsynced before it is acknowledged.",
+    ),
+    Setting::new(
+        "engine",
+        Some("write"),
+        "bytes_per_second",
+        "size",
+        Some("0"),
+        "The most bytes of keys and values per second that the engine accepts writes of. 0 \
+         means unlimited.",
+    ),
+    Setting::new(
+        "engine",
+        Some("write"),
+        "operations_per_second",
+        "uint",
+        Some("0"),
+        "The most sets and deletes per second that the engine accepts. 0 means unlimited.",
+    ),
+    Setting::new(
+        "engine",
+        None,
+        "max_key_size",
+        "size",
+        Some("64KB"),
+        "The largest key that the engine accepts a write of.",
+    ),
+    Setting::new(
+        "engine",
+        None,
+        "max_value_size",
+        "size",
+        Some("512MB"),
+        "The largest value that the engine accepts a write of.",
+    ),
+    Setting::new(
+        "engine",
+        None,
+        "shards",
+        "uint",
+        Some("1"),
+        "The number of shards, each with its own memtable, WAL and segment files, that keys are \
+         spread over. Fixed when the engine is created.",
+    ),
+    Setting::new(
+        "engine",
+        None,
+        "verify_on_open",
+        "bool",
+        Some("false"),
+        "Whether every segment file is checked for damage when the engine opens, refusing to \
+         open if any is found, or quarantining it.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "databases",
+        "uint",
+        Some("1"),
+        "The number of numbered databases, each with its own engine, that connections can \
+         SELECT between.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "followers",
+        "uint",
+        Some("0"),
+        "The number of followers that the server leads, which a majority of write \
+         acknowledgements is counted out of.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "follower_name",
+        "string",
+        None,
+        "The name that a follower gives its leader, which hints the writes that it misses.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "hint_max_bytes",
+        "size",
+        Some("64MB"),
+        "The most bytes of hints that a leader keeps for each disconnected follower.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "hint_window",
+        "duration",
+        Some("3h"),
+        "How long a leader hints the writes that a disconnected follower misses.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "path",
+        "string",
+        Some("./data"),
+        "The directory that the server keeps its data in.",
+    ),
+    Setting {
+        secret: true,
+        ..Setting::new(
+            "kv",
+            None,
+            "password",
+            "string",
+            None,
+            "The password that connections must AUTH with before running other commands.",
+        )
+    },
+    Setting::new("kv", None, "port", "uint", Some("6210"), "The port that the server listens on."),
+    Setting::new(
+        "kv",
+        None,
+        "raft_id",
+        "uint",
+        None,
+        "The id of this server in its Raft cluster, when it is clustered.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "raft_members",
+        "string",
+        None,
+        "Every member of the Raft cluster, as id=host:port pairs separated by commas.",
+    ),
+    Setting {
+        secret: true,
+        ..Setting::new(
+            "kv",
+            None,
+            "raft_secret",
+            "string",
+            None,
+            "The secret that Raft cluster members authenticate each other with.",
+        )
+    },
+    Setting::new(
+        "kv",
+        None,
+        "repair_interval",
+        "duration",
+        Some("10m"),
+        "How often a follower compares its keys with its leader's and repairs those that differ.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "replicate_from",
+        "string",
+        None,
+        "The host:port of the leader to follow, if this server is a follower.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "replication_backlog",
+        "uint",
+        Some("100000"),
+        "The number of recent writes that the server retains for followers to catch up from.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "script_memory_limit",
+        "size",
+        Some("64MB"),
+        "The most memory that a script run with EVAL can allocate.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "slots",
+        "string",
+        None,
+        "The node that owns each range of slots in a slot cluster, as start-end=host:port pairs \
+         separated by commas.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "slot_address",
+        "string",
+        None,
+        "The address of this server in CRUNCH_KV__SLOTS. Defaults to 127.0.0.1 and the port.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "write_acks",
+        "local|leader+1|majority",
+        Some("local"),
+        "How many servers must have applied a write before the leader acknowledges it.",
+    ),
+    Setting::new(
+        "kv",
+        None,
+        "write_ack_timeout",
+        "duration",
+        Some("1s"),
+        "How long a write waits for followers to acknowledge it.",
+    ),
+];
+
+/// The value that a [`Config`] gives a setting.
+#[derive(Clone, Debug)]
+pub struct EffectiveValue {
+    pub setting: &'static Setting,
+
+    /// The raw value, or `None` if the setting is unset. The value of a
+    /// secret setting is replaced by `<redacted>`.
+    pub value: Option<String>,
+    pub source: Source,
+}
+
+/// The effective value of every known setting in `config`.
+pub fn effective_values(config: &Config) -> Vec<EffectiveValue> {
+    SETTINGS
+        .iter()
+        .map(|setting| {
+            let (value, source) =
+                match config.lookup(setting.component, setting.namespace, setting.name) {
+                    Some((source, value)) => (Some(value), source),
+                    None => (setting.default.map(str::to_owned), Source::Default),
+                };
+            let value = match value {
+                Some(_) if setting.secret => Some("<redacted>".to_owned()),
+                value => value,
+            };
+            EffectiveValue { setting, value, source }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::env::{ByteSize, FromEnv};
+
+    #[test]
+    fn defaults_parse() {
+        let mut names = std::collections::HashSet::new();
+        for setting in SETTINGS {
+            assert!(names.insert(setting.dotted_name()), "{} is registered twice", setting.name);
+            let Some(default) = setting.default else { continue };
+            let parsed = match setting.kind {
+                "bool" => bool::from_env(default).map(drop),
+                "uint" => u64::from_env(default).map(drop),
+                "duration" => Duration::from_env(default).map(drop),
+                "size" => ByteSize::from_env(default).map(drop),
+                "string" | "hex" => PathBuf::from_env(default).map(drop),
+                kind if kind.contains('|') => match kind.split('|').any(|choice| choice == default)
+                {
+                    true => Ok(()),
+                    false => Err(anyhow::anyhow!("{default} isn't one of {kind}")),
+                },
+                kind => panic!("{} has an unknown kind {kind}", setting.name),
+            };
+            assert!(parsed.is_ok(), "the default of {} doesn't parse", setting.name);
+        }
+    }
+
+    #[test]
+    fn effective_values() {
+        let mut config = Config::from_toml("[engine.store]\nwal_sync = false").unwrap();
+        config.set_override("kv.password=hunter2").unwrap();
+        let values = super::effective_values(&config);
+        let find = |name: &str| {
+            let value = values.iter().find(|value| value.setting.dotted_name() == name).unwrap();
+            (value.value.as_deref(), value.source)
+        };
+        assert_eq!(find("engine.store.wal_sync"), (Some("false"), Source::File));
+        assert_eq!(find("engine.memtable.capacity"), (Some("1024"), Source::Default));
+        assert_eq!(find("kv.password"), (Some("<redacted>"), Source::Override));
+        assert_eq!(find("kv.raft_id"), (None, Source::Default));
+    }
+}
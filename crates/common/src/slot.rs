@@ -0,0 +1,49 @@
+//! The hash slots that a slot cluster splits the keyspace into, which the
+//! server and client have to agree on.
+
+/// The number of hash slots, which are numbered from 0 up to this.
+pub const SLOT_COUNT: u16 = 16384;
+
+/// The slot that `key` belongs to.
+///
+/// If the key holds a `{`, followed later by a `}` with something between
+/// them, only that part of it is hashed, so that keys like `{user:1}:name` and
+/// `{user:1}:email` are kept together on one node.
+pub fn key_slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key).unwrap_or(key)) % SLOT_COUNT
+}
+
+fn hash_tag(key: &[u8]) -> Option<&[u8]> {
+    let start = key.iter().position(|&byte| byte == b'{')? + 1;
+    let length = key[start..].iter().position(|&byte| byte == b'}')?;
+    (length > 0).then(|| &key[start..start + length])
+}
+
+/// The CRC-16/XMODEM checksum of `data`.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(super::key_slot(b"foo"), 12182);
+        assert_eq!(super::key_slot(b"bar"), 5061);
+        assert_eq!(super::key_slot(b"{user:1}:name"), super::key_slot(b"user:1"));
+        assert_eq!(super::key_slot(b"{user:1}:email"), super::key_slot(b"user:1"));
+        // An empty tag doesn't count, so the whole key is hashed.
+        assert_eq!(super::key_slot(b"{}:name"), crc16(b"{}:name") % SLOT_COUNT);
+        assert_eq!(super::key_slot(b"{unclosed"), crc16(b"{unclosed") % SLOT_COUNT);
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+use crunch_common::config::Config;
+use crunch_engine::encryption::Cipher;
+use crunch_engine::repair;
+use crunch_engine::store::StoreArgs;
+
+/// Checks a Crunch store directory for damage, without opening it, and can
+/// repair it by salvaging what is readable
+///
+/// The encryption key of an encrypted store is read from the same settings as
+/// the engine's. Stop anything that has the store open before running this.
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// The store directory
+    path: PathBuf,
+
+    /// Rebuild a consistent store out of the readable entries, if any damage
+    /// is found. Damaged segment files are left in the directory, but are no
+    /// longer part of the store
+    #[arg(long)]
+    repair: bool,
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    env_logger::init();
+    let cli = Cli::parse();
+    let args = StoreArgs::from_config(&Config::default());
+    let cipher = args.encryption.as_deref().map(Cipher::from_provider).transpose()?;
+
+    let report = repair::check(&cli.path, cipher.as_ref())?;
+    println!(
+        "Checked {} segment files ({} entries) and {} WAL files",
+        report.segment_files, report.entries, report.wal_files
+    );
+    for path in &report.unlisted_segments {
+        println!("Note: {path:?} isn't listed in the manifest, so it isn't part of the store");
+    }
+    if report.is_healthy() {
+        println!("No problems found");
+        return Ok(ExitCode::SUCCESS);
+    }
+    for problem in &report.problems {
+        println!("Problem: {problem}");
+    }
+    if !cli.repair {
+        println!("Run again with --repair to salvage what is readable");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let repairs = repair::repair(&cli.path, cipher.as_ref())?;
+    if repairs.manifest_rebuilt {
+        println!("Rebuilt the manifest from the segment files in the directory");
+    }
+    for (damaged, salvaged) in &repairs.salvaged_segments {
+        match salvaged {
+            Some(salvaged) => {
+                println!("Salvaged the readable entries of {damaged:?} to {salvaged:?}")
+            },
+            None => println!("Dropped {damaged:?}, which had no readable entries"),
+        }
+    }
+    for path in &repairs.dropped_segments {
+        println!("Dropped missing segment file {path:?}");
+    }
+    if repairs.wal_dropped_bytes > 0 {
+        println!("Truncated the WAL, dropping {} bytes", repairs.wal_dropped_bytes);
+    }
+    let report = repair::check(&cli.path, cipher.as_ref())?;
+    for problem in &report.problems {
+        println!("Problem remaining: {problem}");
+    }
+    Ok(if report.is_healthy() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
@@ -0,0 +1,303 @@
+//! Backups of a store to somewhere off the host, taken with
+//! [`Engine::backup`](crate::engine::Engine::backup).
+//!
+//! A backup is a copy of the files that make up the store, uploaded under a
+//! name of the caller's choosing: every live segment file, the WAL files that
+//! haven't been flushed yet, and the manifest. They are captured at a single
+//! point in time, while writes are paused, and laid out as they are in the
+//! store directory, so the files under a backup's name can be opened as a
+//! store in their own right. The manifest is uploaded last, so a backup that
+//! has one is complete.
+//!
+//! Files are uploaded as they are on disk, so the backup of an encrypted
+//! store can only be opened with its key. Secondary indexes aren't backed up,
+//! since they are rebuilt when they are registered with a restored engine.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+
+use crate::error::Error;
+use crate::segment::SegmentFile;
+
+/// Somewhere that backups can be uploaded to.
+pub trait BackupTarget: Send + Sync {
+    /// Store `contents`, which are `length` bytes long, at `path`, replacing
+    /// anything that is already there. Paths are made of `/` separated parts.
+    fn put(&self, path: &str, contents: &mut dyn Read, length: u64) -> Result<(), Error>;
+}
+
+/// A directory on a local or mounted filesystem, with backups in
+/// subdirectories of it.
+pub struct LocalDirectory {
+    root: PathBuf,
+}
+
+impl LocalDirectory {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl BackupTarget for LocalDirectory {
+    fn put(&self, path: &str, contents: &mut dyn Read, length: u64) -> Result<(), Error> {
+        let path = self.root.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Written to the side first, so that a file that exists is complete.
+        let temp_path = path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        let copied = io::copy(contents, &mut file)?;
+        if copied != length {
+            return Err(anyhow!("expected {length} bytes for {path:?}, but read {copied}").into());
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        if let Some(parent) = path.parent() {
+            crate::util::sync_directory(parent)?;
+        }
+        Ok(())
+    }
+}
+
+/// What [`Engine::backup`](crate::engine::Engine::backup) uploaded.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BackupReport {
+    pub segment_files: usize,
+    pub wal_files: usize,
+
+    /// The combined size of every file uploaded, including the manifest.
+    pub bytes: u64,
+}
+
+/// The files of a store at a point in time, from
+/// [`Store::snapshot_files`](crate::store::Store::snapshot_files).
+pub struct StoreSnapshot {
+    /// The live segment files, each with its filename. They are pinned, so
+    /// they stay on disk even if compaction replaces them.
+    pub(crate) segments: Vec<(String, Arc<SegmentFile>)>,
+
+    /// The contents of the unflushed WAL files, each with its filename. These
+    /// are read upfront, since the active file keeps growing and flushed ones
+    /// are removed.
+    pub(crate) wal: Vec<(String, Vec<u8>)>,
+    pub(crate) manifest: Vec<u8>,
+}
+
+impl StoreSnapshot {
+    /// Upload every file in the snapshot to `target`, under `name`.
+    pub fn upload(&self, target: &dyn BackupTarget, name: &str) -> Result<BackupReport, Error> {
+        let mut report = BackupReport::default();
+        for (filename, segment) in &self.segments {
+            let mut file = File::open(segment.path())?;
+            let length = file.metadata()?.len();
+            target.put(&format!("{name}/{filename}"), &mut file, length)?;
+            report.segment_files += 1;
+            report.bytes += length;
+        }
+        let buffers = self.wal.iter().map(|(filename, contents)| (filename.as_str(), contents));
+        let manifest = [(crate::manifest::MANIFEST_FILENAME, &self.manifest)];
+        for (filename, contents) in buffers.chain(manifest) {
+            let length = contents.len() as u64;
+            target.put(&format!("{name}/{filename}"), &mut contents.as_slice(), length)?;
+            report.bytes += length;
+        }
+        report.wal_files = self.wal.len();
+        log::info!("uploaded backup {name}: {report:?}");
+        Ok(report)
+    }
+}
+
+/// Where an [`S3Target`] uploads to, and the credentials it signs requests
+/// with.
+#[cfg(feature = "s3")]
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// The base URL of the service, like `https://s3.us-east-1.amazonaws.com`
+    /// or that of any S3-compatible store.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+
+    /// Put in front of the path of every object, like `backups/`.
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+/// A bucket in Amazon S3 or an S3-compatible object store, addressed by path,
+/// with requests signed with AWS Signature Version 4.
+///
+/// Bodies are streamed rather than hashed upfront, so the endpoint should use
+/// HTTPS.
+#[cfg(feature = "s3")]
+pub struct S3Target {
+    config: S3Config,
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "s3")]
+impl S3Target {
+    pub fn new(config: S3Config) -> Self {
+        Self { config, agent: ureq::Agent::new() }
+    }
+}
+
+#[cfg(feature = "s3")]
+impl BackupTarget for S3Target {
+    fn put(&self, path: &str, contents: &mut dyn Read, length: u64) -> Result<(), Error> {
+        let config = &self.config;
+        let endpoint = config.endpoint.trim_end_matches('/');
+        let host = endpoint.split_once("://").map_or(endpoint, |(_, host)| host);
+        let object = format!("/{}/{}{path}", config.bucket, config.prefix);
+        let object = s3::uri_encode_path(&object);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|error| anyhow!("the clock is before 1970: {error}"))?;
+        let timestamp = s3::amz_timestamp(now.as_secs());
+        let authorization = s3::authorization(config, host, &object, &timestamp);
+        let response = self
+            .agent
+            .put(&format!("{endpoint}{object}"))
+            .set("Authorization", &authorization)
+            .set("Content-Length", &length.to_string())
+            .set("x-amz-content-sha256", s3::UNSIGNED_PAYLOAD)
+            .set("x-amz-date", &timestamp)
+            .send(contents);
+        match response {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                Err(anyhow!("uploading {object} failed with status {status}: {body}").into())
+            },
+            Err(error) => Err(anyhow!("uploading {object} failed: {error}").into()),
+        }
+    }
+}
+
+/// Signing for [`S3Target`].
+#[cfg(feature = "s3")]
+mod s3 {
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    use super::S3Config;
+
+    /// Sent in place of the hash of a body, so that it can be streamed.
+    pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+    const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+    /// The `Authorization` header of a PUT of the object at `path` on `host`,
+    /// made at `timestamp`.
+    pub fn authorization(config: &S3Config, host: &str, path: &str, timestamp: &str) -> String {
+        let date = &timestamp[..8];
+        let scope = format!("{date}/{}/s3/aws4_request", config.region);
+        let canonical_request = format!(
+            "PUT\n{path}\n\nhost:{host}\nx-amz-content-sha256:{UNSIGNED_PAYLOAD}\nx-amz-date:\
+             {timestamp}\n\n{SIGNED_HEADERS}\n{UNSIGNED_PAYLOAD}"
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = hmac(format!("AWS4{}", config.secret_access_key).as_bytes(), date);
+        for part in [config.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature = hex(&hmac(&key, &string_to_sign));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={SIGNED_HEADERS}, \
+             Signature={signature}",
+            config.access_key_id
+        )
+    }
+
+    fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Percent-encode everything in `path` other than unreserved characters
+    /// and `/`.
+    pub fn uri_encode_path(path: &str) -> String {
+        let mut encoded = String::with_capacity(path.len());
+        for byte in path.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                    encoded.push(byte as char);
+                },
+                _ => encoded.push_str(&format!("%{byte:02X}")),
+            }
+        }
+        encoded
+    }
+
+    /// The `YYYYMMDDTHHMMSSZ` form of the UTC time `seconds` after the epoch.
+    pub fn amz_timestamp(seconds: u64) -> String {
+        let (days, time) = (seconds / 86400, seconds % 86400);
+        // Howard Hinnant's days_from_civil, run backwards.
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        format!(
+            "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+            time / 3600,
+            time % 3600 / 60,
+            time % 60
+        )
+    }
+
+    #[cfg(test)]
+    mod test {
+        #[test]
+        fn amz_timestamp() {
+            assert_eq!(super::amz_timestamp(0), "19700101T000000Z");
+            assert_eq!(super::amz_timestamp(1369353600), "20130524T000000Z");
+            assert_eq!(super::amz_timestamp(951782400 + 3661), "20000229T010101Z");
+        }
+
+        #[test]
+        fn uri_encode_path() {
+            assert_eq!(
+                super::uri_encode_path("/bucket/a b/segment-1.dat"),
+                "/bucket/a%20b/segment-1.dat"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::StoreFixture;
+
+    #[test]
+    fn local_directory() {
+        let fixture = StoreFixture::init("./test-db-backup-local-directory");
+        let target = LocalDirectory::new(fixture.path());
+        target.put("nightly/MANIFEST", &mut "contents".as_bytes(), 8).unwrap();
+        assert_eq!(
+            fs::read_to_string(fixture.path().join("nightly/MANIFEST")).unwrap(),
+            "contents"
+        );
+        assert!(target.put("nightly/other", &mut "short".as_bytes(), 8).is_err());
+        assert!(!fixture.path().join("nightly/other").exists());
+    }
+}
@@ -0,0 +1,40 @@
+use crate::segment::Entry;
+
+/// A group of writes that are applied to the engine atomically, with
+/// [`Engine::apply`](crate::engine::Engine::apply).
+///
+/// Either every write in the batch survives a crash, or none of them do.
+/// Writes are applied in the order that they were added, so a later write to a
+/// key wins over an earlier one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteBatch {
+    entries: Vec<Entry>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `key` to `value`.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.entries.push(Entry::Assignment { key: key.into(), value: value.into() });
+    }
+
+    /// Delete `key`.
+    pub fn delete(&mut self, key: impl Into<String>) {
+        self.entries.push(Entry::Tombstone { key: key.into() });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+}
@@ -0,0 +1,78 @@
+//! The bloom filters that let lookups skip segment files which can't hold a
+//! key.
+//!
+//! Filters are rebuilt from a segment's keys whenever it is opened, and never
+//! written to disk, so the hashes only need to be stable for as long as the
+//! process runs. They are keyed with fixed keys rather than random ones, which
+//! means building a filter doesn't need a source of randomness, and works on
+//! targets like WASI that don't give the usual one.
+
+use std::f64::consts::LN_2;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Bounds how many hash functions a filter uses, however low the false
+/// positive rate asked for.
+const MAX_HASHES: u32 = 200;
+
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    bit_count: u64,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    /// A filter sized to have a false positive rate of `rate` once it holds
+    /// `expected_items` items.
+    pub fn with_rate(rate: f32, expected_items: u32) -> Self {
+        let items = expected_items.max(1) as f64;
+        let bit_count = ((items * (1.0 / rate as f64).ln() / (LN_2 * LN_2)).round() as u64).max(1);
+        let hash_count = ((bit_count as f64 / items * LN_2).round() as u32).clamp(2, MAX_HASHES);
+        Self { bits: vec![0; bit_count.div_ceil(64) as usize], bit_count, hash_count }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        for bit in self.bits_of(item) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether `item` may have been inserted. It can be wrong about one that
+    /// wasn't, but never about one that was.
+    pub fn contains<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        self.bits_of(item).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits that stand for `item`, from two hashes of it combined in a
+    /// different way for each hash function.
+    fn bits_of<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = u64> {
+        let mut first = DefaultHasher::new();
+        item.hash(&mut first);
+        let first = first.finish();
+        let mut second = DefaultHasher::new();
+        second.write_u64(0x9E3779B97F4A7C15);
+        item.hash(&mut second);
+        let second = second.finish();
+        let bit_count = self.bit_count;
+        (0..self.hash_count as u64)
+            .map(move |i| first.wrapping_add(i.wrapping_mul(second)) % bit_count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn false_positive_rate() {
+        let mut filter = BloomFilter::with_rate(0.01, 1000);
+        for n in 0..1000 {
+            filter.insert(&format!("key{n}"));
+        }
+        assert!((0..1000).all(|n| filter.contains(&format!("key{n}"))));
+        let false_positives = (0..10_000).filter(|n| filter.contains(&format!("other{n}"))).count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        let empty = BloomFilter::with_rate(0.01, 0);
+        assert!(!empty.contains("key"));
+    }
+}
@@ -0,0 +1,175 @@
+//! Opening an engine through a chain of options, from
+//! [`Engine::builder`](crate::engine::Engine::builder).
+//!
+//! ```no_run
+//! # use crunch_engine::engine::Engine;
+//! let engine = Engine::builder("data").memtable_capacity(4096).compaction(false).open()?;
+//! # Ok::<(), crunch_engine::error::Error>(())
+//! ```
+//!
+//! Every option starts out as it would be read from the environment, so a
+//! builder only has to name the ones it changes. New settings get new
+//! methods, which leaves code that uses a builder working as they are added,
+//! where code that fills in [`EngineArgs`] by hand has to be updated for each
+//! one.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use crunch_common::config::Config;
+
+use crate::encryption::KeyProvider;
+use crate::engine::{Engine, EngineArgs, WriteLimits};
+use crate::error::Error;
+use crate::events::EventListener;
+use crate::segment::Compression;
+use crate::store::StoreArgs;
+
+/// The options to open an engine with. See the [module docs](self).
+#[must_use]
+pub struct EngineBuilder {
+    path: PathBuf,
+    args: EngineArgs,
+
+    /// Whether compaction was asked for, rather than being on by default,
+    /// which a read-only engine can't have.
+    compaction: Option<bool>,
+}
+
+impl EngineBuilder {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path, args: EngineArgs::from_config(&Config::default()), compaction: None }
+    }
+
+    /// The number of keys that the memtable holds before it is flushed.
+    pub fn memtable_capacity(mut self, capacity: usize) -> Self {
+        self.args.memtable.capacity = capacity;
+        self
+    }
+
+    /// Whether segment files are compacted together in the background.
+    pub fn compaction(mut self, enabled: bool) -> Self {
+        self.compaction = Some(enabled);
+        self
+    }
+
+    /// Whether writes are refused, as with [`EngineArgs::read_only`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.args.read_only = read_only;
+        self
+    }
+
+    /// Whether every write to the WAL is fsynced before it is acknowledged.
+    pub fn wal_sync(mut self, sync: bool) -> Self {
+        self.args.store.wal_sync = sync;
+        self
+    }
+
+    /// Encrypt the engine's files with the key from `provider`.
+    pub fn encryption(mut self, provider: Arc<dyn KeyProvider>) -> Self {
+        self.args.store.encryption = Some(provider);
+        self
+    }
+
+    /// How large values are compressed, or `None` to store them as they are.
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.args.store.compression = compression;
+        self
+    }
+
+    /// The most versions of each key to keep, counting the current one.
+    pub fn retained_versions(mut self, versions: usize) -> Self {
+        self.args.store.retained_versions = versions;
+        self
+    }
+
+    /// The number of shards to split the engine into.
+    pub fn shards(mut self, count: usize) -> Self {
+        self.args.shards = count;
+        self
+    }
+
+    /// The most bytes of keys and values that the row cache holds, or 0 for
+    /// no row cache.
+    pub fn row_cache_capacity(mut self, bytes: usize) -> Self {
+        self.args.row_cache.capacity = bytes;
+        self
+    }
+
+    pub fn write_limits(mut self, limits: WriteLimits) -> Self {
+        self.args.write_limits = limits;
+        self
+    }
+
+    /// Notify `listener` about writes and background work, along with any
+    /// listeners added before it.
+    pub fn listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.args.listeners.push(listener);
+        self
+    }
+
+    /// Change the store settings that don't have a method of their own.
+    pub fn store(mut self, configure: impl FnOnce(&mut StoreArgs)) -> Self {
+        configure(&mut self.args.store);
+        self
+    }
+
+    /// Check that the options make sense together, and open the engine with
+    /// them.
+    pub fn open(mut self) -> Result<Engine, Error> {
+        let args = &mut self.args;
+        if args.memtable.capacity == 0 {
+            return Err(anyhow!("the memtable capacity must be at least 1").into());
+        }
+        if args.read_only && self.compaction == Some(true) {
+            return Err(anyhow!("a read-only engine can't compact").into());
+        }
+        if args.shards > 1 && args.store.retained_versions > 1 {
+            return Err(anyhow!("a sharded engine can't retain versions").into());
+        }
+        if let Some(enabled) = self.compaction {
+            args.store.compaction_enabled = enabled;
+        }
+        Engine::with_args(self.path, self.args)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::StoreFixture;
+
+    #[test]
+    fn open() {
+        let fixture = StoreFixture::init("./test-db-builder-open");
+        let engine = Engine::builder(fixture.path())
+            .memtable_capacity(2)
+            .compaction(false)
+            .row_cache_capacity(1024)
+            .open()
+            .unwrap();
+        for key in ["a", "b", "c"] {
+            engine.set(key, "1").unwrap();
+        }
+        let stats = engine.stats().unwrap();
+        assert_eq!((stats.memtable_capacity, stats.store.segment_count()), (2, 1));
+        engine.stop().unwrap();
+
+        let engine = Engine::builder(fixture.path()).read_only(true).open().unwrap();
+        assert_eq!(engine.get("c").unwrap().as_deref(), Some("1"));
+        assert!(engine.set("d", "1").is_err());
+        assert!(engine.delete("a").is_err());
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn invalid_options() {
+        let fixture = StoreFixture::init("./test-db-builder-invalid-options");
+        let builder = || Engine::builder(fixture.path());
+        assert!(builder().memtable_capacity(0).open().is_err());
+        assert!(builder().read_only(true).compaction(true).open().is_err());
+        assert!(builder().shards(2).retained_versions(2).open().is_err());
+        assert!(Engine::builder(fixture.path().join("missing")).read_only(true).open().is_err());
+    }
+}
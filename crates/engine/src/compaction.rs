@@ -1,136 +1,916 @@
-use std::collections::VecDeque;
+use std::collections::{BinaryHeap, VecDeque};
 use std::fs::{self, File, OpenOptions};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
-use std::{cmp, thread};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use std::{cmp, io, mem};
 
-use crate::segment::EntryIter;
+use crate::encryption::{Cipher, Reader, Writer};
+use crate::error::Error;
+use crate::events::{CompactionInfo, Listeners};
+use crate::io::IoBackend;
+use crate::metrics::Metrics;
+use crate::quarantine::quarantine_segment;
+use crate::rate_limiter::RateLimiter;
+use crate::segment::{self, segment_filename, Compression, Entry, EntryIter, SegmentHandle};
+use crate::store::SegmentSet;
+use crate::util::sync_directory;
 
+/// The level that compaction output is placed on.
+const COMPACTED_LEVEL: u32 = 1;
+
+/// Thresholds on the size of the segment set that, once crossed, wake the
+/// compaction loop early instead of waiting for its next interval.
+#[derive(Debug)]
+pub struct CompactionTrigger {
+    /// The number of live segment files.
+    pub segment_count: usize,
+
+    /// The combined size of the live segment files, in bytes.
+    pub bytes: u64,
+}
+
+impl CompactionTrigger {
+    pub fn is_tripped(&self, segments: &SegmentSet) -> bool {
+        segments.handles.len() >= self.segment_count || segments.total_bytes() >= self.bytes
+    }
+}
+
+/// Measurements taken from a single compaction.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompactionStats {
+    /// The number of segment files that were merged.
+    pub input_files: usize,
+
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+
+    /// The number of entries that were shadowed by a newer entry for the same
+    /// key, or were tombstones with nothing older left to delete, and so were
+    /// left out of the output.
+    pub entries_dropped: u64,
+
+    pub duration: Duration,
+}
+
+impl CompactionStats {
+    fn add(&mut self, other: &Self) {
+        self.input_files += other.input_files;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.entries_dropped += other.entries_dropped;
+        self.duration += other.duration;
+    }
+}
+
+/// Statistics about every compaction that a store has run since it was opened.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionHistory {
+    /// The number of compactions that have finished.
+    pub compactions: u64,
+
+    /// The sum of the stats of every finished compaction.
+    pub totals: CompactionStats,
+
+    /// The stats of the most recently finished compaction.
+    pub last: Option<CompactionStats>,
+
+    /// When the most recent compaction finished.
+    pub last_finished_at: Option<SystemTime>,
+}
+
+impl CompactionHistory {
+    pub fn record(&mut self, stats: CompactionStats) {
+        self.compactions += 1;
+        self.totals.add(&stats);
+        self.last = Some(stats);
+        self.last_finished_at = Some(SystemTime::now());
+    }
+}
+
+/// Settings for the compaction loop, taken from [`StoreArgs`].
+///
+/// [`StoreArgs`]: crate::store::StoreArgs
+#[derive(Debug)]
+pub struct CompactionArgs {
+    pub interval: Duration,
+
+    /// The most segment files that a single compaction will merge together.
+    pub max_inputs: usize,
+
+    /// The most bytes per second that compaction will read or write, combined.
+    /// Zero means unlimited.
+    pub bytes_per_second: u64,
+
+    /// Decrypts the inputs and encrypts the output, if the store is encrypted.
+    pub cipher: Option<Cipher>,
+
+    /// How values in the output are compressed.
+    pub compression: Option<Compression>,
+
+    /// The most versions of each key to keep in the output, counting the
+    /// current one.
+    pub retained_versions: usize,
+
+    /// How many leading bytes of each key go into the output's prefix bloom
+    /// filter, or 0 for none.
+    pub prefix_bloom_length: usize,
+
+    /// What lookups and scans read the output with.
+    pub io: Arc<dyn IoBackend>,
+
+    /// Whether an input that is found to be corrupt is quarantined, and
+    /// counted in `metrics`.
+    pub quarantine_corrupt_segments: bool,
+    pub metrics: Arc<Metrics>,
+}
+
+/// A set of segment files chosen to be merged together.
+#[derive(Debug)]
+pub struct CompactionPlan {
+    /// The segment files to merge, oldest first.
+    pub inputs: Vec<PlannedInput>,
+
+    /// Whether the inputs start with the oldest segment file in the store, so
+    /// that no file older than the output holds any of its keys.
+    pub bottom: bool,
+}
+
+#[derive(Debug)]
+pub struct PlannedInput {
+    pub path: PathBuf,
+    pub sequence: u64,
+}
+
+impl CompactionPlan {
+    /// Plan a compaction of the run of (up to) `max_inputs` segment files in
+    /// `segments` that holds the most dead entries, or return `None` if there
+    /// are fewer than two of them.
+    ///
+    /// A run is scored by the overwrite counts of all but its oldest segment,
+    /// which are the keys whose older entries it may hold, and so drop. The
+    /// run that starts with the oldest segment also counts the tombstones in
+    /// all of its segments, since its output has nothing older left for them
+    /// to delete, and so drops them too. Ties go to the oldest run, so a store
+    /// without dead data compacts its oldest segment files first, and one with
+    /// a lot of overwrites or deletes gets to them sooner.
+    ///
+    /// The inputs are always a run of segments that are adjacent in sequence
+    /// order. If a segment between two inputs were skipped, the merged output
+    /// would hold data both older and newer than it, and there would be no
+    /// position in the set that could keep newest-wins lookups correct.
+    pub fn pick(segments: &SegmentSet, max_inputs: usize) -> Option<Self> {
+        let mut handles: Vec<_> = segments.handles.iter().collect();
+        handles.sort_by_key(|segment| segment.sequence());
+        let length = max_inputs.min(handles.len());
+        if length < 2 {
+            return None;
+        }
+        let dead = |start: usize, run: &[&SegmentHandle]| {
+            let overwrites =
+                run[1..].iter().map(|segment| segment.overwrite_count() as u64).sum::<u64>();
+            let tombstones = match start {
+                0 => run.iter().map(|segment| segment.tombstone_count() as u64).sum(),
+                _ => 0,
+            };
+            overwrites + tombstones
+        };
+        let (start, _) = handles
+            .windows(length)
+            .enumerate()
+            .map(|(start, run)| (start, dead(start, run)))
+            .fold((0, 0), |best, (start, dead)| if dead > best.1 { (start, dead) } else { best });
+        let inputs = handles[start..start + length]
+            .iter()
+            .map(|segment| PlannedInput {
+                path: segment.path().to_owned(),
+                sequence: segment.sequence(),
+            })
+            .collect();
+        Some(Self { inputs, bottom: start == 0 })
+    }
+
+    /// The sequence of the merged output. It holds the newest data of any
+    /// input, so it takes the newest sequence among them.
+    pub fn output_sequence(&self) -> u64 {
+        self.inputs.iter().map(|input| input.sequence).max().unwrap_or_default()
+    }
+}
+
+/// Compact the oldest segment files together every `args.interval`,
+/// or whenever a message arrives on `wakeups`.
 pub fn compaction_loop(
-    interval_seconds: u64,
+    args: CompactionArgs,
     path: PathBuf,
-    segments: Arc<RwLock<VecDeque<PathBuf>>>,
+    segments: Arc<RwLock<SegmentSet>>,
+    wakeups: Receiver<()>,
+    listeners: Listeners,
+    history: Arc<Mutex<CompactionHistory>>,
     compaction_kill_flag: Arc<AtomicBool>,
 ) {
+    // This lives across compactions so that back-to-back runs can't exceed the
+    // limit between them.
+    let mut rate_limiter = RateLimiter::new(args.bytes_per_second);
     let mut last_compact_at = Instant::now();
     while !compaction_kill_flag.load(Ordering::Relaxed) {
-        if last_compact_at.elapsed().as_secs() >= interval_seconds {
-            let segments_read = segments.read().expect("segments lock is poisoned");
-            if segments_read.len() >= 2 {
-                let first = &segments_read[0];
-                let second = &segments_read[1];
-                log::debug!("starting compaction of {first:?} and {second:?}");
-                let mut first = File::open(first).expect("failed to open first segment file");
-                let mut second = File::open(second).expect("failed to open second segment file");
-                let new_segment_path = path.clone().join("new-segment.dat");
-                compact(&mut first, &mut second, new_segment_path.clone());
-
-                // This explicit drop is pivotal to avoid deadlocks, otherwise the write lock
-                // on the following line can not be acquired.
-                drop(segments_read);
-
-                // This separate swaperoo step is so that we only need to hold a *read* lock on
-                // the segment buffer when doing the compaction, and those files can continue to
-                // service read requests on the engine thread.
-                //
-                // TODO: Don't need to acquire a write lock over the whole buffer for this
-                // section. We only need write locks on the two original segment files until the
-                // new one is swapped in. We still need a write lock on the buffer for the final
-                // `pop_front`, but the runtime of that is very short.
+        let woken = match wakeups.recv_timeout(Duration::from_secs(1)) {
+            Ok(()) => {
+                // A burst of flushes may have sent several wakeups, but one compaction
+                // handles all of them.
+                while wakeups.try_recv().is_ok() {}
+                true
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if compaction_kill_flag.load(Ordering::Relaxed) {
+            break;
+        }
+        if woken || last_compact_at.elapsed() >= args.interval {
+            // Only the compactor retires segments, and flushes only ever append them, so
+            // the planned inputs stay in the set and their files stay on disk
+            // for the duration of the merge without a lock having to be held.
+            // That lets flushes and reads continue while the compaction runs. The
+            // one exception is a corrupt input being quarantined, which the
+            // compaction gives up on.
+            let plan = CompactionPlan::pick(
+                &segments.read().expect("segments lock is poisoned"),
+                args.max_inputs,
+            );
+            if let Some(plan) = plan {
+                log::debug!("starting compaction of {:?}", plan.inputs);
+                let inputs: Result<Vec<_>, _> = plan
+                    .inputs
+                    .iter()
+                    .map(|input| {
+                        CompactionInput::open(
+                            input.path.clone(),
+                            input.sequence,
+                            args.cipher.as_ref(),
+                            args.io.clone(),
+                        )
+                    })
+                    .collect();
+                let mut inputs = match inputs {
+                    Ok(inputs) => inputs,
+                    Err(error) if args.quarantine_corrupt_segments => {
+                        log::warn!("skipped a compaction, since an input can't be opened: {error}");
+                        last_compact_at = Instant::now();
+                        continue;
+                    },
+                    Err(error) => panic!("failed to open input segment file: {error}"),
+                };
+                let new_segment_id =
+                    segments.write().expect("segments lock is poisoned").allocate_id();
+                let temp_segment_path = path.join(compaction_temp_filename(new_segment_id));
+                let new_segment_path = path.join(segment_filename(new_segment_id));
+                let info = CompactionInfo {
+                    inputs: plan.inputs.iter().map(|input| input.path.clone()).collect(),
+                    output: new_segment_path.clone(),
+                };
+                let started_at = Instant::now();
+                listeners.notify(|listener| listener.on_compaction_started(&info));
+                let output = temp_segment_path.clone();
+                let (new_file, mut stats) = match compact(
+                    &mut inputs,
+                    output,
+                    args.cipher.as_ref(),
+                    args.compression,
+                    args.retained_versions,
+                    plan.bottom,
+                    &mut rate_limiter,
+                ) {
+                    Ok(output) => output,
+                    Err(error) => {
+                        // The inputs stay live, so nothing is lost, but unless the corrupt one
+                        // is quarantined, they are compacted again (and fail again) until the
+                        // corruption is dealt with.
+                        log::error!("compaction of {:?} failed: {error}", info.inputs);
+                        _ = fs::remove_file(&temp_segment_path);
+                        if args.quarantine_corrupt_segments {
+                            quarantine_segment(&path, &segments, &error, &args.metrics, &listeners)
+                                .expect("failed to quarantine corrupt segment file");
+                        }
+                        last_compact_at = Instant::now();
+                        continue;
+                    },
+                };
+                new_file.sync_all().expect("failed to sync new segment file");
+
+                // The new segment only takes its real name once its contents are durable, and
+                // that rename is made durable before the manifest starts referring to it. Until
+                // the manifest is committed, a crash leaves the inputs as the live segments.
+                fs::rename(&temp_segment_path, &new_segment_path)
+                    .expect("failed to rename new segment file");
+                sync_directory(&path).expect("failed to sync store directory");
+                let new_segment = SegmentHandle::open_at_level(
+                    new_segment_path,
+                    COMPACTED_LEVEL,
+                    args.cipher.clone(),
+                    args.prefix_bloom_length,
+                    args.io.clone(),
+                )
+                .expect("failed to open new segment file")
+                .with_sequence(plan.output_sequence());
+
+                // The output takes the place of the inputs, which were adjacent, so the set
+                // stays in sequence order.
                 let mut segments_write = segments.write().expect("segments lock is poisoned");
-                fs::remove_file(&segments_write[0]).expect("failed to delete first segment file");
-                fs::remove_file(&segments_write[1]).expect("failed to delete second segment file");
-                fs::rename(&new_segment_path, &segments_write[1])
-                    .expect("failed to swap in new segment file");
-                segments_write.pop_front();
-                log::debug!("compaction finished");
+                let live = |input: &PathBuf| {
+                    segments_write.handles.iter().any(|segment| segment.path() == input)
+                };
+                if !info.inputs.iter().all(live) {
+                    // An input was quarantined during the merge, so the output holds entries
+                    // that are no longer part of the store.
+                    drop(segments_write);
+                    log::warn!(
+                        "discarding compaction of {:?}, since an input was quarantined",
+                        info.inputs
+                    );
+                    new_segment.retire();
+                    last_compact_at = Instant::now();
+                    continue;
+                }
+                let position = segments_write
+                    .handles
+                    .iter()
+                    .position(|segment| segment.path() == info.inputs[0])
+                    .expect("input segment is missing from the segment set");
+                let (retired, kept): (VecDeque<_>, _) = mem::take(&mut segments_write.handles)
+                    .into_iter()
+                    .partition(|segment| info.inputs.iter().any(|input| segment.path() == input));
+                // The output's overwrites are those of the inputs, less the ones that were
+                // between the inputs and so dropped. With nothing older left, there are none.
+                let overwrites = match position {
+                    0 => 0,
+                    _ => retired
+                        .iter()
+                        .map(|segment| segment.overwrite_count() as u64)
+                        .sum::<u64>()
+                        .saturating_sub(stats.entries_dropped)
+                        .min(u32::MAX as u64) as u32,
+                };
+                segments_write.handles = kept;
+                segments_write
+                    .handles
+                    .insert(position, new_segment.with_overwrite_count(overwrites));
+                segments_write.commit(&path).expect("failed to commit manifest");
+                drop(segments_write);
+
+                // The inputs are no longer referenced by the manifest, so a crash from here on
+                // can only leave behind unreferenced files, never lose data. Each is deleted
+                // now, or once the last iterator that pinned it is dropped.
+                for segment in retired {
+                    segment.retire();
+                    listeners.notify(|listener| listener.on_segment_deleted(segment.path()));
+                }
+                stats.input_files = info.inputs.len();
+                stats.duration = started_at.elapsed();
+                log::debug!("compaction finished: {stats:?}");
+                listeners.notify(|listener| listener.on_compaction_finished(&info, stats.duration));
+                history.lock().expect("compaction history lock is poisoned").record(stats);
             } else {
                 log::debug!("compaction loop ticked, but there was nothing to do");
             }
             last_compact_at = Instant::now();
         }
-        thread::sleep(Duration::from_secs(1));
-    }
-}
-
-fn compact(file1: &mut File, file2: &mut File, path: PathBuf) {
-    let mut new_file = OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .read(true)
-        .open(&path)
-        .expect("failed to create new segment file");
-
-    let mut file1_entries =
-        EntryIter::from_start(file1).expect("failed to initialize first iter").peekable();
-    let mut file2_entries =
-        EntryIter::from_start(file2).expect("failed to initialize second iter").peekable();
-
-    while let (Some(file1_entry), Some(file2_entry)) = (file1_entries.peek(), file2_entries.peek())
-    {
-        match file1_entry.key().cmp(&file2_entry.key()) {
-            cmp::Ordering::Less => {
-                log::trace!("file1 ({file1_entry:?}) -> {path:?}");
-                file1_entry.write(&mut new_file).expect("failed to write to new file");
-                file1_entries.next();
-            },
-            cmp::Ordering::Greater => {
-                log::trace!("file2 ({file2_entry:?}) -> {path:?}");
-                file2_entry.write(&mut new_file).expect("failed to write to new file");
-                file2_entries.next();
-            },
-            cmp::Ordering::Equal => {
-                log::trace!("equal, dedupe ({file2_entry:?}) -> {path:?}");
-                file2_entry.write(&mut new_file).expect("failed to write to new file");
-                file1_entries.next();
-                file2_entries.next();
-            },
+    }
+}
+
+/// The name of the file that compaction output is written to before it is
+/// swapped in as the segment with the given `id`.
+fn compaction_temp_filename(id: u32) -> String {
+    format!("compaction-{id}.tmp")
+}
+
+/// Whether `filename` is compaction output that was never swapped in, from
+/// [`compaction_temp_filename`] or the single name that older versions wrote
+/// all of it to.
+pub fn is_compaction_temp_filename(filename: &str) -> bool {
+    filename == "new-segment.dat"
+        || filename.strip_prefix("compaction-").and_then(|rest| rest.strip_suffix(".tmp")).is_some()
+}
+
+/// A segment file to be merged, along with its sequence.
+struct CompactionInput {
+    path: PathBuf,
+    file: Reader,
+    sequence: u64,
+}
+
+impl CompactionInput {
+    fn open(
+        path: PathBuf,
+        sequence: u64,
+        cipher: Option<&Cipher>,
+        io: Arc<dyn IoBackend>,
+    ) -> Result<Self, io::Error> {
+        Ok(Self { file: Reader::open_with(&path, cipher, io)?, path, sequence })
+    }
+}
+
+/// Merge the entries of `inputs`, which may be in any order, into a new segment
+/// file at `path`, which is encrypted with `cipher` if one is given.
+///
+/// When more than one input contains the same key, the entry from the one with
+/// the highest sequence is kept, along with older versions of the key up to
+/// `retained_versions` in all. With `drop_tombstones`, which is only right
+/// when no file older than the output holds any of its keys, a key whose
+/// newest entry is a tombstone is left out altogether, unless versions are
+/// retained, since the tombstone hides the older versions from current reads.
+/// Every byte read or written is charged to `rate_limiter`.
+///
+/// The returned stats only cover the merge itself; the caller fills in the
+/// rest. If an input is corrupt, the merge stops with an
+/// [`Error::Corruption`], and the output is incomplete.
+fn compact(
+    inputs: &mut [CompactionInput],
+    path: PathBuf,
+    cipher: Option<&Cipher>,
+    compression: Option<Compression>,
+    retained_versions: usize,
+    drop_tombstones: bool,
+    rate_limiter: &mut RateLimiter,
+) -> Result<(File, CompactionStats), Error> {
+    let new_file = OpenOptions::new().create_new(true).write(true).read(true).open(&path)?;
+    let mut new_file = Writer::new(new_file, cipher)?;
+    // The output holds no more than the inputs do, and usually not much less.
+    let mut expected_size = 0;
+    for input in inputs.iter() {
+        expected_size += fs::metadata(&input.path)?.len();
+    }
+    new_file.preallocate(expected_size)?;
+
+    let sequences: Vec<_> = inputs.iter().map(|input| input.sequence).collect();
+    let mut iters = Vec::with_capacity(inputs.len());
+    for input in inputs.iter_mut() {
+        iters.push(EntryIter::from_start(&mut input.file)?.with_path(&input.path));
+    }
+
+    // The heap holds the next unmerged entry of each file, so popping it always
+    // yields the smallest key left across all of them. The entries for a key come
+    // off it newest first, since newer files win ties and each file lists the
+    // versions of a key newest first.
+    let mut heap = BinaryHeap::new();
+    let mut stats = CompactionStats::default();
+    for source in 0..iters.len() {
+        let next = next_merge_entry(&mut iters, source, None, &sequences, rate_limiter)?;
+        if let Some(next) = next {
+            stats.bytes_read += iters[source].last_stride();
+            heap.push(next);
+        }
+    }
+
+    let mut last_key = String::new();
+    let mut versions = 0;
+    while let Some(MergeEntry { entry, source, version, .. }) = heap.pop() {
+        if versions > 0 && last_key == *entry.key() {
+            versions += 1;
+        } else {
+            last_key.clone_from(entry.key());
+            versions = 1;
+        }
+        if versions > retained_versions.max(1) {
+            log::trace!("dedupe, dropping file{source} ({entry:?})");
+            stats.entries_dropped += 1;
+        } else if drop_tombstones
+            && retained_versions <= 1
+            && matches!(entry, Entry::Tombstone { .. })
+        {
+            log::trace!("nothing older to delete, dropping file{source} ({entry:?})");
+            stats.entries_dropped += 1;
+        } else {
+            log::trace!("file{source} ({entry:?}) -> {path:?}");
+            let mut written = 0;
+            if let Some(version) = version {
+                written += segment::write_version(&mut new_file, version)?;
+            }
+            written += entry.write_with(&mut new_file, compression)?;
+            rate_limiter.acquire(written as u64);
+            stats.bytes_written += written as u64;
+        }
+        // The popped entry's buffers are reused for the next one from its file.
+        let next = next_merge_entry(&mut iters, source, Some(entry), &sequences, rate_limiter)?;
+        if let Some(next) = next {
+            stats.bytes_read += iters[source].last_stride();
+            heap.push(next);
         }
     }
 
-    while let Some(entry) = file1_entries.next() {
-        log::trace!("file1 ({entry:?}) -> {path:?}");
-        entry.write(&mut new_file).expect("failed to write to new file");
+    Ok((new_file.finish()?, stats))
+}
+
+/// Read the next entry of the input at index `source`, if it has one left,
+/// into `spare` if it is given.
+fn next_merge_entry(
+    iters: &mut [EntryIter<'_, Reader>],
+    source: usize,
+    spare: Option<Entry>,
+    sequences: &[u64],
+    rate_limiter: &mut RateLimiter,
+) -> Result<Option<MergeEntry>, Error> {
+    let iter = &mut iters[source];
+    let entry = match (iter.next_ref().transpose()?, spare) {
+        (None, _) => return Ok(None),
+        (Some(next), Some(mut spare)) => {
+            next.copy_to(&mut spare);
+            spare
+        },
+        (Some(next), None) => next.to_entry(),
+    };
+    rate_limiter.acquire(iter.last_stride());
+    Ok(Some(MergeEntry {
+        entry,
+        source,
+        sequence: sequences[source],
+        version: iter.last_version(),
+    }))
+}
+
+/// An entry waiting to be merged, tagged with the index and sequence of the
+/// input it came from.
+struct MergeEntry {
+    entry: Entry,
+    source: usize,
+    sequence: u64,
+
+    /// The sequence number the entry was written at, if it is a version.
+    version: Option<u64>,
+}
+
+impl Ord for MergeEntry {
+    /// [`BinaryHeap`] is a max-heap, so this orders the *smallest* key as the
+    /// greatest, breaking ties in favor of the highest sequence.
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        other
+            .entry
+            .key()
+            .cmp(self.entry.key())
+            .then(self.sequence.cmp(&other.sequence))
+            .then(self.source.cmp(&other.source))
+    }
+}
+
+impl PartialOrd for MergeEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    while let Some(entry) = file2_entries.next() {
-        log::trace!("file1 ({entry:?}) -> {path:?}");
-        entry.write(&mut new_file).expect("failed to write to new file");
+impl PartialEq for MergeEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
     }
 }
 
+impl Eq for MergeEntry {}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::segment::Entry;
+    use crate::io::StdIo;
+    use crate::memtable::{Memtable, MemtableArgs};
+    use crate::store::{Store, StoreArgs};
     use crate::test::StoreFixture;
 
+    fn assignments(pairs: impl IntoIterator<Item = (&'static str, &'static str)>) -> Vec<Entry> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| Entry::Assignment { key: key.to_owned(), value: value.to_owned() })
+            .collect()
+    }
+
+    /// Open each of `paths` with the sequence of its position, so that they
+    /// are ordered from oldest to newest.
+    fn in_order(paths: impl IntoIterator<Item = PathBuf>) -> Vec<CompactionInput> {
+        paths
+            .into_iter()
+            .zip(1..)
+            .map(|(path, sequence)| {
+                CompactionInput::open(path, sequence, None, Arc::new(StdIo)).unwrap()
+            })
+            .collect()
+    }
+
+    fn read_all(path: PathBuf) -> Vec<Entry> {
+        let mut file = File::open(path).unwrap();
+        EntryIter::new(&mut file).collect::<Result<_, _>>().unwrap()
+    }
+
     #[test]
     fn compaction() {
         _ = env_logger::try_init();
         let mut fixture = StoreFixture::init("./test-db-compaction");
-        let mut file1 = fixture.create_segment_file([("a", "1"), ("c", "3"), ("e", "5")]);
-        let mut file2 = fixture.create_segment_file([("b", "2"), ("d", "4"), ("f", "6")]);
-        let mut file3 = fixture.create_segment_file([("a", "7"), ("d", "9"), ("e", "8")]);
+        let file1 = fixture.write_segment_file([("a", "1"), ("c", "3"), ("e", "5")]);
+        let file2 = fixture.write_segment_file([("b", "2"), ("d", "4"), ("f", "6")]);
+        let file3 = fixture.write_segment_file([("a", "7"), ("d", "9"), ("e", "8")]);
 
         let new1 = fixture.allocate_segment_file();
-        compact(&mut file1, &mut file2, new1.clone());
-        let mut new1 = File::open(new1).unwrap();
+        compact(
+            &mut in_order([file1, file2]),
+            new1.clone(),
+            None,
+            None,
+            1,
+            false,
+            &mut RateLimiter::unlimited(),
+        )
+        .unwrap();
 
         let new2 = fixture.allocate_segment_file();
-        compact(&mut new1, &mut file3, new2.clone());
-        let mut new2 = File::open(new2).unwrap();
+        compact(
+            &mut in_order([new1, file3]),
+            new2.clone(),
+            None,
+            None,
+            1,
+            false,
+            &mut RateLimiter::unlimited(),
+        )
+        .unwrap();
 
         pretty_assertions::assert_eq!(
-            EntryIter::new(&mut new2).collect::<Vec<_>>(),
-            [("a", "7"), ("b", "2"), ("c", "3"), ("d", "9"), ("e", "8"), ("f", "6")]
-                .into_iter()
-                .map(|(key, value)| {
-                    Entry::Assignment { key: key.to_owned(), value: value.to_owned() }
-                })
-                .collect::<Vec<_>>()
+            read_all(new2),
+            assignments([("a", "7"), ("b", "2"), ("c", "3"), ("d", "9"), ("e", "8"), ("f", "6")])
         );
     }
+
+    #[test]
+    fn compaction_trims_versions() {
+        let mut fixture = StoreFixture::init("./test-db-compaction-versions");
+        let write_versions = |path: &PathBuf, versions: &[(&str, u64, &str)]| {
+            let mut file = File::create_new(path).unwrap();
+            for (key, sequence, value) in versions {
+                segment::write_version(&mut file, *sequence).unwrap();
+                segment::write(&mut file, key, value).unwrap();
+            }
+        };
+        let (old, new) = (fixture.allocate_segment_file(), fixture.allocate_segment_file());
+        write_versions(&old, &[("a", 2, "2"), ("a", 1, "1"), ("b", 3, "3")]);
+        write_versions(&new, &[("a", 5, "5"), ("a", 4, "4")]);
+
+        let output = fixture.allocate_segment_file();
+        let (_, stats) = compact(
+            &mut in_order([old, new]),
+            output.clone(),
+            None,
+            None,
+            3,
+            false,
+            &mut RateLimiter::unlimited(),
+        )
+        .unwrap();
+        assert_eq!(stats.entries_dropped, 1);
+        let mut file = File::open(output).unwrap();
+        let mut entries = EntryIter::new(&mut file);
+        let mut versions = Vec::new();
+        while let Some(entry) = entries.next() {
+            versions.push((entry.unwrap(), entries.last_version()));
+        }
+        let version = |key: &str, value: &str, sequence| {
+            (Entry::Assignment { key: key.to_owned(), value: value.to_owned() }, Some(sequence))
+        };
+        assert_eq!(versions, [
+            version("a", "5", 5),
+            version("a", "4", 4),
+            version("a", "2", 2),
+            version("b", "3", 3)
+        ]);
+    }
+
+    #[test]
+    fn multi_way_compaction() {
+        _ = env_logger::try_init();
+        let mut fixture = StoreFixture::init("./test-db-multi-way-compaction");
+        let mut files = in_order([
+            fixture.write_segment_file([("a", "1"), ("c", "3"), ("e", "5")]),
+            fixture.write_segment_file([("b", "2"), ("c", "4"), ("f", "6")]),
+            fixture.write_segment_file([("a", "7"), ("c", "9")]),
+            fixture.write_segment_file([("e", "8"), ("g", "0")]),
+        ]);
+
+        let new = fixture.allocate_segment_file();
+        let (_, stats) =
+            compact(&mut files, new.clone(), None, None, 1, false, &mut RateLimiter::unlimited())
+                .unwrap();
+
+        pretty_assertions::assert_eq!(
+            read_all(new),
+            assignments([("a", "7"), ("b", "2"), ("c", "9"), ("e", "8"), ("f", "6"), ("g", "0")])
+        );
+        // Every entry is 11 bytes, and "a", "c", "c" and "e" are shadowed.
+        assert_eq!(stats, CompactionStats {
+            bytes_read: 110,
+            bytes_written: 66,
+            entries_dropped: 4,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn bottom_compaction_drops_tombstones() {
+        let mut fixture = StoreFixture::init("./test-db-compaction-tombstones");
+        let old = fixture.write_segment_file([("a", "1"), ("b", "2")]);
+        let new = fixture.allocate_segment_file();
+        let mut file = File::create_new(&new).unwrap();
+        segment::tombstone(&mut file, "a").unwrap();
+        segment::tombstone(&mut file, "c").unwrap();
+        drop(file);
+        let tombstone = |key: &str| Entry::Tombstone { key: key.to_owned() };
+
+        // Without the oldest data, the tombstones still have to hide it.
+        let output = fixture.allocate_segment_file();
+        compact(
+            &mut in_order([old.clone(), new.clone()]),
+            output.clone(),
+            None,
+            None,
+            1,
+            false,
+            &mut RateLimiter::unlimited(),
+        )
+        .unwrap();
+        assert_eq!(read_all(output), [
+            tombstone("a"),
+            Entry::Assignment { key: "b".to_owned(), value: "2".to_owned() },
+            tombstone("c")
+        ]);
+
+        let output = fixture.allocate_segment_file();
+        let (_, stats) = compact(
+            &mut in_order([old, new]),
+            output.clone(),
+            None,
+            None,
+            1,
+            true,
+            &mut RateLimiter::unlimited(),
+        )
+        .unwrap();
+        assert_eq!(read_all(output), assignments([("b", "2")]));
+        // The shadowed "a", along with both tombstones.
+        assert_eq!(stats.entries_dropped, 3);
+    }
+
+    #[test]
+    fn newest_sequence_wins_in_any_order() {
+        let mut fixture = StoreFixture::init("./test-db-compaction-sequence");
+        let mut open = |pairs, sequence| {
+            CompactionInput::open(
+                fixture.write_segment_file(pairs),
+                sequence,
+                None,
+                Arc::new(StdIo),
+            )
+            .unwrap()
+        };
+        let mut inputs = vec![
+            open(vec![("a", "new"), ("b", "new")], 9),
+            open(vec![("a", "old"), ("c", "old")], 2),
+            open(vec![("b", "mid")], 5),
+        ];
+
+        let new = fixture.allocate_segment_file();
+        compact(&mut inputs, new.clone(), None, None, 1, false, &mut RateLimiter::unlimited())
+            .unwrap();
+
+        pretty_assertions::assert_eq!(
+            read_all(new),
+            assignments([("a", "new"), ("b", "new"), ("c", "old")])
+        );
+    }
+
+    #[test]
+    fn plan_prefers_dead_data() {
+        let mut fixture = StoreFixture::init("./test-db-compaction-plan");
+        let open = |path, sequence| SegmentHandle::open(path).unwrap().with_sequence(sequence);
+        let (first, second, third) = (
+            fixture.write_segment_file([("a", "1")]),
+            fixture.write_segment_file([("b", "1")]),
+            fixture.write_segment_file([("c", "1")]),
+        );
+        let segments = SegmentSet {
+            handles: [open(third.clone(), 7), open(first.clone(), 3), open(second.clone(), 4)]
+                .into(),
+            next_segment_id: 4,
+            wal_start: 1,
+        };
+
+        let paths = |plan: CompactionPlan| -> Vec<_> {
+            plan.inputs.iter().map(|input| input.path.clone()).collect()
+        };
+
+        let plan = CompactionPlan::pick(&segments, 2).unwrap();
+        assert_eq!(plan.output_sequence(), 4);
+        assert_eq!(paths(plan), [first.clone(), second.clone()]);
+        assert!(CompactionPlan::pick(&segments, 1).is_none());
+
+        // The newest segment overwrites keys in the others, so compacting it with the
+        // one before it drops more.
+        let segments = SegmentSet {
+            handles: [
+                open(first.clone(), 3),
+                open(second.clone(), 4).with_overwrite_count(1),
+                open(third.clone(), 7).with_overwrite_count(2),
+            ]
+            .into(),
+            ..segments
+        };
+        let plan = CompactionPlan::pick(&segments, 2).unwrap();
+        assert!(!plan.bottom);
+        assert_eq!(paths(plan), [second.clone(), third.clone()]);
+        assert_eq!(CompactionPlan::pick(&segments, 5).unwrap().inputs.len(), 3);
+
+        // Tombstones in the oldest run can be dropped, so they count toward it.
+        let deletes = fixture.allocate_segment_file();
+        let mut file = File::create_new(&deletes).unwrap();
+        for key in ["x", "y", "z"] {
+            segment::tombstone(&mut file, key).unwrap();
+        }
+        drop(file);
+        let segments = SegmentSet {
+            handles: [
+                open(deletes.clone(), 3),
+                open(second.clone(), 4).with_overwrite_count(1),
+                open(third, 7).with_overwrite_count(2),
+            ]
+            .into(),
+            ..segments
+        };
+        let plan = CompactionPlan::pick(&segments, 2).unwrap();
+        assert!(plan.bottom);
+        assert_eq!(paths(plan), [deletes, second]);
+    }
+
+    #[test]
+    fn interrupted_compaction_leaves_inputs_live() {
+        let fixture = StoreFixture::init("./test-db-compaction-interrupted");
+        let args = || StoreArgs { compaction_enabled: false, ..Default::default() };
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        for (key, value) in [("a", "1"), ("b", "1"), ("a", "2")] {
+            let mut memtable = Memtable::new(MemtableArgs::default());
+            memtable.set(key, value);
+            store.write_memtable(&memtable).unwrap();
+        }
+        let inputs = store.list_segments().unwrap();
+        store.stop().unwrap();
+
+        // A crash mid-merge leaves part of the output under its temporary name, and one
+        // after the rename but before the manifest commit leaves all of it under its
+        // real one. Only the inputs holding "a" are merged, so a store that read the
+        // output would lose "b".
+        for (id, truncate) in [(4, true), (5, false)] {
+            let temp = fixture.path().join(compaction_temp_filename(id));
+            let (file, _) = compact(
+                &mut in_order([inputs[0].clone(), inputs[2].clone()]),
+                temp.clone(),
+                None,
+                None,
+                1,
+                false,
+                &mut RateLimiter::unlimited(),
+            )
+            .unwrap();
+            if truncate {
+                file.set_len(file.metadata().unwrap().len() / 2).unwrap();
+            } else {
+                fs::rename(&temp, fixture.path().join(segment_filename(id))).unwrap();
+            }
+        }
+
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        assert_eq!(store.list_segments().unwrap(), inputs);
+        assert!(!fixture.path().join(compaction_temp_filename(4)).exists());
+        assert!(!fixture.path().join(segment_filename(5)).exists());
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("2"));
+        assert_eq!(store.get("b").unwrap().as_deref(), Some("1"));
+        store.stop().unwrap();
+    }
+
+    #[test]
+    fn rate_limited_compaction() {
+        let mut fixture = StoreFixture::init("./test-db-rate-limited-compaction");
+        // Each entry is 11 bytes, so this reads 44 bytes and writes 44 bytes.
+        let mut files = in_order([
+            fixture.write_segment_file([("a", "1"), ("c", "3")]),
+            fixture.write_segment_file([("b", "2"), ("d", "4")]),
+        ]);
+
+        // Half of the traffic is covered by the limiter's initial burst, and the rest
+        // has to wait for it to refill.
+        let start = Instant::now();
+        compact(
+            &mut files,
+            fixture.allocate_segment_file(),
+            None,
+            None,
+            1,
+            false,
+            &mut RateLimiter::new(44),
+        )
+        .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
 }
@@ -0,0 +1,707 @@
+//! Encryption at rest, with AES-256-GCM.
+//!
+//! An encrypted store seals its segment files in blocks of [`BLOCK_SIZE`]
+//! bytes, and its WAL one committed write at a time. Every block and write is
+//! sealed under a fresh random nonce, with its position bound in as associated
+//! data, so data that is damaged, moved or read with the wrong key fails to
+//! decrypt rather than being misread. Each segment file starts with a random
+//! id that is bound into its blocks too, so that a block can't be swapped for
+//! the one at the same position of another file. The manifest isn't encrypted,
+//! since it only holds file ids.
+//!
+//! Whether a store is encrypted is decided when it is created, and recorded by
+//! a sealed check value in its [`KEY_CHECK_FILENAME`] file.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::anyhow;
+use crunch_common::env::FromEnv;
+
+use crate::error::Error;
+use crate::io::{advise, preallocate, Advice, IoBackend, StdIo};
+use crate::util::sync_directory;
+
+/// The size of a key, in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// The number of plaintext bytes in each block of an encrypted segment file.
+pub const BLOCK_SIZE: usize = 4096;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// The bytes that sealing adds to a plaintext.
+const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// The size of a full block of an encrypted segment file, on disk.
+const SEALED_BLOCK_SIZE: u64 = (BLOCK_SIZE + OVERHEAD) as u64;
+
+/// The size of the random id that an encrypted segment file starts with,
+/// ahead of its blocks.
+const FILE_ID_SIZE: usize = 16;
+
+/// The file in an encrypted store that holds [`KEY_CHECK`], sealed with the
+/// store's key.
+pub const KEY_CHECK_FILENAME: &str = "ENCRYPTION";
+const KEY_CHECK: &[u8] = b"crunch key check";
+
+/// Supplies the key that a store is encrypted with, such as from a secrets
+/// manager. It is asked once, when the store is opened.
+pub trait KeyProvider: fmt::Debug + Send + Sync {
+    fn key(&self) -> anyhow::Result<[u8; KEY_SIZE]>;
+}
+
+/// A key that is given up front.
+#[derive(Clone)]
+pub struct StaticKey([u8; KEY_SIZE]);
+
+impl StaticKey {
+    pub fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Debug for StaticKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StaticKey(<redacted>)")
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn key(&self) -> anyhow::Result<[u8; KEY_SIZE]> {
+        Ok(self.0)
+    }
+}
+
+/// A key written as 64 hex digits.
+impl FromEnv for StaticKey {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        if value.len() != KEY_SIZE * 2 || !value.is_ascii() {
+            return Err(anyhow!("an encryption key must be {} hex digits", KEY_SIZE * 2));
+        }
+        let mut key = [0; KEY_SIZE];
+        for (byte, digits) in key.iter_mut().zip(value.as_bytes().chunks(2)) {
+            // The digits are ASCII, so this can't split a character.
+            *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)?;
+        }
+        Ok(Self(key))
+    }
+}
+
+/// Seals and opens data with a store's key.
+#[derive(Clone)]
+pub struct Cipher(Arc<Aes256Gcm>);
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher")
+    }
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self(Arc::new(Aes256Gcm::new(key.into())))
+    }
+
+    pub fn from_provider(provider: &dyn KeyProvider) -> Result<Self, Error> {
+        Ok(Self::new(&provider.key()?))
+    }
+
+    /// Encrypt `plaintext`, authenticating it along with `associated`. The
+    /// result holds the nonce, followed by the ciphertext and its tag.
+    pub fn seal(&self, associated: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, Payload { msg: plaintext, aad: associated })
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "can't seal the plaintext"))?;
+        let mut sealed = Vec::with_capacity(OVERHEAD + plaintext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt what [`Self::seal`] returned for the same `associated` data, or
+    /// return `None` if it was sealed with another key or has been changed.
+    pub fn open(&self, associated: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let (nonce, ciphertext) = sealed.split_at_checked(NONCE_SIZE)?;
+        self.0.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: associated }).ok()
+    }
+}
+
+/// Make sure that the store at `directory` is readable with `cipher`, which
+/// is `None` for a store that isn't encrypted.
+///
+/// A store without a key check is unencrypted, unless it has no data yet
+/// (which `has_data` says), in which case it is set up for `cipher`.
+pub fn check_key(directory: &Path, cipher: Option<&Cipher>, has_data: bool) -> Result<(), Error> {
+    let path = directory.join(KEY_CHECK_FILENAME);
+    let check = match fs::read(&path) {
+        Ok(check) => Some(check),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error.into()),
+    };
+    match (check, cipher) {
+        (None, None) => Ok(()),
+        (Some(check), Some(cipher)) => match cipher.open(KEY_CHECK_FILENAME.as_bytes(), &check) {
+            Some(plaintext) if plaintext == KEY_CHECK => Ok(()),
+            _ => Err(Error::General(anyhow!(
+                "the store at {directory:?} is encrypted with a different key"
+            ))),
+        },
+        (Some(_), None) => Err(Error::General(anyhow!(
+            "the store at {directory:?} is encrypted, but no key was given"
+        ))),
+        (None, Some(_)) if has_data => Err(Error::General(anyhow!(
+            "the store at {directory:?} isn't encrypted, so it can't be opened with a key"
+        ))),
+        (None, Some(cipher)) => {
+            let mut file = File::create_new(&path)?;
+            file.write_all(&cipher.seal(KEY_CHECK_FILENAME.as_bytes(), KEY_CHECK)?)?;
+            file.sync_all()?;
+            sync_directory(directory)?;
+            log::info!("set up encryption for the store at {directory:?}");
+            Ok(())
+        },
+    }
+}
+
+/// Reads the plaintext of a segment file, decrypting it if it is encrypted.
+/// Offsets are in the plaintext.
+pub enum Reader {
+    Plain(PlainReader),
+    Encrypted(BlockReader),
+}
+
+impl Reader {
+    pub fn open(path: &Path, cipher: Option<&Cipher>) -> Result<Self, io::Error> {
+        Self::open_with(path, cipher, Arc::new(StdIo))
+    }
+
+    /// Like [`Self::open`], but reads the file through `io`.
+    pub fn open_with(
+        path: &Path,
+        cipher: Option<&Cipher>,
+        io: Arc<dyn IoBackend>,
+    ) -> Result<Self, io::Error> {
+        let file = io.open(path)?;
+        Ok(match cipher {
+            Some(cipher) => Self::Encrypted(BlockReader::new(file, cipher.clone(), io)?),
+            None => Self::Plain(PlainReader::new(file, io)?),
+        })
+    }
+
+    /// Read ahead of reads that follow on from each other, by up to
+    /// `max_bytes` at a time, for readers that scan through the file. 0 leaves
+    /// reads as they are.
+    ///
+    /// An unencrypted file is read ahead into a buffer of the reader's own. An
+    /// encrypted one is already read a block at a time, so the OS is just told
+    /// that it will be read through.
+    pub fn with_read_ahead(mut self, max_bytes: usize) -> Self {
+        if max_bytes > 0 {
+            match &mut self {
+                Self::Plain(reader) => reader.read_ahead = Some(ReadAhead::new(max_bytes)),
+                Self::Encrypted(reader) => advise(&reader.file, Advice::Sequential),
+            }
+        }
+        self
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(reader) => reader.read(buf),
+            Self::Encrypted(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for Reader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Plain(reader) => reader.seek(position),
+            Self::Encrypted(reader) => reader.seek(position),
+        }
+    }
+}
+
+/// Reads an unencrypted segment file.
+pub struct PlainReader {
+    file: File,
+    io: Arc<dyn IoBackend>,
+    len: u64,
+
+    /// The offset that the next read starts from.
+    position: u64,
+    read_ahead: Option<ReadAhead>,
+}
+
+impl PlainReader {
+    fn new(file: File, io: Arc<dyn IoBackend>) -> Result<Self, io::Error> {
+        let len = file.metadata()?.len();
+        Ok(Self { file, io, len, position: 0, read_ahead: None })
+    }
+}
+
+impl Read for PlainReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = match self.read_ahead.as_mut() {
+            Some(read_ahead) => read_ahead.read(&self.file, &*self.io, buf, self.position)?,
+            None => self.io.read_at(&self.file, buf, self.position)?,
+        };
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+/// The size that a [`ReadAhead`] window starts at, when reads first follow on
+/// from each other.
+const READ_AHEAD_INITIAL_WINDOW: usize = 16 * 1024;
+
+/// Reads a file ahead of a [`PlainReader`] once its reads look sequential, so
+/// that a scan makes a few large reads rather than several small ones for
+/// every entry.
+///
+/// The window doubles with each refill while reads keep following on from
+/// each other, up to `max`, and a read anywhere else starts it over. As it is
+/// refilled, the OS is asked to start reading the window after it.
+struct ReadAhead {
+    max: usize,
+    window: usize,
+    buffer: Vec<u8>,
+
+    /// The offset in the file of the start of `buffer`.
+    start: u64,
+
+    /// The offset that the last read ended at.
+    last_end: Option<u64>,
+}
+
+impl ReadAhead {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            window: READ_AHEAD_INITIAL_WINDOW.min(max),
+            buffer: Vec::new(),
+            start: 0,
+            last_end: None,
+        }
+    }
+
+    fn read(
+        &mut self,
+        file: &File,
+        io: &dyn IoBackend,
+        buf: &mut [u8],
+        position: u64,
+    ) -> io::Result<usize> {
+        let sequential = self.last_end == Some(position);
+        let buffered =
+            position.checked_sub(self.start).filter(|&offset| offset < self.buffer.len() as u64);
+        let count = match buffered {
+            Some(offset) => {
+                let available = &self.buffer[offset as usize..];
+                let count = buf.len().min(available.len());
+                buf[..count].copy_from_slice(&available[..count]);
+                count
+            },
+            None if sequential && buf.len() < self.window => {
+                self.buffer.resize(self.window, 0);
+                let filled = io.read_at(file, &mut self.buffer, position)?;
+                self.buffer.truncate(filled);
+                self.start = position;
+                let next = position + filled as u64;
+                self.window = (self.window * 2).min(self.max);
+                if filled > 0 {
+                    advise(file, Advice::WillNeed { offset: next, len: self.window as u64 });
+                }
+                let count = buf.len().min(filled);
+                buf[..count].copy_from_slice(&self.buffer[..count]);
+                count
+            },
+            None => {
+                if !sequential {
+                    self.window = READ_AHEAD_INITIAL_WINDOW.min(self.max);
+                }
+                io.read_at(file, buf, position)?
+            },
+        };
+        self.last_end = Some(position + count as u64);
+        Ok(count)
+    }
+}
+
+impl Seek for PlainReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(position, self.position, self.len)?;
+        Ok(self.position)
+    }
+}
+
+/// Where a seek to `position` lands, from `current` in a file that is `len`
+/// bytes long.
+fn seek_position(position: SeekFrom, current: u64, len: u64) -> io::Result<u64> {
+    let position = match position {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(offset) => len.checked_add_signed(offset),
+        SeekFrom::Current(offset) => current.checked_add_signed(offset),
+    };
+    position.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "seek to before the start of the file")
+    })
+}
+
+/// Writes a new segment file, encrypting it if `cipher` is given.
+/// [`Self::finish`] must be called once everything has been written.
+pub enum Writer {
+    Plain(File),
+    Encrypted(BlockWriter),
+}
+
+impl Writer {
+    pub fn new(file: File, cipher: Option<&Cipher>) -> Result<Self, io::Error> {
+        Ok(match cipher {
+            Some(cipher) => Self::Encrypted(BlockWriter::new(file, cipher.clone())?),
+            None => Self::Plain(file),
+        })
+    }
+
+    /// Reserve `len` bytes on disk for what is about to be written, with
+    /// [`preallocate`]. Whatever isn't written over is cut off by
+    /// [`Self::finish`].
+    pub fn preallocate(&self, len: u64) -> Result<(), io::Error> {
+        match self {
+            Self::Plain(file) => preallocate(file, len),
+            Self::Encrypted(writer) => preallocate(&writer.file, len),
+        }
+    }
+
+    /// Write out anything that is buffered, truncate the file to what was
+    /// written, and return it.
+    pub fn finish(self) -> Result<File, io::Error> {
+        let mut file = match self {
+            Self::Plain(file) => file,
+            Self::Encrypted(writer) => writer.finish()?,
+        };
+        let written = file.stream_position()?;
+        if file.metadata()?.len() != written {
+            file.set_len(written)?;
+        }
+        Ok(file)
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(file) => file.write(buf),
+            Self::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush(),
+            Self::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/// The associated data of the block at `index` of the file with `file_id`,
+/// which stops blocks from being reordered or moved between files.
+fn block_associated_data(file_id: &[u8; FILE_ID_SIZE], index: u64) -> [u8; FILE_ID_SIZE + 8] {
+    let mut associated = [0; FILE_ID_SIZE + 8];
+    associated[..FILE_ID_SIZE].copy_from_slice(file_id);
+    associated[FILE_ID_SIZE..].copy_from_slice(&index.to_be_bytes());
+    associated
+}
+
+fn decryption_error(index: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("block {index} can't be decrypted, so the key is wrong or the data is damaged"),
+    )
+}
+
+/// Reads an encrypted segment file, one decrypted block at a time.
+pub struct BlockReader {
+    file: File,
+    cipher: Cipher,
+    io: Arc<dyn IoBackend>,
+    file_id: [u8; FILE_ID_SIZE],
+
+    /// The size of the file's blocks on disk, after its id.
+    sealed_len: u64,
+
+    /// The size of the plaintext.
+    len: u64,
+
+    /// The offset in the plaintext that the next read starts from.
+    position: u64,
+
+    /// The index and plaintext of the last block that was decrypted.
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl BlockReader {
+    fn new(file: File, cipher: Cipher, io: Arc<dyn IoBackend>) -> Result<Self, io::Error> {
+        let Some(sealed_len) = file.metadata()?.len().checked_sub(FILE_ID_SIZE as u64) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file is too short to have been encrypted",
+            ));
+        };
+        let mut file_id = [0; FILE_ID_SIZE];
+        io.read_exact_at(&file, &mut file_id, 0)?;
+        let partial = sealed_len % SEALED_BLOCK_SIZE;
+        if partial != 0 && partial <= OVERHEAD as u64 {
+            return Err(decryption_error(sealed_len / SEALED_BLOCK_SIZE));
+        }
+        let len = sealed_len / SEALED_BLOCK_SIZE * BLOCK_SIZE as u64
+            + partial.saturating_sub(OVERHEAD as u64);
+        Ok(Self { file, cipher, io, file_id, sealed_len, len, position: 0, block: None })
+    }
+
+    fn load(&mut self, index: u64) -> Result<&[u8], io::Error> {
+        if self.block.as_ref().is_none_or(|(loaded, _)| *loaded != index) {
+            let start = index * SEALED_BLOCK_SIZE;
+            let mut sealed = vec![0; SEALED_BLOCK_SIZE.min(self.sealed_len - start) as usize];
+            self.io.read_exact_at(&self.file, &mut sealed, FILE_ID_SIZE as u64 + start)?;
+            let plaintext = self
+                .cipher
+                .open(&block_associated_data(&self.file_id, index), &sealed)
+                .ok_or_else(|| decryption_error(index))?;
+            self.block = Some((index, plaintext));
+        }
+        Ok(&self.block.as_ref().unwrap().1)
+    }
+}
+
+impl Read for BlockReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let offset = (self.position % BLOCK_SIZE as u64) as usize;
+        let block = self.load(self.position / BLOCK_SIZE as u64)?;
+        let count = buf.len().min(block.len() - offset);
+        buf[..count].copy_from_slice(&block[offset..offset + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for BlockReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(position, self.position, self.len)?;
+        Ok(self.position)
+    }
+}
+
+/// Writes an encrypted segment file, sealing each block once it is full.
+pub struct BlockWriter {
+    file: File,
+    cipher: Cipher,
+    file_id: [u8; FILE_ID_SIZE],
+    buffer: Vec<u8>,
+
+    /// The index of the block that is being filled in `buffer`.
+    index: u64,
+}
+
+impl BlockWriter {
+    fn new(mut file: File, cipher: Cipher) -> Result<Self, io::Error> {
+        let file_id: [u8; FILE_ID_SIZE] = rand::random();
+        file.write_all(&file_id)?;
+        Ok(Self { file, cipher, file_id, buffer: Vec::with_capacity(BLOCK_SIZE), index: 0 })
+    }
+
+    fn seal_block(&mut self) -> Result<(), io::Error> {
+        let associated = block_associated_data(&self.file_id, self.index);
+        let sealed = self.cipher.seal(&associated, &self.buffer)?;
+        self.file.write_all(&sealed)?;
+        self.buffer.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<File, io::Error> {
+        if !self.buffer.is_empty() {
+            self.seal_block()?;
+        }
+        Ok(self.file)
+    }
+}
+
+impl Write for BlockWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..count]);
+        if self.buffer.len() == BLOCK_SIZE {
+            self.seal_block()?;
+        }
+        Ok(count)
+    }
+
+    /// Only full blocks are written out, since a block can't be added to once
+    /// it is sealed.
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::StoreFixture;
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+
+    #[test]
+    fn finish_truncates_preallocated_space() {
+        let fixture = StoreFixture::init("./test-db-encryption-preallocate");
+        let cipher = Cipher::new(&KEY);
+        for (name, cipher) in [("plain", None), ("encrypted", Some(&cipher))] {
+            let path = fixture.path().join(name);
+            let mut writer = Writer::new(File::create(&path).unwrap(), cipher).unwrap();
+            writer.preallocate(1 << 20).unwrap();
+            writer.write_all(b"data").unwrap();
+            let file = writer.finish().unwrap();
+            let expected = if cipher.is_some() { FILE_ID_SIZE + 4 + OVERHEAD } else { 4 };
+            assert_eq!(file.metadata().unwrap().len(), expected as u64);
+
+            let mut read = Vec::new();
+            Reader::open(&path, cipher).unwrap().read_to_end(&mut read).unwrap();
+            assert_eq!(read, b"data");
+        }
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let fixture = StoreFixture::init("./test-db-encryption-blocks");
+        let cipher = Cipher::new(&KEY);
+        let path = fixture.path().join("data");
+        let plaintext: Vec<u8> = (0..BLOCK_SIZE * 2 + 100).map(|n| n as u8).collect();
+        let mut writer = Writer::new(File::create(&path).unwrap(), Some(&cipher)).unwrap();
+        writer.write_all(&plaintext).unwrap();
+        writer.finish().unwrap();
+        let sealed = fs::read(&path).unwrap();
+        assert_eq!(sealed.len(), FILE_ID_SIZE + plaintext.len() + 3 * OVERHEAD);
+        assert!(!sealed.windows(16).any(|window| plaintext.starts_with(window)));
+
+        let mut reader = Reader::open(&path, Some(&cipher)).unwrap();
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, plaintext);
+        // Reads can start anywhere, and run across blocks.
+        reader.seek(SeekFrom::Start(BLOCK_SIZE as u64 - 2)).unwrap();
+        let mut straddle = [0; 4];
+        reader.read_exact(&mut straddle).unwrap();
+        assert_eq!(straddle, plaintext[BLOCK_SIZE - 2..BLOCK_SIZE + 2]);
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), plaintext.len() as u64);
+
+        let mut other = Reader::open(&path, Some(&Cipher::new(&[8; KEY_SIZE]))).unwrap();
+        let error = other.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn blocks_are_bound_to_their_file() {
+        let fixture = StoreFixture::init("./test-db-encryption-swapped-blocks");
+        let cipher = Cipher::new(&KEY);
+        let write = |name: &str| {
+            let path = fixture.path().join(name);
+            let mut writer = Writer::new(File::create(&path).unwrap(), Some(&cipher)).unwrap();
+            writer.write_all(name.as_bytes()).unwrap();
+            writer.finish().unwrap();
+            path
+        };
+        let (first, second) = (write("first"), write("other"));
+        // Give the first file the block of the second, keeping its own id.
+        let mut swapped = fs::read(&first).unwrap();
+        swapped.truncate(FILE_ID_SIZE);
+        swapped.extend_from_slice(&fs::read(&second).unwrap()[FILE_ID_SIZE..]);
+        fs::write(&first, swapped).unwrap();
+
+        let mut reader = Reader::open(&first, Some(&cipher)).unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let mut read = Vec::new();
+        Reader::open(&second, Some(&cipher)).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"other");
+    }
+
+    /// Counts the reads made through it.
+    #[derive(Debug, Default)]
+    struct CountingIo(std::sync::atomic::AtomicUsize);
+
+    impl IoBackend for CountingIo {
+        fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            StdIo.read_at(file, buf, offset)
+        }
+
+        fn write_all_at(&self, file: &File, buf: &[u8], offset: u64, sync: bool) -> io::Result<()> {
+            StdIo.write_all_at(file, buf, offset, sync)
+        }
+    }
+
+    #[test]
+    fn read_ahead() {
+        let fixture = StoreFixture::init("./test-db-encryption-read-ahead");
+        let path = fixture.path().join("data");
+        let contents: Vec<u8> = (0..200_000).map(|n| (n % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let io = Arc::new(CountingIo::default());
+        let mut reader =
+            Reader::open_with(&path, None, io.clone()).unwrap().with_read_ahead(64 * 1024);
+        let mut read = Vec::new();
+        let mut chunk = [0; 5];
+        while read.len() < contents.len() {
+            reader.read_exact(&mut chunk).unwrap();
+            read.extend_from_slice(&chunk);
+        }
+        assert_eq!(read, contents);
+        // 40,000 reads were asked for, and made in windows of 16, 32 and then 64 KiB.
+        let reads = io.0.load(std::sync::atomic::Ordering::Relaxed);
+        assert!(reads < 10, "{reads} reads");
+
+        // Reads elsewhere in the file are made as they are asked for.
+        reader.seek(SeekFrom::Start(7)).unwrap();
+        reader.read_exact(&mut chunk).unwrap();
+        assert_eq!(chunk, contents[7..12]);
+        reader.seek(SeekFrom::Start(150_001)).unwrap();
+        reader.read_exact(&mut chunk).unwrap();
+        assert_eq!(chunk, contents[150_001..150_006]);
+        reader.read_exact(&mut chunk).unwrap();
+        assert_eq!(chunk, contents[150_006..150_011]);
+    }
+
+    #[test]
+    fn key_check() {
+        let fixture = StoreFixture::init("./test-db-encryption-key-check");
+        let cipher = Cipher::new(&KEY);
+        assert!(check_key(fixture.path(), Some(&cipher), true).is_err());
+        check_key(fixture.path(), Some(&cipher), false).unwrap();
+        check_key(fixture.path(), Some(&cipher), true).unwrap();
+        assert!(check_key(fixture.path(), None, true).is_err());
+        assert!(check_key(fixture.path(), Some(&Cipher::new(&[8; KEY_SIZE])), true).is_err());
+    }
+
+    #[test]
+    fn static_key_from_hex() {
+        let key = StaticKey::from_env(&"0f".repeat(KEY_SIZE)).unwrap();
+        assert_eq!(key.key().unwrap(), [15; KEY_SIZE]);
+        assert!(StaticKey::from_env("0f0f").is_err());
+        assert!(StaticKey::from_env(&"zz".repeat(KEY_SIZE)).is_err());
+    }
+}
@@ -1,38 +1,546 @@
-use std::path::PathBuf;
-use std::thread;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::UNIX_EPOCH;
+use std::{mem, thread};
 
-use crate::error::Error;
-use crate::memtable::{Memtable, MemtableArgs};
-use crate::store::{Store, StoreArgs};
+use anyhow::anyhow;
+use crunch_common::config::Config;
+use crunch_common::env::ByteSize;
 
+use crate::backup::{BackupReport, BackupTarget};
+use crate::batch::WriteBatch;
+use crate::builder::EngineBuilder;
+use crate::error::{Error, PairComponent};
+use crate::events::{EventListener, Listeners};
+use crate::index::{Extractor, SecondaryIndex};
+use crate::memtable::{Memtable, MemtableArgs, SnapshotRange, Value};
+use crate::metrics::MetricsSnapshot;
+use crate::rate_limiter::RateLimiter;
+use crate::row_cache::{RowCache, RowCacheArgs};
+use crate::segment::{self, Entry, EntryRef};
+use crate::shard;
+use crate::store::{Store, StoreArgs, StoreStats};
+use crate::util::TempDirectory;
+use crate::wal::{Pending, RecoveryReport, Salvage};
+
+/// The storage engine.
+///
+/// Every method takes `&self`, so an engine can be shared between threads.
+/// Reads only hold a lock on the memtables for as long as it takes to look in
+/// them, and never wait on the disk I/O done by writes. Writes are serialized
+/// with each other, unless the engine is split into [`EngineArgs::shards`], in
+/// which case only writes to the same shard are. They wait on the WAL's write
+/// and fsync after that, so writers that come together share one.
+///
+/// A write can be read as soon as it is applied, which is just before it is
+/// durable. If committing it to the WAL then fails, the write returns an error
+/// but stays visible, and may not survive a restart.
 pub struct Engine {
-    memtable: Memtable,
+    memtables: RwLock<Memtables>,
     store: Store,
+
+    /// Held while every write is added to the WAL and applied to the
+    /// memtable, so that writes reach the two in the same order. It isn't held
+    /// while the write is committed, so that writers that come together share
+    /// an fsync. It holds the sequence number of the last write, which is only
+    /// counted when versions are retained.
+    writer: Mutex<u64>,
+
+    /// The most versions of each key to keep, counting the current one.
+    retained_versions: usize,
+
+    /// Taken before `writer`, so that a write waiting on its limit doesn't
+    /// hold up reads. Shared between the shards of an engine, so that the
+    /// limits cover all of them.
+    write_limiter: Arc<Mutex<WriteLimiter>>,
+
+    /// What was dropped from the WAL when it was replayed, if it was salvaged.
+    recovery: RecoveryReport,
+
+    /// Kept up to date by every write. Only taken for writing by
+    /// [`Self::register_index`].
+    indexes: RwLock<Vec<SecondaryIndex>>,
+
+    /// The settings that the stores of secondary indexes are opened with.
+    index_args: StoreArgs,
+
+    /// Shared with the store, which notifies them about flushes and
+    /// compactions. The engine notifies them about writes.
+    listeners: Listeners,
+
+    /// Holds values read off disk, if it is turned on.
+    row_cache: Option<RowCache>,
+
+    /// The inner engines that keys are spread over, if the engine is sharded,
+    /// with this one only routing to them. See [`crate::shard`].
+    shards: Vec<Engine>,
+
+    /// Set by [`EngineArgs::read_only`], to refuse every write.
+    read_only: bool,
+
+    size_limits: SizeLimits,
+
+    /// The directory that the engine is in, if it was opened with
+    /// [`Self::temp`]. This comes last so that it is removed after everything
+    /// else is dropped.
+    temp_directory: Option<TempDirectory>,
+}
+
+struct WriteLimiter {
+    bytes: RateLimiter,
+    operations: RateLimiter,
+}
+
+impl WriteLimiter {
+    fn new(limits: WriteLimits) -> Self {
+        Self {
+            bytes: RateLimiter::new(limits.bytes_per_second),
+            operations: RateLimiter::new(limits.operations_per_second),
+        }
+    }
+
+    fn limits(&self) -> WriteLimits {
+        WriteLimits {
+            bytes_per_second: self.bytes.rate(),
+            operations_per_second: self.operations.rate(),
+        }
+    }
+}
+
+/// The memtables that reads have to look in, newest first.
+struct Memtables {
+    /// The memtable that writes go to. Iterators share it rather than copying
+    /// it, so the first write after one is created copies it instead.
+    active: Arc<Memtable>,
+
+    /// A full memtable that is being written out to a segment file. It stays
+    /// readable here until that segment file is part of the store.
+    flushing: Option<Arc<Memtable>>,
+
+    /// Everything flushed out of the active memtable, for an in-memory engine,
+    /// which has no segment files to flush to. It sits under the others, so it
+    /// holds no tombstones.
+    resident: Option<Arc<Memtable>>,
+}
+
+impl Memtables {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.active
+            .get(key)
+            .or_else(|| self.flushing.as_ref()?.get(key))
+            .or_else(|| self.resident.as_ref()?.get(key))
+    }
+
+    fn get_at(&self, key: &str, sequence: u64) -> Option<Value> {
+        self.active
+            .get_at(key, sequence)
+            .or_else(|| self.flushing.as_ref()?.get_at(key, sequence))
+            .or_else(|| self.resident.as_ref()?.get_at(key, sequence))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Arc<Memtable>> {
+        [Some(&self.active), self.flushing.as_ref(), self.resident.as_ref()].into_iter().flatten()
+    }
+
+    /// The memtable that writes go to, for making one.
+    fn active_mut(&mut self) -> &mut Memtable {
+        Arc::make_mut(&mut self.active)
+    }
+
+    /// Set `key` to `value`, or delete it if that is `None`, in the active
+    /// memtable, as the version written at `sequence` if one is given.
+    fn write(&mut self, key: &str, value: Option<&str>, sequence: Option<u64>) {
+        let active = self.active_mut();
+        match (value, sequence) {
+            (value, Some(sequence)) => active.write_version(key, value.map(Into::into), sequence),
+            (Some(value), None) => active.set(key, value),
+            (None, None) => active.delete(key),
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct EngineArgs {
     pub memtable: MemtableArgs,
     pub store: StoreArgs,
+
+    /// Notified about writes and background work done by the engine.
+    pub listeners: Vec<Arc<dyn EventListener>>,
+
+    /// The initial caps on the rate of writes, which can be changed later with
+    /// [`Engine::set_write_limits`].
+    pub write_limits: WriteLimits,
+    pub row_cache: RowCacheArgs,
+
+    /// The number of shards to split the engine into, each with its own
+    /// memtable, WAL and segment files. 0 or 1 leaves it whole. An engine is
+    /// always opened with the number it was created with, and a sharded one
+    /// can't retain versions.
+    pub shards: usize,
+
+    /// Refuse every write, and don't compact, so that nothing that opens the
+    /// engine this way changes its files. The engine must already exist.
+    pub read_only: bool,
+    pub size_limits: SizeLimits,
+
+    /// Check every segment file for damage when the engine opens, with
+    /// [`Store::verify`], and refuse to open if any is found, rather than
+    /// leaving reads to run into it. With
+    /// [`StoreArgs::quarantine_corrupt_segments`], damaged files are
+    /// quarantined instead.
+    pub verify_on_open: bool,
+}
+
+/// Caps on how fast the engine accepts writes, so that heavy ingest can't
+/// starve reads of disk bandwidth. Zero means unlimited.
+///
+/// Writes over a limit block until it allows them. A single write larger than
+/// a limit is let through once the limit has caught up with it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WriteLimits {
+    /// The most bytes of keys and values written per second.
+    pub bytes_per_second: u64,
+
+    /// The most sets and deletes per second. Each write in a batch counts.
+    pub operations_per_second: u64,
+}
+
+impl WriteLimits {
+    pub fn from_config(config: &Config) -> Self {
+        let bytes_per_second =
+            config.get("engine", Some("write"), "bytes_per_second", ByteSize(0)).0;
+        let operations_per_second = config.get("engine", Some("write"), "operations_per_second", 0);
+        Self { bytes_per_second, operations_per_second }
+    }
+}
+
+/// The largest keys and values that the engine accepts writes of, in bytes.
+///
+/// A set, delete or batch with a key or value over its limit fails with
+/// [`Error::TooLarge`] before any of it is written, as does an append that
+/// would take a value over. Bulk loads and ingested segment files aren't
+/// checked.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SizeLimits {
+    pub max_key_bytes: usize,
+    pub max_value_bytes: usize,
+}
+
+impl SizeLimits {
+    pub fn from_config(config: &Config) -> Self {
+        let default = Self::default();
+        let max_key_bytes =
+            config.get("engine", None, "max_key_size", ByteSize(default.max_key_bytes as u64)).0;
+        let max_value_bytes = config
+            .get("engine", None, "max_value_size", ByteSize(default.max_value_bytes as u64))
+            .0;
+        Self { max_key_bytes: max_key_bytes as usize, max_value_bytes: max_value_bytes as usize }
+    }
+
+    /// Fail if `key` or `value` is over its limit.
+    pub fn check(&self, key: &str, value: Option<&str>) -> Result<(), Error> {
+        if key.len() > self.max_key_bytes {
+            return Err(Error::TooLarge(PairComponent::Key, key.len(), self.max_key_bytes));
+        }
+        self.check_value(value.map_or(0, str::len))
+    }
+
+    /// Fail if a value of `size` bytes is over the limit.
+    fn check_value(&self, size: usize) -> Result<(), Error> {
+        if size > self.max_value_bytes {
+            return Err(Error::TooLarge(PairComponent::Value, size, self.max_value_bytes));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self { max_key_bytes: 64 * 1024, max_value_bytes: 512 * 1024 * 1024 }
+    }
+}
+
+/// The live key-value pairs of an engine, in key order.
+type LiveEntries = Box<dyn Iterator<Item = Result<(String, String), Error>> + Send>;
+
+/// A page of results from [`Engine::scan`].
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ScanPage {
+    /// The live key-value pairs in the page, in key order.
+    pub entries: Vec<(String, String)>,
+
+    /// The key that the next page starts from, or `None` if this is the last
+    /// page.
+    pub next: Option<String>,
+}
+
+/// The names of the properties that [`Engine::get_property`] knows, where
+/// `<N>` is a level number. Names won't be removed or change meaning, so
+/// monitoring scripts can rely on them.
+pub const PROPERTIES: &[&str] = &[
+    "crunch.num-segments",
+    "crunch.num-segments-at-level<N>",
+    "crunch.segment-bytes",
+    "crunch.memtable-entries",
+    "crunch.memtable-bytes",
+    "crunch.wal-bytes",
+    "crunch.estimate-num-keys",
+    "crunch.estimate-dead-ratio",
+    "crunch.num-compactions",
+    "crunch.last-compaction",
+    "crunch.last-sequence",
+];
+
+/// A snapshot of the engine's state, from [`Engine::stats`].
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct EngineStats {
+    /// The number of keys in the memtable, including tombstones.
+    pub memtable_len: usize,
+    pub memtable_capacity: usize,
+
+    /// The segment files, the WAL, and compaction.
+    pub store: StoreStats,
 }
 
 impl EngineArgs {
-    pub fn from_env() -> Self {
-        Self { memtable: MemtableArgs::from_env(), store: StoreArgs::from_env() }
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            memtable: MemtableArgs::from_config(config),
+            store: StoreArgs::from_config(config),
+            listeners: Vec::new(),
+            write_limits: WriteLimits::from_config(config),
+            row_cache: RowCacheArgs::from_config(config),
+            shards: config.get("engine", None, "shards", 1),
+            read_only: false,
+            size_limits: SizeLimits::from_config(config),
+            verify_on_open: config.get("engine", None, "verify_on_open", false),
+        }
     }
 }
 
 impl Engine {
+    /// Open the engine at `path`, with its settings read from the environment.
     pub fn new(path: PathBuf) -> Result<Self, Error> {
-        Self::with_args(path, EngineArgs::from_env())
+        Self::with_args(path, EngineArgs::from_config(&Config::default()))
+    }
+
+    pub fn with_args(path: PathBuf, mut args: EngineArgs) -> Result<Self, Error> {
+        if args.read_only {
+            if !path.exists() {
+                return Err(anyhow!("there is no engine at {path:?} to open read-only").into());
+            }
+            args.store.compaction_enabled = false;
+        }
+        let shard_count = args.shards.max(1);
+        shard::check_shard_count(&path, shard_count)?;
+        if shard_count > 1 {
+            return Self::sharded(path, args, shard_count);
+        }
+        let write_limiter = Arc::new(Mutex::new(WriteLimiter::new(args.write_limits)));
+        Self::open(path, args, write_limiter)
+    }
+
+    /// Start setting the options to open the engine at `path` with, as an
+    /// alternative to filling in [`EngineArgs`].
+    pub fn builder(path: impl Into<PathBuf>) -> EngineBuilder {
+        EngineBuilder::new(path.into())
     }
 
-    pub fn with_args(path: PathBuf, args: EngineArgs) -> Result<Self, Error> {
-        let mut memtable = Memtable::new(args.memtable);
-        let mut store = Store::new(path, args.store)?;
-        store.replay_wal(&mut memtable)?;
+    /// Open an engine in a new, uniquely named directory in the system's
+    /// temporary directory, with its settings read from the environment, for
+    /// tests. The directory is removed when the engine is dropped, or once
+    /// [`Self::stop`] has shut it down.
+    pub fn temp() -> Result<Self, Error> {
+        Self::temp_with_args(EngineArgs::from_config(&Config::default()))
+    }
+
+    /// Like [`Self::temp`], but with `args`.
+    pub fn temp_with_args(args: EngineArgs) -> Result<Self, Error> {
+        let directory = TempDirectory::new()?;
+        let mut engine = Self::with_args(directory.path().to_owned(), args)?;
+        engine.temp_directory = Some(directory);
+        Ok(engine)
+    }
+
+    /// Open an engine that keeps everything in memory, with no WAL or segment
+    /// files, for tests and caches. Its data is gone once it is dropped.
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::in_memory_with_args(EngineArgs::default())
+    }
+
+    /// Like [`Self::in_memory`], but with `args`.
+    ///
+    /// Full memtables are merged into an in-memory segment rather than written
+    /// out, so the memtable capacity still bounds the copy of the active
+    /// memtable that a write makes while a scan shares it. The store settings
+    /// and row cache are ignored, and the engine can't retain versions or be
+    /// sharded. Anything else that needs files, such as ingesting a segment
+    /// file, bulk loading, backups and secondary indexes, fails.
+    pub fn in_memory_with_args(args: EngineArgs) -> Result<Self, Error> {
+        if args.store.retained_versions > 1 || args.shards > 1 {
+            return Err(anyhow!("an in-memory engine can't retain versions or be sharded").into());
+        }
+        let listeners = Listeners::new(args.listeners);
+        let resident = Memtable::new(MemtableArgs { capacity: usize::MAX });
+        log::debug!("in-memory engine initialized");
+        Ok(Self {
+            memtables: RwLock::new(Memtables {
+                active: Arc::new(Memtable::new(args.memtable)),
+                flushing: None,
+                resident: Some(Arc::new(resident)),
+            }),
+            store: Store::in_memory(listeners.clone()),
+            writer: Mutex::new(0),
+            retained_versions: 1,
+            write_limiter: Arc::new(Mutex::new(WriteLimiter::new(args.write_limits))),
+            recovery: RecoveryReport::default(),
+            indexes: RwLock::default(),
+            index_args: StoreArgs::default(),
+            listeners,
+            row_cache: None,
+            shards: Vec::new(),
+            read_only: args.read_only,
+            size_limits: args.size_limits,
+            temp_directory: None,
+        })
+    }
+
+    /// Open an engine split into `count` shards, which routes to an engine for
+    /// each of them.
+    fn sharded(path: PathBuf, args: EngineArgs, count: usize) -> Result<Self, Error> {
+        if args.store.retained_versions > 1 {
+            return Err(anyhow!("a sharded engine can't retain versions").into());
+        }
+        let write_limiter = Arc::new(Mutex::new(WriteLimiter::new(args.write_limits)));
+        let mut shards = Vec::with_capacity(count);
+        for index in 0..count {
+            let shard_args = EngineArgs {
+                memtable: MemtableArgs { capacity: args.memtable.capacity },
+                store: args.store.clone(),
+                listeners: args.listeners.clone(),
+                row_cache: RowCacheArgs { capacity: args.row_cache.capacity / count },
+                read_only: args.read_only,
+                size_limits: args.size_limits,
+                verify_on_open: args.verify_on_open,
+                ..Default::default()
+            };
+            let directory = shard::shard_directory(&path, index);
+            shards.push(Self::open(directory, shard_args, write_limiter.clone())?);
+        }
+        // The engine's own store never holds anything, so it has nothing to compact.
+        let args = EngineArgs {
+            store: StoreArgs { compaction_enabled: false, ..args.store },
+            listeners: args.listeners,
+            read_only: args.read_only,
+            size_limits: args.size_limits,
+            ..Default::default()
+        };
+        let mut engine = Self::open(path, args, write_limiter)?;
+        engine.shards = shards;
+        log::debug!("engine split into {count} shards");
+        Ok(engine)
+    }
+
+    fn open(
+        path: PathBuf,
+        args: EngineArgs,
+        write_limiter: Arc<Mutex<WriteLimiter>>,
+    ) -> Result<Self, Error> {
+        let retained_versions = args.store.retained_versions.max(1);
+        let index_args = StoreArgs { retained_versions: 1, ..args.store.clone() };
+        let mut memtable = Memtable::new(args.memtable).with_retained_versions(retained_versions);
+        let listeners = Listeners::new(args.listeners);
+        let store = Store::with_listeners(path, args.store, listeners.clone())?;
+        if args.verify_on_open {
+            store.verify()?;
+        }
+        let recovery = store.replay_wal(&mut memtable)?;
+        let last_sequence = store.max_version()?.max(memtable.max_version());
         log::debug!("engine initialized");
-        Ok(Self { memtable, store })
+        Ok(Self {
+            memtables: RwLock::new(Memtables {
+                active: Arc::new(memtable),
+                flushing: None,
+                resident: None,
+            }),
+            store,
+            writer: Mutex::new(last_sequence),
+            retained_versions,
+            write_limiter,
+            recovery,
+            indexes: RwLock::default(),
+            index_args,
+            listeners,
+            row_cache: (args.row_cache.capacity > 0).then(|| RowCache::new(args.row_cache)),
+            shards: Vec::new(),
+            read_only: args.read_only,
+            size_limits: args.size_limits,
+            temp_directory: None,
+        })
+    }
+
+    /// The largest keys and values that the engine accepts.
+    pub fn size_limits(&self) -> SizeLimits {
+        self.size_limits
+    }
+
+    /// The shards that the engine is split into, or none if it isn't.
+    pub fn shards(&self) -> &[Engine] {
+        &self.shards
+    }
+
+    /// The shard that `key` belongs to, if the engine is sharded.
+    fn shard(&self, key: &str) -> Option<&Engine> {
+        (!self.shards.is_empty()).then(|| &self.shards[shard::shard_of(key, self.shards.len())])
+    }
+
+    /// What was dropped from the WAL when the engine opened, if it was corrupt
+    /// and [`RecoveryMode::Salvage`](crate::wal::RecoveryMode::Salvage) is in
+    /// use. For a sharded engine, this is what was dropped from the first shard
+    /// that dropped anything.
+    pub fn wal_salvage(&self) -> Option<&Salvage> {
+        self.recovery.salvage.as_ref().or_else(|| self.shards.iter().find_map(Engine::wal_salvage))
+    }
+
+    /// What was replayed from the WAL when the engine opened. A sharded engine
+    /// replays nothing itself, and each of its [`Self::shards`] has its own
+    /// report.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+
+    pub fn write_limits(&self) -> Result<WriteLimits, Error> {
+        Ok(self.write_limiter.lock()?.limits())
+    }
+
+    /// Replace the caps on the rate of writes. Writes that are already waiting
+    /// on the old limits wait them out.
+    pub fn set_write_limits(&self, limits: WriteLimits) -> Result<(), Error> {
+        let mut limiter = self.write_limiter.lock()?;
+        limiter.bytes.set_rate(limits.bytes_per_second);
+        limiter.operations.set_rate(limits.operations_per_second);
+        log::debug!("write limits set to {limits:?}");
+        Ok(())
+    }
+
+    /// Take `writer` for a write, which a read-only engine refuses.
+    fn lock_for_write(&self) -> Result<MutexGuard<'_, u64>, Error> {
+        if self.read_only {
+            return Err(anyhow!("the engine was opened read-only").into());
+        }
+        Ok(self.writer.lock()?)
+    }
+
+    /// Block until the write limits allow `operations` writes of `bytes`.
+    fn throttle(&self, operations: u64, bytes: u64) -> Result<(), Error> {
+        let mut limiter = self.write_limiter.lock()?;
+        limiter.operations.acquire(operations);
+        limiter.bytes.acquire(bytes);
+        Ok(())
     }
 
     /// Set `key` to `value`.
@@ -40,47 +548,962 @@ impl Engine {
     /// This operation is fast in LSM storage engines because the data is only
     /// written to the append-only WAL and stored in the memtable at write time.
     /// Data is flushed to segment files *asynchronously*.
-    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
-        self.store.set(key, value)?;
-        self.memtable.set(key, value);
-        if self.memtable.full() {
+    pub fn set(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.size_limits.check(key, Some(value))?;
+        if let Some(shard) = self.shard(key) {
+            return shard.set(key, value);
+        }
+        self.store.metrics().sets.increment();
+        self.throttle(1, (key.len() + value.len()) as u64)?;
+        let pending = self.set_locked(&mut *self.lock_for_write()?, key, value)?;
+        self.store.commit(pending)
+    }
+
+    /// Set `key` to `value`, returning the value that it had before. No other
+    /// write can come between the two.
+    pub fn get_set(&self, key: &str, value: &str) -> Result<Value, Error> {
+        self.size_limits.check(key, Some(value))?;
+        if let Some(shard) = self.shard(key) {
+            return shard.get_set(key, value);
+        }
+        self.store.metrics().sets.increment();
+        self.throttle(1, (key.len() + value.len()) as u64)?;
+        let mut writer = self.lock_for_write()?;
+        let previous = self.current_value(key)?;
+        let pending = self.set_locked(&mut writer, key, value)?;
+        drop(writer);
+        self.store.commit(pending)?;
+        Ok(previous)
+    }
+
+    /// Set `key` to `value` only if it has no value, returning whether it was
+    /// set. No other write can come between checking the key and setting it.
+    pub fn set_if_absent(&self, key: &str, value: &str) -> Result<bool, Error> {
+        self.size_limits.check(key, Some(value))?;
+        if let Some(shard) = self.shard(key) {
+            return shard.set_if_absent(key, value);
+        }
+        self.throttle(1, (key.len() + value.len()) as u64)?;
+        let mut writer = self.lock_for_write()?;
+        if self.current_value(key)?.is_some() {
+            return Ok(false);
+        }
+        self.store.metrics().sets.increment();
+        let pending = self.set_locked(&mut writer, key, value)?;
+        drop(writer);
+        self.store.commit(pending)?;
+        Ok(true)
+    }
+
+    /// Add `suffix` to the end of the value of `key`, which counts as empty if
+    /// it has none, and return the new value. No other write can come between
+    /// reading the value and replacing it.
+    pub fn append(&self, key: &str, suffix: &str) -> Result<Arc<str>, Error> {
+        self.size_limits.check(key, Some(suffix))?;
+        if let Some(shard) = self.shard(key) {
+            return shard.append(key, suffix);
+        }
+        self.store.metrics().sets.increment();
+        self.throttle(1, (key.len() + suffix.len()) as u64)?;
+        let mut writer = self.lock_for_write()?;
+        let value: Arc<str> = match self.current_value(key)? {
+            Some(value) => {
+                self.size_limits.check_value(value.len() + suffix.len())?;
+                format!("{value}{suffix}").into()
+            },
+            None => suffix.into(),
+        };
+        let pending = self.set_locked(&mut writer, key, &value)?;
+        drop(writer);
+        self.store.commit(pending)?;
+        Ok(value)
+    }
+
+    /// Set `key` to `value`, while holding the `writer` lock. The write must
+    /// be committed once the lock is released.
+    fn set_locked(&self, writer: &mut u64, key: &str, value: &str) -> Result<Pending, Error> {
+        let indexes = self.indexes.read()?;
+        let writes = [(key, Some(value))];
+        let replaced = self.begin_index_updates(&indexes, &writes)?;
+        let sequence = self.next_sequence(writer);
+        let pending = self.store.set(key, value, sequence)?;
+        let full = {
+            let mut memtables = self.memtables.write()?;
+            memtables.write(key, Some(value), sequence);
+            memtables.active.full()
+        };
+        self.invalidate_cached([key])?;
+        self.listeners.notify(|listener| listener.on_set(key, value));
+        if full {
             self.flush_memtable()?;
         }
-        Ok(())
+        Self::finish_index_updates(&indexes, &writes, replaced)?;
+        Ok(pending)
+    }
+
+    /// Like [`Self::get`], without counting it as one.
+    fn current_value(&self, key: &str) -> Result<Value, Error> {
+        let epoch = self.row_cache.as_ref().map_or(0, RowCache::epoch);
+        if let Some(value) = self.memtables.read()?.get(key) {
+            return Ok(value);
+        }
+        self.get_from_store(key, epoch)
     }
 
     /// Get the value for `key`, if any.
-    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
-        if let Some(value) = self.memtable.get(key) {
+    ///
+    /// A value that is still in a memtable, or in the row cache, is shared
+    /// with it rather than copied, so reads of recently written or read keys
+    /// don't allocate.
+    pub fn get(&self, key: &str) -> Result<Value, Error> {
+        if let Some(shard) = self.shard(key) {
+            return shard.get(key);
+        }
+        // A flush only drops its memtable once the segment file holding its contents
+        // is part of the store, so a key can't fall between the two lookups.
+        self.store.metrics().gets.increment();
+        let epoch = self.row_cache.as_ref().map_or(0, RowCache::epoch);
+        if let Some(value) = self.memtables.read()?.get(key) {
+            self.store.metrics().segment_probes.record(0);
             return Ok(value);
         }
-        self.store.get(key)
+        self.get_from_store(key, epoch)
+    }
+
+    /// Read `key` from the row cache if it is there, or otherwise off disk,
+    /// caching what is found unless a write was made since `epoch`, which is
+    /// taken before the memtables are looked in.
+    fn get_from_store(&self, key: &str, epoch: u64) -> Result<Value, Error> {
+        let Some(cache) = &self.row_cache else {
+            return self.store.get(key);
+        };
+        let metrics = self.store.metrics();
+        if let Some(value) = cache.get(key)? {
+            metrics.row_cache_hits.increment();
+            metrics.segment_probes.record(0);
+            return Ok(value);
+        }
+        metrics.row_cache_misses.increment();
+        let value = self.store.get(key)?;
+        cache.insert(key, value.clone(), epoch)?;
+        Ok(value)
+    }
+
+    /// Drop `keys` from the row cache, once writes to them are in the
+    /// memtable.
+    fn invalidate_cached<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Result<(), Error> {
+        match &self.row_cache {
+            Some(cache) => cache.invalidate(keys),
+            None => Ok(()),
+        }
+    }
+
+    /// Empty the row cache, once writes that skip the memtable are in the
+    /// store.
+    fn clear_cache(&self) -> Result<(), Error> {
+        match &self.row_cache {
+            Some(cache) => cache.clear(),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the value that `key` had as of the write with `sequence`, if
+    /// versions are retained and that one still is. Writes made before
+    /// versions were retained count as made at sequence 0.
+    ///
+    /// Every write is numbered, one after another, as it is made. A batch is
+    /// numbered as a single write. Compaction discards versions beyond the
+    /// [`StoreArgs::retained_versions`], so reads far enough back find nothing.
+    pub fn get_at(&self, key: &str, sequence: u64) -> Result<Value, Error> {
+        if self.retained_versions == 1 {
+            return Err(anyhow!("versions aren't retained, so they can't be read").into());
+        }
+        if let Some(value) = self.memtables.read()?.get_at(key, sequence) {
+            return Ok(value);
+        }
+        Ok(self.store.get_at(key, sequence)?.flatten())
+    }
+
+    /// The sequence number of the last write, which [`Self::get_at`] reads as
+    /// of. It is always 0 unless versions are retained.
+    pub fn last_sequence(&self) -> Result<u64, Error> {
+        Ok(*self.writer.lock()?)
+    }
+
+    /// The sequence number for the next write, given the `last` one, or `None`
+    /// if versions aren't retained.
+    fn next_sequence(&self, last: &mut u64) -> Option<u64> {
+        if self.retained_versions == 1 {
+            return None;
+        }
+        *last += 1;
+        Some(*last)
+    }
+
+    /// Whether `key` has a value, without reading that value off disk.
+    pub fn exists(&self, key: &str) -> Result<bool, Error> {
+        if let Some(shard) = self.shard(key) {
+            return shard.exists(key);
+        }
+        let value = self.memtables.read()?.get(key);
+        if let Some(value) = value {
+            return Ok(value.is_some());
+        }
+        match self.row_cache.as_ref().map(|cache| cache.get(key)).transpose()?.flatten() {
+            Some(value) => Ok(value.is_some()),
+            None => self.store.exists(key),
+        }
     }
 
     /// Delete the `key`.
-    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
-        self.store.delete(key)?;
-        self.memtable.delete(key);
+    pub fn delete(&self, key: &str) -> Result<(), Error> {
+        self.size_limits.check(key, None)?;
+        if let Some(shard) = self.shard(key) {
+            return shard.delete(key);
+        }
+        self.store.metrics().deletes.increment();
+        self.throttle(1, key.len() as u64)?;
+        let pending = self.delete_locked(&mut *self.lock_for_write()?, key)?;
+        self.store.commit(pending)
+    }
+
+    /// Delete `key`, returning the value that it had. No other write can come
+    /// between the two, so when several callers race to take the same key,
+    /// only one of them gets its value. A key without a value is left as it
+    /// is.
+    pub fn get_delete(&self, key: &str) -> Result<Value, Error> {
+        self.size_limits.check(key, None)?;
+        if let Some(shard) = self.shard(key) {
+            return shard.get_delete(key);
+        }
+        self.throttle(1, key.len() as u64)?;
+        let mut writer = self.lock_for_write()?;
+        let value = self.current_value(key)?;
+        if value.is_some() {
+            self.store.metrics().deletes.increment();
+            let pending = self.delete_locked(&mut writer, key)?;
+            drop(writer);
+            self.store.commit(pending)?;
+        }
+        Ok(value)
+    }
+
+    /// Delete `key`, while holding the `writer` lock. Like
+    /// [`Self::set_locked`], the write must be committed once the lock is
+    /// released.
+    fn delete_locked(&self, writer: &mut u64, key: &str) -> Result<Pending, Error> {
+        let indexes = self.indexes.read()?;
+        let writes = [(key, None)];
+        let replaced = self.begin_index_updates(&indexes, &writes)?;
+        let sequence = self.next_sequence(writer);
+        let pending = self.store.delete(key, sequence)?;
+        self.memtables.write()?.write(key, None, sequence);
+        self.invalidate_cached([key])?;
+        self.listeners.notify(|listener| listener.on_delete(key));
+        Self::finish_index_updates(&indexes, &writes, replaced)?;
+        Ok(pending)
+    }
+
+    /// Apply every write in `batch`, atomically.
+    ///
+    /// In a sharded engine, a batch is only atomic within each shard. The
+    /// writes to each shard are applied one shard after another, so a
+    /// reader can see some of them before the rest, and a crash partway
+    /// through can leave some shards written and not others.
+    pub fn apply(&self, batch: &WriteBatch) -> Result<(), Error> {
+        for entry in batch.entries() {
+            match entry {
+                Entry::Assignment { key, value } => self.size_limits.check(key, Some(value))?,
+                Entry::Tombstone { key } => self.size_limits.check(key, None)?,
+            }
+        }
+        if !self.shards.is_empty() {
+            let mut batches: Vec<_> = self.shards.iter().map(|_| WriteBatch::new()).collect();
+            for entry in batch.entries() {
+                let shard = &mut batches[shard::shard_of(entry.key(), self.shards.len())];
+                match entry {
+                    Entry::Assignment { key, value } => shard.set(key, value),
+                    Entry::Tombstone { key } => shard.delete(key),
+                }
+            }
+            for (shard, batch) in self.shards.iter().zip(&batches) {
+                if !batch.is_empty() {
+                    shard.apply(batch)?;
+                }
+            }
+            return Ok(());
+        }
+        let metrics = self.store.metrics();
+        let mut bytes = 0;
+        for entry in batch.entries() {
+            match entry {
+                Entry::Assignment { key, value } => {
+                    metrics.sets.increment();
+                    bytes += key.len() + value.len();
+                },
+                Entry::Tombstone { key } => {
+                    metrics.deletes.increment();
+                    bytes += key.len();
+                },
+            }
+        }
+        self.throttle(batch.len() as u64, bytes as u64)?;
+        let mut writer = self.lock_for_write()?;
+        let indexes = self.indexes.read()?;
+        // Only the last write to each key in the batch is ever visible.
+        let writes: Vec<_> = if indexes.is_empty() {
+            Vec::new()
+        } else {
+            let mut writes = BTreeMap::new();
+            for entry in batch.entries() {
+                match entry {
+                    Entry::Assignment { key, value } => writes.insert(key.as_str(), Some(&**value)),
+                    Entry::Tombstone { key } => writes.insert(key.as_str(), None),
+                };
+            }
+            writes.into_iter().collect()
+        };
+        let replaced = self.begin_index_updates(&indexes, &writes)?;
+        let sequence = self.next_sequence(&mut writer);
+        let pending = self.store.write(batch, sequence)?;
+        let full = {
+            let mut memtables = self.memtables.write()?;
+            for entry in batch.entries() {
+                match entry {
+                    Entry::Assignment { key, value } => memtables.write(key, Some(value), sequence),
+                    Entry::Tombstone { key } => memtables.write(key, None, sequence),
+                }
+            }
+            memtables.active.full()
+        };
+        self.invalidate_cached(batch.entries().iter().map(|entry| entry.key().as_str()))?;
+        for entry in batch.entries() {
+            match entry {
+                Entry::Assignment { key, value } => {
+                    self.listeners.notify(|listener| listener.on_set(key, value));
+                },
+                Entry::Tombstone { key } => {
+                    self.listeners.notify(|listener| listener.on_delete(key))
+                },
+            }
+        }
+        if full {
+            self.flush_memtable()?;
+        }
+        Self::finish_index_updates(&indexes, &writes, replaced)?;
+        drop((indexes, writer));
+        self.store.commit(pending)
+    }
+
+    /// Add the pre-built segment file at `path` to the store, as if its
+    /// entries were written now. It must be unencrypted, with its keys in
+    /// order, like those written by [`segment::write`](crate::segment::write)
+    /// in an offline job. The file itself is left where it is.
+    ///
+    /// The memtable is flushed first, so that the ingested entries replace
+    /// the ones written before them. Either every entry is added, or none are,
+    /// except in a sharded engine, where the file is split up and each shard
+    /// ingests its part in turn.
+    pub fn ingest_segment(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        if !self.shards.is_empty() {
+            return self.ingest_sharded(path);
+        }
+        let mut writer = self.lock_for_write()?;
+        let indexes = self.indexes.read()?;
+        let mut pairs = Vec::new();
+        if !indexes.is_empty() {
+            // Only the newest version of each key is visible.
+            let (_, error) = segment::read_sorted(path, None, |_, entry| {
+                if pairs.last().is_none_or(|(key, _): &(String, _)| key != entry.key()) {
+                    let value = match entry {
+                        EntryRef::Assignment { value, .. } => Some(value.to_owned()),
+                        EntryRef::Tombstone { .. } => None,
+                    };
+                    pairs.push((entry.key().to_owned(), value));
+                }
+                Ok(())
+            })?;
+            if let Some(error) = error {
+                return Err(anyhow!("{path:?} can't be ingested: {error}").into());
+            }
+        }
+        let writes: Vec<_> =
+            pairs.iter().map(|(key, value)| (key.as_str(), value.as_deref())).collect();
+        if !self.memtables.read()?.active.is_empty() {
+            self.flush_memtable()?;
+        }
+        let replaced = self.begin_index_updates(&indexes, &writes)?;
+        self.store.ingest_segment(path)?;
+        self.clear_cache()?;
+        // Versions in the file may be newer than any written here.
+        *writer = (*writer).max(self.store.max_version()?);
+        Self::finish_index_updates(&indexes, &writes, replaced)
+    }
+
+    /// Split the segment file at `path` into a file for each shard, next to the
+    /// engine's own store, and have each shard ingest its own.
+    fn ingest_sharded(&self, path: &Path) -> Result<(), Error> {
+        let count = self.shards.len();
+        let part_paths: Vec<_> = (0..count)
+            .map(|index| self.store.directory().join(format!("ingest-{index}.tmp")))
+            .collect();
+        let result = (|| {
+            let mut parts = Vec::with_capacity(count);
+            for part_path in &part_paths {
+                parts.push((BufWriter::new(File::create(part_path)?), 0));
+            }
+            let (_, error) = segment::read_sorted(path, None, |version, entry| {
+                let (part, entries) = &mut parts[shard::shard_of(entry.key(), count)];
+                if let Some(version) = version {
+                    segment::write_version(part, version)?;
+                }
+                *entries += 1;
+                entry.write_with(part, None).map(drop)
+            })?;
+            if let Some(error) = error {
+                return Err(anyhow!("{path:?} can't be ingested: {error}").into());
+            }
+            let mut filled = Vec::with_capacity(count);
+            for (part, entries) in parts {
+                part.into_inner().map_err(|error| error.into_error())?.sync_all()?;
+                filled.push(entries > 0);
+            }
+            for ((shard, part_path), filled) in self.shards.iter().zip(&part_paths).zip(filled) {
+                if filled {
+                    shard.ingest_segment(part_path)?;
+                }
+            }
+            Ok(())
+        })();
+        for part_path in &part_paths {
+            _ = fs::remove_file(part_path);
+        }
+        result
+    }
+
+    /// Write `pairs`, which must be sorted by key with no key repeated,
+    /// straight to a new segment file, as if they were written now. This skips
+    /// the WAL and the memtable, so it is much faster than setting each pair,
+    /// for loading a dataset into the engine. Event listeners aren't told
+    /// about the pairs.
+    ///
+    /// The memtable is flushed first, so that the pairs replace the values
+    /// written before them. Either every pair is written, or none are. Returns
+    /// the number of pairs written.
+    pub fn bulk_load(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<u64, Error> {
+        if !self.shards.is_empty() {
+            let mut parts: Vec<_> = self.shards.iter().map(|_| Vec::new()).collect();
+            let mut last: Option<String> = None;
+            for (key, value) in pairs {
+                if last.as_ref().is_some_and(|last| *last >= key) {
+                    return Err(
+                        anyhow!("bulk loaded keys must increase, but {key:?} doesn't").into()
+                    );
+                }
+                last = Some(key.clone());
+                parts[shard::shard_of(&key, self.shards.len())].push((key, value));
+            }
+            let mut written = 0;
+            for (shard, part) in self.shards.iter().zip(parts) {
+                if !part.is_empty() {
+                    written += shard.bulk_load(part)?;
+                }
+            }
+            return Ok(written);
+        }
+        let mut writer = self.lock_for_write()?;
+        let indexes = self.indexes.read()?;
+        if !self.memtables.read()?.active.is_empty() {
+            self.flush_memtable()?;
+        }
+        let sequence = self.next_sequence(&mut writer);
+        if indexes.is_empty() {
+            let written = self.store.bulk_load(pairs, sequence)?;
+            self.clear_cache()?;
+            return Ok(written);
+        }
+        // The pairs are held onto, to update the indexes with.
+        let pairs: Vec<_> = pairs.into_iter().collect();
+        let writes: Vec<_> =
+            pairs.iter().map(|(key, value)| (key.as_str(), Some(value.as_str()))).collect();
+        let replaced = self.begin_index_updates(&indexes, &writes)?;
+        let written = self.store.bulk_load(pairs.iter().cloned(), sequence)?;
+        self.clear_cache()?;
+        Self::finish_index_updates(&indexes, &writes, replaced)?;
+        Ok(written)
+    }
+
+    /// Register a secondary index called `name`, which indexes each value
+    /// under what `extract` derives from it, for [`Self::get_by_index`].
+    ///
+    /// Indexes aren't remembered between runs, so register each one every time
+    /// the engine is opened, with the same extractor. The first time, every
+    /// live value is indexed before this returns, and writes wait until then.
+    pub fn register_index(
+        &self,
+        name: &str,
+        extract: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        let extract: Extractor = Arc::new(extract);
+        if self.shards.is_empty() {
+            return self.register_shared_index(name, extract);
+        }
+        for shard in &self.shards {
+            shard.register_shared_index(name, extract.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::register_index`], for an engine that isn't sharded, with
+    /// an extractor that its shards can share.
+    fn register_shared_index(&self, name: &str, extract: Extractor) -> Result<(), Error> {
+        if self.store.is_in_memory() {
+            return Err(anyhow!("an in-memory engine can't have secondary indexes").into());
+        }
+        let _writer = self.lock_for_write()?;
+        let mut indexes = self.indexes.write()?;
+        if indexes.iter().any(|index| index.name() == name) {
+            return Err(anyhow!("an index called {name} is already registered").into());
+        }
+        let args = EngineArgs {
+            memtable: MemtableArgs { capacity: self.memtables.read()?.active.capacity() },
+            store: self.index_args.clone(),
+            ..Default::default()
+        };
+        let (index, built) = SecondaryIndex::open(name, extract, self.store.directory(), args)?;
+        if !built {
+            for entry in self.entries()? {
+                let (key, value) = entry?;
+                index.insert(&key, &value)?;
+            }
+            index.mark_built()?;
+            log::info!("built index {name}");
+        }
+        indexes.push(index);
+        Ok(())
+    }
+
+    /// The keys whose values the index called `index` derives `value` from, in
+    /// key order.
+    pub fn get_by_index(&self, index: &str, value: &str) -> Result<Vec<String>, Error> {
+        if !self.shards.is_empty() {
+            let mut keys = Vec::new();
+            for shard in &self.shards {
+                keys.extend(shard.get_by_index(index, value)?);
+            }
+            keys.sort_unstable();
+            return Ok(keys);
+        }
+        let indexes = self.indexes.read()?;
+        let index = indexes
+            .iter()
+            .find(|registered| registered.name() == index)
+            .ok_or_else(|| anyhow!("no index called {index} is registered"))?;
+        let mut keys = Vec::new();
+        for key in index.candidates(value)? {
+            // The entry may have been left behind by a crash partway through a write.
+            if self.current_value(&key)?.is_some_and(|current| index.matches(&current, value)) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Read the values that `writes` replace, and add the index entries for
+    /// their new values. Returns the replaced values, to pass on to
+    /// [`Self::finish_index_updates`] once the writes are made.
+    fn begin_index_updates(
+        &self,
+        indexes: &[SecondaryIndex],
+        writes: &[(&str, Option<&str>)],
+    ) -> Result<Vec<Value>, Error> {
+        if indexes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut replaced = Vec::with_capacity(writes.len());
+        for (key, value) in writes {
+            replaced.push(self.current_value(key)?);
+            if let Some(value) = value {
+                for index in indexes {
+                    index.insert(key, value)?;
+                }
+            }
+        }
+        Ok(replaced)
+    }
+
+    /// Remove the index entries for the `replaced` values, which `writes` have
+    /// made stale.
+    fn finish_index_updates(
+        indexes: &[SecondaryIndex],
+        writes: &[(&str, Option<&str>)],
+        replaced: Vec<Value>,
+    ) -> Result<(), Error> {
+        for ((key, value), replaced) in writes.iter().zip(replaced) {
+            for index in indexes {
+                index.remove_stale(key, replaced.as_deref(), *value)?;
+            }
+        }
         Ok(())
     }
 
-    /// List all keys in the database.
-    pub fn list(&self) -> Result<Vec<String>, Error> {
-        Ok(Vec::new())
+    /// Return up to `limit` live key-value pairs, in key order, starting from
+    /// the first key that is at least `start`.
+    ///
+    /// Pass the returned [`ScanPage::next`] back in as `start` to continue
+    /// from where this page left off.
+    pub fn scan(&self, start: &str, limit: usize) -> Result<ScanPage, Error> {
+        let mut entries = self.live_entries(start, false)?;
+        let mut page = ScanPage::default();
+        for entry in entries.by_ref().take(limit) {
+            page.entries.push(entry?);
+        }
+        page.next = entries.next().transpose()?.map(|(key, _)| key);
+        Ok(page)
+    }
+
+    /// Every live key-value pair in the database, in key order.
+    ///
+    /// The pairs are as of when this is called: writes made while iterating
+    /// aren't seen, and neither flushes nor compaction can change what is
+    /// returned. Iteration ends after the first error, such as a corrupt
+    /// segment file.
+    pub fn entries(&self) -> Result<impl Iterator<Item = Result<(String, String), Error>>, Error> {
+        self.live_entries("", false)
+    }
+
+    /// Every live key-value pair whose key starts with `prefix`, in key order,
+    /// as of when this is called, like [`Self::entries`].
+    ///
+    /// Segment files whose key range or prefix bloom filter rules the prefix
+    /// out aren't read at all.
+    pub fn scan_prefix<'a>(
+        &self,
+        prefix: &'a str,
+    ) -> Result<impl Iterator<Item = Result<(String, String), Error>> + 'a, Error> {
+        Ok(self.live_entries(prefix, true)?.map_while(move |entry| match entry {
+            Ok((key, value)) => key.starts_with(prefix).then_some(Ok((key, value))),
+            Err(error) => Some(Err(error)),
+        }))
+    }
+
+    /// Every live key-value pair from the first key that is at least `start`,
+    /// in key order, as of when this is called. If `prefix` is set, `start` is
+    /// a prefix, and only segment files that may hold keys with it are read,
+    /// so iteration must stop at the first key without it.
+    fn live_entries(&self, start: &str, prefix: bool) -> Result<LiveEntries, Error> {
+        if !self.shards.is_empty() {
+            // No two shards hold the same key, so the merge only has to pick the
+            // smallest next key.
+            let mut shards = Vec::with_capacity(self.shards.len());
+            for shard in &self.shards {
+                shards.push(shard.live_entries(start, prefix)?.peekable());
+            }
+            return Ok(Box::new(std::iter::from_fn(move || {
+                let next = shards
+                    .iter_mut()
+                    .enumerate()
+                    .filter_map(|(index, entries)| Some((index, entries.peek()?)))
+                    .min_by(|(_, a), (_, b)| match (a, b) {
+                        (Ok((a, _)), Ok((b, _))) => a.cmp(b),
+                        (Err(_), _) => Ordering::Less,
+                        (_, Err(_)) => Ordering::Greater,
+                    })
+                    .map(|(index, _)| index)?;
+                shards[next].next()
+            })));
+        }
+        // The memtables are shared rather than copied, and frozen from here on, so
+        // that the lock on them isn't held while the bulk of the segment files are
+        // read.
+        let (memtable, store) = {
+            let memtables = self.memtables.read()?;
+            let memtable = SnapshotRange::new(memtables.iter().cloned().collect(), start);
+            // The segment set is pinned before the lock is released, so that a flush
+            // can't move entries out of the memtables and into a new segment file in
+            // between. Files that compaction retires later stay on disk until the
+            // iterator is dropped.
+            let store = match prefix {
+                true => self.store.prefix_range(start)?,
+                false => self.store.range(start)?,
+            };
+            (memtable, store)
+        };
+        let mut memtable = memtable.peekable();
+        let mut store = store.peekable();
+        let merged = std::iter::from_fn(move || {
+            // The memtable is newer than anything on disk, so it wins ties.
+            let order = match (memtable.peek(), store.peek()) {
+                (None, None) => return None,
+                (_, Some(Err(_))) => return store.next(),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((memtable_key, _)), Some(Ok((store_key, _)))) => memtable_key.cmp(store_key),
+            };
+            match order {
+                Ordering::Greater => store.next(),
+                Ordering::Equal | Ordering::Less => {
+                    if order == Ordering::Equal {
+                        store.next();
+                    }
+                    memtable
+                        .next()
+                        .map(|(key, value)| Ok((key, value.map(|value| value.to_string()))))
+                },
+            }
+        });
+        Ok(Box::new(merged.filter_map(|entry| match entry {
+            Ok((key, value)) => Some(Ok((key, value?))),
+            Err(error) => Some(Err(error)),
+        })))
+    }
+
+    /// The engine's counters since it was opened, summed over its shards if it
+    /// has any.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.shards.iter().fold(self.store.metrics().snapshot(), |mut metrics, shard| {
+            metrics += shard.metrics();
+            metrics
+        })
+    }
+
+    /// For a sharded engine, the memtable counts are summed over the shards,
+    /// and the segment files of every shard are listed one shard after another.
+    pub fn stats(&self) -> Result<EngineStats, Error> {
+        if !self.shards.is_empty() {
+            let mut stats = EngineStats::default();
+            for shard in &self.shards {
+                let shard = shard.stats()?;
+                stats.memtable_len += shard.memtable_len;
+                stats.memtable_capacity += shard.memtable_capacity;
+                stats.store.segments.extend(shard.store.segments);
+                stats.store.wal_bytes += shard.store.wal_bytes;
+                stats.store.last_compaction =
+                    stats.store.last_compaction.max(shard.store.last_compaction);
+                stats.store.flushed_bytes += shard.store.flushed_bytes;
+                stats.store.compacted_bytes += shard.store.compacted_bytes;
+                stats.store.gets += shard.store.gets;
+                stats.store.segment_probes += shard.store.segment_probes;
+            }
+            return Ok(stats);
+        }
+        let (memtable_len, memtable_capacity) = {
+            let memtables = self.memtables.read()?;
+            (memtables.active.len(), memtables.active.capacity())
+        };
+        Ok(EngineStats { memtable_len, memtable_capacity, store: self.store.stats()? })
+    }
+
+    /// The value of the property called `name`, one of [`PROPERTIES`], or
+    /// `None` if there is no such property or it has no value yet.
+    ///
+    /// Counts and sizes are plain integers, with sizes in bytes, ratios are
+    /// decimals between 0 and 1, and times are Unix timestamps in seconds.
+    /// Memtable properties cover the memtable being flushed, if there is one.
+    /// For a sharded engine, counts and sizes are summed over the shards.
+    pub fn get_property(&self, name: &str) -> Result<Option<String>, Error> {
+        let Some(property) = name.strip_prefix("crunch.") else {
+            return Ok(None);
+        };
+        if !self.shards.is_empty() {
+            return self.sharded_property(name, property);
+        }
+        if let Some(level) = property.strip_prefix("num-segments-at-level") {
+            let Ok(level) = level.parse::<u32>() else {
+                return Ok(None);
+            };
+            let stats = self.store.stats()?;
+            let count = stats.segments.iter().filter(|segment| segment.level == level).count();
+            return Ok(Some(count.to_string()));
+        }
+        let value = match property {
+            "num-segments" => self.store.stats()?.segment_count().to_string(),
+            "segment-bytes" => self.store.stats()?.segment_bytes().to_string(),
+            "memtable-entries" => self
+                .memtables
+                .read()?
+                .iter()
+                .map(|memtable| memtable.len())
+                .sum::<usize>()
+                .to_string(),
+            "memtable-bytes" => {
+                let memtables = self.memtables.read()?;
+                memtables.iter().map(|memtable| memtable.data_size()).sum::<usize>().to_string()
+            },
+            "wal-bytes" => self.store.wal_size()?.to_string(),
+            "estimate-num-keys" => self.approximate_len()?.to_string(),
+            "estimate-dead-ratio" => match self.store.stats()?.estimated_dead_ratio() {
+                Some(ratio) => ratio.to_string(),
+                None => return Ok(None),
+            },
+            "num-compactions" => self.store.compaction_stats()?.compactions.to_string(),
+            "last-compaction" => {
+                let finished_at = self.store.compaction_stats()?.last_finished_at;
+                match finished_at.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+                    Some(since) => since.as_secs().to_string(),
+                    None => return Ok(None),
+                }
+            },
+            "last-sequence" => self.last_sequence()?.to_string(),
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    /// [`Self::get_property`] for a sharded engine, where `property` is `name`
+    /// without its prefix.
+    fn sharded_property(&self, name: &str, property: &str) -> Result<Option<String>, Error> {
+        let value = match property {
+            "estimate-dead-ratio" => match self.stats()?.store.estimated_dead_ratio() {
+                Some(ratio) => ratio.to_string(),
+                None => return Ok(None),
+            },
+            "last-compaction" => {
+                let mut last: Option<u64> = None;
+                for shard in &self.shards {
+                    last = last.max(shard.get_property(name)?.and_then(|time| time.parse().ok()));
+                }
+                match last {
+                    Some(last) => last.to_string(),
+                    None => return Ok(None),
+                }
+            },
+            "last-sequence" => self.last_sequence()?.to_string(),
+            _ => {
+                let mut total = 0;
+                for shard in &self.shards {
+                    let Some(value) = shard.get_property(name)? else {
+                        return Ok(None);
+                    };
+                    total += value.parse::<u64>().map_err(|error| anyhow!("{name}: {error}"))?;
+                }
+                total.to_string()
+            },
+        };
+        Ok(Some(value))
+    }
+
+    /// Estimate the number of live keys, without reading through the data.
+    ///
+    /// Every value counts once and every tombstone cancels one out. A key that
+    /// has been written in more than one segment (or in a segment and the
+    /// memtable) is overcounted until compaction merges those writes together,
+    /// and a tombstone for a key that was never flushed cancels out some other
+    /// key, until compaction drops it.
+    pub fn approximate_len(&self) -> Result<u64, Error> {
+        if !self.shards.is_empty() {
+            return self.shards.iter().map(Engine::approximate_len).sum();
+        }
+        let (mut values, mut tombstones) = self.store.entry_counts()?;
+        for memtable in self.memtables.read()?.iter() {
+            let memtable_tombstones = memtable.tombstone_count() as u64;
+            values += memtable.len() as u64 - memtable_tombstones;
+            tombstones += memtable_tombstones;
+        }
+        Ok(values.saturating_sub(tombstones))
+    }
+
+    /// List the live keys that start with `prefix`, in key order, stopping
+    /// after `limit` of them if it is given.
+    pub fn list(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<String>, Error> {
+        let keys = self.scan_prefix(prefix)?.map(|entry| entry.map(|(key, _)| key));
+        match limit {
+            Some(limit) => keys.take(limit).collect(),
+            None => keys.collect(),
+        }
+    }
+
+    /// Upload a consistent copy of the store to `target`, under `name`. See
+    /// [`crate::backup`] for what a backup holds.
+    ///
+    /// Writes are only paused while the store's files are captured, and not
+    /// while they are uploaded. A sharded engine pauses writes to every shard
+    /// at once, and uploads each shard where it is in the engine's directory.
+    pub fn backup(&self, target: &dyn BackupTarget, name: &str) -> Result<BackupReport, Error> {
+        if !self.shards.is_empty() {
+            let snapshots = {
+                let mut writers = Vec::with_capacity(self.shards.len());
+                for shard in &self.shards {
+                    writers.push(shard.writer.lock()?);
+                }
+                let mut snapshots = Vec::with_capacity(self.shards.len());
+                for shard in &self.shards {
+                    snapshots.push(shard.store.snapshot_files()?);
+                }
+                snapshots
+            };
+            let mut report = BackupReport::default();
+            for (index, snapshot) in snapshots.iter().enumerate() {
+                let shard = snapshot.upload(target, &format!("{name}/shards/{index}"))?;
+                report.segment_files += shard.segment_files;
+                report.wal_files += shard.wal_files;
+                report.bytes += shard.bytes;
+            }
+            let count = self.shards.len().to_string();
+            let path = format!("{name}/{}", shard::SHARDS_FILENAME);
+            target.put(&path, &mut count.as_bytes(), count.len() as u64)?;
+            report.bytes += count.len() as u64;
+            return Ok(report);
+        }
+        let snapshot = {
+            let _writer = self.writer.lock()?;
+            self.store.snapshot_files()?
+        };
+        snapshot.upload(target, name)
     }
 
     /// Gracefully shutdown the storage engine.
     pub fn stop(self) -> thread::Result<()> {
+        for shard in self.shards {
+            shard.stop()?;
+        }
+        for index in self.indexes.into_inner().unwrap_or_else(PoisonError::into_inner) {
+            index.stop()?;
+        }
         self.store.stop()
     }
 
-    fn flush_memtable(&mut self) -> Result<(), Error> {
-        log::debug!("memtable has hit capacity ({}), flushing to disk", self.memtable.capacity());
-        self.store.write_memtable(&self.memtable)?;
-        self.memtable.reset();
-        Ok(())
+    /// Write the active memtable out to a segment file, and start a new one. An
+    /// in-memory engine merges it into its in-memory segment instead.
+    ///
+    /// Only call this while holding `writer`. Reads carry on against the full
+    /// memtable while it is written out.
+    fn flush_memtable(&self) -> Result<(), Error> {
+        let memtable = {
+            let mut memtables = self.memtables.write()?;
+            let capacity = memtables.active.capacity();
+            let empty = Memtable::new(MemtableArgs { capacity })
+                .with_retained_versions(self.retained_versions);
+            let empty = Arc::new(empty);
+            if let Some(mut resident) = memtables.resident.take() {
+                log::debug!("memtable has hit capacity ({capacity}), merging it into memory");
+                let memtable = mem::replace(&mut memtables.active, empty);
+                Arc::make_mut(&mut resident).absorb(&memtable);
+                memtables.resident = Some(resident);
+                return Ok(());
+            }
+            log::debug!("memtable has hit capacity ({capacity}), flushing to disk");
+            let memtable = mem::replace(&mut memtables.active, empty);
+            memtables.flushing = Some(memtable.clone());
+            memtable
+        };
+        let result = self.store.write_memtable(&memtable);
+        let mut memtables = self.memtables.write()?;
+        if result.is_err() {
+            // Put the full memtable back, so that the flush is tried again on the next
+            // write. No write can have reached the new one while `writer` is held.
+            memtables.active = memtable;
+        }
+        memtables.flushing = None;
+        result
     }
 
+    /// The engine's own store. In a sharded engine, this holds none of the
+    /// data, which is in the stores of the [`Self::shards`].
     pub fn store(&self) -> &Store {
         &self.store
     }
@@ -89,14 +1512,844 @@ impl Engine {
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
-    use std::fs::remove_dir_all;
-    use std::time::Duration;
+    use std::fs::{self, remove_dir_all};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::{Duration, Instant};
 
     use rand::seq::SliceRandom;
     use rand::Rng;
 
     use super::*;
+    use crate::backup::LocalDirectory;
+    use crate::encryption::{KeyProvider, StaticKey};
     use crate::segment::is_segment_filename;
+    use crate::test::StoreFixture;
+
+    #[test]
+    fn reads_share_memtable_values() {
+        let fixture = StoreFixture::init("./test-db-engine-shared-reads");
+        let engine = Engine::new(fixture.path().to_owned()).unwrap();
+        engine.set("a", "1").unwrap();
+        let first = engine.get("a").unwrap().unwrap();
+        let second = engine.get("a").unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn concurrent_writes_share_fsyncs() {
+        let fixture = StoreFixture::init("./test-db-engine-group-commit");
+        let engine = Engine::new(fixture.path().to_owned()).unwrap();
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let engine = &engine;
+                scope.spawn(move || {
+                    for n in 0..50 {
+                        engine.set(&format!("{thread}-{n}"), "1").unwrap();
+                    }
+                });
+            }
+        });
+        // Writers that commit while another's fsync is in flight share the next one.
+        let metrics = engine.metrics();
+        assert_eq!(metrics.sets, 400);
+        assert!(metrics.wal_batches < 400, "{}", metrics.wal_batches);
+        assert_eq!(engine.approximate_len().unwrap(), 400);
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn size_limits() {
+        let size_limits = SizeLimits { max_key_bytes: 4, max_value_bytes: 8 };
+        let engine =
+            Engine::temp_with_args(EngineArgs { size_limits, ..Default::default() }).unwrap();
+        assert!(matches!(
+            engine.set("long key", "1"),
+            Err(Error::TooLarge(PairComponent::Key, 8, 4))
+        ));
+        assert!(matches!(
+            engine.set("key", "long value"),
+            Err(Error::TooLarge(PairComponent::Value, 10, 8))
+        ));
+        assert!(engine.delete("long key").is_err());
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1");
+        batch.set("b", "long value");
+        assert!(engine.apply(&batch).is_err());
+        assert_eq!(engine.get("a").unwrap(), None);
+
+        engine.set("key", "1234").unwrap();
+        engine.append("key", "5678").unwrap();
+        assert!(matches!(
+            engine.append("key", "9"),
+            Err(Error::TooLarge(PairComponent::Value, 9, 8))
+        ));
+        assert_eq!(engine.get("key").unwrap().as_deref(), Some("12345678"));
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn temp() {
+        let args = EngineArgs { memtable: MemtableArgs { capacity: 1 }, ..Default::default() };
+        let engine = Engine::temp_with_args(args).unwrap();
+        let other = Engine::temp().unwrap();
+        let path = engine.store().directory().to_owned();
+        assert_ne!(path, other.store().directory());
+        engine.set("a", "1").unwrap();
+        assert_eq!(engine.stats().unwrap().store.segment_count(), 1);
+        assert_eq!(engine.get("a").unwrap().as_deref(), Some("1"));
+        engine.stop().unwrap();
+        assert!(!path.exists());
+
+        let path = other.store().directory().to_owned();
+        drop(other);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn in_memory() {
+        let args = EngineArgs { memtable: MemtableArgs { capacity: 4 }, ..Default::default() };
+        let engine = Engine::in_memory_with_args(args).unwrap();
+        for n in 0..10 {
+            engine.set(&format!("key{n}"), &n.to_string()).unwrap();
+        }
+        engine.delete("key3").unwrap();
+        // These push the tombstone out of the active memtable.
+        for n in 10..14 {
+            engine.set(&format!("key{n}"), &n.to_string()).unwrap();
+        }
+        assert_eq!(engine.get("key2").unwrap().as_deref(), Some("2"));
+        assert_eq!(engine.get("key3").unwrap(), None);
+        assert!(!engine.exists("key3").unwrap());
+        assert_eq!(engine.list("key1", None).unwrap(), [
+            "key1", "key10", "key11", "key12", "key13"
+        ]);
+        assert_eq!(engine.approximate_len().unwrap(), 13);
+        // Only the two gets, both answered by the memtables, are counted.
+        assert_eq!(engine.stats().unwrap().store, StoreStats { gets: 2, ..Default::default() });
+
+        assert!(engine.bulk_load([("z".to_owned(), "1".to_owned())]).is_err());
+        assert!(engine.register_index("index", |value| Some(value.to_owned())).is_err());
+        assert!(
+            Engine::in_memory_with_args(EngineArgs { shards: 2, ..Default::default() }).is_err()
+        );
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn get_set_and_append() {
+        let fixture = StoreFixture::init("./test-db-engine-get-set");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(engine.get_set("a", "1").unwrap(), None);
+        assert_eq!(engine.get_set("a", "2").unwrap().as_deref(), Some("1"));
+        assert_eq!(&*engine.append("b", "x").unwrap(), "x");
+        // Both keys are in a segment file by now.
+        engine.set("c", "3").unwrap();
+        assert_eq!(&*engine.append("b", "yz").unwrap(), "xyz");
+        assert_eq!(engine.get_set("a", "3").unwrap().as_deref(), Some("2"));
+        assert_eq!(engine.get("a").unwrap().as_deref(), Some("3"));
+        assert_eq!(engine.get("b").unwrap().as_deref(), Some("xyz"));
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn set_if_absent() {
+        let fixture = StoreFixture::init("./test-db-engine-set-if-absent");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(engine.set_if_absent("a", "1").unwrap());
+        assert!(!engine.set_if_absent("a", "2").unwrap());
+        engine.set("b", "2").unwrap();
+        // "a" is only in a segment file by now.
+        assert!(!engine.set_if_absent("a", "3").unwrap());
+        assert_eq!(engine.get("a").unwrap().as_deref(), Some("1"));
+        engine.delete("a").unwrap();
+        assert!(engine.set_if_absent("a", "4").unwrap());
+        assert_eq!(engine.get("a").unwrap().as_deref(), Some("4"));
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn get_delete() {
+        let fixture = StoreFixture::init("./test-db-engine-get-delete");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            ..Default::default()
+        })
+        .unwrap();
+        engine.set("a", "1").unwrap();
+        engine.set("b", "2").unwrap();
+        // Both keys are only in a segment file by now.
+        assert_eq!(engine.get_delete("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(engine.get_delete("a").unwrap(), None);
+        assert_eq!(engine.get("a").unwrap(), None);
+        assert_eq!(engine.get_delete("c").unwrap(), None);
+        assert_eq!(engine.metrics().deletes, 1);
+
+        let engine = Arc::new(engine);
+        let takers: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = engine.clone();
+                thread::spawn(move || engine.get_delete("b").unwrap())
+            })
+            .collect();
+        let taken: Vec<_> = takers.into_iter().filter_map(|taker| taker.join().unwrap()).collect();
+        assert_eq!(taken.len(), 1);
+        Arc::into_inner(engine).unwrap().stop().unwrap();
+    }
+
+    #[test]
+    fn sharded() {
+        let fixture = StoreFixture::init("./test-db-engine-sharded");
+        let args = || EngineArgs {
+            memtable: MemtableArgs { capacity: 8 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            shards: 4,
+            ..Default::default()
+        };
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        engine.register_index("parity", |value| Some((value.len() % 2).to_string())).unwrap();
+        let mut expected = BTreeMap::new();
+        for n in 0..100 {
+            let (key, value) = (format!("key{n:03}"), "x".repeat(n % 3 + 1));
+            engine.set(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+        let mut batch = WriteBatch::new();
+        for n in (0..100).step_by(7) {
+            batch.delete(format!("key{n:03}"));
+            expected.remove(&format!("key{n:03}"));
+        }
+        batch.set("key100", "yy");
+        expected.insert("key100".to_owned(), "yy".to_owned());
+        engine.apply(&batch).unwrap();
+        assert!(engine.shards().iter().all(|shard| shard.approximate_len().unwrap() > 10));
+
+        assert_eq!(engine.get("key001").unwrap().as_deref(), Some("xx"));
+        assert_eq!(engine.get("key007").unwrap(), None);
+        let entries: Vec<_> = engine.entries().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries, expected.clone().into_iter().collect::<Vec<_>>());
+        let page = engine.scan("key050", 3).unwrap();
+        assert_eq!(page.entries.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(), [
+            "key050", "key051", "key052"
+        ]);
+        assert_eq!(page.next.as_deref(), Some("key053"));
+        assert_eq!(engine.list("key09", None).unwrap().len(), 8);
+        let odd: Vec<_> =
+            expected.iter().filter(|(_, value)| value.len() % 2 == 1).map(|(key, _)| key).collect();
+        assert_eq!(engine.get_by_index("parity", "1").unwrap().iter().collect::<Vec<_>>(), odd);
+        assert_eq!(engine.metrics().sets, 101);
+        assert_eq!(
+            engine.get_property("crunch.num-segments").unwrap(),
+            Some(engine.stats().unwrap().store.segment_count().to_string())
+        );
+        engine.stop().unwrap();
+
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        assert_eq!(engine.get("key100").unwrap().as_deref(), Some("yy"));
+        engine
+            .bulk_load([("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())])
+            .unwrap();
+        assert_eq!(engine.get("b").unwrap().as_deref(), Some("2"));
+        let ingested = fixture.path().join("ingested");
+        let mut file = File::create(&ingested).unwrap();
+        for n in 0..20 {
+            segment::write(&mut file, &format!("i{n:02}"), "3").unwrap();
+        }
+        drop(file);
+        engine.ingest_segment(&ingested).unwrap();
+        assert_eq!(engine.list("i", None).unwrap().len(), 20);
+        let leftovers = fs::read_dir(fixture.path()).unwrap().filter(|entry| {
+            entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".tmp")
+        });
+        assert_eq!(leftovers.count(), 0);
+        engine.stop().unwrap();
+
+        let error =
+            Engine::with_args(fixture.path().to_owned(), EngineArgs { shards: 2, ..args() });
+        assert!(error.is_err());
+        let error = Engine::with_args(fixture.path().join("versions"), EngineArgs {
+            store: StoreArgs { retained_versions: 2, ..Default::default() },
+            ..args()
+        });
+        assert!(error.is_err());
+    }
+
+    #[test]
+    fn row_cache() {
+        let fixture = StoreFixture::init("./test-db-engine-row-cache");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            row_cache: RowCacheArgs { capacity: 1024 },
+            ..Default::default()
+        })
+        .unwrap();
+        engine.set("a", "1").unwrap();
+        engine.set("b", "2").unwrap();
+        assert_eq!(engine.get("a").unwrap().as_deref(), Some("1"));
+        let first = engine.get("a").unwrap().unwrap();
+        let second = engine.get("a").unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(engine.get("z").unwrap(), None);
+        assert_eq!(engine.get("z").unwrap(), None);
+        let metrics = engine.metrics();
+        assert_eq!((metrics.row_cache_hits, metrics.row_cache_misses), (3, 2));
+
+        // Writes replace what is cached, once they are flushed too.
+        engine.set("a", "3").unwrap();
+        engine.delete("b").unwrap();
+        assert_eq!(engine.get("a").unwrap().as_deref(), Some("3"));
+        assert_eq!(engine.get("b").unwrap(), None);
+        assert!(engine.exists("a").unwrap());
+        engine.bulk_load([("z".to_owned(), "4".to_owned())]).unwrap();
+        assert_eq!(engine.get("z").unwrap().as_deref(), Some("4"));
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn scan() {
+        let fixture = StoreFixture::init("./test-db-engine-scan");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 3 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
+        })
+        .unwrap();
+        // This spreads the keys, and the overwrites and deletes of them, across
+        // several segments and the memtable.
+        for key in ["e", "a", "c", "b", "d", "f", "g"] {
+            engine.set(key, "1").unwrap();
+        }
+        engine.set("c", "2").unwrap();
+        engine.delete("d").unwrap();
+        engine.delete("a").unwrap();
+        engine.set("h", "1").unwrap();
+
+        let first = engine.scan("", 3).unwrap();
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        assert_eq!(first.entries, pairs(&[("b", "1"), ("c", "2"), ("e", "1")]));
+        assert_eq!(first.next.as_deref(), Some("f"));
+
+        let second = engine.scan("f", 3).unwrap();
+        assert_eq!(second.entries, pairs(&[("f", "1"), ("g", "1"), ("h", "1")]));
+        assert_eq!(second.next, None);
+
+        assert_eq!(engine.scan("bb", 1).unwrap().entries, pairs(&[("c", "2")]));
+
+        assert!(engine.exists("e").unwrap());
+        assert!(engine.exists("h").unwrap());
+        assert!(!engine.exists("a").unwrap());
+        assert!(!engine.exists("d").unwrap());
+        assert!(!engine.exists("z").unwrap());
+        // "c" is overcounted, since it's written in two places.
+        assert_eq!(engine.approximate_len().unwrap(), 7);
+
+        engine.set("ga", "1").unwrap();
+        engine.set("gb", "1").unwrap();
+        assert_eq!(engine.list("g", None).unwrap(), ["g", "ga", "gb"]);
+        assert_eq!(engine.list("g", Some(2)).unwrap(), ["g", "ga"]);
+        assert_eq!(engine.list("", Some(3)).unwrap(), ["b", "c", "e"]);
+        assert!(engine.list("d", None).unwrap().is_empty());
+        let scanned: Result<Vec<_>, _> = engine.scan_prefix("g").unwrap().collect();
+        assert_eq!(scanned.unwrap(), pairs(&[("g", "1"), ("ga", "1"), ("gb", "1")]));
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn entries_is_a_snapshot() {
+        let fixture = StoreFixture::init("./test-db-engine-entries");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
+        })
+        .unwrap();
+        for key in ["a", "b", "c"] {
+            engine.set(key, "1").unwrap();
+        }
+        let entries = engine.entries().unwrap();
+        // These flush "c" out of the memtable that was copied, and add keys on
+        // either side of it.
+        engine.set("c", "2").unwrap();
+        engine.set("0", "2").unwrap();
+        engine.set("d", "2").unwrap();
+        let expected: Vec<_> =
+            ["a", "b", "c"].into_iter().map(|key| (key.to_owned(), "1".to_owned())).collect();
+        assert_eq!(entries.collect::<Result<Vec<_>, _>>().unwrap(), expected);
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn backup_opens_as_a_store() {
+        let fixture = StoreFixture::init("./test-db-engine-backup");
+        let backups = StoreFixture::init("./test-db-engine-backup-target");
+        let args = || EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
+        };
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        // Two keys are flushed to a segment file, and the third is only in the WAL.
+        for key in ["a", "b", "c"] {
+            engine.set(key, "1").unwrap();
+        }
+        let report = engine.backup(&LocalDirectory::new(backups.path()), "first").unwrap();
+        assert_eq!((report.segment_files, report.wal_files), (1, 1));
+        engine.set("c", "2").unwrap();
+        engine.stop().unwrap();
+
+        let restored = Engine::with_args(backups.path().join("first"), args()).unwrap();
+        assert_eq!(restored.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(restored.get("c").unwrap().as_deref(), Some("1"));
+        restored.stop().unwrap();
+    }
+
+    #[test]
+    fn versioned_reads() {
+        let fixture = StoreFixture::init("./test-db-engine-versioned-reads");
+        let args = || EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs {
+                compaction_enabled: false,
+                retained_versions: 3,
+                ..Default::default()
+            },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
+        };
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        engine.set("a", "1").unwrap();
+        engine.set("a", "2").unwrap();
+        engine.delete("a").unwrap();
+        // This fills the memtable, which flushes the versions of "a" to a segment.
+        engine.set("b", "1").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("a", "5");
+        batch.set("c", "5");
+        engine.apply(&batch).unwrap();
+
+        let check = |engine: &Engine| {
+            assert_eq!(engine.last_sequence().unwrap(), 5);
+            assert_eq!(engine.get_at("a", 0).unwrap(), None);
+            assert_eq!(engine.get_at("a", 1).unwrap().as_deref(), Some("1"));
+            assert_eq!(engine.get_at("a", 2).unwrap().as_deref(), Some("2"));
+            assert_eq!(engine.get_at("a", 4).unwrap(), None);
+            assert_eq!(engine.get_at("a", 5).unwrap().as_deref(), Some("5"));
+            assert_eq!(engine.get_at("b", 3).unwrap(), None);
+            assert_eq!(engine.get_at("b", 9).unwrap().as_deref(), Some("1"));
+            assert_eq!(engine.get("a").unwrap().as_deref(), Some("5"));
+        };
+        check(&engine);
+        engine.stop().unwrap();
+
+        // The sequence carries on from the versions in the WAL and segments.
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        check(&engine);
+        engine.set("a", "6").unwrap();
+        assert_eq!(engine.last_sequence().unwrap(), 6);
+        assert_eq!(engine.get_at("a", 5).unwrap().as_deref(), Some("5"));
+        let entries: Vec<_> = engine.entries().unwrap().collect::<Result<_, _>>().unwrap();
+        let expected: Vec<_> = [("a", "6"), ("b", "1"), ("c", "5")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        assert_eq!(entries, expected);
+        engine.stop().unwrap();
+
+        let engine = Engine::new(fixture.path().to_owned()).unwrap();
+        assert!(engine.get_at("a", 5).is_err());
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn secondary_index() {
+        let fixture = StoreFixture::init("./test-db-engine-secondary-index");
+        let args = || EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
+        };
+        // Values are "name|city", indexed by city.
+        let city = |value: &str| value.split_once('|').map(|(_, city)| city.to_owned());
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        engine.set("1", "ann|paris").unwrap();
+        engine.set("2", "bob|rome").unwrap();
+        engine.set("3", "cat").unwrap();
+        engine.register_index("city", city).unwrap();
+        assert_eq!(engine.get_by_index("city", "paris").unwrap(), ["1"]);
+
+        engine.set("4", "dan|paris").unwrap();
+        engine.set("1", "ann|rome").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("5", "eve|oslo");
+        batch.set("5", "eve|paris");
+        batch.delete("2");
+        engine.apply(&batch).unwrap();
+        assert_eq!(engine.get_by_index("city", "paris").unwrap(), ["4", "5"]);
+        assert_eq!(engine.get_by_index("city", "rome").unwrap(), ["1"]);
+        assert!(engine.get_by_index("city", "oslo").unwrap().is_empty());
+        assert!(engine.register_index("city", city).is_err());
+        assert!(engine.register_index("../city", city).is_err());
+        assert!(engine.get_by_index("name", "ann").is_err());
+        engine.stop().unwrap();
+
+        // The index is already built, so registering it again only reopens it.
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        engine.register_index("city", city).unwrap();
+        engine.delete("4").unwrap();
+        assert_eq!(engine.get_by_index("city", "paris").unwrap(), ["5"]);
+        assert_eq!(engine.get_by_index("city", "rome").unwrap(), ["1"]);
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn metrics() {
+        let fixture = StoreFixture::init("./test-db-engine-metrics");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
+        })
+        .unwrap();
+        // "a" and "b" are flushed, and "c" stays in the memtable.
+        for key in ["a", "b", "c"] {
+            engine.set(key, "1").unwrap();
+        }
+        engine.get("c").unwrap();
+        engine.get("a").unwrap();
+        // This is within the segment's key range, but not its bloom filter.
+        engine.get("aa").unwrap();
+        engine.get("z").unwrap();
+        engine.delete("c").unwrap();
+
+        let metrics = engine.metrics();
+        assert_eq!((metrics.gets, metrics.sets, metrics.deletes), (4, 3, 1));
+        assert_eq!((metrics.bloom_filter_skips, metrics.flushes), (1, 1));
+        assert_eq!(&metrics.segment_probes.counts[..3], [3, 1, 0]);
+        assert_eq!(metrics.segment_probes.sum, 1);
+        // Each set takes 11 bytes, and the tombstone 6.
+        assert_eq!(metrics.wal_bytes, 3 * 11 + 6);
+        assert_eq!(metrics.wal_batches, 4);
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn encryption() {
+        let fixture = StoreFixture::init("./test-db-engine-encryption");
+        let args = |key: Option<[u8; 32]>| EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs {
+                compaction_interval: Duration::ZERO,
+                compaction_trigger_segment_count: 2,
+                encryption: key.map(|key| Arc::new(StaticKey::new(key)) as Arc<dyn KeyProvider>),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let secret = "hunter2".repeat(1000);
+        let engine = Engine::with_args(fixture.path().to_owned(), args(Some([1; 32]))).unwrap();
+        for key in ["a", "b", "c", "d", "e"] {
+            engine.set(key, &secret).unwrap();
+        }
+        // Two flushes trip compaction, which merges them into one segment.
+        let started = Instant::now();
+        while engine.stats().unwrap().store.segment_count() > 1 {
+            assert!(started.elapsed() < Duration::from_secs(10), "compaction didn't run");
+            thread::sleep(Duration::from_millis(10));
+        }
+        engine.stop().unwrap();
+        for entry in fs::read_dir(fixture.path()).unwrap() {
+            let contents = fs::read(entry.unwrap().path()).unwrap();
+            assert!(!contents.windows(7).any(|window| window == b"hunter2"));
+        }
+
+        let engine = Engine::with_args(fixture.path().to_owned(), args(Some([1; 32]))).unwrap();
+        for key in ["a", "c", "e"] {
+            assert_eq!(engine.get(key).unwrap().as_deref(), Some(secret.as_str()));
+        }
+        engine.stop().unwrap();
+        assert!(Engine::with_args(fixture.path().to_owned(), args(None)).is_err());
+        assert!(Engine::with_args(fixture.path().to_owned(), args(Some([2; 32]))).is_err());
+    }
+
+    #[test]
+    fn write_limits() {
+        let fixture = StoreFixture::init("./test-db-engine-write-limits");
+        let limits = WriteLimits { bytes_per_second: 0, operations_per_second: 10 };
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            write_limits: limits,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(engine.write_limits().unwrap(), limits);
+
+        // The first second's worth of writes goes through as a burst, and the
+        // rest wait on the limit.
+        let start = Instant::now();
+        for n in 0..10 {
+            engine.set(&n.to_string(), "1").unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(400));
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1");
+        batch.delete("b");
+        batch.set("c", "1");
+        engine.apply(&batch).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        let start = Instant::now();
+        engine.set_write_limits(WriteLimits::default()).unwrap();
+        for n in 0..100 {
+            engine.set(&n.to_string(), "2").unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(400));
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn reads_during_flushes() {
+        let fixture = StoreFixture::init("./test-db-engine-concurrent");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 4 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
+        })
+        .unwrap();
+        // Once a key has been written, it must be readable from then on, even while
+        // the memtable holding it is being flushed.
+        let written = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while written.load(Relaxed) < 200 {
+                        let last = written.load(Relaxed);
+                        if last > 0 {
+                            let key = format!("{:03}", last - 1);
+                            assert_eq!(engine.get(&key).unwrap().as_deref(), Some("1"), "{key}");
+                        }
+                    }
+                });
+            }
+            for n in 0..200 {
+                engine.set(&format!("{n:03}"), "1").unwrap();
+                written.store(n + 1, Relaxed);
+            }
+        });
+        assert_eq!(engine.scan("", 1000).unwrap().entries.len(), 200);
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn apply_batch() {
+        let fixture = StoreFixture::init("./test-db-engine-batch");
+        let args = || EngineArgs {
+            memtable: MemtableArgs { capacity: 3 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
+        };
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        engine.set("a", "1").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("b", "1");
+        batch.delete("a");
+        batch.set("b", "2");
+        batch.set("c", "1");
+        engine.apply(&batch).unwrap();
+        assert_eq!(engine.get("a").unwrap(), None);
+        assert_eq!(engine.get("b").unwrap().as_deref(), Some("2"));
+        engine.stop().unwrap();
+
+        // The batch filled the memtable, so it was flushed to a segment file.
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        assert_eq!(engine.get("a").unwrap(), None);
+        assert_eq!(engine.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(engine.get("c").unwrap().as_deref(), Some("1"));
+
+        let stats = engine.stats().unwrap();
+        assert_eq!(stats.store.segment_count(), 1);
+        assert!(stats.store.segment_bytes() > 0);
+        assert_eq!((stats.memtable_len, stats.memtable_capacity), (0, 3));
+        assert_eq!(stats.store.wal_bytes, 0);
+        assert_eq!(stats.store.segments[0].entry_count, 3);
+        // Each of the gets since reopening found its key in the segment.
+        let bloom_filter = stats.store.segments[0].bloom_filter;
+        assert_eq!((bloom_filter.checks, bloom_filter.false_positives), (3, 0));
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn properties() {
+        let fixture = StoreFixture::init("./test-db-engine-properties");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            ..Default::default()
+        })
+        .unwrap();
+        for (key, value) in [("a", "1"), ("b", "22"), ("c", "333")] {
+            engine.set(key, value).unwrap();
+        }
+        let property = |name| engine.get_property(name).unwrap();
+        assert_eq!(property("crunch.num-segments").as_deref(), Some("1"));
+        assert_eq!(property("crunch.num-segments-at-level0").as_deref(), Some("1"));
+        assert_eq!(property("crunch.num-segments-at-level1").as_deref(), Some("0"));
+        assert_eq!(property("crunch.memtable-entries").as_deref(), Some("1"));
+        assert_eq!(property("crunch.memtable-bytes").as_deref(), Some("4"));
+        assert_eq!(property("crunch.estimate-num-keys").as_deref(), Some("3"));
+        assert_eq!(property("crunch.estimate-dead-ratio").as_deref(), Some("0"));
+        assert_eq!(property("crunch.last-compaction"), None);
+        assert_eq!(property("crunch.num-segments-at-levelx"), None);
+        assert_eq!(property("crunch.unknown"), None);
+        assert_eq!(property("num-segments"), None);
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn verify_on_open() {
+        let fixture = StoreFixture::init("./test-db-engine-verify-on-open");
+        let args = |verify_on_open| EngineArgs {
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            verify_on_open,
+            ..Default::default()
+        };
+        let engine = Engine::with_args(fixture.path().to_owned(), args(true)).unwrap();
+        let pairs = ["a", "b"].map(|key| (key.to_owned(), "1".to_owned()));
+        engine.bulk_load(pairs).unwrap();
+        let path = engine.store().list_segments().unwrap().remove(0);
+        engine.stop().unwrap();
+        Engine::with_args(fixture.path().to_owned(), args(true)).unwrap().stop().unwrap();
+
+        // Swapping "b" for " " leaves the file readable, but out of order.
+        let mut contents = fs::read(&path).unwrap();
+        let position = contents.iter().rposition(|byte| *byte == b'b').unwrap();
+        contents[position] = b' ';
+        fs::write(&path, contents).unwrap();
+        Engine::with_args(fixture.path().to_owned(), args(false)).unwrap().stop().unwrap();
+        let error = Engine::with_args(fixture.path().to_owned(), args(true)).err().unwrap();
+        assert!(matches!(error, Error::Corruption { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn bulk_load() {
+        let fixture = StoreFixture::init("./test-db-engine-bulk-load");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            ..Default::default()
+        })
+        .unwrap();
+        engine.set("b", "old").unwrap();
+        let pairs = |keys: &[&str]| -> Vec<_> {
+            keys.iter().map(|key| (key.to_string(), "new".to_owned())).collect()
+        };
+        assert!(engine.bulk_load(pairs(&["a", "c", "c"])).is_err());
+        assert_eq!(engine.store().list_segments().unwrap().len(), 1);
+        assert_eq!(engine.get("a").unwrap(), None);
+
+        assert_eq!(engine.bulk_load(pairs(&["a", "b", "c"])).unwrap(), 3);
+        assert_eq!(engine.store().list_segments().unwrap().len(), 2);
+        for key in ["a", "b", "c"] {
+            assert_eq!(engine.get(key).unwrap().as_deref(), Some("new"));
+        }
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn ingest_segment() {
+        let fixture = StoreFixture::init("./test-db-engine-ingest-segment");
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            ..Default::default()
+        })
+        .unwrap();
+        engine.register_index("value", |value| Some(value.to_owned())).unwrap();
+        engine.set("a", "old").unwrap();
+        engine.set("c", "old").unwrap();
+
+        let unsorted = fixture.path().join("unsorted.dat");
+        let mut file = fs::File::create(&unsorted).unwrap();
+        segment::write(&mut file, "b", "new").unwrap();
+        segment::write(&mut file, "a", "new").unwrap();
+        assert!(engine.ingest_segment(&unsorted).is_err());
+        assert_eq!(engine.store().list_segments().unwrap().len(), 0);
+
+        let sorted = fixture.path().join("sorted.dat");
+        let mut file = fs::File::create(&sorted).unwrap();
+        segment::write(&mut file, "a", "new").unwrap();
+        segment::write(&mut file, "b", "new").unwrap();
+        engine.ingest_segment(&sorted).unwrap();
+        assert_eq!(engine.get("a").unwrap().as_deref(), Some("new"));
+        assert_eq!(engine.get("b").unwrap().as_deref(), Some("new"));
+        assert_eq!(engine.get("c").unwrap().as_deref(), Some("old"));
+        assert_eq!(engine.get_by_index("value", "new").unwrap(), ["a", "b"]);
+        assert_eq!(engine.get_by_index("value", "old").unwrap(), ["c"]);
+        engine.stop().unwrap();
+
+        let engine = Engine::new(fixture.path().to_owned()).unwrap();
+        assert_eq!(engine.get("b").unwrap().as_deref(), Some("new"));
+        engine.stop().unwrap();
+    }
 
     #[test]
     fn sledgehammer() {
@@ -107,9 +2360,20 @@ mod test {
         let mut map = HashMap::new();
 
         _ = remove_dir_all(DIR);
-        let mut engine = Engine::with_args(PathBuf::from(DIR), EngineArgs {
+        let engine = Engine::with_args(PathBuf::from(DIR), EngineArgs {
             memtable: MemtableArgs { capacity: 10 },
-            store: StoreArgs { compaction_enabled: true, compaction_interval_seconds: 0 },
+            store: StoreArgs {
+                compaction_enabled: true,
+                compaction_interval: Duration::ZERO,
+                ..Default::default()
+            },
+            listeners: Vec::new(),
+            write_limits: WriteLimits::default(),
+            row_cache: RowCacheArgs::default(),
+            shards: 1,
+            read_only: false,
+            size_limits: SizeLimits::default(),
+            verify_on_open: false,
         })
         .unwrap();
 
@@ -143,7 +2407,7 @@ mod test {
                     let key = keys.choose(&mut rng).unwrap();
                     let map_value = map.get(key);
                     let eng_value = engine.get(key).unwrap();
-                    assert_eq!(map_value, eng_value.as_ref());
+                    assert_eq!(map_value.map(String::as_str), eng_value.as_deref());
                     reads += 1;
                 },
                 _ => unreachable!(),
@@ -166,7 +2430,7 @@ mod test {
 
         // One final assertion loop to ensure that the compactor worked properly.
         for (key, value) in map {
-            assert_eq!(engine.get(key).unwrap().unwrap(), value);
+            assert_eq!(engine.get(key).unwrap().as_deref(), Some(value.as_str()));
         }
 
         remove_dir_all(DIR).unwrap();
@@ -1,4 +1,5 @@
 use std::fmt;
+use std::path::PathBuf;
 use std::sync::PoisonError;
 
 #[derive(Debug, thiserror::Error)]
@@ -12,6 +13,11 @@ pub enum Error {
 
     #[error("{0} was too large. length: {1}, max: {2}")]
     TooLarge(PairComponent, usize, usize),
+
+    /// Data on disk that can't be decoded, starting at byte `offset` of
+    /// `file`. Nothing after that point in the file can be read.
+    #[error("corruption in {} at byte {offset}: {reason}", file.display())]
+    Corruption { file: PathBuf, offset: u64, reason: String },
 }
 
 impl<T> From<PoisonError<T>> for Error {
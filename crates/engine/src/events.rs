@@ -0,0 +1,271 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// Callbacks for writes and background work done by the engine.
+///
+/// Register implementations on [`EngineArgs::listeners`] to log, trace, or
+/// export metrics about flushes and compactions, or to audit writes and keep
+/// derived data up to date. Every method has an empty default implementation,
+/// so listeners only need to implement the events they care about.
+///
+/// Callbacks are run synchronously on the thread doing the work (the writing
+/// thread for writes and flushes, and the compaction thread for compactions),
+/// so they should return quickly. Listeners that do more can hand events off
+/// to another thread with a [`ChannelListener`].
+///
+/// [`EngineArgs::listeners`]: crate::engine::EngineArgs::listeners
+pub trait EventListener: Send + Sync {
+    /// `key` was set to `value`. Writes are reported in the order they were
+    /// applied, once they are in the WAL and the memtable, and writes wait for
+    /// this to return. Each write in a batch is reported separately.
+    fn on_set(&self, _key: &str, _value: &str) {}
+
+    /// `key` was deleted, reported like [`Self::on_set`].
+    fn on_delete(&self, _key: &str) {}
+
+    fn on_flush_started(&self, _info: &FlushInfo) {}
+
+    fn on_flush_finished(&self, _info: &FlushInfo, _duration: Duration) {}
+
+    fn on_compaction_started(&self, _info: &CompactionInfo) {}
+
+    fn on_compaction_finished(&self, _info: &CompactionInfo, _duration: Duration) {}
+
+    /// A segment file was dropped from the store by compaction. The file is
+    /// deleted right away, unless an iterator is still reading it, in which
+    /// case that happens once the iterator is dropped.
+    fn on_segment_deleted(&self, _path: &Path) {}
+
+    /// The segment file at `path` was dropped from the store because of
+    /// `error`, and moved to `destination`, in its quarantine directory. See
+    /// [`crate::quarantine`].
+    fn on_segment_quarantined(&self, _path: &Path, _destination: &Path, _error: &Error) {}
+}
+
+#[derive(Clone, Debug)]
+pub struct FlushInfo {
+    /// The segment file that the memtable is written to.
+    pub path: PathBuf,
+
+    /// The number of entries in the memtable.
+    pub entries: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompactionInfo {
+    /// The segment files being merged, oldest first.
+    pub inputs: Vec<PathBuf>,
+
+    /// The segment file that the inputs are merged into.
+    pub output: PathBuf,
+}
+
+/// An event reported to an [`EventListener`], as sent by a
+/// [`ChannelListener`].
+#[derive(Clone, Debug)]
+pub enum Event {
+    Set { key: String, value: String },
+    Delete { key: String },
+    FlushStarted(FlushInfo),
+    FlushFinished(FlushInfo, Duration),
+    CompactionStarted(CompactionInfo),
+    CompactionFinished(CompactionInfo, Duration),
+    SegmentDeleted(PathBuf),
+    SegmentQuarantined { path: PathBuf, destination: PathBuf, error: String },
+}
+
+/// A listener that sends every event down a channel, for handling on a thread
+/// of the receiver's choosing rather than the one doing the work.
+///
+/// The channel is unbounded, so a receiver that falls behind holds a backlog
+/// of events in memory. Once the receiver is dropped, events are discarded.
+pub struct ChannelListener(Sender<Event>);
+
+impl ChannelListener {
+    pub fn new() -> (Self, Receiver<Event>) {
+        let (sender, receiver) = mpsc::channel();
+        (Self(sender), receiver)
+    }
+
+    fn send(&self, event: Event) {
+        _ = self.0.send(event);
+    }
+}
+
+impl EventListener for ChannelListener {
+    fn on_set(&self, key: &str, value: &str) {
+        self.send(Event::Set { key: key.to_owned(), value: value.to_owned() });
+    }
+
+    fn on_delete(&self, key: &str) {
+        self.send(Event::Delete { key: key.to_owned() });
+    }
+
+    fn on_flush_started(&self, info: &FlushInfo) {
+        self.send(Event::FlushStarted(info.clone()));
+    }
+
+    fn on_flush_finished(&self, info: &FlushInfo, duration: Duration) {
+        self.send(Event::FlushFinished(info.clone(), duration));
+    }
+
+    fn on_compaction_started(&self, info: &CompactionInfo) {
+        self.send(Event::CompactionStarted(info.clone()));
+    }
+
+    fn on_compaction_finished(&self, info: &CompactionInfo, duration: Duration) {
+        self.send(Event::CompactionFinished(info.clone(), duration));
+    }
+
+    fn on_segment_deleted(&self, path: &Path) {
+        self.send(Event::SegmentDeleted(path.to_owned()));
+    }
+
+    fn on_segment_quarantined(&self, path: &Path, destination: &Path, error: &Error) {
+        self.send(Event::SegmentQuarantined {
+            path: path.to_owned(),
+            destination: destination.to_owned(),
+            error: error.to_string(),
+        });
+    }
+}
+
+/// The set of listeners registered on an engine.
+#[derive(Clone, Default)]
+pub struct Listeners(Arc<[Arc<dyn EventListener>]>);
+
+impl Listeners {
+    pub fn new(listeners: Vec<Arc<dyn EventListener>>) -> Self {
+        Self(listeners.into())
+    }
+
+    /// Run `callback` against every registered listener.
+    pub fn notify(&self, callback: impl Fn(&dyn EventListener)) {
+        self.0.iter().for_each(|listener| callback(listener.as_ref()));
+    }
+}
+
+impl fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Listeners({})", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::batch::WriteBatch;
+    use crate::engine::{Engine, EngineArgs};
+    use crate::memtable::{Memtable, MemtableArgs};
+    use crate::store::{Store, StoreArgs};
+    use crate::test::StoreFixture;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<&'static str>>);
+
+    impl EventListener for Recorder {
+        fn on_flush_started(&self, _info: &FlushInfo) {
+            self.0.lock().unwrap().push("flush_started");
+        }
+
+        fn on_flush_finished(&self, _info: &FlushInfo, _duration: Duration) {
+            self.0.lock().unwrap().push("flush_finished");
+        }
+
+        fn on_compaction_started(&self, info: &CompactionInfo) {
+            assert_eq!(info.inputs.len(), 2);
+            self.0.lock().unwrap().push("compaction_started");
+        }
+
+        fn on_compaction_finished(&self, _info: &CompactionInfo, _duration: Duration) {
+            self.0.lock().unwrap().push("compaction_finished");
+        }
+
+        fn on_segment_deleted(&self, _path: &Path) {
+            self.0.lock().unwrap().push("segment_deleted");
+        }
+    }
+
+    #[test]
+    fn flush_and_compaction_events() {
+        let fixture = StoreFixture::init("./test-db-events");
+        let recorder = Arc::new(Recorder::default());
+        let args = StoreArgs {
+            compaction_interval: Duration::from_secs(3600),
+            compaction_trigger_segment_count: 2,
+            ..Default::default()
+        };
+        let listeners = Listeners::new(vec![recorder.clone()]);
+        let store = Store::with_listeners(fixture.path().to_owned(), args, listeners).unwrap();
+        for key in ["a", "b"] {
+            let mut memtable = Memtable::new(MemtableArgs::default());
+            memtable.set(key, "1");
+            store.write_memtable(&memtable).unwrap();
+        }
+
+        let start = Instant::now();
+        while !recorder.0.lock().unwrap().contains(&"compaction_finished") {
+            assert!(start.elapsed() < Duration::from_secs(10), "compaction never finished");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        store.stop().unwrap();
+
+        assert_eq!(*recorder.0.lock().unwrap(), [
+            "flush_started",
+            "flush_finished",
+            "flush_started",
+            "flush_finished",
+            "compaction_started",
+            "segment_deleted",
+            "segment_deleted",
+            "compaction_finished",
+        ]);
+    }
+
+    #[test]
+    fn write_events_over_a_channel() {
+        let fixture = StoreFixture::init("./test-db-events-writes");
+        let (listener, receiver) = ChannelListener::new();
+        let engine = Engine::with_args(fixture.path().to_owned(), EngineArgs {
+            memtable: MemtableArgs { capacity: 3 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            listeners: vec![Arc::new(listener)],
+            ..Default::default()
+        })
+        .unwrap();
+        engine.set("a", "1").unwrap();
+        engine.delete("a").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("b", "2");
+        batch.delete("c");
+        engine.apply(&batch).unwrap();
+        engine.stop().unwrap();
+
+        let events: Vec<_> = receiver
+            .into_iter()
+            .map(|event| match event {
+                Event::Set { key, value } => format!("set {key} {value}"),
+                Event::Delete { key } => format!("delete {key}"),
+                Event::FlushStarted(_) => "flush_started".to_owned(),
+                Event::FlushFinished(..) => "flush_finished".to_owned(),
+                event => panic!("unexpected event {event:?}"),
+            })
+            .collect();
+        assert_eq!(events, [
+            "set a 1",
+            "delete a",
+            "set b 2",
+            "delete c",
+            "flush_started",
+            "flush_finished"
+        ]);
+    }
+}
@@ -0,0 +1,122 @@
+//! Secondary indexes, which map a value derived from each live value back to
+//! the keys that hold it, registered with
+//! [`Engine::register_index`](crate::engine::Engine::register_index).
+//!
+//! Each index keeps its entries in a store of its own, under the `indexes`
+//! directory of the main store. Writes update an index in two steps around
+//! the write itself: the new entry is added before it, and the stale one is
+//! removed after it. A crash between the steps can only leave an extra entry
+//! behind, so lookups check every entry they find against the value it points
+//! at, and skip the ones that no longer match.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+
+use crate::engine::{Engine, EngineArgs};
+use crate::error::Error;
+
+/// Derives the indexed value from a value, or `None` to leave it out. It is
+/// shared between the shards of a sharded engine.
+pub type Extractor = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// Created in an index's directory once it holds an entry for every key that
+/// was in the engine when it was registered.
+const BUILT_FILENAME: &str = "BUILT";
+
+pub struct SecondaryIndex {
+    name: String,
+    extract: Extractor,
+
+    /// Holds an entry for each indexed key, with an empty value.
+    entries: Engine,
+}
+
+impl SecondaryIndex {
+    /// Open the index called `name` in the `indexes` directory of the store at
+    /// `directory`. If it was never fully built, it is emptied, and `built` is
+    /// `false` until [`Self::mark_built`] is called.
+    pub(crate) fn open(
+        name: &str,
+        extract: Extractor,
+        directory: &Path,
+        args: EngineArgs,
+    ) -> Result<(Self, bool), Error> {
+        let valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            return Err(anyhow!(
+                "index name {name:?} must be made of letters, digits, '_' and '-'"
+            )
+            .into());
+        }
+        let path = directory.join("indexes").join(name);
+        let built = path.join(BUILT_FILENAME).exists();
+        if !built && path.exists() {
+            log::info!("index {name} was never fully built, rebuilding it");
+            fs::remove_dir_all(&path)?;
+        }
+        let entries = Engine::with_args(path, args)?;
+        Ok((Self { name: name.to_owned(), extract, entries }, built))
+    }
+
+    /// Record that the index holds an entry for every key it should.
+    pub(crate) fn mark_built(&self) -> Result<(), Error> {
+        fs::File::create(self.entries.store().directory().join(BUILT_FILENAME))?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Add the entry for `key` having `value`, if the value is indexed.
+    pub(crate) fn insert(&self, key: &str, value: &str) -> Result<(), Error> {
+        match (self.extract)(value) {
+            Some(indexed) => self.entries.set(&entry_key(&indexed, key), ""),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove the entry for `key` having had the `old` value, unless the `new`
+    /// one is indexed the same way.
+    pub(crate) fn remove_stale(
+        &self,
+        key: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> Result<(), Error> {
+        let Some(old) = old.and_then(|old| (self.extract)(old)) else {
+            return Ok(());
+        };
+        if new.and_then(|new| (self.extract)(new)).as_ref() == Some(&old) {
+            return Ok(());
+        }
+        self.entries.delete(&entry_key(&old, key))
+    }
+
+    /// The keys that the index has an entry for under `indexed`, in key order.
+    /// Some of them may be stale.
+    pub(crate) fn candidates(&self, indexed: &str) -> Result<Vec<String>, Error> {
+        let prefix = entry_key(indexed, "");
+        let keys = self.entries.list(&prefix, None)?;
+        Ok(keys.into_iter().map(|key| key[prefix.len()..].to_owned()).collect())
+    }
+
+    /// Whether `value` is indexed under `indexed`.
+    pub(crate) fn matches(&self, value: &str, indexed: &str) -> bool {
+        (self.extract)(value).as_deref() == Some(indexed)
+    }
+
+    pub(crate) fn stop(self) -> std::thread::Result<()> {
+        self.entries.stop()
+    }
+}
+
+/// The key of the entry for `key` under `indexed`. The indexed value is length
+/// prefixed, so that no two pairs can share a key.
+fn entry_key(indexed: &str, key: &str) -> String {
+    format!("{:08x}{indexed}{key}", indexed.len())
+}
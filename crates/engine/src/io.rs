@@ -0,0 +1,487 @@
+//! How the store reads segment files and appends to the WAL.
+//!
+//! Lookups and scans read segment files, and commits append to the active WAL
+//! file, through an [`IoBackend`], picked with
+//! [`StoreArgs::io_backend`](crate::store::StoreArgs::io_backend). [`StdIo`]
+//! makes the usual system calls. With the `io-uring` feature on Linux,
+//! [`UringIo`] submits the same operations through io_uring instead, which
+//! spends less time in system calls when many threads read at once.
+//!
+//! Either can be wrapped in [`DirectIo`], with
+//! [`StoreArgs::direct_io`](crate::store::StoreArgs::direct_io), so that
+//! segment files are read around the OS page cache.
+//!
+//! Whatever the backend, scans can ask the OS to read ahead of them with
+//! [`advise`].
+
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fmt, io};
+
+use anyhow::anyhow;
+use crunch_common::env::FromEnv;
+
+use crate::error::Error;
+
+/// Positioned reads and writes on open files.
+pub trait IoBackend: Send + Sync + fmt::Debug {
+    /// Open the file at `path` for reading.
+    fn open(&self, path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
+
+    /// Read into `buf` from `offset` in `file`. Returns the number of bytes
+    /// read, which is 0 at the end of the file.
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Write all of `buf` to `file` at `offset`, and then sync its data to disk
+    /// if `sync` is set. Files opened for appending are written at their end
+    /// whatever `offset` is.
+    fn write_all_at(&self, file: &File, buf: &[u8], offset: u64, sync: bool) -> io::Result<()>;
+
+    /// Like [`Self::read_at`], but fills the whole of `buf`.
+    fn read_exact_at(&self, file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(file, buf, offset)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                read => {
+                    buf = &mut buf[read..];
+                    offset += read as u64;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which [`IoBackend`] the store uses.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IoBackendKind {
+    #[default]
+    Std,
+
+    /// [`UringIo`], which is only available with the `io-uring` feature on
+    /// Linux.
+    IoUring,
+}
+
+impl IoBackendKind {
+    pub fn open(self) -> Result<Arc<dyn IoBackend>, Error> {
+        match self {
+            Self::Std => Ok(Arc::new(StdIo)),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Self::IoUring => Ok(Arc::new(UringIo::new()?)),
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            Self::IoUring => Err(anyhow!(
+                "io_uring needs crunch to be built for Linux with the io-uring feature"
+            )
+            .into()),
+        }
+    }
+}
+
+impl FromEnv for IoBackendKind {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        match value.to_lowercase().as_str() {
+            "std" => Ok(Self::Std),
+            "io_uring" => Ok(Self::IoUring),
+            _ => Err(anyhow!("unknown IO backend {value:?}")),
+        }
+    }
+}
+
+/// Blocking system calls, one per operation.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdIo;
+
+impl IoBackend for StdIo {
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        #[cfg(unix)]
+        return std::os::unix::fs::FileExt::read_at(file, buf, offset);
+        #[cfg(windows)]
+        return std::os::windows::fs::FileExt::seek_read(file, buf, offset);
+        // Elsewhere, such as on WASI, positioned reads aren't stable, so the file's
+        // cursor is moved instead. Those builds don't have threads to read the same
+        // file at once.
+        #[cfg(not(any(unix, windows)))]
+        {
+            use std::io::{Read, Seek, SeekFrom};
+
+            let mut file = file;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read(buf)
+        }
+    }
+
+    fn write_all_at(&self, mut file: &File, buf: &[u8], offset: u64, sync: bool) -> io::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buf)?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// What a reader expects of its next reads of a file, for [`advise`].
+#[derive(Clone, Copy, Debug)]
+pub enum Advice {
+    /// The file will be read from start to end.
+    Sequential,
+
+    /// The `len` bytes from `offset` will be read soon.
+    WillNeed { offset: u64, len: u64 },
+}
+
+/// Tell the OS how `file` is about to be read, with `posix_fadvise`, so that
+/// it can read ahead into the page cache. This is only a hint, and it does
+/// nothing off Linux or to a file opened with direct IO.
+pub fn advise(file: &File, advice: Advice) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let (offset, len, advice) = match advice {
+            Advice::Sequential => (0, 0, libc::POSIX_FADV_SEQUENTIAL),
+            Advice::WillNeed { offset, len } => (offset, len, libc::POSIX_FADV_WILLNEED),
+        };
+        let (Ok(offset), Ok(len)) = (i64::try_from(offset), i64::try_from(len)) else {
+            return;
+        };
+        // SAFETY: `posix_fadvise` only reads its arguments, and the descriptor is
+        // open for as long as `file` is borrowed.
+        let result = unsafe { libc::posix_fadvise(file.as_raw_fd(), offset, len, advice) };
+        if result != 0 {
+            log::trace!("posix_fadvise failed: {}", io::Error::from_raw_os_error(result));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, advice);
+}
+
+/// Reserve space on disk for the first `len` bytes of `file`, which is about
+/// to be written from the start, with `fallocate`. This keeps the file from
+/// being scattered over the disk as it grows, and makes a write that the disk
+/// has no room for fail before any of it is made, rather than partway
+/// through.
+///
+/// The file is at least `len` bytes long afterwards, so whatever isn't
+/// written over has to be truncated. Running out of space is an error, but
+/// anything else that stops the space from being reserved isn't, since the
+/// file can still be written without it. Off Linux, this does nothing.
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let Ok(len) = i64::try_from(len) else {
+            return Ok(());
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        // SAFETY: `fallocate` only reads its arguments, and the descriptor is open
+        // for as long as `file` is borrowed.
+        if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len) } != 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ENOSPC) {
+                return Err(error);
+            }
+            log::trace!("fallocate failed: {error}");
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, len);
+    Ok(())
+}
+
+/// Opens files for reading with `O_DIRECT`, so that reading them leaves the OS
+/// page cache alone, and reads them through another backend.
+///
+/// Direct reads have to start and end on a block boundary, so each read is
+/// made through a buffer of whole, aligned [`DIRECT_IO_ALIGNMENT`] byte blocks,
+/// and the part that was asked for is copied out of it. Writes go through the
+/// page cache as usual. This is only available on Linux.
+#[derive(Debug)]
+pub struct DirectIo {
+    inner: Arc<dyn IoBackend>,
+}
+
+/// The alignment of direct reads, which covers the logical block size of
+/// nearly every device and filesystem.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+impl DirectIo {
+    pub fn new(inner: Arc<dyn IoBackend>) -> Result<Self, Error> {
+        if cfg!(not(target_os = "linux")) {
+            return Err(anyhow!("direct IO is only supported on Linux").into());
+        }
+        Ok(Self { inner })
+    }
+}
+
+impl IoBackend for DirectIo {
+    fn open(&self, path: &Path) -> io::Result<File> {
+        #[cfg(target_os = "linux")]
+        return std::os::unix::fs::OpenOptionsExt::custom_flags(
+            std::fs::OpenOptions::new().read(true),
+            libc::O_DIRECT,
+        )
+        .open(path);
+        #[cfg(not(target_os = "linux"))]
+        return self.inner.open(path);
+    }
+
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let alignment = DIRECT_IO_ALIGNMENT as u64;
+        let start = offset - offset % alignment;
+        let end = (offset + buf.len() as u64).next_multiple_of(alignment);
+        let mut aligned = AlignedBuffer::new((end - start) as usize);
+        let aligned = aligned.as_mut_slice();
+        let mut filled = 0;
+        while filled < aligned.len() {
+            let read = self.inner.read_at(file, &mut aligned[filled..], start + filled as u64)?;
+            filled += read;
+            // A read that stops short of a block boundary has reached the end of the
+            // file, and one after it wouldn't be aligned.
+            if read == 0 || filled % DIRECT_IO_ALIGNMENT != 0 {
+                break;
+            }
+        }
+        let skip = (offset - start) as usize;
+        let count = filled.saturating_sub(skip).min(buf.len());
+        buf[..count].copy_from_slice(&aligned[skip..skip + count]);
+        Ok(count)
+    }
+
+    fn write_all_at(&self, file: &File, buf: &[u8], offset: u64, sync: bool) -> io::Result<()> {
+        self.inner.write_all_at(file, buf, offset, sync)
+    }
+}
+
+/// A zeroed buffer of whole blocks, starting on a block boundary in memory.
+struct AlignedBuffer {
+    blocks: Vec<AlignedBlock>,
+}
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct AlignedBlock([u8; DIRECT_IO_ALIGNMENT]);
+
+impl AlignedBuffer {
+    /// A buffer of at least `len` bytes.
+    fn new(len: usize) -> Self {
+        let blocks =
+            vec![AlignedBlock([0; DIRECT_IO_ALIGNMENT]); len.div_ceil(DIRECT_IO_ALIGNMENT)];
+        Self { blocks }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        let len = self.blocks.len() * DIRECT_IO_ALIGNMENT;
+        // The blocks are laid out back to back, with no padding, since their size is
+        // a multiple of their alignment.
+        unsafe { std::slice::from_raw_parts_mut(self.blocks.as_mut_ptr().cast(), len) }
+    }
+}
+
+/// Operations submitted through io_uring, each on a ring belonging to the
+/// thread that submits it, so that threads never wait on each other to reach
+/// the kernel.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[derive(Debug)]
+pub struct UringIo {
+    _private: (),
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl UringIo {
+    /// Fails if the kernel doesn't support io_uring, or doesn't allow it.
+    pub fn new() -> Result<Self, Error> {
+        uring::with_ring(|_| Ok(()))
+            .map_err(|error| anyhow!("io_uring isn't available: {error}"))?;
+        Ok(Self { _private: () })
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl IoBackend for UringIo {
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        uring::read(file, buf, offset)
+    }
+
+    fn write_all_at(
+        &self,
+        file: &File,
+        mut buf: &[u8],
+        mut offset: u64,
+        sync: bool,
+    ) -> io::Result<()> {
+        while !buf.is_empty() {
+            match uring::write(file, buf, offset)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => {
+                    buf = &buf[written..];
+                    offset += written as u64;
+                },
+            }
+        }
+        if sync {
+            uring::sync_data(file)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring {
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    use io_uring::{opcode, squeue, types, IoUring};
+
+    /// Every operation is waited on before the next one is submitted, so a
+    /// ring never holds more than one.
+    const RING_ENTRIES: u32 = 8;
+
+    thread_local! {
+        static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+    }
+
+    /// Run `f` with this thread's ring, setting it up first if it hasn't been.
+    pub fn with_ring<T>(f: impl FnOnce(&mut IoUring) -> io::Result<T>) -> io::Result<T> {
+        RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            if ring.is_none() {
+                *ring = Some(IoUring::new(RING_ENTRIES)?);
+            }
+            f(ring.as_mut().unwrap())
+        })
+    }
+
+    /// Submit `entry` and wait for it to complete, returning its result.
+    ///
+    /// # Safety
+    ///
+    /// Any buffer that `entry` points at must stay valid until this returns.
+    unsafe fn run(entry: squeue::Entry) -> io::Result<usize> {
+        with_ring(|ring| {
+            // The queue is empty, since every submission is waited on.
+            unsafe { ring.submission().push(&entry) }
+                .map_err(|_| io::Error::other("the io_uring submission queue is full"))?;
+            loop {
+                match ring.submit_and_wait(1) {
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result.map(drop)?,
+                }
+            }
+            let completion = ring
+                .completion()
+                .next()
+                .ok_or_else(|| io::Error::other("io_uring returned no completion"))?;
+            match completion.result() {
+                result if result < 0 => Err(io::Error::from_raw_os_error(-result)),
+                result => Ok(result as usize),
+            }
+        })
+    }
+
+    /// The most bytes that a single operation can move.
+    fn clamp(len: usize) -> u32 {
+        len.min(u32::MAX as usize) as u32
+    }
+
+    pub fn read(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let fd = types::Fd(file.as_raw_fd());
+        let entry =
+            opcode::Read::new(fd, buf.as_mut_ptr(), clamp(buf.len())).offset(offset).build();
+        // `buf` is borrowed until the read has completed.
+        unsafe { run(entry) }
+    }
+
+    pub fn write(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let fd = types::Fd(file.as_raw_fd());
+        let entry = opcode::Write::new(fd, buf.as_ptr(), clamp(buf.len())).offset(offset).build();
+        // `buf` is borrowed until the write has completed.
+        unsafe { run(entry) }
+    }
+
+    pub fn sync_data(file: &File) -> io::Result<()> {
+        let fd = types::Fd(file.as_raw_fd());
+        let entry = opcode::Fsync::new(fd).flags(types::FsyncFlags::DATASYNC).build();
+        unsafe { run(entry) }.map(drop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::test::StoreFixture;
+
+    fn round_trip(io: &dyn IoBackend, fixture: &StoreFixture) {
+        let path = fixture.path().join("file");
+        let file = fs::OpenOptions::new().create(true).append(true).read(true).open(&path).unwrap();
+        io.write_all_at(&file, b"hello", 0, true).unwrap();
+        io.write_all_at(&file, b" world", 5, false).unwrap();
+        let mut buf = [0; 5];
+        io.read_exact_at(&file, &mut buf, 6).unwrap();
+        assert_eq!(&buf, b"world");
+        assert_eq!(io.read_at(&file, &mut buf, 11).unwrap(), 0);
+        assert!(io.read_exact_at(&file, &mut buf, 8).is_err());
+    }
+
+    /// Fail on a backend this machine can't run, unless skipping those
+    /// was asked for by setting `CRUNCH_TEST_SKIP_UNSUPPORTED`.
+    fn unsupported(error: impl fmt::Display) {
+        if std::env::var_os("CRUNCH_TEST_SKIP_UNSUPPORTED").is_none() {
+            panic!("{error}; set CRUNCH_TEST_SKIP_UNSUPPORTED to skip this test");
+        }
+    }
+
+    #[test]
+    fn direct_io_reads_unaligned_ranges() {
+        let fixture = StoreFixture::init("./test-db-io-direct");
+        let path = fixture.path().join("file");
+        let contents: Vec<u8> = (0..3 * DIRECT_IO_ALIGNMENT).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let io = DirectIo::new(Arc::new(StdIo)).unwrap();
+        // Not every filesystem supports direct IO, tmpfs among them.
+        let file = match io.open(&path) {
+            Ok(file) => file,
+            Err(error) => return unsupported(error),
+        };
+        let mut buf = vec![0; DIRECT_IO_ALIGNMENT + 10];
+        io.read_exact_at(&file, &mut buf, 4090).unwrap();
+        assert_eq!(buf, contents[4090..4090 + buf.len()]);
+        let mut tail = [0; 64];
+        let read = io.read_at(&file, &mut tail, contents.len() as u64 - 20).unwrap();
+        assert_eq!(tail[..read], contents[contents.len() - 20..]);
+        assert_eq!(io.read_at(&file, &mut tail, contents.len() as u64).unwrap(), 0);
+    }
+
+    #[test]
+    fn std_io() {
+        let fixture = StoreFixture::init("./test-db-io-std");
+        round_trip(&StdIo, &fixture);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn uring_io() {
+        let fixture = StoreFixture::init("./test-db-io-uring");
+        // Sandboxes and older kernels often turn io_uring off.
+        match UringIo::new() {
+            Ok(io) => round_trip(&io, &fixture),
+            Err(error) => unsupported(error),
+        }
+    }
+}
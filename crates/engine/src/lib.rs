@@ -1,10 +1,26 @@
+pub mod backup;
+pub mod batch;
+pub mod bloom_filter;
+pub mod builder;
 pub mod compaction;
+pub mod encryption;
 pub mod engine;
 pub mod error;
+pub mod events;
+pub mod index;
+pub mod io;
+pub mod manifest;
 pub mod memtable;
+pub mod metrics;
+pub mod quarantine;
+pub mod rate_limiter;
+pub mod repair;
+pub mod row_cache;
 pub mod segment;
+pub mod shard;
 pub mod sparse_index;
 pub mod store;
 #[cfg(test)]
 pub mod test;
 pub mod util;
+pub mod wal;
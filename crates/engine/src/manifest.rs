@@ -0,0 +1,188 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+
+use crate::error::Error;
+use crate::util::sync_directory;
+
+pub(crate) const MANIFEST_FILENAME: &str = "MANIFEST";
+const MANIFEST_TEMP_FILENAME: &str = "MANIFEST.tmp";
+
+/// The manifest is the authoritative list of live segment files in a store.
+///
+/// It is stored as a small text file, with one record per line:
+///
+/// ```text
+/// next-segment-id 4
+/// wal-start 7
+/// segment 1 1 2 0
+/// segment 3 0 3 5
+/// ```
+///
+/// Segment records are listed oldest first, and each holds the segment's id,
+/// level, sequence, and overwrite count. Any segment file on disk that is not
+/// listed in the manifest is not part of the store, and neither is any WAL file
+/// with an id below `wal-start`.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Manifest {
+    /// The id that will be given to the next segment file that is created.
+    pub next_segment_id: u32,
+
+    /// The id of the oldest WAL file whose contents have not been flushed to a
+    /// segment file yet.
+    pub wal_start: u32,
+
+    pub segments: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ManifestEntry {
+    pub id: u32,
+    pub level: u32,
+
+    /// The recency of the segment's data, relative to the other segments.
+    pub sequence: u64,
+
+    /// The number of keys in the segment that shadow an entry in an older
+    /// one, as of when it was added.
+    pub overwrites: u32,
+}
+
+impl Manifest {
+    pub fn new(
+        next_segment_id: u32,
+        wal_start: u32,
+        segments: impl IntoIterator<Item = ManifestEntry>,
+    ) -> Self {
+        Self { next_segment_id, wal_start, segments: segments.into_iter().collect() }
+    }
+
+    /// Read the manifest for the store at `directory`, if one exists.
+    pub fn load(directory: &Path) -> Result<Option<Self>, Error> {
+        let contents = match fs::read_to_string(manifest_path(directory)) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        Self::parse(&contents).map(Some)
+    }
+
+    /// Atomically replace the manifest for the store at `directory` with this
+    /// one.
+    ///
+    /// The new contents are written and synced to a temporary file, which is
+    /// then renamed over the old manifest, so readers will only ever observe
+    /// the old or the new version in full. The rename is synced before this
+    /// returns, so a crash can't bring back the old version.
+    pub fn commit(&self, directory: &Path) -> Result<(), Error> {
+        let temp_path = directory.join(MANIFEST_TEMP_FILENAME);
+        let mut file = File::create(&temp_path)?;
+        file.write_all(self.serialize().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, manifest_path(directory))?;
+        sync_directory(directory)?;
+        log::trace!("committed manifest {self:?}");
+        Ok(())
+    }
+
+    fn parse(contents: &str) -> Result<Self, Error> {
+        let mut manifest = Self::default();
+        for (idx, line) in contents.lines().enumerate() {
+            let invalid = || Error::General(anyhow!("invalid manifest record on line {}", idx + 1));
+            let tokens: Vec<_> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                [] => {},
+                ["next-segment-id", id] => {
+                    manifest.next_segment_id = id.parse().map_err(|_| invalid())?;
+                },
+                ["wal-start", id] => manifest.wal_start = id.parse().map_err(|_| invalid())?,
+                ["segment", id, level, sequence, overwrites] => {
+                    manifest.segments.push(ManifestEntry {
+                        id: id.parse().map_err(|_| invalid())?,
+                        level: level.parse().map_err(|_| invalid())?,
+                        sequence: sequence.parse().map_err(|_| invalid())?,
+                        overwrites: overwrites.parse().map_err(|_| invalid())?,
+                    })
+                },
+                // Manifests written before overwrites were counted leave them at 0, which
+                // only makes compaction slower to pick those segments.
+                ["segment", id, level, sequence] => manifest.segments.push(ManifestEntry {
+                    id: id.parse().map_err(|_| invalid())?,
+                    level: level.parse().map_err(|_| invalid())?,
+                    sequence: sequence.parse().map_err(|_| invalid())?,
+                    overwrites: 0,
+                }),
+                // Manifests written before segments had sequences list them oldest first, so
+                // their position stands in for it.
+                ["segment", id, level] => manifest.segments.push(ManifestEntry {
+                    id: id.parse().map_err(|_| invalid())?,
+                    level: level.parse().map_err(|_| invalid())?,
+                    sequence: manifest.segments.len() as u64 + 1,
+                    overwrites: 0,
+                }),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub(crate) fn serialize(&self) -> String {
+        let mut contents = format!("next-segment-id {}\n", self.next_segment_id);
+        contents.push_str(&format!("wal-start {}\n", self.wal_start));
+        for entry in &self.segments {
+            contents.push_str(&format!(
+                "segment {} {} {} {}\n",
+                entry.id, entry.level, entry.sequence, entry.overwrites
+            ));
+        }
+        contents
+    }
+}
+
+pub fn manifest_path(store_path: &Path) -> PathBuf {
+    store_path.join(MANIFEST_FILENAME)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::StoreFixture;
+
+    #[test]
+    fn round_trip() {
+        let fixture = StoreFixture::init("./test-db-manifest-round-trip");
+        let entries =
+            [ManifestEntry { id: 2, level: 1, sequence: 3, overwrites: 0 }, ManifestEntry {
+                id: 4,
+                level: 0,
+                sequence: 4,
+                overwrites: 7,
+            }];
+        let manifest = Manifest::new(5, 3, entries);
+        manifest.commit(fixture.path()).unwrap();
+        assert_eq!(Manifest::load(fixture.path()).unwrap(), Some(manifest));
+    }
+
+    #[test]
+    fn missing() {
+        let fixture = StoreFixture::init("./test-db-manifest-missing");
+        assert_eq!(Manifest::load(fixture.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn legacy_segment_records() {
+        let manifest = Manifest::parse("segment 5 1\nsegment 3 0\nsegment 4 0 6\n").unwrap();
+        assert_eq!(manifest.segments, [
+            ManifestEntry { id: 5, level: 1, sequence: 1, overwrites: 0 },
+            ManifestEntry { id: 3, level: 0, sequence: 2, overwrites: 0 },
+            ManifestEntry { id: 4, level: 0, sequence: 6, overwrites: 0 },
+        ]);
+    }
+
+    #[test]
+    fn invalid_record() {
+        assert!(Manifest::parse("next-segment-id 2\nsegment one 0\n").is_err());
+    }
+}
@@ -1,12 +1,32 @@
-use std::collections::{btree_map, BTreeMap};
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Arc;
 
-use crunch_common::env::parse_env;
+use crunch_common::config::Config;
 
-type Value = Option<String>;
+/// A value, or `None` for a tombstone, whether it is in a memtable or read
+/// from a segment file. Values are shared, so that reads and copies of the
+/// memtable don't copy them, and a value read from disk is only copied once,
+/// out of the file's read buffer.
+pub type Value = Option<Arc<str>>;
 
+#[derive(Clone)]
 pub struct Memtable {
     tree: BTreeMap<String, Value>,
     capacity: usize,
+
+    /// The number of entries in `tree` that are tombstones.
+    tombstones: usize,
+
+    /// The most versions of each key to keep, counting the current one.
+    retained_versions: usize,
+
+    /// The sequence number of each key's current value, for keys that were
+    /// written as versions.
+    sequences: HashMap<String, u64>,
+
+    /// The older versions of each key that are kept, newest first.
+    history: HashMap<String, Vec<(u64, Value)>>,
 }
 
 #[derive(Debug)]
@@ -15,8 +35,8 @@ pub struct MemtableArgs {
 }
 
 impl MemtableArgs {
-    pub fn from_env() -> Self {
-        let capacity = parse_env("engine", Some("memtable"), "capacity", 1024);
+    pub fn from_config(config: &Config) -> Self {
+        let capacity = config.get("engine", Some("memtable"), "capacity", 1024);
         Self { capacity }
     }
 }
@@ -31,11 +51,79 @@ impl Memtable {
     pub fn new(args: MemtableArgs) -> Self {
         let tree = BTreeMap::new();
         log::debug!("memtable initialized with {args:?}");
-        Self { tree, capacity: args.capacity }
+        Self {
+            tree,
+            capacity: args.capacity,
+            tombstones: 0,
+            retained_versions: 1,
+            sequences: HashMap::new(),
+            history: HashMap::new(),
+        }
+    }
+
+    /// Keep up to `versions` versions of each key written with
+    /// [`Self::write_version`], counting the current one.
+    pub fn with_retained_versions(mut self, versions: usize) -> Self {
+        self.retained_versions = versions.max(1);
+        self
+    }
+
+    pub fn retained_versions(&self) -> usize {
+        self.retained_versions
+    }
+
+    /// Set `key` to `value`, or delete it if that is `None`, as the version
+    /// written at `sequence`. The version it replaces is kept as history, as
+    /// long as that doesn't take the key over its retained versions.
+    pub fn write_version(&mut self, key: &str, value: Value, sequence: u64) {
+        let previous = self.tree.get(key).cloned();
+        match value {
+            Some(value) => self.set(key, value),
+            None => self.delete(key),
+        }
+        let Some(previous_sequence) = self.sequences.insert(key.to_owned(), sequence) else {
+            return;
+        };
+        // A write batch gives every write in it the same sequence number, so a
+        // key written twice in one only keeps the last value.
+        if previous_sequence == sequence || self.retained_versions == 1 {
+            return;
+        }
+        let history = self.history.entry(key.to_owned()).or_default();
+        history.insert(0, (previous_sequence, previous.flatten()));
+        history.truncate(self.retained_versions - 1);
     }
 
-    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
-        self.tree.insert(key.into(), Some(value.into()));
+    /// The newest version of `key` that was written at or before `sequence`,
+    /// if this memtable has it. A value that wasn't written as a version counts
+    /// as written at sequence 0.
+    pub fn get_at(&self, key: &str, sequence: u64) -> Option<Value> {
+        let current = self.tree.get(key)?;
+        if self.sequences.get(key).copied().unwrap_or(0) <= sequence {
+            return Some(current.clone());
+        }
+        let history = self.history.get(key)?;
+        history.iter().find(|(version, _)| *version <= sequence).map(|(_, value)| value.clone())
+    }
+
+    /// Every version of `key` that this memtable has, newest first, or `None`
+    /// if it wasn't written as a version.
+    pub fn versions(&self, key: &str) -> Option<impl Iterator<Item = (u64, &Value)>> {
+        let current = (*self.sequences.get(key)?, self.tree.get(key)?);
+        let history = self.history.get(key).into_iter().flatten();
+        Some([current].into_iter().chain(history.map(|(sequence, value)| (*sequence, value))))
+    }
+
+    /// The highest sequence number of any version written to this memtable, or
+    /// 0 if it has none.
+    pub fn max_version(&self) -> u64 {
+        self.sequences.values().copied().max().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<Arc<str>>) {
+        if let Some(None) = self.tree.insert(key.into(), Some(value.into())) {
+            self.tombstones -= 1;
+        }
     }
 
     pub fn get(&self, key: &str) -> Option<Value> {
@@ -51,22 +139,114 @@ impl Memtable {
     }
 
     pub fn delete(&mut self, key: &str) {
-        self.tree.insert(key.into(), None);
+        if !matches!(self.tree.insert(key.into(), None), Some(None)) {
+            self.tombstones += 1;
+        }
+    }
+
+    /// Apply the entries of `newer`, a memtable that was written to after this
+    /// one, so that this holds what the two of them do together. Tombstones
+    /// remove keys rather than being kept, so only use this on a memtable that
+    /// doesn't sit over older data, and that doesn't keep versions.
+    pub fn absorb(&mut self, newer: &Memtable) {
+        for (key, value) in newer.iter() {
+            match value {
+                Some(value) => self.set(key.clone(), value.clone()),
+                None => {
+                    if let Some(None) = self.tree.remove(key) {
+                        self.tombstones -= 1;
+                    }
+                },
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// The number of entries that are tombstones, rather than values.
+    pub fn tombstone_count(&self) -> usize {
+        self.tombstones
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// The combined size of the keys and values held, in bytes, including
+    /// older versions of them.
+    pub fn data_size(&self) -> usize {
+        let size = |key: &String, value: &Value| key.len() + value.as_ref().map_or(0, |v| v.len());
+        let current: usize = self.tree.iter().map(|(key, value)| size(key, value)).sum();
+        let history: usize = self
+            .history
+            .iter()
+            .flat_map(|(key, versions)| versions.iter().map(move |(_, value)| size(key, value)))
+            .sum();
+        current + history
     }
 
     pub fn full(&self) -> bool {
         self.tree.len() >= self.capacity
     }
 
-    pub fn iter(&self) -> btree_map::Iter<String, Value> {
+    pub fn iter(&self) -> btree_map::Iter<'_, String, Value> {
         self.tree.iter()
     }
 
+    /// Iterate over the entries whose key is at least `start`, in order.
+    pub fn range(&self, start: &str) -> btree_map::Range<'_, String, Value> {
+        self.tree.range::<str, _>((Bound::Included(start), Bound::Unbounded))
+    }
+
     pub fn reset(&mut self) {
         self.tree = BTreeMap::new();
+        self.tombstones = 0;
+        self.sequences.clear();
+        self.history.clear();
     }
 
     pub fn capacity(&self) -> usize {
         self.capacity
     }
 }
+
+/// An ordered iterator over the entries of several memtables, whose key is at
+/// least some start. Where more than one of them holds a key, the entry from
+/// the first one is yielded, so they go newest first.
+///
+/// The memtables are shared with the engine, which copies one before writing
+/// to it, so the iterator sees them as they were when it was created.
+pub struct SnapshotRange {
+    memtables: Vec<Arc<Memtable>>,
+
+    /// The bound on the next key to yield.
+    next: Bound<String>,
+}
+
+impl SnapshotRange {
+    pub fn new(memtables: Vec<Arc<Memtable>>, start: &str) -> Self {
+        Self { memtables, next: Bound::Included(start.to_owned()) }
+    }
+}
+
+impl Iterator for SnapshotRange {
+    type Item = (String, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bounds = (self.next.as_ref().map(String::as_str), Bound::Unbounded);
+        let mut first: Option<(&String, &Value)> = None;
+        for memtable in &self.memtables {
+            let Some((key, value)) = memtable.tree.range::<str, _>(bounds).next() else {
+                continue;
+            };
+            if first.is_none_or(|(first_key, _)| key < first_key) {
+                first = Some((key, value));
+            }
+        }
+        let (key, value) = first.map(|(key, value)| (key.clone(), value.clone()))?;
+        self.next = Bound::Excluded(key.clone());
+        Some((key, value))
+    }
+}
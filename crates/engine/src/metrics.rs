@@ -0,0 +1,303 @@
+//! Counters for the work that the engine does, read back as a
+//! [`MetricsSnapshot`] from
+//! [`Engine::metrics`](crate::engine::Engine::metrics).
+//!
+//! Every counter is a relaxed atomic, so recording is cheap enough to do on
+//! every operation, but a snapshot taken while operations are running may be
+//! slightly out of step between counters.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The upper bounds of the buckets for the number of segment files that a get
+/// reads from.
+const SEGMENT_PROBE_BOUNDS: &[u64] = &[0, 1, 2, 4, 8, 16, 32];
+
+/// A count that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A distribution of values, counted into buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    /// The inclusive upper bound of each bucket, in ascending order. Values
+    /// above the last bound go in one more bucket at the end.
+    bounds: &'static [u64],
+    buckets: Vec<Counter>,
+    sum: Counter,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Self {
+        let buckets = (0..=bounds.len()).map(|_| Counter::default()).collect();
+        Self { bounds, buckets, sum: Counter::default() }
+    }
+
+    pub fn record(&self, value: u64) {
+        self.buckets[self.bounds.partition_point(|&bound| bound < value)].increment();
+        self.sum.add(value);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds: self.bounds,
+            counts: self.buckets.iter().map(Counter::get).collect(),
+            sum: self.sum.get(),
+        }
+    }
+}
+
+/// The state of a [`Histogram`] at some point in time.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistogramSnapshot {
+    pub bounds: &'static [u64],
+
+    /// The number of values in each bucket, with one more than there are
+    /// bounds, for the values above the last bound.
+    pub counts: Vec<u64>,
+
+    /// The sum of every value recorded.
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    /// The number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The number of values at or below each bound, followed by the total
+    /// count, in the cumulative form that Prometheus uses.
+    pub fn cumulative_counts(&self) -> impl Iterator<Item = (Option<u64>, u64)> + '_ {
+        let bounds = self.bounds.iter().copied().map(Some).chain([None]);
+        bounds.zip(self.counts.iter().scan(0, |total, count| {
+            *total += count;
+            Some(*total)
+        }))
+    }
+
+    /// An upper bound on the `quantile` of the values recorded, between 0 and
+    /// 1, as the bound of the bucket that it falls in. This is `None` if it
+    /// falls above the last bound, or no values were recorded.
+    pub fn quantile(&self, quantile: f64) -> Option<u64> {
+        let rank = ((quantile * self.count() as f64).ceil() as u64).max(1);
+        let (bound, _) = self.cumulative_counts().find(|&(_, count)| count >= rank)?;
+        bound
+    }
+}
+
+impl std::ops::AddAssign<&HistogramSnapshot> for HistogramSnapshot {
+    /// Add the counts of `other`, which must have the same bounds.
+    fn add_assign(&mut self, other: &Self) {
+        debug_assert_eq!(self.bounds, other.bounds);
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.sum += other.sum;
+    }
+}
+
+/// How a segment's bloom filter has answered lookups since the segment was
+/// opened.
+#[derive(Debug, Default)]
+pub struct BloomFilterCounters {
+    pub checks: Counter,
+    pub negatives: Counter,
+
+    /// Checks that allowed the key, where the segment turned out not to have
+    /// it.
+    pub false_positives: Counter,
+}
+
+impl BloomFilterCounters {
+    pub fn snapshot(&self) -> BloomFilterStats {
+        BloomFilterStats {
+            checks: self.checks.get(),
+            negatives: self.negatives.get(),
+            false_positives: self.false_positives.get(),
+        }
+    }
+}
+
+/// The state of a [`BloomFilterCounters`] at some point in time.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BloomFilterStats {
+    pub checks: u64,
+    pub negatives: u64,
+    pub false_positives: u64,
+}
+
+impl BloomFilterStats {
+    /// The share of checks for keys that weren't in the segment which the
+    /// filter failed to rule out, or `None` if there were no such checks.
+    pub fn false_positive_rate(&self) -> Option<f64> {
+        let absent = self.negatives + self.false_positives;
+        (absent > 0).then(|| self.false_positives as f64 / absent as f64)
+    }
+}
+
+impl std::ops::AddAssign for BloomFilterStats {
+    fn add_assign(&mut self, other: Self) {
+        self.checks += other.checks;
+        self.negatives += other.negatives;
+        self.false_positives += other.false_positives;
+    }
+}
+
+/// The engine's counters, shared between the engine and its store.
+#[derive(Debug)]
+pub struct Metrics {
+    pub gets: Counter,
+    pub sets: Counter,
+    pub deletes: Counter,
+
+    /// Segment files that a get skipped because their bloom filter ruled the
+    /// key out.
+    pub bloom_filter_skips: Counter,
+
+    /// The number of segment files that each get read from, after key range
+    /// and bloom filter checks. Gets answered by the memtable read none.
+    pub segment_probes: Histogram,
+
+    /// Gets that missed the memtables and were answered by the row cache, or
+    /// weren't, when it is turned on.
+    pub row_cache_hits: Counter,
+    pub row_cache_misses: Counter,
+    pub flushes: Counter,
+
+    /// Bytes written to segment files by flushes, ingests and bulk loads.
+    pub flushed_bytes: Counter,
+
+    /// Bytes appended to the WAL, including the framing of batches.
+    pub wal_bytes: Counter,
+
+    /// Batches written to the WAL. Each is one write, and one fsync when
+    /// syncing is turned on, however many records it holds.
+    pub wal_batches: Counter,
+
+    /// Segment files that were found to be corrupt and quarantined.
+    pub quarantined_segments: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            gets: Counter::default(),
+            sets: Counter::default(),
+            deletes: Counter::default(),
+            bloom_filter_skips: Counter::default(),
+            segment_probes: Histogram::new(SEGMENT_PROBE_BOUNDS),
+            row_cache_hits: Counter::default(),
+            row_cache_misses: Counter::default(),
+            flushes: Counter::default(),
+            flushed_bytes: Counter::default(),
+            wal_bytes: Counter::default(),
+            wal_batches: Counter::default(),
+            quarantined_segments: Counter::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            gets: self.gets.get(),
+            sets: self.sets.get(),
+            deletes: self.deletes.get(),
+            bloom_filter_skips: self.bloom_filter_skips.get(),
+            segment_probes: self.segment_probes.snapshot(),
+            row_cache_hits: self.row_cache_hits.get(),
+            row_cache_misses: self.row_cache_misses.get(),
+            flushes: self.flushes.get(),
+            flushed_bytes: self.flushed_bytes.get(),
+            wal_bytes: self.wal_bytes.get(),
+            wal_batches: self.wal_batches.get(),
+            quarantined_segments: self.quarantined_segments.get(),
+        }
+    }
+}
+
+/// The engine's counters since it was opened, from
+/// [`Engine::metrics`](crate::engine::Engine::metrics).
+///
+/// Sets and deletes that are part of a batch count individually.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MetricsSnapshot {
+    pub gets: u64,
+    pub sets: u64,
+    pub deletes: u64,
+    pub bloom_filter_skips: u64,
+    pub segment_probes: HistogramSnapshot,
+    pub row_cache_hits: u64,
+    pub row_cache_misses: u64,
+    pub flushes: u64,
+    pub flushed_bytes: u64,
+    pub wal_bytes: u64,
+    pub wal_batches: u64,
+    pub quarantined_segments: u64,
+}
+
+impl std::ops::AddAssign for MetricsSnapshot {
+    fn add_assign(&mut self, other: Self) {
+        self.gets += other.gets;
+        self.sets += other.sets;
+        self.deletes += other.deletes;
+        self.bloom_filter_skips += other.bloom_filter_skips;
+        self.segment_probes += &other.segment_probes;
+        self.row_cache_hits += other.row_cache_hits;
+        self.row_cache_misses += other.row_cache_misses;
+        self.flushes += other.flushes;
+        self.flushed_bytes += other.flushed_bytes;
+        self.wal_bytes += other.wal_bytes;
+        self.wal_batches += other.wal_batches;
+        self.quarantined_segments += other.quarantined_segments;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::new(&[1, 4]);
+        for value in [0, 1, 2, 4, 5, 100] {
+            histogram.record(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.counts, [2, 2, 2]);
+        assert_eq!(snapshot.count(), 6);
+        assert_eq!(snapshot.sum, 112);
+        assert_eq!(snapshot.cumulative_counts().collect::<Vec<_>>(), [
+            (Some(1), 2),
+            (Some(4), 4),
+            (None, 6)
+        ]);
+        assert_eq!(snapshot.quantile(0.0), Some(1));
+        assert_eq!(snapshot.quantile(0.5), Some(4));
+        assert_eq!(snapshot.quantile(0.99), None);
+        assert_eq!(Histogram::new(&[1]).snapshot().quantile(0.5), None);
+    }
+
+    #[test]
+    fn false_positive_rate() {
+        let mut stats = BloomFilterStats { checks: 10, negatives: 0, false_positives: 0 };
+        assert_eq!(stats.false_positive_rate(), None);
+        stats += BloomFilterStats { checks: 5, negatives: 3, false_positives: 1 };
+        assert_eq!(stats, BloomFilterStats { checks: 15, negatives: 3, false_positives: 1 });
+        assert_eq!(stats.false_positive_rate(), Some(0.25));
+    }
+}
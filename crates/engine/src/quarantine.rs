@@ -0,0 +1,78 @@
+//! Moving damaged segment files out of a store, set with
+//! [`StoreArgs::quarantine_corrupt_segments`](crate::store::StoreArgs::quarantine_corrupt_segments).
+//!
+//! A segment file that turns out to be corrupt, when the store opens, when a
+//! lookup or [`Store::verify`](crate::store::Store::verify) reads it, or when
+//! compaction merges it, is dropped from the manifest and moved into the
+//! `quarantine` directory of the store's directory. The store carries on with
+//! the files that are left, rather than failing every read that reaches the
+//! damaged one. The quarantined file is kept for `crunch-doctor` or a person to
+//! look at, and nothing reads it again.
+//!
+//! Whatever the file held is lost to the store, so keys in it fall back to the
+//! values, or deletes, in older segment files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use anyhow::anyhow;
+
+use crate::error::Error;
+use crate::events::Listeners;
+use crate::metrics::Metrics;
+use crate::store::SegmentSet;
+use crate::util::sync_directory;
+
+/// The directory, within a store's directory, that damaged segment files are
+/// moved into.
+pub const QUARANTINE_DIRECTORY: &str = "quarantine";
+
+/// Drop the segment file that `error` found to be corrupt from `segments`,
+/// and move it into the quarantine directory of the store at `directory`,
+/// counting it in `metrics` and telling `listeners`. Returns whether a file
+/// was quarantined, which it isn't if `error` isn't an
+/// [`Error::Corruption`] in a live segment file, such as one that was already
+/// quarantined.
+pub fn quarantine_segment(
+    directory: &Path,
+    segments: &RwLock<SegmentSet>,
+    error: &Error,
+    metrics: &Metrics,
+    listeners: &Listeners,
+) -> Result<bool, Error> {
+    let Error::Corruption { file: path, .. } = error else {
+        return Ok(false);
+    };
+    let mut segments = segments.write()?;
+    let Some(position) = segments.handles.iter().position(|segment| segment.path() == path) else {
+        return Ok(false);
+    };
+    // The manifest stops listing the file before it is moved, so that a crash in
+    // between leaves a file that isn't part of the store, rather than a store
+    // that is missing a file.
+    let segment = segments.handles.remove(position).expect("segment position is in bounds");
+    if let Err(error) = segments.commit(directory) {
+        segments.handles.insert(position, segment);
+        return Err(error);
+    }
+    drop(segments);
+    let destination = move_to_quarantine(directory, path)?;
+    log::error!("quarantined {path:?} in {destination:?}, since it is corrupt: {error}");
+    metrics.quarantined_segments.increment();
+    listeners.notify(|listener| listener.on_segment_quarantined(path, &destination, error));
+    Ok(true)
+}
+
+/// Move the file at `path` into the quarantine directory of the store at
+/// `directory`, and return where it was moved to.
+pub fn move_to_quarantine(directory: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let quarantine = directory.join(QUARANTINE_DIRECTORY);
+    fs::create_dir_all(&quarantine)?;
+    let filename = path.file_name().ok_or_else(|| anyhow!("{path:?} has no filename"))?;
+    let destination = quarantine.join(filename);
+    fs::rename(path, &destination)?;
+    sync_directory(&quarantine)?;
+    sync_directory(directory)?;
+    Ok(destination)
+}
@@ -0,0 +1,108 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket that limits how fast some resource can be consumed.
+///
+/// The bucket refills at `rate` tokens per second, and holds at most one
+/// second worth of tokens, so short bursts are allowed but the long-run
+/// throughput never exceeds `rate`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens per second. Zero disables the limiter.
+    rate: u64,
+
+    /// This goes negative when a caller takes more tokens than are available.
+    /// That debt is paid off by sleeping.
+    available: f64,
+
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self { rate, available: rate as f64, last_refill: Instant::now() }
+    }
+
+    /// A limiter that never blocks.
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Change the rate to `rate` tokens per second. Any debt carries over, and
+    /// a limiter that was disabled starts with a full bucket.
+    pub fn set_rate(&mut self, rate: u64) {
+        if self.rate == 0 {
+            self.available = rate as f64;
+            self.last_refill = Instant::now();
+        } else {
+            self.refill();
+            self.available = self.available.min(rate as f64);
+        }
+        self.rate = rate;
+    }
+
+    /// Take `amount` tokens from the bucket, blocking the current thread until
+    /// they are available.
+    pub fn acquire(&mut self, amount: u64) {
+        if self.rate == 0 {
+            return;
+        }
+        self.refill();
+        self.available -= amount as f64;
+        if self.available < 0.0 {
+            let wait = Duration::from_secs_f64(-self.available / self.rate as f64);
+            log::trace!("rate limited, waiting {wait:?}");
+            thread::sleep(wait);
+            self.refill();
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blocks_once_burst_is_spent() {
+        let mut limiter = RateLimiter::new(2000);
+        let start = Instant::now();
+        limiter.acquire(2000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        limiter.acquire(1000);
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn set_rate() {
+        let mut limiter = RateLimiter::unlimited();
+        limiter.set_rate(1000);
+        let start = Instant::now();
+        limiter.acquire(1000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        limiter.acquire(500);
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        limiter.set_rate(0);
+        limiter.acquire(u64::MAX);
+        assert!(start.elapsed() < Duration::from_millis(900));
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut limiter = RateLimiter::unlimited();
+        let start = Instant::now();
+        limiter.acquire(u64::MAX);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,328 @@
+//! Checking a store directory for damage, and repairing it, without opening
+//! it as a [`Store`](crate::store::Store). This is what the `crunch-doctor`
+//! binary runs.
+//!
+//! [`check`] reads the manifest, every segment file it lists, and the WAL
+//! files that haven't been flushed, and reports what it can't read, without
+//! changing anything. [`repair`] then rebuilds a consistent store out of what
+//! is readable:
+//!
+//! - A damaged segment file is replaced by a new one holding every entry before
+//!   the damage. The original is left in the directory, but is no longer part
+//!   of the store.
+//! - A segment file that is missing is dropped from the store.
+//! - The WAL is truncated at its first damaged record, like
+//!   [`RecoveryMode::Salvage`] does.
+//! - A missing or unreadable manifest is rebuilt from the segment files in the
+//!   directory, with every WAL file replayed on top of them. Segment files left
+//!   behind by an interrupted compaction are included, as are WAL files that
+//!   were flushed but not yet removed, so this can bring back overwritten or
+//!   deleted values.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::encryption::{self, Cipher, Writer};
+use crate::error::Error;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::memtable::{Memtable, MemtableArgs};
+use crate::segment::{self, is_segment_filename, segment_filename, segment_id};
+use crate::wal::{self, RecoveryMode, Wal};
+
+/// Something wrong with a store, found by [`check`].
+#[derive(Debug)]
+pub enum Problem {
+    /// The store can't be read with the key that was given, or without one.
+    Key(Error),
+
+    /// There is no manifest.
+    ManifestMissing,
+
+    ManifestUnreadable(Error),
+
+    /// The manifest lists a segment file that isn't there.
+    SegmentMissing(PathBuf),
+
+    /// A segment file can't be read past some point. `readable` entries come
+    /// before it.
+    SegmentDamaged {
+        error: Error,
+        readable: u64,
+    },
+
+    /// A record in the WAL can't be decoded. Nothing after it is replayed.
+    WalDamaged(Error),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key(error) => write!(f, "{error}"),
+            Self::ManifestMissing => write!(f, "the manifest is missing"),
+            Self::ManifestUnreadable(error) => write!(f, "the manifest can't be read: {error}"),
+            Self::SegmentMissing(path) => write!(f, "segment file {path:?} is missing"),
+            Self::SegmentDamaged { error, readable } => {
+                write!(f, "{error} ({readable} entries before it are readable)")
+            },
+            Self::WalDamaged(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+/// What [`check`] found.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+
+    /// The number of segment files that were read through.
+    pub segment_files: usize,
+
+    /// The number of entries that were read from those segment files.
+    pub entries: u64,
+    pub wal_files: usize,
+
+    /// Segment files in the directory that the manifest doesn't list, such as
+    /// those left behind by an interrupted compaction. They aren't part of
+    /// the store, so they aren't checked.
+    pub unlisted_segments: Vec<PathBuf>,
+}
+
+impl Report {
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// What [`repair`] changed.
+#[derive(Debug, Default)]
+pub struct Repairs {
+    /// Each damaged segment file, along with the file holding its readable
+    /// entries, or `None` if it had none.
+    pub salvaged_segments: Vec<(PathBuf, Option<PathBuf>)>,
+
+    /// Segment files that were missing, and dropped from the manifest.
+    pub dropped_segments: Vec<PathBuf>,
+
+    /// The number of bytes dropped from the WAL.
+    pub wal_dropped_bytes: u64,
+    pub manifest_rebuilt: bool,
+}
+
+/// Check the store in `directory`, which is decrypted with `cipher` if one
+/// is given, without changing it.
+pub fn check(directory: &Path, cipher: Option<&Cipher>) -> Result<Report, Error> {
+    let mut report = Report::default();
+    // Assuming the store has data means that a key check is never written.
+    if let Err(error) = encryption::check_key(directory, cipher, true) {
+        report.problems.push(Problem::Key(error));
+        return Ok(report);
+    }
+    let manifest = match Manifest::load(directory) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            report.problems.push(Problem::ManifestMissing);
+            discover(directory)?
+        },
+        Err(error) => {
+            report.problems.push(Problem::ManifestUnreadable(error));
+            discover(directory)?
+        },
+    };
+    for entry in &manifest.segments {
+        let path = directory.join(segment_filename(entry.id));
+        if !path.exists() {
+            report.problems.push(Problem::SegmentMissing(path));
+            continue;
+        }
+        let (readable, error) = segment::read_sorted(&path, cipher, |_, _| Ok(()))?;
+        report.segment_files += 1;
+        report.entries += readable;
+        if let Some(error) = error {
+            report.problems.push(Problem::SegmentDamaged { error, readable });
+        }
+    }
+    let listed: Vec<_> = manifest.segments.iter().map(|entry| entry.id).collect();
+    report.unlisted_segments = segment_ids(directory)?
+        .into_iter()
+        .filter(|id| !listed.contains(id))
+        .map(|id| directory.join(segment_filename(id)))
+        .collect();
+    let (wal_files, error) = wal::check(directory, manifest.wal_start, cipher)?;
+    report.wal_files = wal_files;
+    report.problems.extend(error.map(Problem::WalDamaged));
+    Ok(report)
+}
+
+/// Repair the store in `directory`, which is decrypted with `cipher` if one
+/// is given, so that it opens. See the [module docs](self) for what is done.
+///
+/// Nothing is done if the store can't be read with `cipher`.
+pub fn repair(directory: &Path, cipher: Option<&Cipher>) -> Result<Repairs, Error> {
+    encryption::check_key(directory, cipher, true)?;
+    let mut repairs = Repairs::default();
+    let mut manifest = match Manifest::load(directory) {
+        Ok(Some(manifest)) => manifest,
+        result => {
+            if let Err(error) = result {
+                log::warn!("rebuilding the manifest, which can't be read: {error}");
+            }
+            repairs.manifest_rebuilt = true;
+            discover(directory)?
+        },
+    };
+    let mut segments = Vec::with_capacity(manifest.segments.len());
+    for entry in &manifest.segments {
+        let path = directory.join(segment_filename(entry.id));
+        if !path.exists() {
+            log::warn!("dropping missing segment file {path:?}");
+            repairs.dropped_segments.push(path);
+            continue;
+        }
+        let (_, error) = segment::read_sorted(&path, cipher, |_, _| Ok(()))?;
+        if error.is_none() {
+            segments.push(*entry);
+            continue;
+        }
+        let id = manifest.next_segment_id;
+        manifest.next_segment_id += 1;
+        let salvaged_path = directory.join(segment_filename(id));
+        let mut salvaged = Writer::new(File::create_new(&salvaged_path)?, cipher)?;
+        let (readable, error) = segment::read_sorted(&path, cipher, |version, entry| {
+            if let Some(version) = version {
+                segment::write_version(&mut salvaged, version)?;
+            }
+            entry.write_with(&mut salvaged, None).map(drop)
+        })?;
+        salvaged.finish()?.sync_all()?;
+        log::warn!("salvaged {readable} entries of {path:?} before {}", error.unwrap());
+        if readable == 0 {
+            fs::remove_file(&salvaged_path)?;
+            repairs.salvaged_segments.push((path, None));
+            continue;
+        }
+        segments.push(ManifestEntry { id, ..*entry });
+        repairs.salvaged_segments.push((path, Some(salvaged_path)));
+    }
+    manifest.segments = segments;
+    manifest.commit(directory)?;
+
+    let wal = Wal::open(directory, manifest.wal_start, u64::MAX, true)?
+        .with_cipher(cipher.cloned())
+        .with_recovery_mode(RecoveryMode::Salvage);
+    let mut memtable = Memtable::new(MemtableArgs { capacity: usize::MAX });
+    if let Some(salvage) = wal.replay(&mut memtable)?.salvage {
+        repairs.wal_dropped_bytes = salvage.dropped_bytes;
+    }
+    Ok(repairs)
+}
+
+/// The ids of the segment files in `directory`, in ascending order.
+fn segment_ids(directory: &Path) -> Result<Vec<u32>, Error> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let filename = entry.file_name();
+        if entry.file_type()?.is_file() && filename.to_str().is_some_and(is_segment_filename) {
+            ids.extend(segment_id(entry.path()));
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// A manifest listing every segment file in `directory` at level 0, newest
+/// last, with every WAL file to be replayed.
+fn discover(directory: &Path) -> Result<Manifest, Error> {
+    let ids = segment_ids(directory)?;
+    let next_segment_id = ids.last().map_or(1, |id| id + 1);
+    let entries = ids.into_iter().map(|id| ManifestEntry {
+        id,
+        level: 0,
+        sequence: u64::from(id),
+        overwrites: 0,
+    });
+    Ok(Manifest::new(next_segment_id, 0, entries))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::engine::{Engine, EngineArgs};
+    use crate::store::StoreArgs;
+    use crate::test::StoreFixture;
+
+    fn args() -> EngineArgs {
+        EngineArgs {
+            memtable: MemtableArgs { capacity: 2 },
+            store: StoreArgs { compaction_enabled: false, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn repairs_damaged_segment_and_wal() {
+        let fixture = StoreFixture::init("./test-db-repair");
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        for key in ["a", "b", "c", "d", "e"] {
+            engine.set(key, "1").unwrap();
+        }
+        engine.stop().unwrap();
+        assert!(check(fixture.path(), None).unwrap().is_healthy());
+
+        // Each entry is 11 bytes, so this cuts into the second entry of the first
+        // segment, and adds a bad record to the end of the WAL.
+        let first = fixture.path().join(segment_filename(1));
+        File::options().write(true).open(&first).unwrap().set_len(15).unwrap();
+        let wal_path = fixture.path().join(wal::wal_filename(3));
+        File::options().append(true).open(&wal_path).unwrap().write_all(&[9; 12]).unwrap();
+        fs::remove_file(fixture.path().join(segment_filename(2))).unwrap();
+
+        let report = check(fixture.path(), None).unwrap();
+        assert_eq!((report.segment_files, report.entries, report.wal_files), (1, 1, 1));
+        assert!(matches!(report.problems[..], [
+            Problem::SegmentDamaged { readable: 1, .. },
+            Problem::SegmentMissing(_),
+            Problem::WalDamaged(_),
+        ]));
+        assert!(Engine::with_args(fixture.path().to_owned(), args()).is_err());
+
+        let repairs = repair(fixture.path(), None).unwrap();
+        assert_eq!(repairs.salvaged_segments, [(
+            first,
+            Some(fixture.path().join(segment_filename(3)))
+        )]);
+        assert_eq!(repairs.dropped_segments.len(), 1);
+        assert_eq!(repairs.wal_dropped_bytes, 12);
+        assert!(!repairs.manifest_rebuilt);
+        let report = check(fixture.path(), None).unwrap();
+        assert!(report.is_healthy(), "{:?}", report.problems);
+        assert_eq!(report.unlisted_segments, [fixture.path().join(segment_filename(1))]);
+
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        let keys: Vec<_> = engine.entries().unwrap().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, ["a", "e"]);
+        engine.stop().unwrap();
+    }
+
+    #[test]
+    fn rebuilds_missing_manifest() {
+        let fixture = StoreFixture::init("./test-db-repair-manifest");
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        for key in ["a", "b", "c"] {
+            engine.set(key, "1").unwrap();
+        }
+        engine.stop().unwrap();
+        fs::write(fixture.path().join("MANIFEST"), "garbage").unwrap();
+        let report = check(fixture.path(), None).unwrap();
+        assert!(matches!(report.problems[..], [Problem::ManifestUnreadable(_)]));
+        assert!(repair(fixture.path(), None).unwrap().manifest_rebuilt);
+        assert!(check(fixture.path(), None).unwrap().is_healthy());
+
+        let engine = Engine::with_args(fixture.path().to_owned(), args()).unwrap();
+        assert_eq!(engine.entries().unwrap().count(), 3);
+        engine.stop().unwrap();
+    }
+}
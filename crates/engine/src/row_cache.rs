@@ -0,0 +1,206 @@
+//! A cache of recently read key-value pairs in front of the segment files, so
+//! that reads that keep coming back to the same keys don't go to disk for
+//! them. It is turned on with [`RowCacheArgs::capacity`].
+//!
+//! Only reads that miss the memtables are cached, since those are already in
+//! memory. Each write drops its keys from the cache once it is in the
+//! memtable, and a read only caches what it found if no write was made since
+//! it started, so the cache never holds a value that a newer write replaced.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crunch_common::config::Config;
+use crunch_common::env::ByteSize;
+
+use crate::error::Error;
+use crate::memtable::Value;
+
+/// What each cached pair is charged on top of its key and value, for the maps
+/// that hold it.
+const ENTRY_OVERHEAD: usize = 64;
+
+#[derive(Clone, Debug, Default)]
+pub struct RowCacheArgs {
+    /// The most bytes of keys and values that the cache holds, after which the
+    /// least recently read pairs are evicted. 0 turns the cache off.
+    pub capacity: usize,
+}
+
+impl RowCacheArgs {
+    pub fn from_config(config: &Config) -> Self {
+        let capacity = config.get("engine", Some("row_cache"), "capacity", ByteSize(0)).0;
+        Self { capacity: capacity as usize }
+    }
+}
+
+/// A least recently used cache of the values read for keys, including the
+/// keys that turned out to have none.
+pub struct RowCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+
+    /// Bumped by every invalidation, so that reads which started before one
+    /// know not to cache what they found.
+    epoch: AtomicU64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Arc<str>, Slot>,
+
+    /// The cached keys by when they were last read, least recent first.
+    recency: BTreeMap<u64, Arc<str>>,
+
+    /// Counts up with every read, to order `recency` by.
+    clock: u64,
+
+    /// The combined charge of every cached pair.
+    size: usize,
+}
+
+struct Slot {
+    value: Value,
+    last_read: u64,
+}
+
+impl RowCache {
+    pub fn new(args: RowCacheArgs) -> Self {
+        log::debug!("row cache initialized with {args:?}");
+        Self { capacity: args.capacity, inner: Mutex::default(), epoch: AtomicU64::new(0) }
+    }
+
+    /// The value cached for `key`, if there is one. `Some(None)` means that the
+    /// key was read and had no value.
+    pub fn get(&self, key: &str) -> Result<Option<Value>, Error> {
+        let mut inner = self.inner.lock()?;
+        let inner = &mut *inner;
+        inner.clock += 1;
+        let Some(slot) = inner.entries.get_mut(key) else {
+            return Ok(None);
+        };
+        if let Some(key) = inner.recency.remove(&slot.last_read) {
+            inner.recency.insert(inner.clock, key);
+        }
+        slot.last_read = inner.clock;
+        Ok(Some(slot.value.clone()))
+    }
+
+    /// Taken before a read looks in the memtables, to pass to [`Self::insert`].
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Cache `value` for `key`, as read by a read that started at `epoch`,
+    /// unless a write was made since then, or the pair would take up more
+    /// than the whole cache.
+    pub fn insert(&self, key: &str, value: Value, epoch: u64) -> Result<(), Error> {
+        let size = charge(key, &value);
+        if size > self.capacity {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock()?;
+        if self.epoch() != epoch {
+            return Ok(());
+        }
+        inner.remove(key);
+        inner.clock += 1;
+        let key: Arc<str> = key.into();
+        let last_read = inner.clock;
+        inner.recency.insert(last_read, key.clone());
+        inner.entries.insert(key, Slot { value, last_read });
+        inner.size += size;
+        while inner.size > self.capacity {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            if let Some(slot) = inner.entries.remove(&oldest) {
+                inner.size -= charge(&oldest, &slot.value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop `keys` from the cache, once the writes to them are visible.
+    pub fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Result<(), Error> {
+        let mut inner = self.inner.lock()?;
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        for key in keys {
+            inner.remove(key);
+        }
+        Ok(())
+    }
+
+    /// Drop everything from the cache, once writes that may have replaced any
+    /// value are visible.
+    pub fn clear(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock()?;
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        *inner = Inner::default();
+        Ok(())
+    }
+
+    /// The combined size of the cached pairs, as charged against the capacity.
+    pub fn size(&self) -> Result<usize, Error> {
+        Ok(self.inner.lock()?.size)
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some((key, slot)) = self.entries.remove_entry(key) {
+            self.recency.remove(&slot.last_read);
+            self.size -= charge(&key, &slot.value);
+        }
+    }
+}
+
+fn charge(key: &str, value: &Value) -> usize {
+    key.len() + value.as_deref().map_or(0, str::len) + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cache(capacity: usize) -> RowCache {
+        RowCache::new(RowCacheArgs { capacity })
+    }
+
+    #[test]
+    fn evicts_least_recently_read() {
+        let cache = cache(3 * (ENTRY_OVERHEAD + 2));
+        for key in ["a", "b", "c"] {
+            cache.insert(key, Some(key.into()), cache.epoch()).unwrap();
+        }
+        assert_eq!(cache.get("a").unwrap(), Some(Some("a".into())));
+        cache.insert("d", None, cache.epoch()).unwrap();
+        assert_eq!(cache.get("b").unwrap(), None);
+        assert_eq!(cache.get("a").unwrap(), Some(Some("a".into())));
+        assert_eq!(cache.get("d").unwrap(), Some(None));
+        assert_eq!(cache.size().unwrap(), 2 * (ENTRY_OVERHEAD + 2) + ENTRY_OVERHEAD + 1);
+
+        // A pair larger than the whole cache isn't cached.
+        cache.insert("e", Some("x".repeat(1000).into()), cache.epoch()).unwrap();
+        assert_eq!(cache.get("e").unwrap(), None);
+        assert_eq!(cache.get("c").unwrap(), Some(Some("c".into())));
+    }
+
+    #[test]
+    fn invalidation() {
+        let cache = cache(1024);
+        cache.insert("a", Some("1".into()), cache.epoch()).unwrap();
+        let epoch = cache.epoch();
+        cache.invalidate(["a"]).unwrap();
+        assert_eq!(cache.get("a").unwrap(), None);
+        // Read before the write, so it may be stale.
+        cache.insert("a", Some("1".into()), epoch).unwrap();
+        assert_eq!(cache.get("a").unwrap(), None);
+
+        cache.insert("b", Some("2".into()), cache.epoch()).unwrap();
+        cache.clear().unwrap();
+        assert_eq!(cache.get("b").unwrap(), None);
+        assert_eq!(cache.size().unwrap(), 0);
+    }
+}
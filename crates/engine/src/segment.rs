@@ -1,164 +1,858 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::{self, SeekFrom};
+use std::io::{self, BufReader, SeekFrom};
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use anyhow::anyhow;
-use bloom::BloomFilter;
-
+use crate::bloom_filter::BloomFilter;
+use crate::encryption::{Cipher, Reader};
 use crate::error::{Error, PairComponent};
+use crate::io::{IoBackend, StdIo};
+use crate::memtable::Value;
+use crate::metrics::{BloomFilterCounters, BloomFilterStats};
 use crate::sparse_index::SparseIndex;
+use crate::util::sync_directory;
 
 // TODO: These should probably be configurable at the Database level.
 const BLOOM_FILTER_FALSE_POSITIVE_RATE: f32 = 0.0001;
 const SPARSE_INDEX_RANGE_SIZE: usize = 4;
 
-type Value = Option<String>;
-
 pub struct SegmentHandle {
-    file: File,
-    path: PathBuf,
+    file: Arc<SegmentFile>,
+    level: u32,
+
+    /// Where this segment falls in the order that data was written to the
+    /// store. A segment with a higher sequence holds newer data.
+    sequence: u64,
+
+    size: u64,
+
+    /// The number of entries in the file that are tombstones.
+    tombstone_count: u32,
+
+    /// The number of keys in the file that an older segment also held when
+    /// this one was added, whose entries there are dead until the two are
+    /// compacted together. Segment files have no footer, so the count is kept
+    /// in the manifest instead.
+    overwrite_count: u32,
+
+    entry_count: u32,
+
+    /// The highest sequence number of any version in the file, or 0 if it
+    /// holds no versions.
+    max_version: u64,
     bloom_filter: BloomFilter,
+    bloom_filter_counters: BloomFilterCounters,
+
+    /// A bloom filter over the first bytes of every key at least that long,
+    /// along with how many bytes that is.
+    prefix_bloom_filter: Option<(usize, BloomFilter)>,
     sparse_index: SparseIndex,
+    key_range: Option<KeyRange>,
+
+    /// Decrypts the file, if the store is encrypted.
+    cipher: Option<Cipher>,
+
+    /// What lookups and scans read the file with.
+    io: Arc<dyn IoBackend>,
+}
+
+/// A snapshot of a segment's state, from
+/// [`Engine::stats`](crate::engine::Engine::stats).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SegmentStats {
+    pub path: PathBuf,
+    pub level: u32,
+
+    /// The size of the segment file, in bytes.
+    pub size: u64,
+    pub entry_count: u32,
+    pub tombstone_count: u32,
+
+    /// The number of keys in the file that shadow an entry in an older one,
+    /// which compacting the two together would drop.
+    pub overwrite_count: u32,
+    pub bloom_filter: BloomFilterStats,
+}
+
+/// A segment file on disk, shared between its [`SegmentHandle`] and anything
+/// reading from it, such as a [`StoreRange`](crate::store::StoreRange).
+///
+/// Once compaction has replaced the segment, the file is retired, and it is
+/// deleted when the last of these lets go of it. That way a scan never sees
+/// its files disappear partway through.
+#[derive(Debug)]
+pub struct SegmentFile {
+    path: PathBuf,
+    retired: AtomicBool,
+}
+
+impl SegmentFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SegmentFile {
+    fn drop(&mut self) {
+        if !self.retired.load(Ordering::Acquire) {
+            return;
+        }
+        // Without syncing the directory, a crash could bring the file back,
+        // where it would be taken for a segment that was never committed.
+        let deleted = fs::remove_file(&self.path)
+            .and_then(|()| self.path.parent().map_or(Ok(()), sync_directory));
+        match deleted {
+            Ok(()) => log::debug!("deleted retired segment file {:?}", self.path),
+            Err(error) => log::error!("failed to delete segment file {:?}: {error}", self.path),
+        }
+    }
 }
 
 impl SegmentHandle {
-    pub fn open(path: PathBuf) -> Result<Self, io::Error> {
-        let mut file = File::open(&path)?;
-        let size = EntryIter::from_start(&mut file)?.count() as u32;
-        log::trace!("size of {path:?}: {size}");
-        let mut bloom_filter = BloomFilter::with_rate(BLOOM_FILTER_FALSE_POSITIVE_RATE, size);
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        Self::open_at_level(path, 0, None, 0, Arc::new(StdIo))
+    }
+
+    /// Open the segment file at `path`, recording that it lives at `level` of
+    /// the store. The file is decrypted with `cipher`, if one is given, and
+    /// read with `io`, both now and by later lookups and scans.
+    ///
+    /// If `prefix_bloom_length` isn't 0, a second bloom filter is built over
+    /// the first that many bytes of each key, for [`Self::may_have_prefix`].
+    ///
+    /// Freshly flushed segments live at level 0, and compaction output is
+    /// placed on higher levels.
+    ///
+    /// Every entry in the file is read, so a file that is corrupt anywhere
+    /// fails to open with an [`Error::Corruption`].
+    pub fn open_at_level(
+        path: PathBuf,
+        level: u32,
+        cipher: Option<Cipher>,
+        prefix_bloom_length: usize,
+        io: Arc<dyn IoBackend>,
+    ) -> Result<Self, Error> {
+        let size = fs::metadata(&path)?.len();
+        let mut file = Reader::open_with(&path, cipher.as_ref(), io.clone())?;
+        let mut entries = EntryIter::from_start(&mut file)?.with_path(&path);
+        let mut entry_count = 0;
+        while let Some(result) = entries.advance() {
+            result?;
+            entry_count += 1;
+        }
+        log::trace!("entry count of {path:?}: {entry_count}");
+        let mut bloom_filter =
+            BloomFilter::with_rate(BLOOM_FILTER_FALSE_POSITIVE_RATE, entry_count);
+        let mut prefix_bloom_filter = (prefix_bloom_length > 0).then(|| {
+            let filter = BloomFilter::with_rate(BLOOM_FILTER_FALSE_POSITIVE_RATE, entry_count);
+            (prefix_bloom_length, filter)
+        });
         let mut sparse_index = SparseIndex::new();
+        let mut key_range: Option<KeyRange> = None;
         let mut elapsed_bytes = 0;
+        let mut tombstone_count = 0;
+        let mut max_version = 0;
+        let mut index_pending = false;
 
-        for (idx, entry) in EntryIter::from_start(&mut file)?.enumerate() {
-            bloom_filter.insert(entry.key());
-            if idx % SPARSE_INDEX_RANGE_SIZE == 0 {
+        let mut entries = EntryIter::from_start(&mut file)?.with_path(&path);
+        let mut idx = 0;
+        while let Some(result) = entries.advance() {
+            result?;
+            let entry = entries.current();
+            bloom_filter.insert(&entry.key());
+            if let Some((length, filter)) = prefix_bloom_filter.as_mut() {
+                if let Some(prefix) = entry.key().as_bytes().get(..*length) {
+                    filter.insert(&prefix);
+                }
+            }
+            if let EntryRef::Tombstone { .. } = entry {
+                tombstone_count += 1;
+            }
+            // Only the newest version of a key is indexed, since lookups read forward
+            // from the indexed position.
+            index_pending |= idx % SPARSE_INDEX_RANGE_SIZE == 0;
+            let newest_version =
+                key_range.as_ref().is_none_or(|range: &KeyRange| range.max != entry.key());
+            if index_pending && newest_version {
                 sparse_index.insert(entry.key(), elapsed_bytes);
+                index_pending = false;
             }
-            elapsed_bytes += entry.stride() as u64;
+            // Entries are sorted by key, so the first entry holds the minimum and the
+            // last entry holds the maximum.
+            match key_range.as_mut() {
+                Some(range) => {
+                    range.max.clear();
+                    range.max.push_str(entry.key());
+                },
+                None => key_range = Some(KeyRange::new(entry.key(), entry.key())),
+            };
+            max_version = max_version.max(entries.last_version().unwrap_or(0));
+            elapsed_bytes += entries.last_stride();
+            idx += 1;
         }
 
-        Ok(Self { file, path, bloom_filter, sparse_index })
+        Ok(Self {
+            file: Arc::new(SegmentFile { path, retired: AtomicBool::new(false) }),
+            level,
+            sequence: 0,
+            size,
+            tombstone_count,
+            overwrite_count: 0,
+            entry_count,
+            max_version,
+            bloom_filter,
+            bloom_filter_counters: BloomFilterCounters::default(),
+            prefix_bloom_filter,
+            sparse_index,
+            key_range,
+            cipher,
+            io,
+        })
     }
 
-    pub fn get(&mut self, key: &str) -> Result<Option<Value>, io::Error> {
-        log::trace!("looking in {:?} for {key}", self.path);
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn with_overwrite_count(mut self, overwrite_count: u32) -> Self {
+        self.overwrite_count = overwrite_count;
+        self
+    }
+
+    /// Set the overwrite count to the number of keys in the file that any of
+    /// `older` may hold, reading the file through once. See
+    /// [`count_overwrites`] for a segment whose keys are already known.
+    pub fn count_overwrites_in_file<'a>(
+        &mut self,
+        older: impl Iterator<Item = &'a SegmentHandle> + Clone,
+    ) -> Result<(), Error> {
+        let mut file = Reader::open_with(self.path(), self.cipher.as_ref(), self.io.clone())?;
+        let mut entries = EntryIter::from_start(&mut file)?.with_path(self.path());
+        let mut last_key: Option<String> = None;
+        let mut overwrite_count = 0;
+        while let Some(result) = entries.advance() {
+            result?;
+            let entry = entries.current();
+            let key = entry.key();
+            // Only the newest version of each key is counted.
+            if last_key.as_deref() == Some(key) {
+                continue;
+            }
+            if overwrites(key, older.clone()) {
+                overwrite_count += 1;
+            }
+            match last_key.as_mut() {
+                Some(last_key) => {
+                    last_key.clear();
+                    last_key.push_str(key);
+                },
+                None => last_key = Some(key.to_owned()),
+            }
+        }
+        self.overwrite_count = overwrite_count;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Value>, Error> {
+        log::trace!("looking in {:?} for {key}", self.path());
 
         // Each lookup in the bloom filter has a chance of being a false positive, but
         // every negative is correct. So we can exit early if the membership test
         // returns false.
-        if !self.bloom_filter.contains(&key) {
-            log::trace!("{key} was not in bloom filter for {:?}", self.path);
+        if !self.bloom_filter_contains(key) {
+            log::trace!("{key} was not in bloom filter for {:?}", self.path());
             return Ok(None);
         }
+        let value = self.search(key)?;
+        if value.is_none() {
+            self.record_bloom_filter_false_positive();
+        }
+        Ok(value)
+    }
+
+    /// Whether the bloom filter allows that `key` may be in this segment. A
+    /// `false` is always right, but a `true` may not be.
+    ///
+    /// The check is counted in the segment's [`SegmentStats`], and a caller
+    /// that goes on to find nothing should report it with
+    /// [`Self::record_bloom_filter_false_positive`].
+    pub fn bloom_filter_contains(&self, key: &str) -> bool {
+        self.bloom_filter_counters.checks.increment();
+        let contains = self.bloom_filter.contains(&key);
+        if !contains {
+            self.bloom_filter_counters.negatives.increment();
+        }
+        contains
+    }
 
+    /// Record that a key which the bloom filter allowed wasn't in the segment.
+    pub fn record_bloom_filter_false_positive(&self) {
+        self.bloom_filter_counters.false_positives.increment();
+    }
+
+    /// Like [`Self::get`], but returns the newest version of `key` that was
+    /// written at or before `sequence`. Entries without a version count as
+    /// written at sequence 0.
+    pub fn get_at(&self, key: &str, sequence: u64) -> Result<Option<Value>, Error> {
+        if !self.bloom_filter_contains(key) {
+            return Ok(None);
+        }
+        let (value, present) = self.find(key, Some(sequence))?;
+        if !present {
+            self.record_bloom_filter_false_positive();
+        }
+        Ok(value)
+    }
+
+    /// Like [`Self::get`], but reads the file without checking the bloom
+    /// filter first.
+    pub fn search(&self, key: &str) -> Result<Option<Value>, Error> {
+        self.find(key, None).map(|(value, _)| value)
+    }
+
+    /// Find the newest entry for `key`, skipping versions newer than
+    /// `sequence` if one is given. Also returns whether the file has any entry
+    /// for `key`.
+    fn find(&self, key: &str, sequence: Option<u64>) -> Result<(Option<Value>, bool), Error> {
         let (byte_start, byte_end) = self.sparse_index.get_byte_range(key);
         let byte_start = byte_start.unwrap_or(0);
-        self.file.seek(SeekFrom::Start(byte_start))?;
+        let mut file = self.reader()?;
+        file.seek(SeekFrom::Start(byte_start))?;
         log::trace!("byte range constrained to {byte_start}..{byte_end:?}");
 
         let mut elapsed_bytes = byte_start;
-        for entry in EntryIter::new(&mut self.file) {
+        let mut present = false;
+        let mut entries = EntryIter::new(&mut file).with_path(self.path());
+        while let Some(result) = entries.advance() {
             if byte_end.is_some_and(|end| elapsed_bytes >= end) {
                 break;
             }
-            match entry {
-                Entry::Assignment { key: k, value } if k == key => {
-                    log::trace!("found {key} in {:?}", self.path);
-                    return Ok(Some(Some(value)));
+            result?;
+            let entry = entries.current();
+            elapsed_bytes += entries.last_stride();
+            if entry.key() != key {
+                continue;
+            }
+            present = true;
+            if sequence.is_some_and(|sequence| entries.last_version().unwrap_or(0) > sequence) {
+                continue;
+            }
+            return Ok(match entry {
+                EntryRef::Assignment { value, .. } => {
+                    log::trace!("found {key} in {:?}", self.path());
+                    (Some(Some(Arc::from(value))), true)
                 },
-                Entry::Tombstone { key: k } if k == key => {
-                    log::trace!("found tombstone for {key} in {:?}", self.path);
-                    return Ok(Some(None));
+                EntryRef::Tombstone { .. } => {
+                    log::trace!("found tombstone for {key} in {:?}", self.path());
+                    (Some(None), true)
                 },
-                _ => {},
-            };
-            elapsed_bytes += entry.stride() as u64;
+            });
         }
 
-        log::trace!("{key} was not in {:?}", self.path);
+        log::trace!("{key} was not in {:?}", self.path());
+        Ok((None, present))
+    }
+
+    /// Whether this segment has an entry for `key`: `Some(true)` if it is
+    /// assigned, `Some(false)` if it is deleted, or `None` if it isn't here.
+    ///
+    /// Unlike [`Self::get`], this skips over the values in the file rather than
+    /// reading them.
+    pub fn contains(&self, key: &str) -> Result<Option<bool>, io::Error> {
+        if !self.bloom_filter_contains(key) {
+            log::trace!("{key} was not in bloom filter for {:?}", self.path());
+            return Ok(None);
+        }
+
+        let (byte_start, byte_end) = self.sparse_index.get_byte_range(key);
+        let mut elapsed_bytes = byte_start.unwrap_or(0);
+        let mut file = BufReader::new(self.reader()?);
+        file.seek(SeekFrom::Start(elapsed_bytes))?;
+        while byte_end.is_none_or(|end| elapsed_bytes < end) {
+            let Some(header) = EntryHeader::read(&mut file)? else {
+                break;
+            };
+            if header.key == key {
+                return Ok(Some(header.value_len.is_some()));
+            }
+            if let Some(value_len) = header.value_len {
+                file.seek_relative(value_len as i64)?;
+            }
+            elapsed_bytes += header.stride();
+        }
+        self.record_bloom_filter_false_positive();
         Ok(None)
     }
 
+    /// Open the segment file, positioned at or before the first entry whose key
+    /// is at least `key`.
+    pub fn open_from(&self, key: &str) -> Result<Reader, io::Error> {
+        let (byte_start, _) = self.sparse_index.get_byte_range(key);
+        let mut file = self.reader()?;
+        file.seek(SeekFrom::Start(byte_start.unwrap_or(0)))?;
+        Ok(file)
+    }
+
+    fn reader(&self) -> Result<Reader, io::Error> {
+        Reader::open_with(self.path(), self.cipher.as_ref(), self.io.clone())
+    }
+
+    /// Whether `key` falls within this segment's key range.
+    ///
+    /// This is cheaper than the bloom filter check, and lets readers skip the
+    /// segment entirely when it returns `false`. An empty segment contains no
+    /// keys.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.key_range.as_ref().is_some_and(|range| range.contains(key))
+    }
+
+    /// Whether this segment may hold keys that start with `prefix`. A `false`
+    /// is always right, but a `true` may not be.
+    ///
+    /// Beyond the key range, this checks the prefix bloom filter, if the
+    /// segment has one and `prefix` is at least as long as the prefixes in it.
+    pub fn may_have_prefix(&self, prefix: &str) -> bool {
+        let Some(range) = &self.key_range else {
+            return false;
+        };
+        if range.max.as_str() < prefix
+            || (range.min.as_str() > prefix && !range.min.starts_with(prefix))
+        {
+            return false;
+        }
+        match &self.prefix_bloom_filter {
+            Some((length, filter)) => match prefix.as_bytes().get(..*length) {
+                Some(prefix) => filter.contains(&prefix),
+                None => true,
+            },
+            None => true,
+        }
+    }
+
+    pub fn key_range(&self) -> Option<&KeyRange> {
+        self.key_range.as_ref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    /// Keep the segment file on disk for as long as the returned pin is held,
+    /// even if compaction retires it in the meantime.
+    pub fn pin(&self) -> Arc<SegmentFile> {
+        self.file.clone()
+    }
+
+    /// Mark the segment file to be deleted once neither this handle nor any
+    /// pin of it is left.
+    pub fn retire(&self) {
+        self.file.retired.store(true, Ordering::Release);
+    }
+
+    /// The id of this segment, parsed from its filename.
+    pub fn id(&self) -> Option<u32> {
+        segment_id(self.path())
+    }
+
+    /// The highest sequence number of any version in the file, or 0 if it
+    /// holds none.
+    pub fn max_version(&self) -> u64 {
+        self.max_version
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The size of the segment file, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The number of entries in the file, including tombstones.
+    pub fn entry_count(&self) -> u32 {
+        self.entry_count
+    }
+
+    pub fn tombstone_count(&self) -> u32 {
+        self.tombstone_count
+    }
+
+    pub fn overwrite_count(&self) -> u32 {
+        self.overwrite_count
+    }
+
+    pub fn stats(&self) -> SegmentStats {
+        SegmentStats {
+            path: self.path().to_owned(),
+            level: self.level,
+            size: self.size,
+            entry_count: self.entry_count,
+            tombstone_count: self.tombstone_count,
+            overwrite_count: self.overwrite_count,
+            bloom_filter: self.bloom_filter_counters.snapshot(),
+        }
+    }
+
     pub fn inspect(&self) {
+        match &self.key_range {
+            Some(range) => println!("Key Range: {}..={}", range.min, range.max),
+            None => println!("Key Range: (empty)"),
+        };
         println!("Sparse Index");
-        self.sparse_index.inner().iter().for_each(|(key, offset)| println!("{key} @ {offset}"));
+        self.sparse_index.iter().for_each(|(key, offset)| println!("{key} @ {offset}"));
     }
 }
 
-/// Iterator over the entries in a segment file.
-pub struct EntryIter<'a> {
-    file: &'a mut File,
+/// The number of `keys`, which must not repeat, that any of `older` may hold.
+/// Like every bloom filter check, this may count a few keys that aren't really
+/// in them.
+pub fn count_overwrites<'a, 'b>(
+    keys: impl IntoIterator<Item = &'a str>,
+    older: impl Iterator<Item = &'b SegmentHandle> + Clone,
+) -> u32 {
+    keys.into_iter().filter(|key| overwrites(key, older.clone())).count() as u32
+}
+
+/// Whether any of `older` may hold `key`. The key range of each is checked
+/// first, since that's cheaper than its bloom filter.
+fn overwrites<'a>(key: &str, mut older: impl Iterator<Item = &'a SegmentHandle>) -> bool {
+    older.any(|segment| segment.may_contain(key) && segment.bloom_filter.contains(&key))
 }
 
-impl<'a> EntryIter<'a> {
-    pub fn new(file: &'a mut File) -> Self {
-        Self { file }
+/// The smallest and largest keys in a segment file, inclusive.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyRange {
+    pub min: String,
+    pub max: String,
+}
+
+impl KeyRange {
+    pub fn new(min: impl Into<String>, max: impl Into<String>) -> Self {
+        Self { min: min.into(), max: max.into() }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.min.as_str() <= key && key <= self.max.as_str()
+    }
+}
+
+/// Iterator over the entries in a segment file, or any other reader of encoded
+/// entries.
+///
+/// An entry that can't be decoded is yielded as an [`Error::Corruption`], after
+/// which the iterator ends, since the entries that follow it can't be framed.
+///
+/// Entries are decoded into buffers that are reused from one to the next.
+/// [`Self::next_ref`] lends them out as an [`EntryRef`], so that reading a
+/// file doesn't allocate for every entry, while iterating copies each one out
+/// into an [`Entry`] of its own.
+pub struct EntryIter<'a, R = File> {
+    file: &'a mut R,
+
+    /// The file that is being read, for errors.
+    path: PathBuf,
+
+    /// Where the next entry starts in the reader, once it is known.
+    position: Option<u64>,
+
+    /// Whether an error has been yielded.
+    failed: bool,
+
+    /// Whether the error that was yielded is from the reader ending partway
+    /// through an entry.
+    truncated: bool,
+
+    /// The size on disk of the last entry that was yielded.
+    last_stride: u64,
+
+    /// The sequence number that the last entry yielded was written with, if
+    /// it is a version.
+    last_version: Option<u64>,
+
+    /// The key and value of the last entry decoded. The value is left over
+    /// from an earlier entry if this one is a tombstone.
+    key: String,
+    value: String,
+    tombstone: bool,
+
+    /// Holds a value as it was stored, before it is decompressed.
+    compressed: Vec<u8>,
+}
+
+impl<'a, R: Read + Seek> EntryIter<'a, R> {
+    pub fn new(file: &'a mut R) -> Self {
+        Self {
+            file,
+            path: PathBuf::new(),
+            position: None,
+            failed: false,
+            truncated: false,
+            last_stride: 0,
+            last_version: None,
+            key: String::new(),
+            value: String::new(),
+            tombstone: false,
+            compressed: Vec::new(),
+        }
     }
 
     /// Seek to the start of the file before iteration.
-    pub fn from_start(file: &'a mut File) -> Result<Self, io::Error> {
+    pub fn from_start(file: &'a mut R) -> Result<Self, io::Error> {
         file.seek(SeekFrom::Start(0))?;
         Ok(Self::new(file))
     }
 
-    fn step(&mut self) -> anyhow::Result<Option<Entry>> {
+    /// Name the file that is being read in any errors.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Whether iteration ended because the reader ends partway through an
+    /// entry, as it does when a crash interrupts a write.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The size on disk of the last entry that was yielded, in bytes. This is
+    /// less than its [`Entry::stride`] if its value was compressed.
+    pub fn last_stride(&self) -> u64 {
+        self.last_stride
+    }
+
+    /// The sequence number that the last entry yielded was written with, or
+    /// `None` if it wasn't written as a version.
+    pub fn last_version(&self) -> Option<u64> {
+        self.last_version
+    }
+
+    /// Like [`Iterator::next`], but borrows the entry from the iterator's
+    /// buffers instead of copying it out.
+    pub fn next_ref(&mut self) -> Option<Result<EntryRef<'_>, Error>> {
+        match self.advance()? {
+            Ok(()) => Some(Ok(self.current())),
+            Err(error) => Some(Err(error)),
+        }
+    }
+
+    /// Decode the next entry, which is then borrowed with [`Self::current`].
+    /// Returns `None` at the end of the reader.
+    ///
+    /// Unlike [`Self::next_ref`], this leaves the iterator free to be asked
+    /// about the entry, such as for its [`Self::last_version`].
+    pub fn advance(&mut self) -> Option<Result<(), Error>> {
+        if self.failed {
+            return None;
+        }
+        match self.step() {
+            Ok(true) => Some(Ok(())),
+            Ok(false) => None,
+            Err(error) => {
+                self.failed = true;
+                Some(Err(error))
+            },
+        }
+    }
+
+    /// The entry decoded by the last call to [`Self::advance`].
+    ///
+    /// Before the first entry is decoded, or after the reader fails, this is
+    /// an empty assignment.
+    pub fn current(&self) -> EntryRef<'_> {
+        match self.tombstone {
+            true => EntryRef::Tombstone { key: &self.key },
+            false => EntryRef::Assignment { key: &self.key, value: &self.value },
+        }
+    }
+
+    /// Decode the next entry into the buffers. Returns `false` at the end of
+    /// the reader.
+    fn step(&mut self) -> Result<bool, Error> {
+        let position = match self.position {
+            Some(position) => position,
+            None => *self.position.insert(self.file.stream_position()?),
+        };
         let mut indicator_bytes = [0; 1];
         match self.file.read_exact(&mut indicator_bytes) {
-            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
             error => error?,
         };
+        self.last_version = None;
+        let mut prefix = 0;
+        if indicator_bytes[0] == VERSION_INDICATOR {
+            let mut sequence_bytes = [0; 8];
+            self.read_exact(position, &mut sequence_bytes, "sequence number")?;
+            self.read_exact(position, &mut indicator_bytes, "entry")?;
+            self.last_version = Some(u64::from_be_bytes(sequence_bytes));
+            prefix = 1 + 8;
+        }
 
-        match EntryIndicator::from_u8_opt(indicator_bytes[0]) {
-            Some(EntryIndicator::Assignment) => {
-                let mut size_bytes = [0; 4];
-                self.file.read_exact(&mut size_bytes)?;
-                let size = u32::from_be_bytes(size_bytes);
-                let mut key_buffer = vec![0; size as usize];
-                self.file.read_exact(&mut key_buffer)?;
-
-                let mut size_bytes = [0; 4];
-                self.file.read_exact(&mut size_bytes)?;
-                let size = u32::from_be_bytes(size_bytes);
-                let mut value_buffer = vec![0; size as usize];
-                self.file.read_exact(&mut value_buffer)?;
-
-                let key = std::str::from_utf8(&key_buffer)?;
-                let value = std::str::from_utf8(&value_buffer)?;
-                Ok(Some(Entry::Assignment { key: key.to_owned(), value: value.to_owned() }))
+        let Some(indicator) = EntryIndicator::from_u8_opt(indicator_bytes[0]) else {
+            let reason = format!("unknown entry indicator {}", indicator_bytes[0]);
+            return Err(self.corruption(position, reason));
+        };
+        let mut key = mem::take(&mut self.key);
+        let result = self.read_string(position, "key", &mut key);
+        self.key = key;
+        result?;
+        let header = 1 + 4 + self.key.len() as u64;
+        let stride = match indicator {
+            EntryIndicator::Assignment => {
+                let mut value = mem::take(&mut self.value);
+                let result = self.read_string(position, "value", &mut value);
+                self.value = value;
+                result?;
+                self.tombstone = false;
+                header + 4 + self.value.len() as u64
             },
-            Some(EntryIndicator::Tombstone) => {
-                let mut size_bytes = [0; 4];
-                self.file.read_exact(&mut size_bytes)?;
-                let size = u32::from_be_bytes(size_bytes);
-                let mut key_buffer = vec![0; size as usize];
-                self.file.read_exact(&mut key_buffer)?;
-                let key = std::str::from_utf8(&key_buffer)?;
-                Ok(Some(Entry::Tombstone { key: key.to_owned() }))
+            EntryIndicator::Tombstone => {
+                self.tombstone = true;
+                header
             },
-            None => {
-                let position = self.file.seek(SeekFrom::Current(0))?;
-                Err(anyhow!("failed to parse indicator {} @ {position}", indicator_bytes[0]))
+            EntryIndicator::CompressedAssignment => {
+                let mut codec = [0; 1];
+                self.read_exact(position, &mut codec, "value")?;
+                let codec = Codec::from_u8_opt(codec[0]).ok_or_else(|| {
+                    self.corruption(position, format!("unknown compression codec {}", codec[0]))
+                })?;
+                let mut compressed = mem::take(&mut self.compressed);
+                let result = self.read_bytes(position, "value", &mut compressed);
+                self.compressed = compressed;
+                result?;
+                let mut value = mem::take(&mut self.value).into_bytes();
+                let decompressed = codec.decompress_into(&self.compressed, &mut value);
+                self.value = String::from_utf8(value).map_err(|_| {
+                    self.corruption(position, "the value isn't valid UTF-8".to_owned())
+                })?;
+                decompressed.ok_or_else(|| {
+                    self.corruption(position, "the value can't be decompressed".to_owned())
+                })?;
+                self.tombstone = false;
+                header + 1 + 4 + self.compressed.len() as u64
             },
+        };
+        self.position = Some(position + prefix + stride);
+        self.last_stride = prefix + stride;
+        Ok(true)
+    }
+
+    /// Read a length prefixed string, which is the `part` of the entry
+    /// starting at `position`, into `buffer`.
+    fn read_string(&mut self, position: u64, part: &str, buffer: &mut String) -> Result<(), Error> {
+        let mut bytes = mem::take(buffer).into_bytes();
+        self.read_bytes(position, part, &mut bytes)?;
+        *buffer = String::from_utf8(bytes)
+            .map_err(|_| self.corruption(position, format!("the {part} isn't valid UTF-8")))?;
+        Ok(())
+    }
+
+    /// Like [`Self::read_string`], for bytes that aren't text.
+    fn read_bytes(&mut self, position: u64, part: &str, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        let mut size_bytes = [0; 4];
+        self.read_exact(position, &mut size_bytes, part)?;
+        buffer.clear();
+        buffer.resize(u32::from_be_bytes(size_bytes) as usize, 0);
+        self.read_exact(position, buffer, part)
+    }
+
+    fn read_exact(&mut self, position: u64, buffer: &mut [u8], part: &str) -> Result<(), Error> {
+        match self.file.read_exact(buffer) {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                self.truncated = true;
+                Err(self.corruption(position, format!("the file ends within the {part}")))
+            },
+            Err(error) => Err(error.into()),
         }
     }
+
+    fn corruption(&self, offset: u64, reason: String) -> Error {
+        Error::Corruption { file: self.path.clone(), offset, reason }
+    }
 }
 
-impl Iterator for EntryIter<'_> {
-    type Item = Entry;
+impl<R: Read + Seek> Iterator for EntryIter<'_, R> {
+    type Item = Result<Entry, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.step()
-            .inspect_err(|error| {
-                log::warn!("failed to step entry iter: {error}");
-            })
-            .ok()
-            .flatten()
+        self.next_ref().map(|entry| entry.map(|entry| entry.to_entry()))
+    }
+}
+
+/// The leading part of an entry, up to (but not including) its value.
+struct EntryHeader {
+    key: String,
+
+    /// The length of the value that follows, or `None` for a tombstone.
+    value_len: Option<u32>,
+
+    /// Whether the value is compressed, in which case a codec byte comes
+    /// before its length.
+    compressed: bool,
+
+    /// Whether the entry is a version, and so starts with its sequence number.
+    versioned: bool,
+}
+
+impl EntryHeader {
+    /// Read the header of the next entry from `reader`, leaving it positioned
+    /// at the start of the entry's value. Returns `None` at the end of the
+    /// file.
+    fn read(reader: &mut impl Read) -> Result<Option<Self>, io::Error> {
+        let mut indicator_bytes = [0; 1];
+        match reader.read_exact(&mut indicator_bytes) {
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            error => error?,
+        };
+        let versioned = indicator_bytes[0] == VERSION_INDICATOR;
+        if versioned {
+            reader.read_exact(&mut [0; 8])?;
+            reader.read_exact(&mut indicator_bytes)?;
+        }
+        let indicator = EntryIndicator::from_u8_opt(indicator_bytes[0]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to parse indicator {}", indicator_bytes[0]),
+            )
+        })?;
+        let mut size_bytes = [0; 4];
+        reader.read_exact(&mut size_bytes)?;
+        let mut key_buffer = vec![0; u32::from_be_bytes(size_bytes) as usize];
+        reader.read_exact(&mut key_buffer)?;
+        let key = String::from_utf8(key_buffer)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let compressed = matches!(indicator, EntryIndicator::CompressedAssignment);
+        if compressed {
+            reader.read_exact(&mut [0; 1])?;
+        }
+        let value_len = match indicator {
+            EntryIndicator::Assignment | EntryIndicator::CompressedAssignment => {
+                reader.read_exact(&mut size_bytes)?;
+                Some(u32::from_be_bytes(size_bytes))
+            },
+            EntryIndicator::Tombstone => None,
+        };
+        Ok(Some(Self { key, value_len, compressed, versioned }))
+    }
+
+    /// The size of the whole entry on disk, in bytes.
+    fn stride(&self) -> u64 {
+        let value_stride = self.value_len.map_or(0, |len| len as u64 + 4);
+        let version_stride = if self.versioned { 1 + 8 } else { 0 };
+        version_stride + 1 + 4 + self.key.len() as u64 + u64::from(self.compressed) + value_stride
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Entry {
     Assignment { key: String, value: String },
     Tombstone { key: String },
@@ -167,33 +861,111 @@ pub enum Entry {
 impl Entry {
     pub fn key(&self) -> &String {
         match self {
-            Self::Assignment { key, .. } => &key,
-            Self::Tombstone { key } => &key,
+            Self::Assignment { key, .. } => key,
+            Self::Tombstone { key } => key,
         }
     }
 
-    pub fn write(&self, file: &mut File) -> Result<(), Error> {
+    pub fn write(&self, file: &mut impl Write) -> Result<(), Error> {
+        self.write_with(file, None).map(drop)
+    }
+
+    /// Like [`Self::write`], but compresses the value according to
+    /// `compression`. Returns the number of bytes written.
+    pub fn write_with(
+        &self,
+        file: &mut impl Write,
+        compression: Option<Compression>,
+    ) -> Result<usize, Error> {
         match self {
-            Self::Assignment { key, value } => write(file, key, value),
-            Self::Tombstone { key } => tombstone(file, key),
+            Self::Assignment { key, value } => write_with(file, key, value, compression),
+            Self::Tombstone { key } => tombstone(file, key).map(|_| self.stride()),
         }
     }
 
+    /// The size of this entry when it is written to disk uncompressed, in
+    /// bytes.
     // TODO: Should this be usize?
-    fn stride(&self) -> usize {
+    pub fn stride(&self) -> usize {
+        match self {
+            Self::Assignment { key, value } => key.len() + value.len() + 8 + 1,
+            Self::Tombstone { key } => key.len() + 4 + 1,
+        }
+    }
+}
+
+/// An [`Entry`] borrowed from the buffers of an [`EntryIter`], from
+/// [`EntryIter::next_ref`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntryRef<'a> {
+    Assignment { key: &'a str, value: &'a str },
+    Tombstone { key: &'a str },
+}
+
+impl EntryRef<'_> {
+    pub fn key(&self) -> &str {
         match self {
+            Self::Assignment { key, .. } => key,
+            Self::Tombstone { key } => key,
+        }
+    }
+
+    pub fn to_entry(&self) -> Entry {
+        match *self {
             Self::Assignment { key, value } => {
-                key.as_bytes().len() + value.as_bytes().len() + 8 + 1
+                Entry::Assignment { key: key.to_owned(), value: value.to_owned() }
             },
-            Self::Tombstone { key } => key.as_bytes().len() + 4 + 1,
+            Self::Tombstone { key } => Entry::Tombstone { key: key.to_owned() },
+        }
+    }
+
+    /// Copy this into `entry`, reusing the allocations it already has.
+    pub fn copy_to(&self, entry: &mut Entry) {
+        match (*self, entry) {
+            (
+                Self::Assignment { key, value },
+                Entry::Assignment { key: entry_key, value: entry_value },
+            ) => {
+                entry_key.clear();
+                entry_key.push_str(key);
+                entry_value.clear();
+                entry_value.push_str(value);
+            },
+            (Self::Tombstone { key }, Entry::Tombstone { key: entry_key }) => {
+                entry_key.clear();
+                entry_key.push_str(key);
+            },
+            (_, entry) => *entry = self.to_entry(),
+        }
+    }
+
+    /// Like [`Entry::write_with`].
+    pub fn write_with(
+        &self,
+        file: &mut impl Write,
+        compression: Option<Compression>,
+    ) -> Result<usize, Error> {
+        match *self {
+            Self::Assignment { key, value } => write_with(file, key, value, compression),
+            Self::Tombstone { key } => tombstone(file, key).map(|_| key.len() + 4 + 1),
         }
     }
 }
 
+/// Comes before an entry that is one version of its key, followed by the
+/// sequence number it was written with. A segment file lists the versions of
+/// a key newest first.
+const VERSION_INDICATOR: u8 = 5;
+
+// The WAL marks write batches with an indicator of 3, so entries can't use it.
 #[repr(u8)]
 enum EntryIndicator {
     Assignment = 1,
     Tombstone,
+
+    /// An assignment whose value is compressed. A codec byte comes between the
+    /// key and the value.
+    CompressedAssignment = 4,
 }
 
 impl EntryIndicator {
@@ -201,40 +973,163 @@ impl EntryIndicator {
         match num {
             1 => Some(Self::Assignment),
             2 => Some(Self::Tombstone),
+            4 => Some(Self::CompressedAssignment),
+            _ => None,
+        }
+    }
+}
+
+/// How a compressed value is encoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Codec {
+    /// LZ4 block compression, with the decompressed size prepended.
+    Lz4 = 1,
+}
+
+impl Codec {
+    fn from_u8_opt(num: u8) -> Option<Self> {
+        match num {
+            1 => Some(Self::Lz4),
             _ => None,
         }
     }
+
+    fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Lz4 => lz4_flex::compress_prepend_size(bytes),
+        }
+    }
+
+    /// Decompress `bytes` into `output`, replacing what it held.
+    fn decompress_into(&self, bytes: &[u8], output: &mut Vec<u8>) -> Option<()> {
+        match self {
+            Self::Lz4 => {
+                let (size, bytes) = lz4_flex::block::uncompressed_size(bytes).ok()?;
+                output.clear();
+                output.resize(size, 0);
+                let decompressed = lz4_flex::decompress_into(bytes, output).ok()?;
+                (decompressed == size).then_some(())
+            },
+        }
+    }
+}
+
+/// When values are compressed as they are written.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Compression {
+    pub codec: Codec,
+
+    /// Values shorter than this many bytes are left as they are. So is any
+    /// value that compressing wouldn't make smaller.
+    pub threshold: usize,
 }
 
-pub fn write(file: &mut File, key: &str, value: &str) -> Result<(), Error> {
+/// Call `visit` with each entry of the segment file at `path`, along with its
+/// version if it has one, for as long as the file can be read and its keys
+/// are in order. Returns the number of entries visited, and what stopped the
+/// rest from being read, if anything did.
+pub fn read_sorted(
+    path: &Path,
+    cipher: Option<&Cipher>,
+    mut visit: impl FnMut(Option<u64>, EntryRef<'_>) -> Result<(), Error>,
+) -> Result<(u64, Option<Error>), Error> {
+    let mut file = match Reader::open(path, cipher) {
+        Ok(file) => file,
+        Err(error) => return Ok((0, Some(error.into()))),
+    };
+    let mut entries = EntryIter::from_start(&mut file)?.with_path(path);
+    let mut previous = String::new();
+    let mut offset = 0;
+    let mut readable = 0;
+    while let Some(result) = entries.advance() {
+        if let Err(error) = result {
+            return Ok((readable, Some(error)));
+        }
+        let entry = entries.current();
+        let version = entries.last_version();
+        // Only versions of a key may share it, and they are written newest first.
+        let in_order = readable == 0
+            || match entry.key().cmp(&previous) {
+                std::cmp::Ordering::Greater => true,
+                std::cmp::Ordering::Equal => version.is_some(),
+                std::cmp::Ordering::Less => false,
+            };
+        if !in_order {
+            let reason = format!("key {:?} is out of order", entry.key());
+            return Ok((
+                readable,
+                Some(Error::Corruption { file: path.to_owned(), offset, reason }),
+            ));
+        }
+        visit(version, entry)?;
+        offset += entries.last_stride();
+        readable += 1;
+        previous.clear();
+        previous.push_str(entry.key());
+    }
+    Ok((readable, None))
+}
+
+/// Mark the entry written next to `file` as the version of its key written at
+/// `sequence`. Returns the number of bytes written.
+pub fn write_version(file: &mut impl Write, sequence: u64) -> Result<usize, Error> {
+    let mut bytes = [VERSION_INDICATOR; 9];
+    bytes[1..].copy_from_slice(&sequence.to_be_bytes());
+    file.write_all(&bytes)?;
+    Ok(bytes.len())
+}
+
+pub fn write(file: &mut impl Write, key: &str, value: &str) -> Result<(), Error> {
+    write_with(file, key, value, None).map(drop)
+}
+
+/// Like [`write`], but compresses the value according to `compression`.
+/// Returns the number of bytes written.
+pub fn write_with(
+    file: &mut impl Write,
+    key: &str,
+    value: &str,
+    compression: Option<Compression>,
+) -> Result<usize, Error> {
     let key_bytes = key.as_bytes();
     let value_bytes = value.as_bytes();
+    let compressed = compression
+        .filter(|compression| value_bytes.len() >= compression.threshold)
+        .map(|compression| (compression.codec, compression.codec.compress(value_bytes)))
+        .filter(|(_, compressed)| compressed.len() < value_bytes.len());
 
-    // Add 8 bytes here for the two u32 length prefixes.
-    // TODO: Is it wise to pre-allocate this if our key or value might be too long?
-    // We should do that check earlier...
-    let mut bytes = Vec::with_capacity(key_bytes.len() + value_bytes.len() + 8 + 1);
-    bytes.extend([EntryIndicator::Assignment as u8]);
+    // Add 8 bytes here for the two u32 length prefixes. Writes to the engine are
+    // held to its size limits before they get here, so this can't be made huge.
+    let mut bytes = Vec::with_capacity(key_bytes.len() + value_bytes.len() + 8 + 2);
+    let (indicator, value_bytes) = match &compressed {
+        Some((_, compressed)) => (EntryIndicator::CompressedAssignment, compressed.as_slice()),
+        None => (EntryIndicator::Assignment, value_bytes),
+    };
+    bytes.extend([indicator as u8]);
 
     for (component_bytes, component) in
         [(key_bytes, PairComponent::Key), (value_bytes, PairComponent::Value)]
     {
+        if let (PairComponent::Value, Some((codec, _))) = (&component, &compressed) {
+            bytes.push(*codec as u8);
+        }
         let size = component_bytes.len();
-        let size = u32::try_from(size)
-            .map_err(|_| Error::TooLarge(component, size, u32::max_value() as usize))?;
+        let size =
+            u32::try_from(size).map_err(|_| Error::TooLarge(component, size, u32::MAX as usize))?;
         bytes.extend(size.to_be_bytes());
         bytes.extend(component_bytes);
     }
 
     file.write_all(&bytes)?;
-    Ok(())
+    Ok(bytes.len())
 }
 
-pub fn tombstone(file: &mut File, key: &str) -> Result<(), Error> {
+pub fn tombstone(file: &mut impl Write, key: &str) -> Result<(), Error> {
     let key_bytes = key.as_bytes();
     let size = key_bytes.len();
     let size = u32::try_from(size)
-        .map_err(|_| Error::TooLarge(PairComponent::Key, size, u32::max_value() as usize))?;
+        .map_err(|_| Error::TooLarge(PairComponent::Key, size, u32::MAX as usize))?;
 
     let mut bytes = Vec::with_capacity(size as usize + 4 + 1);
     bytes.extend([EntryIndicator::Tombstone as u8]);
@@ -262,3 +1157,217 @@ pub fn segment_filename(id: u32) -> String {
 pub fn is_segment_filename(filename: &str) -> bool {
     filename.starts_with("segment")
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test::StoreFixture;
+
+    #[test]
+    fn key_range() {
+        let mut fixture = StoreFixture::init("./test-db-segment-key-range");
+        let path = fixture.write_segment_file([("d", "4"), ("b", "2"), ("f", "6")]);
+        let segment = SegmentHandle::open(path).unwrap();
+        assert_eq!(segment.key_range(), Some(&KeyRange::new("b", "f")));
+        assert!(segment.may_contain("b"));
+        assert!(segment.may_contain("c"));
+        assert!(segment.may_contain("f"));
+        assert!(!segment.may_contain("a"));
+        assert!(!segment.may_contain("g"));
+    }
+
+    #[test]
+    fn overwrite_counts() {
+        let mut fixture = StoreFixture::init("./test-db-segment-overwrites");
+        let older = [
+            SegmentHandle::open(fixture.write_segment_file([("b", "1"), ("d", "1")])).unwrap(),
+            SegmentHandle::open(fixture.write_segment_file([("x", "1")])).unwrap(),
+        ];
+        let keys = ["a", "b", "d", "x", "z"];
+        assert_eq!(count_overwrites(keys, older.iter()), 3);
+
+        // Reading the keys from the file finds the same ones.
+        let path = fixture.write_segment_file(keys.map(|key| (key, "2")));
+        let mut segment = SegmentHandle::open(path).unwrap();
+        segment.count_overwrites_in_file(older.iter()).unwrap();
+        assert_eq!(segment.overwrite_count(), 3);
+        assert_eq!(count_overwrites(keys, older[..0].iter()), 0);
+    }
+
+    #[test]
+    fn contains() {
+        let mut fixture = StoreFixture::init("./test-db-segment-contains");
+        let path = fixture.allocate_segment_file();
+        let mut file = File::create_new(&path).unwrap();
+        for n in 0..10 {
+            write(&mut file, &format!("key{n}"), "value").unwrap();
+        }
+        tombstone(&mut file, "key9x").unwrap();
+        let segment = SegmentHandle::open(path).unwrap();
+
+        assert_eq!(segment.contains("key0").unwrap(), Some(true));
+        assert_eq!(segment.contains("key7").unwrap(), Some(true));
+        assert_eq!(segment.contains("key9x").unwrap(), Some(false));
+        assert_eq!(segment.contains("key5x").unwrap(), None);
+    }
+
+    #[test]
+    fn bloom_filter_stats() {
+        let mut fixture = StoreFixture::init("./test-db-segment-bloom-filter-stats");
+        let path = fixture.write_segment_file([("a", "1"), ("b", "2"), ("c", "3")]);
+        let segment = SegmentHandle::open(path).unwrap();
+        assert_eq!(segment.get("a").unwrap(), Some(Some("1".into())));
+        assert_eq!(segment.contains("b").unwrap(), Some(true));
+        assert_eq!(segment.stats().bloom_filter, BloomFilterStats {
+            checks: 2,
+            negatives: 0,
+            false_positives: 0
+        });
+
+        // Look up absent keys until one gets past the bloom filter, which at
+        // its false positive rate is all but certain to happen well within this
+        // many.
+        let mut lookups = 0;
+        for n in 0..1_000_000 {
+            lookups += 1;
+            assert_eq!(segment.get(&format!("missing{n}")).unwrap(), None);
+            if segment.stats().bloom_filter.false_positives > 0 {
+                break;
+            }
+        }
+        let stats = segment.stats().bloom_filter;
+        assert_eq!(stats.false_positives, 1);
+        assert_eq!(stats.checks, 2 + lookups);
+        assert_eq!(stats.negatives, lookups - 1);
+    }
+
+    #[test]
+    fn compressed_values() {
+        let mut fixture = StoreFixture::init("./test-db-segment-compressed-values");
+        let path = fixture.allocate_segment_file();
+        let mut file = File::create_new(&path).unwrap();
+        let compression = Some(Compression { codec: Codec::Lz4, threshold: 64 });
+        let large = "{\"field\": \"value\"}".repeat(20);
+        let mut uncompressed = 0;
+        for n in 0..100 {
+            let value = if n % 2 == 0 { large.clone() } else { format!("small{n}") };
+            let entry = Entry::Assignment { key: format!("key{n:02}"), value };
+            uncompressed += entry.stride();
+            entry.write_with(&mut file, compression).unwrap();
+        }
+        tombstone(&mut file, "key99x").unwrap();
+        drop(file);
+        assert!(fs::metadata(&path).unwrap().len() < uncompressed as u64 / 2);
+
+        let segment = SegmentHandle::open(path).unwrap();
+        assert_eq!(segment.entry_count(), 101);
+        for n in [0, 1, 50, 98, 99] {
+            let expected = if n % 2 == 0 { large.clone() } else { format!("small{n}") };
+            assert_eq!(segment.get(&format!("key{n:02}")).unwrap(), Some(Some(expected.into())));
+            assert_eq!(segment.contains(&format!("key{n:02}")).unwrap(), Some(true));
+        }
+        assert_eq!(segment.contains("key99x").unwrap(), Some(false));
+        assert_eq!(segment.get("key50x").unwrap(), None);
+    }
+
+    #[test]
+    fn borrowed_entries() {
+        let mut bytes = Vec::new();
+        let compression = Some(Compression { codec: Codec::Lz4, threshold: 16 });
+        let large = "abc".repeat(50);
+        write_with(&mut bytes, "a", &large, compression).unwrap();
+        write_version(&mut bytes, 7).unwrap();
+        tombstone(&mut bytes, "b").unwrap();
+        write(&mut bytes, "c", "3").unwrap();
+        let owned: Vec<_> =
+            EntryIter::new(&mut Cursor::new(&bytes)).collect::<Result<_, _>>().unwrap();
+
+        let mut reader = Cursor::new(&bytes);
+        let mut entries = EntryIter::new(&mut reader);
+        let mut reused = Entry::Tombstone { key: String::new() };
+        let mut versions = Vec::new();
+        for expected in &owned {
+            entries.advance().unwrap().unwrap();
+            versions.push(entries.last_version());
+            assert_eq!(entries.current().to_entry(), *expected);
+            entries.current().copy_to(&mut reused);
+            assert_eq!(reused, *expected);
+        }
+        assert!(entries.next_ref().is_none());
+        assert_eq!(versions, [None, Some(7), None]);
+        assert_eq!(owned[0], Entry::Assignment { key: "a".to_owned(), value: large });
+    }
+
+    #[test]
+    fn incompressible_value_is_stored_as_is() {
+        let mut bytes = Vec::new();
+        let compression = Some(Compression { codec: Codec::Lz4, threshold: 1 });
+        let written = write_with(&mut bytes, "a", "xyz", compression).unwrap();
+        assert_eq!(written, 13);
+        assert_eq!(bytes[0], EntryIndicator::Assignment as u8);
+    }
+
+    #[test]
+    fn may_have_prefix() {
+        let mut fixture = StoreFixture::init("./test-db-segment-may-have-prefix");
+        let path = fixture.write_segment_file([("user:1", "a"), ("user:2", "b"), ("zone:1", "c")]);
+        let segment =
+            SegmentHandle::open_at_level(path.clone(), 0, None, 5, Arc::new(StdIo)).unwrap();
+        for prefix in ["", "u", "user", "user:", "user:1", "zone:"] {
+            assert!(segment.may_have_prefix(prefix), "{prefix:?}");
+        }
+        // Within the key range, but ruled out by the prefix filter.
+        assert!(!segment.may_have_prefix("year:"));
+        // Outside the key range.
+        assert!(!segment.may_have_prefix("a"));
+        assert!(!segment.may_have_prefix("zz"));
+
+        // Without a prefix filter, only the key range rules prefixes out.
+        let segment = SegmentHandle::open(path).unwrap();
+        assert!(segment.may_have_prefix("year:"));
+        assert!(!segment.may_have_prefix("zz"));
+    }
+
+    #[test]
+    fn key_range_empty_segment() {
+        let mut fixture = StoreFixture::init("./test-db-segment-key-range-empty");
+        let path = fixture.write_segment_file([]);
+        let segment = SegmentHandle::open(path).unwrap();
+        assert_eq!(segment.key_range(), None);
+        assert!(!segment.may_contain("a"));
+    }
+
+    #[test]
+    fn corruption() {
+        let mut fixture = StoreFixture::init("./test-db-segment-corruption");
+        let path = fixture.write_segment_file([("a", "1"), ("b", "2")]);
+        let mut file = File::options().append(true).read(true).open(&path).unwrap();
+        file.write_all(&[9]).unwrap();
+
+        // Each entry is 11 bytes, so the bad indicator follows them.
+        let mut entries = EntryIter::from_start(&mut file).unwrap().with_path(&path);
+        assert!(entries.next().unwrap().is_ok());
+        assert!(entries.next().unwrap().is_ok());
+        assert!(matches!(
+            entries.next(),
+            Some(Err(Error::Corruption { offset: 22, ref reason, .. }))
+                if reason == "unknown entry indicator 9"
+        ));
+        assert!(entries.next().is_none());
+        assert!(!entries.truncated());
+        let error = SegmentHandle::open(path.clone()).err().unwrap();
+        assert!(matches!(error, Error::Corruption { file, .. } if file == path));
+
+        file.set_len(21).unwrap();
+        let mut entries = EntryIter::from_start(&mut file).unwrap().with_path(&path);
+        assert!(entries.next().unwrap().is_ok());
+        assert!(matches!(
+            entries.next(),
+            Some(Err(Error::Corruption { offset: 11, ref reason, .. }))
+                if reason == "the file ends within the value"
+        ));
+        assert!(entries.truncated());
+    }
+}
@@ -0,0 +1,108 @@
+//! Partitioning of an engine into shards, set with
+//! [`EngineArgs::shards`](crate::engine::EngineArgs::shards).
+//!
+//! A sharded engine spreads keys over a number of inner engines by a hash of
+//! each key, with each shard in the `shards` directory of the engine's
+//! directory, under its index. Every shard has its own memtable, WAL, segment
+//! files and compaction loop, so writes to different shards don't wait on
+//! each other and compactions run side by side. Reads of a key go to its
+//! shard, and scans merge every shard in key order.
+//!
+//! The number of shards is recorded when the engine is created, since keys
+//! would be looked for in the wrong shard if it changed.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+
+use crate::error::Error;
+use crate::util::sync_directory;
+
+/// Holds the number of shards in a sharded engine's directory.
+pub const SHARDS_FILENAME: &str = "SHARDS";
+
+/// The shard out of `count` that `key` belongs to.
+///
+/// The hash is FNV-1a, which is stable across builds and platforms, unlike
+/// the hashers in the standard library, so keys stay in their shards.
+pub fn shard_of(key: &str, count: usize) -> usize {
+    let hash = key
+        .bytes()
+        .fold(0xCBF29CE484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001B3));
+    (hash % count as u64) as usize
+}
+
+/// The directory of the shard at `index` of the engine at `directory`.
+pub fn shard_directory(directory: &Path, index: usize) -> PathBuf {
+    directory.join("shards").join(index.to_string())
+}
+
+/// Check that the engine at `directory` was created with `count` shards,
+/// recording that it was if it is new. An engine without shards counts as
+/// having 1.
+pub fn check_shard_count(directory: &Path, count: usize) -> Result<(), Error> {
+    let path = directory.join(SHARDS_FILENAME);
+    let recorded = match fs::read_to_string(&path) {
+        Ok(contents) => contents
+            .trim()
+            .parse::<usize>()
+            .map_err(|error| anyhow!("{path:?} doesn't hold a number of shards: {error}"))?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let has_data =
+                fs::read_dir(directory).is_ok_and(|mut entries| entries.next().is_some());
+            if count == 1 || has_data {
+                1
+            } else {
+                fs::create_dir_all(directory)?;
+                let mut file = fs::File::create_new(&path)?;
+                file.write_all(count.to_string().as_bytes())?;
+                file.sync_all()?;
+                sync_directory(directory)?;
+                count
+            }
+        },
+        Err(error) => return Err(error.into()),
+    };
+    if recorded != count {
+        return Err(anyhow!(
+            "the engine at {directory:?} has {recorded} shards, so it can't be opened with \
+             {count}"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::StoreFixture;
+
+    #[test]
+    fn shard_of() {
+        assert_eq!(super::shard_of("", 4), 0xCBF29CE484222325 % 4);
+        let counts = (0..1000).fold([0; 4], |mut counts, n| {
+            counts[super::shard_of(&format!("key{n}"), 4)] += 1;
+            counts
+        });
+        assert!(counts.iter().all(|&count| count > 200), "{counts:?}");
+    }
+
+    #[test]
+    fn shard_count_is_recorded() {
+        let fixture = StoreFixture::init("./test-db-shard-count");
+        fs::remove_dir_all(fixture.path()).unwrap();
+        check_shard_count(fixture.path(), 4).unwrap();
+        check_shard_count(fixture.path(), 4).unwrap();
+        assert!(check_shard_count(fixture.path(), 2).is_err());
+        assert!(check_shard_count(fixture.path(), 1).is_err());
+
+        let unsharded = fixture.path().join("unsharded");
+        fs::create_dir_all(&unsharded).unwrap();
+        fs::write(unsharded.join("MANIFEST"), "").unwrap();
+        check_shard_count(&unsharded, 1).unwrap();
+        assert!(check_shard_count(&unsharded, 4).is_err());
+    }
+}
@@ -1,40 +1,167 @@
-use std::collections::BTreeMap;
-use std::ops::Bound;
+/// The number of entries in each block of the index.
+const RESTART_INTERVAL: usize = 16;
 
 /// The sparse index keeps track of a subset of keys and their offsets within
 /// segment files, to enable faster lookups.
+///
+/// Rather than holding each key in its own allocation, entries are serialized
+/// back to back into a single buffer, and that buffer is split into blocks of
+/// [`RESTART_INTERVAL`] entries. The position of the first entry in each block
+/// (its *restart point*) is recorded, so a lookup can binary search the blocks
+/// by their first key, and then only has to scan the one block that could hold
+/// the key.
+///
+/// Keys are prefix compressed: each entry only stores the part of its key that
+/// differs from the key before it. The first entry of every block stores its
+/// key in full, so decoding can start from any restart point. Each entry is
+/// encoded as:
+///
+/// ```text
+/// shared length (varint) | suffix length (varint) | suffix | offset (varint)
+/// ```
+///
+/// Keys must be inserted in ascending order.
+#[derive(Default)]
 pub struct SparseIndex {
-    index: BTreeMap<String, u64>,
+    data: Vec<u8>,
+    restarts: Vec<usize>,
+    len: usize,
+
+    /// The most recently inserted key, which the next one is compressed
+    /// against.
+    last_key: String,
 }
 
 impl SparseIndex {
     pub fn new() -> Self {
-        Self { index: BTreeMap::new() }
+        Self::default()
     }
 
     /// Return the byte range in which the key would exist in the segment file.
     ///
     /// NOTE: This function does not actually guarantee existence.
     pub fn get_byte_range(&self, key: &str) -> (Option<u64>, Option<u64>) {
-        let start = self
-            .index
-            .range::<str, (Bound<&str>, Bound<&str>)>((Bound::Unbounded, Bound::Included(key)))
-            .last()
-            .map(|(_, offset)| *offset);
-        let end = self
-            .index
-            .range::<str, (Bound<&str>, Bound<&str>)>((Bound::Excluded(key), Bound::Unbounded))
-            .next()
-            .map(|(_, offset)| *offset);
-        (start, end)
+        let key = key.as_bytes();
+
+        // The blocks before this one all start with a key that is at most `key`, so
+        // the greatest such key lives in the block just before it.
+        let block = self.restarts.partition_point(|restart| self.restart_key(*restart) <= key);
+        let next_block_offset = || {
+            self.restarts.get(block).map(|restart| {
+                let mut scratch = Vec::new();
+                self.decode(*restart, &mut scratch).0
+            })
+        };
+        if block == 0 {
+            return (None, next_block_offset());
+        }
+
+        let mut position = self.restarts[block - 1];
+        let block_end = self.restarts.get(block).copied().unwrap_or(self.data.len());
+        let mut entry_key = Vec::new();
+        let mut start = None;
+        while position < block_end {
+            let (offset, next) = self.decode(position, &mut entry_key);
+            if entry_key.as_slice() > key {
+                return (start, Some(offset));
+            }
+            start = Some(offset);
+            position = next;
+        }
+        (start, next_block_offset())
     }
 
     pub fn insert(&mut self, key: &str, offset: u64) {
-        self.index.insert(key.into(), offset);
+        let shared = if self.len.is_multiple_of(RESTART_INTERVAL) {
+            self.restarts.push(self.data.len());
+            0
+        } else {
+            shared_prefix_len(self.last_key.as_bytes(), key.as_bytes())
+        };
+        let suffix = &key.as_bytes()[shared..];
+        write_varint(&mut self.data, shared as u64);
+        write_varint(&mut self.data, suffix.len() as u64);
+        self.data.extend_from_slice(suffix);
+        write_varint(&mut self.data, offset);
+        self.last_key.replace_range(.., key);
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of bytes that the serialized entries take up.
+    pub fn encoded_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Iterate over the indexed keys and their offsets, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (String, u64)> + '_ {
+        let mut position = 0;
+        let mut key = Vec::new();
+        std::iter::from_fn(move || {
+            (position < self.data.len()).then(|| {
+                let (offset, next) = self.decode(position, &mut key);
+                position = next;
+                let key = String::from_utf8(key.clone()).expect("sparse index keys are strings");
+                (key, offset)
+            })
+        })
+    }
+
+    /// The key of the entry at `restart`, which is stored in full.
+    fn restart_key(&self, restart: usize) -> &[u8] {
+        let (_, position) = read_varint(&self.data, restart);
+        let (suffix_len, position) = read_varint(&self.data, position);
+        &self.data[position..position + suffix_len as usize]
+    }
+
+    /// Decode the entry at `position` in the buffer, and return its offset
+    /// along with the position of the entry after it.
+    ///
+    /// `key` must hold the key of the previous entry, unless `position` is a
+    /// restart point, and is replaced with the key of this one.
+    fn decode(&self, position: usize, key: &mut Vec<u8>) -> (u64, usize) {
+        let (shared, position) = read_varint(&self.data, position);
+        let (suffix_len, position) = read_varint(&self.data, position);
+        let suffix_end = position + suffix_len as usize;
+        key.truncate(shared as usize);
+        key.extend_from_slice(&self.data[position..suffix_end]);
+        read_varint(&self.data, suffix_end)
+    }
+}
+
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Append `value` to `buffer` as a LEB128 varint.
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
     }
+    buffer.push(value as u8);
+}
 
-    pub fn inner(&self) -> &BTreeMap<String, u64> {
-        &self.index
+/// Read the varint at `position` in `buffer`, and return it along with the
+/// position of the byte after it.
+fn read_varint(buffer: &[u8], mut position: usize) -> (u64, usize) {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buffer[position];
+        position += 1;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return (value, position);
+        }
+        shift += 7;
     }
 }
 
@@ -86,5 +213,52 @@ mod test {
             let range = index.get_byte_range("zebra");
             assert_eq!(range, (Some(1), None));
         }
+
+        #[test]
+        fn across_blocks() {
+            let mut index = SparseIndex::new();
+            let keys: Vec<_> = (0..100).map(|n| format!("key{:03}", n * 2)).collect();
+            for (n, key) in keys.iter().enumerate() {
+                index.insert(key, n as u64);
+            }
+            for n in 0..100 {
+                let range = index.get_byte_range(&format!("key{:03}", n * 2));
+                assert_eq!(range, (Some(n), (n < 99).then_some(n + 1)));
+                let range = index.get_byte_range(&format!("key{:03}", n * 2 + 1));
+                assert_eq!(range, (Some(n), (n < 99).then_some(n + 1)));
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_shared_prefixes() {
+        let mut index = SparseIndex::new();
+        let prefix = "https://example.com/some/long/path/";
+        for n in 0..RESTART_INTERVAL {
+            index.insert(&format!("{prefix}{n:02}"), n as u64);
+        }
+        // Only the first key is stored in full.
+        assert!(index.encoded_len() < prefix.len() * 2 + RESTART_INTERVAL * 5);
+        assert_eq!(index.get_byte_range(&format!("{prefix}05")), (Some(5), Some(6)));
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buffer = Vec::new();
+            write_varint(&mut buffer, value);
+            assert_eq!(read_varint(&buffer, 0), (value, buffer.len()));
+        }
+    }
+
+    #[test]
+    fn iter() {
+        let mut index = SparseIndex::new();
+        let entries: Vec<_> = (0..40).map(|n| (format!("key{n:02}"), n * 10)).collect();
+        for (key, offset) in &entries {
+            index.insert(key, *offset);
+        }
+        assert_eq!(index.len(), 40);
+        assert_eq!(index.iter().collect::<Vec<_>>(), entries);
     }
 }
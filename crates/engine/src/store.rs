@@ -1,25 +1,131 @@
 use std::collections::VecDeque;
-use std::fs::{create_dir_all, remove_file, File, OpenOptions};
+use std::fs::{create_dir_all, remove_file, File};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
-use crunch_common::env::parse_env;
+use anyhow::anyhow;
+use crunch_common::config::Config;
+use crunch_common::env::ByteSize;
 
-use crate::compaction::compaction_loop;
+use crate::backup::StoreSnapshot;
+use crate::batch::WriteBatch;
+use crate::compaction::{
+    compaction_loop, is_compaction_temp_filename, CompactionArgs, CompactionHistory,
+    CompactionTrigger,
+};
+use crate::encryption::{self, Cipher, KeyProvider, Reader, StaticKey, Writer};
 use crate::error::Error;
-use crate::memtable::Memtable;
+use crate::events::{FlushInfo, Listeners};
+use crate::io::{DirectIo, IoBackend, IoBackendKind, StdIo};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::memtable::{Memtable, Value};
+use crate::metrics::Metrics;
+use crate::quarantine::{move_to_quarantine, quarantine_segment};
 use crate::segment::{
-    self, is_segment_filename, segment_filename, segment_id, Entry, EntryIter, SegmentHandle,
+    self, is_segment_filename, segment_filename, segment_id, Codec, Compression, Entry, EntryIter,
+    SegmentFile, SegmentHandle, SegmentStats,
 };
+use crate::util::sync_directory;
+use crate::wal::{self, Pending, RecoveryMode, RecoveryReport, Wal};
+
+/// A snapshot of the state of a [`Store`], from [`Store::stats`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoreStats {
+    /// Each live segment file, oldest first.
+    pub segments: Vec<SegmentStats>,
+
+    /// The combined size of the WAL files, in bytes.
+    pub wal_bytes: u64,
+
+    /// When compaction last finished, if it has since the store was opened.
+    pub last_compaction: Option<SystemTime>,
+
+    /// The bytes written to segment files since the store was opened, by
+    /// flushes, ingests and bulk loads, and by compaction.
+    pub flushed_bytes: u64,
+    pub compacted_bytes: u64,
+
+    /// The number of gets since the store was opened, and the number of
+    /// segment files that they read from between them.
+    pub gets: u64,
+    pub segment_probes: u64,
+}
+
+impl StoreStats {
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// The combined size of the segment files, in bytes.
+    pub fn segment_bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    /// An estimate of the share of entries in the segment files that
+    /// compaction could drop, or `None` if there are no entries.
+    ///
+    /// Each tombstone counts as dead, along with every entry that a newer
+    /// segment overwrites. Overwrites are found with bloom filters, which
+    /// can count a few too many, and segments added before they were counted
+    /// have none, so this can err either way.
+    pub fn estimated_dead_ratio(&self) -> Option<f64> {
+        let (entries, dead) = self.segments.iter().fold((0, 0), |(entries, dead), segment| {
+            let segment_dead = segment.tombstone_count as u64 + segment.overwrite_count as u64;
+            (entries + segment.entry_count as u64, dead + segment_dead)
+        });
+        (entries > 0).then(|| dead.min(entries) as f64 / entries as f64)
+    }
+
+    /// An estimate of the bytes on disk for each byte of live data, from
+    /// [`Self::estimated_dead_ratio`], or `None` if nothing is live.
+    ///
+    /// The WAL files count as live, since they hold the writes that haven't
+    /// been flushed yet.
+    pub fn space_amplification(&self) -> Option<f64> {
+        let segment_bytes = self.segment_bytes() as f64;
+        let dead_ratio = self.estimated_dead_ratio().unwrap_or_default();
+        let live = segment_bytes * (1.0 - dead_ratio) + self.wal_bytes as f64;
+        (live > 0.0).then(|| (segment_bytes + self.wal_bytes as f64) / live)
+    }
+
+    /// The average number of segment files that a get read from, or `None` if
+    /// there were no gets. Gets answered by a memtable or the row cache read
+    /// from none.
+    pub fn read_amplification(&self) -> Option<f64> {
+        (self.gets > 0).then(|| self.segment_probes as f64 / self.gets as f64)
+    }
+
+    /// The bytes that compaction wrote for each byte flushed, or `None` if
+    /// nothing was flushed. Each time data is compacted, it is written again,
+    /// so this grows with the number of compactions that the average byte goes
+    /// through.
+    pub fn write_amplification(&self) -> Option<f64> {
+        (self.flushed_bytes > 0).then(|| self.compacted_bytes as f64 / self.flushed_bytes as f64)
+    }
+}
 
 /// Handles disk I/O for the database engine.
 pub struct Store {
     directory: PathBuf,
-    segments: Arc<RwLock<VecDeque<PathBuf>>>,
-    wal: File,
+    segments: Arc<RwLock<SegmentSet>>,
+
+    /// `None` for an in-memory store, which has no files at all.
+    wal: Option<Wal>,
+
+    /// Wakes the compaction loop before its next interval, if it is running.
+    compaction_wakeup: Option<Sender<()>>,
+
+    compaction_trigger: CompactionTrigger,
+
+    listeners: Listeners,
+
+    /// Updated by the compaction loop every time a compaction finishes.
+    compaction_history: Arc<Mutex<CompactionHistory>>,
 
     /// Set to `true` to kill the compaction loop.
     compaction_kill_flag: Arc<AtomicBool>,
@@ -27,56 +133,350 @@ pub struct Store {
     /// Wait on this after `compaction_kill_flag` is set to cleanly shut down
     /// the compaction loop.
     compaction_join_handle: Option<JoinHandle<()>>,
+    metrics: Arc<Metrics>,
+
+    /// Encrypts the segment files and the WAL, if the store is encrypted.
+    cipher: Option<Cipher>,
+    compression: Option<Compression>,
+    prefix_bloom_length: usize,
+
+    /// What segment files are read, and the WAL is appended to, with.
+    io: Arc<dyn IoBackend>,
+
+    /// The most bytes that ranges read ahead of themselves at a time.
+    read_ahead: usize,
+
+    /// Whether corrupt segment files are quarantined, rather than failing
+    /// reads.
+    quarantine_corrupt_segments: bool,
 }
 
-#[derive(Debug)]
+/// The live segment files of a store, along with the rest of the state that
+/// is recorded in its [`Manifest`].
+///
+/// This is shared between the engine thread and the compaction loop.
+pub struct SegmentSet {
+    /// Handles to the live segment files, oldest first.
+    pub handles: VecDeque<SegmentHandle>,
+
+    /// The id that will be given to the next segment file that is created.
+    pub next_segment_id: u32,
+
+    /// The id of the oldest WAL file that has not been flushed yet.
+    pub wal_start: u32,
+}
+
+impl SegmentSet {
+    /// Reserve an id for a new segment file.
+    pub fn allocate_id(&mut self) -> u32 {
+        let id = self.next_segment_id;
+        self.next_segment_id += 1;
+        id
+    }
+
+    /// Record the current state of the set in the manifest of the store at
+    /// `directory`.
+    ///
+    /// Callers must hold the write lock on the set, so that the manifest can't
+    /// be committed out of order.
+    pub fn commit(&self, directory: &Path) -> Result<(), Error> {
+        self.manifest().commit(directory)
+    }
+
+    /// The manifest that records the current state of the set.
+    pub fn manifest(&self) -> Manifest {
+        let entries = self.handles.iter().filter_map(|segment| {
+            Some(ManifestEntry {
+                id: segment.id()?,
+                level: segment.level(),
+                sequence: segment.sequence(),
+                overwrites: segment.overwrite_count(),
+            })
+        });
+        Manifest::new(self.next_segment_id, self.wal_start, entries)
+    }
+
+    /// The sequence to give the next segment that is flushed, which is newer
+    /// than any live segment.
+    pub fn next_sequence(&self) -> u64 {
+        self.handles.iter().map(SegmentHandle::sequence).max().map_or(1, |sequence| sequence + 1)
+    }
+
+    /// The combined size of the live segment files, in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.handles.iter().map(SegmentHandle::size).sum()
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct StoreArgs {
     /// When this is enabled, a background thread known as the "compaction loop"
-    /// runs and intermittently (on a period defined by
-    /// `compaction_interval_seconds`) compacts segment files together.
+    /// runs and intermittently (on a period defined by `compaction_interval`)
+    /// compacts segment files together.
+    ///
+    /// WebAssembly builds can't start threads, so it is off by default there,
+    /// and the store fails to open with it on.
     pub compaction_enabled: bool,
 
-    pub compaction_interval_seconds: u64,
+    pub compaction_interval: Duration,
+
+    /// The most segment files that a single compaction will merge together.
+    pub compaction_max_inputs: usize,
+
+    /// Once there are at least this many segment files, a flush will wake the
+    /// compaction loop instead of leaving it to wait for its next interval.
+    pub compaction_trigger_segment_count: usize,
+
+    /// Like `compaction_trigger_segment_count`, but for the combined size of
+    /// the segment files, in bytes.
+    pub compaction_trigger_bytes: u64,
+
+    /// The most bytes per second that compaction will read or write, combined,
+    /// so that it doesn't starve foreground reads and writes of disk
+    /// bandwidth. Zero means unlimited.
+    pub compaction_bytes_per_second: u64,
+
+    /// The size, in bytes, past which the active WAL file is closed off and a
+    /// new one is started.
+    pub wal_max_bytes: u64,
+
+    /// Whether every write to the WAL is fsynced before it is acknowledged.
+    pub wal_sync: bool,
+
+    /// What replaying the WAL does with a corrupt record.
+    pub wal_recovery_mode: RecoveryMode,
+
+    /// Supplies the key to encrypt the segment files and the WAL with, or
+    /// `None` to leave them unencrypted. A store can only be opened the way it
+    /// was created.
+    pub encryption: Option<Arc<dyn KeyProvider>>,
+
+    /// How large values are compressed as they are written to the WAL and
+    /// segment files, or `None` to store them as they are. Stores can be read
+    /// either way.
+    pub compression: Option<Compression>,
+
+    /// The most versions of each key that compaction keeps, counting the
+    /// current one. More than 1 has every write record a sequence number, so
+    /// that older values can be read with
+    /// [`Engine::get_at`](crate::engine::Engine::get_at).
+    pub retained_versions: usize,
+
+    /// How many leading bytes of each key go into a second bloom filter in
+    /// every segment, so that prefix scans can skip segments without any keys
+    /// that start with the prefix. 0 means no prefix filters.
+    pub prefix_bloom_length: usize,
+
+    /// What segment files are read, and the WAL is appended to, with.
+    pub io_backend: IoBackendKind,
+
+    /// Whether segment files are read with direct IO, around the OS page
+    /// cache, so that scans and compactions don't evict what other processes
+    /// have cached. Only supported on Linux.
+    pub direct_io: bool,
+
+    /// The number of threads that segment files are opened with when the
+    /// store is. Each one is read in full to build its bloom filters and
+    /// sparse index, so a store with many of them opens faster with more.
+    pub open_threads: usize,
+
+    /// The most bytes at a time that scans read ahead of themselves in each
+    /// segment file, once their reads follow on from each other. 0 turns read
+    /// ahead off, leaving scans to make a read for every part of every entry.
+    pub read_ahead: usize,
+
+    /// Whether a segment file that is found to be corrupt is moved out of the
+    /// store, so that it carries on without it, rather than failing every
+    /// read that reaches it. See [`crate::quarantine`].
+    pub quarantine_corrupt_segments: bool,
 }
 
 impl StoreArgs {
-    /// Parse arguments from environment variables prefixed with
-    /// `CRUNCH_ENGINE_STORE`.
-    pub fn from_env() -> Self {
-        let compaction_enabled = parse_env("engine", Some("store"), "compaction_enabled", true);
-        let compaction_interval_seconds =
-            parse_env("engine", Some("store"), "compaction_interval_seconds", 600);
-        Self { compaction_enabled, compaction_interval_seconds }
+    /// Read arguments from the `engine.store` settings in `config`, which can
+    /// be set by environment variables prefixed with `CRUNCH_ENGINE_STORE`.
+    pub fn from_config(config: &Config) -> Self {
+        let compaction_enabled = config.get(
+            "engine",
+            Some("store"),
+            "compaction_enabled",
+            !cfg!(target_family = "wasm"),
+        );
+        // The interval used to be set as a number of seconds, under a name saying so,
+        // which still works when the new name isn't set.
+        let legacy_compaction_interval = config.get(
+            "engine",
+            Some("store"),
+            "compaction_interval_seconds",
+            Duration::from_secs(600),
+        );
+        let compaction_interval =
+            config.get("engine", Some("store"), "compaction_interval", legacy_compaction_interval);
+        let compaction_max_inputs = config.get("engine", Some("store"), "compaction_max_inputs", 8);
+        let compaction_trigger_segment_count =
+            config.get("engine", Some("store"), "compaction_trigger_segment_count", 8);
+        let compaction_trigger_bytes = config
+            .get("engine", Some("store"), "compaction_trigger_bytes", ByteSize(64 * 1024 * 1024))
+            .0;
+        let compaction_bytes_per_second =
+            config.get("engine", Some("store"), "compaction_bytes_per_second", ByteSize(0)).0;
+        let wal_max_bytes =
+            config.get("engine", Some("store"), "wal_max_bytes", ByteSize(4 * 1024 * 1024)).0;
+        let wal_sync = config.get("engine", Some("store"), "wal_sync", true);
+        let wal_recovery_mode =
+            config.get("engine", Some("store"), "wal_recovery_mode", RecoveryMode::Strict);
+        let encryption = config
+            .get::<Option<StaticKey>>("engine", Some("store"), "encryption_key", None)
+            .map(|key| Arc::new(key) as Arc<dyn KeyProvider>);
+        let compression_threshold =
+            config.get("engine", Some("store"), "compression_threshold", ByteSize(0)).0;
+        let compression = (compression_threshold > 0).then_some(Compression {
+            codec: Codec::Lz4,
+            threshold: compression_threshold as usize,
+        });
+        let retained_versions = config.get("engine", Some("store"), "retained_versions", 1);
+        let prefix_bloom_length = config.get("engine", Some("store"), "prefix_bloom_length", 0);
+        let open_threads = config.get("engine", Some("store"), "open_threads", 1);
+        let io_backend = config.get("engine", Some("store"), "io_backend", IoBackendKind::Std);
+        let direct_io = config.get("engine", Some("store"), "direct_io", false);
+        let read_ahead =
+            config.get("engine", Some("store"), "read_ahead", ByteSize(256 * 1024)).0 as usize;
+        let quarantine_corrupt_segments =
+            config.get("engine", Some("store"), "quarantine_corrupt_segments", false);
+        Self {
+            compaction_enabled,
+            compaction_interval,
+            compaction_max_inputs,
+            compaction_trigger_segment_count,
+            compaction_trigger_bytes,
+            compaction_bytes_per_second,
+            wal_max_bytes,
+            wal_sync,
+            wal_recovery_mode,
+            encryption,
+            compression,
+            retained_versions,
+            prefix_bloom_length,
+            io_backend,
+            direct_io,
+            open_threads,
+            read_ahead,
+            quarantine_corrupt_segments,
+        }
     }
 }
 
 impl Default for StoreArgs {
     fn default() -> Self {
-        Self { compaction_enabled: true, compaction_interval_seconds: 600 }
+        Self {
+            compaction_enabled: !cfg!(target_family = "wasm"),
+            compaction_interval: Duration::from_secs(600),
+            compaction_max_inputs: 8,
+            compaction_trigger_segment_count: 8,
+            compaction_trigger_bytes: 64 * 1024 * 1024,
+            compaction_bytes_per_second: 0,
+            wal_max_bytes: 4 * 1024 * 1024,
+            wal_sync: true,
+            wal_recovery_mode: RecoveryMode::Strict,
+            encryption: None,
+            compression: None,
+            retained_versions: 1,
+            prefix_bloom_length: 0,
+            io_backend: IoBackendKind::Std,
+            direct_io: false,
+            open_threads: 1,
+            read_ahead: 256 * 1024,
+            quarantine_corrupt_segments: false,
+        }
     }
 }
 
 impl Store {
     pub fn new(directory: PathBuf, args: StoreArgs) -> Result<Self, Error> {
-        let segments = initialize_store_at_path(&directory)?;
-        let wal = open_wal(&directory)?;
+        Self::with_listeners(directory, args, Listeners::default())
+    }
+
+    /// Like [`Self::new`], but notifies `listeners` about flushes and
+    /// compactions.
+    pub fn with_listeners(
+        directory: PathBuf,
+        args: StoreArgs,
+        listeners: Listeners,
+    ) -> Result<Self, Error> {
+        if cfg!(target_family = "wasm") && args.compaction_enabled {
+            return Err(anyhow!(
+                "compaction runs on a background thread, which WebAssembly builds can't start, so \
+                 it must be disabled"
+            )
+            .into());
+        }
+        let cipher = args.encryption.as_deref().map(Cipher::from_provider).transpose()?;
+        let mut io = args.io_backend.open()?;
+        if args.direct_io {
+            io = Arc::new(DirectIo::new(io)?);
+        }
+        let (segments, quarantined) =
+            initialize_store_at_path(&directory, cipher.as_ref(), &io, &args)?;
+        let metrics = Arc::new(Metrics::default());
+        for (path, destination, error) in &quarantined {
+            metrics.quarantined_segments.increment();
+            listeners.notify(|listener| listener.on_segment_quarantined(path, destination, error));
+        }
+        let wal = Wal::open(&directory, segments.wal_start, args.wal_max_bytes, args.wal_sync)?
+            .with_metrics(metrics.clone())
+            .with_recovery_mode(args.wal_recovery_mode)
+            .with_cipher(cipher.clone())
+            .with_compression(args.compression)
+            .with_io(io.clone());
         let mut store = Self {
             directory,
             segments: Arc::new(RwLock::new(segments)),
-            wal,
+            wal: Some(wal),
+            compaction_wakeup: None,
+            compaction_trigger: CompactionTrigger {
+                segment_count: args.compaction_trigger_segment_count,
+                bytes: args.compaction_trigger_bytes,
+            },
+            listeners,
+            compaction_history: Arc::default(),
             compaction_kill_flag: Arc::new(AtomicBool::new(false)),
             compaction_join_handle: None,
+            metrics,
+            cipher,
+            compression: args.compression,
+            prefix_bloom_length: args.prefix_bloom_length,
+            io,
+            read_ahead: args.read_ahead,
+            quarantine_corrupt_segments: args.quarantine_corrupt_segments,
         };
         if args.compaction_enabled {
+            let (wakeup, wakeups) = mpsc::channel();
+            store.compaction_wakeup = Some(wakeup);
+            let compaction_args = CompactionArgs {
+                interval: args.compaction_interval,
+                max_inputs: args.compaction_max_inputs,
+                bytes_per_second: args.compaction_bytes_per_second,
+                cipher: store.cipher.clone(),
+                compression: store.compression,
+                retained_versions: args.retained_versions,
+                prefix_bloom_length: args.prefix_bloom_length,
+                io: store.io.clone(),
+                quarantine_corrupt_segments: args.quarantine_corrupt_segments,
+                metrics: store.metrics.clone(),
+            };
             store.compaction_join_handle = Some({
                 let path = store.directory.clone();
                 let segments = store.segments.clone();
+                let listeners = store.listeners.clone();
+                let history = store.compaction_history.clone();
                 let compaction_kill_flag = store.compaction_kill_flag.clone();
                 std::thread::spawn(move || {
                     compaction_loop(
-                        args.compaction_interval_seconds,
+                        compaction_args,
                         path,
                         segments,
+                        wakeups,
+                        listeners,
+                        history,
                         compaction_kill_flag,
                     )
                 })
@@ -86,32 +486,228 @@ impl Store {
         Ok(store)
     }
 
-    /// Write a `key`:`value` pair to the WAL.
-    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
-        segment::write(&mut self.wal, key, value)
+    /// A store that keeps nothing, for an in-memory engine. It has no
+    /// directory, WAL or segment files, so writes to it are dropped, and
+    /// anything that would add a segment file fails.
+    pub fn in_memory(listeners: Listeners) -> Self {
+        let args = StoreArgs::default();
+        Self {
+            directory: PathBuf::new(),
+            segments: Arc::new(RwLock::new(SegmentSet {
+                handles: VecDeque::new(),
+                next_segment_id: 0,
+                wal_start: 0,
+            })),
+            wal: None,
+            compaction_wakeup: None,
+            compaction_trigger: CompactionTrigger {
+                segment_count: args.compaction_trigger_segment_count,
+                bytes: args.compaction_trigger_bytes,
+            },
+            listeners,
+            compaction_history: Arc::default(),
+            compaction_kill_flag: Arc::new(AtomicBool::new(false)),
+            compaction_join_handle: None,
+            metrics: Arc::default(),
+            cipher: None,
+            compression: None,
+            prefix_bloom_length: 0,
+            io: Arc::new(StdIo),
+            read_ahead: args.read_ahead,
+            quarantine_corrupt_segments: false,
+        }
+    }
+
+    /// Whether this is a store from [`Self::in_memory`].
+    pub fn is_in_memory(&self) -> bool {
+        self.wal.is_none()
+    }
+
+    /// The WAL, which an in-memory store fails to give for anything that
+    /// needs files on disk.
+    fn wal(&self) -> Result<&Wal, Error> {
+        self.wal.as_ref().ok_or_else(|| anyhow!("an in-memory store has no files").into())
+    }
+
+    /// The directory that the store lives in.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Write a `key`:`value` pair to the WAL, as the version written at
+    /// `sequence` if one is given. It is only durable once it is passed to
+    /// [`Self::commit`].
+    pub fn set(&self, key: &str, value: &str, sequence: Option<u64>) -> Result<Pending, Error> {
+        self.wal.as_ref().map_or(Ok(Pending::none()), |wal| wal.enqueue_set(key, value, sequence))
+    }
+
+    /// Write every write in `batch` to the WAL, atomically, as versions
+    /// written at `sequence` if one is given. Like [`Self::set`], it is only
+    /// durable once it is committed.
+    pub fn write(&self, batch: &WriteBatch, sequence: Option<u64>) -> Result<Pending, Error> {
+        self.wal.as_ref().map_or(Ok(Pending::none()), |wal| wal.enqueue_write(batch, sequence))
+    }
+
+    /// Return once the WAL record of a write is durable. Writes that are made
+    /// one after another, and only then committed, share an fsync.
+    pub fn commit(&self, pending: Pending) -> Result<(), Error> {
+        self.wal.as_ref().map_or(Ok(()), |wal| wal.commit(pending))
     }
 
     /// Read `key`'s value from disk, if it exists.
-    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+    pub fn get(&self, key: &str) -> Result<Value, Error> {
+        self.quarantining(|| self.get_from_segments(key))
+    }
+
+    fn get_from_segments(&self, key: &str) -> Result<Value, Error> {
         let segments = self.segments.read()?;
-        for segment in segments.iter().rev() {
-            let mut segment = SegmentHandle::open(segment.to_owned())?;
-            match segment.get(key)? {
-                Some(value) => return Ok(value),
-                _ => {},
-            };
+        let mut probes = 0;
+        let mut value = None;
+        for segment in segments.handles.iter().rev() {
+            if !segment.may_contain(key) {
+                log::trace!("{key} is outside the key range of {:?}", segment.path());
+                continue;
+            }
+            if !segment.bloom_filter_contains(key) {
+                log::trace!("{key} was not in bloom filter for {:?}", segment.path());
+                self.metrics.bloom_filter_skips.increment();
+                continue;
+            }
+            probes += 1;
+            if let Some(found) = segment.search(key)? {
+                value = found;
+                break;
+            }
+            segment.record_bloom_filter_false_positive();
         }
-        Ok(None)
+        self.metrics.segment_probes.record(probes);
+        Ok(value)
     }
 
-    /// Write a tombstone for `key` to disk.
-    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
-        segment::tombstone(&mut self.wal, key)
+    /// Read the newest version of `key` on disk that was written at or before
+    /// `sequence`. Returns `None` if there is no such version, and `Some(None)`
+    /// if it is a delete.
+    pub fn get_at(&self, key: &str, sequence: u64) -> Result<Option<Value>, Error> {
+        self.quarantining(|| {
+            let segments = self.segments.read()?;
+            for segment in segments.handles.iter().rev() {
+                if !segment.may_contain(key) {
+                    continue;
+                }
+                if let Some(found) = segment.get_at(key, sequence)? {
+                    return Ok(Some(found));
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    /// The highest sequence number of any version on disk, or 0 if there are
+    /// none.
+    pub fn max_version(&self) -> Result<u64, Error> {
+        let segments = self.segments.read()?;
+        Ok(segments.handles.iter().map(SegmentHandle::max_version).max().unwrap_or(0))
+    }
+
+    /// Whether `key` has a live value on disk.
+    pub fn exists(&self, key: &str) -> Result<bool, Error> {
+        self.quarantining(|| {
+            let segments = self.segments.read()?;
+            for segment in segments.handles.iter().rev() {
+                if !segment.may_contain(key) {
+                    continue;
+                }
+                if let Some(exists) = segment.contains(key)? {
+                    return Ok(exists);
+                }
+            }
+            Ok(false)
+        })
+    }
+
+    /// Run `read`, and if it runs into a corrupt segment file while
+    /// [`StoreArgs::quarantine_corrupt_segments`] is set, quarantine the file
+    /// and run it again without it.
+    fn quarantining<T>(&self, read: impl Fn() -> Result<T, Error>) -> Result<T, Error> {
+        loop {
+            match read() {
+                // Each retry follows the quarantine of a live file, so this ends.
+                Err(error) if self.quarantine_corrupt_segments && self.quarantine(&error)? => {},
+                result => return result,
+            }
+        }
+    }
+
+    /// Quarantine the segment file that `error` found to be corrupt, if it is
+    /// an [`Error::Corruption`] in a live one. Returns whether a file was
+    /// quarantined. See [`crate::quarantine`].
+    pub fn quarantine(&self, error: &Error) -> Result<bool, Error> {
+        quarantine_segment(&self.directory, &self.segments, error, &self.metrics, &self.listeners)
+    }
+
+    /// Iterate over the entries on disk whose key is at least `start`, in
+    /// order. Tombstones are included, as `None` values.
+    ///
+    /// The iterator pins the segment set as it is now, so it is unaffected by
+    /// compactions that run while it is in use: their inputs stay on disk until
+    /// it is dropped.
+    pub fn range(&self, start: &str) -> Result<StoreRange, Error> {
+        self.range_of(start, |_| true)
+    }
+
+    /// Like [`Self::range`], starting from `prefix`, but leaving out segments
+    /// that can't hold any keys starting with it. Iteration should stop at the
+    /// first key that doesn't start with `prefix`, since the keys after it may
+    /// be incomplete.
+    pub fn prefix_range(&self, prefix: &str) -> Result<StoreRange, Error> {
+        self.range_of(prefix, |segment| {
+            let may_have_prefix = segment.may_have_prefix(prefix);
+            if !may_have_prefix {
+                log::trace!("no keys start with {prefix:?} in {:?}", segment.path());
+            }
+            may_have_prefix
+        })
+    }
+
+    /// A range from `start` over the segments that `include` picks.
+    fn range_of(
+        &self,
+        start: &str,
+        include: impl Fn(&SegmentHandle) -> bool,
+    ) -> Result<StoreRange, Error> {
+        let segments = self.segments.read()?;
+        let mut files = Vec::with_capacity(segments.handles.len());
+        for segment in segments.handles.iter().filter(|segment| include(segment)) {
+            files.push((segment.pin(), segment.open_from(start)?.with_read_ahead(self.read_ahead)));
+        }
+        drop(segments);
+
+        let mut heads = Vec::with_capacity(files.len());
+        for (segment, file) in &mut files {
+            let mut entries = EntryIter::new(file).with_path(segment.path());
+            heads.push(loop {
+                match entries.next().transpose()? {
+                    Some(entry) if entry.key().as_str() < start => continue,
+                    head => break head,
+                }
+            });
+        }
+        Ok(StoreRange { files, heads, failed: false })
+    }
+
+    /// Write a tombstone for `key` to the WAL, as the version written at
+    /// `sequence` if one is given. Like [`Self::set`], it is only durable once
+    /// it is committed.
+    pub fn delete(&self, key: &str, sequence: Option<u64>) -> Result<Pending, Error> {
+        self.wal.as_ref().map_or(Ok(Pending::none()), |wal| wal.enqueue_delete(key, sequence))
     }
 
     /// Cleanly shut down the compaction loop, if it is running.
     pub fn stop(self) -> thread::Result<()> {
         self.compaction_kill_flag.swap(true, Ordering::Relaxed);
+        if let Some(wakeup) = &self.compaction_wakeup {
+            _ = wakeup.send(());
+        }
         if let Some(handle) = self.compaction_join_handle {
             handle.join()?;
         }
@@ -119,88 +715,830 @@ impl Store {
     }
 
     /// Write the contents of the `memtable` to a new segment file on disk.
-    pub fn write_memtable(&mut self, memtable: &Memtable) -> Result<(), Error> {
-        // The id of the new segment file will be the highest one on disk + 1.
-        let last_segment_id = self.segments.read()?.iter().last().and_then(segment_id).unwrap_or(0);
-        let next_segment_id = last_segment_id + 1;
+    pub fn write_memtable(&self, memtable: &Memtable) -> Result<(), Error> {
+        // Everything in the memtable was logged to a WAL file older than the new
+        // active one, so once the segment is committed, those files are redundant.
+        let wal_start = self.wal()?.rotate()?;
 
+        let next_segment_id = self.segments.write()?.allocate_id();
         let next_segment_path = self.directory.clone().join(segment_filename(next_segment_id));
-        let mut next_segment = File::create(next_segment_path.clone())?;
+        let info = FlushInfo { path: next_segment_path.clone(), entries: memtable.len() };
+        let started_at = Instant::now();
+        self.listeners.notify(|listener| listener.on_flush_started(&info));
+        let mut next_segment =
+            Writer::new(File::create(next_segment_path.clone())?, self.cipher.as_ref())?;
+        // Each entry adds its indicator and the sizes of its key and value to
+        // them, before any compression.
+        next_segment.preallocate((memtable.data_size() + 9 * memtable.len()) as u64)?;
         for (key, value) in memtable.iter() {
+            if let Some(versions) = memtable.versions(key) {
+                for (sequence, value) in versions {
+                    segment::write_version(&mut next_segment, sequence)?;
+                    match value {
+                        Some(value) => {
+                            segment::write_with(&mut next_segment, key, value, self.compression)?;
+                        },
+                        None => segment::tombstone(&mut next_segment, key)?,
+                    }
+                }
+                continue;
+            }
             match value {
-                Some(value) => segment::write(&mut next_segment, key, value)?,
+                Some(value) => {
+                    segment::write_with(&mut next_segment, key, value, self.compression)?;
+                },
                 None => segment::tombstone(&mut next_segment, key)?,
             }
         }
+        // The segment's contents and its directory entry must both be durable
+        // before the manifest refers to it, and the WAL files behind it go.
+        next_segment.finish()?.sync_all()?;
+        sync_directory(&self.directory)?;
         log::debug!("wrote memtable to {next_segment_path:?}");
-        self.segments.write()?.push_back(next_segment_path);
-
-        // Delete and recreate the WAL, which means that if the engine crashes after the
-        // deletion and before the re-creation, there will be no WAL on disk. Since the
-        // engine expects that it may have to recreate the WAL, and our engine is only
-        // single threaded (outside of compaction, which only touches segment files),
-        // this is fine.
-        remove_file(wal_path(&self.directory))?;
-        self.wal = open_wal(&self.directory)?;
+        let tripped = {
+            let mut segments = self.segments.write()?;
+            let sequence = segments.next_sequence();
+            let segment = SegmentHandle::open_at_level(
+                next_segment_path,
+                0,
+                self.cipher.clone(),
+                self.prefix_bloom_length,
+                self.io.clone(),
+            )?;
+            // The memtable holds every key of the segment, so the file isn't read again.
+            let keys = memtable.iter().map(|(key, _)| key.as_str());
+            let overwrites = segment::count_overwrites(keys, segments.handles.iter());
+            self.metrics.flushed_bytes.add(segment.size());
+            segments
+                .handles
+                .push_back(segment.with_sequence(sequence).with_overwrite_count(overwrites));
+            segments.wal_start = wal_start;
+            segments.commit(&self.directory)?;
+            self.compaction_trigger.is_tripped(&segments)
+        };
+        let duration = started_at.elapsed();
+        self.metrics.flushes.increment();
+        self.listeners.notify(|listener| listener.on_flush_finished(&info, duration));
+        if let Some(wakeup) = self.compaction_wakeup.as_ref().filter(|_| tripped) {
+            log::debug!("segments crossed {:?}, waking compactor", self.compaction_trigger);
+            // The compaction loop only hangs up once it has been stopped.
+            _ = wakeup.send(());
+        }
+
+        // If the engine crashes before this point, the manifest still points at the
+        // old WAL files, and they are replayed on top of the new segment, which is
+        // harmless since they hold the same data.
+        self.wal()?.remove_before(wal_start)
+    }
+
+    /// Copy the segment file at `source` into the store, as its newest
+    /// segment. Returns the number of entries copied.
+    ///
+    /// The file must be unencrypted, and have its keys in order. Nothing is
+    /// added to the store unless every entry in it can be read. Its entries
+    /// are compressed and encrypted the way the store's own are.
+    ///
+    /// Callers must make sure that no write or flush runs until this returns.
+    pub fn ingest_segment(&self, source: &Path) -> Result<u64, Error> {
+        let (path, copied) = self.add_segment(|segment| {
+            let (copied, error) = segment::read_sorted(source, None, |version, entry| {
+                if let Some(version) = version {
+                    segment::write_version(segment, version)?;
+                }
+                entry.write_with(segment, self.compression).map(drop)
+            })?;
+            match error {
+                Some(error) => Err(anyhow!("{source:?} can't be ingested: {error}").into()),
+                None => Ok(copied),
+            }
+        })?;
+        log::info!("ingested {copied} entries of {source:?} as {path:?}");
+        Ok(copied)
+    }
+
+    /// Write `pairs`, which must be in key order with no key repeated, to a
+    /// new segment, as the newest one in the store. Each pair is marked as
+    /// written at `sequence`, if there is one. Returns the number of pairs
+    /// written.
+    ///
+    /// Nothing is added to the store unless every pair is written.
+    ///
+    /// Callers must make sure that no write or flush runs until this returns.
+    pub fn bulk_load(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+        sequence: Option<u64>,
+    ) -> Result<u64, Error> {
+        let (path, written) = self.add_segment(|segment| {
+            let mut previous: Option<String> = None;
+            let mut written = 0;
+            for (key, value) in pairs {
+                if previous.as_ref().is_some_and(|previous| key <= *previous) {
+                    return Err(anyhow!("key {key:?} is out of order").into());
+                }
+                if let Some(sequence) = sequence {
+                    segment::write_version(segment, sequence)?;
+                }
+                segment::write_with(segment, &key, &value, self.compression)?;
+                written += 1;
+                previous = Some(key);
+            }
+            Ok(written)
+        })?;
+        log::info!("bulk loaded {written} pairs into {path:?}");
+        Ok(written)
+    }
+
+    /// Write a new segment file with `write`, and add it to the store as the
+    /// newest segment. If `write` fails, the file is removed. Returns the path
+    /// of the file, and what `write` returned.
+    fn add_segment(
+        &self,
+        write: impl FnOnce(&mut Writer) -> Result<u64, Error>,
+    ) -> Result<(PathBuf, u64), Error> {
+        // Only a store on disk has anywhere to put the file.
+        self.wal()?;
+        let id = self.segments.write()?.allocate_id();
+        let path = self.directory.join(segment_filename(id));
+        let write = || -> Result<u64, Error> {
+            let mut segment = Writer::new(File::create_new(&path)?, self.cipher.as_ref())?;
+            let written = write(&mut segment)?;
+            segment.finish()?.sync_all()?;
+            sync_directory(&self.directory)?;
+            Ok(written)
+        };
+        let written = match write() {
+            Ok(written) => written,
+            Err(error) => {
+                _ = remove_file(&path);
+                return Err(error);
+            },
+        };
+        let tripped = {
+            let mut segments = self.segments.write()?;
+            let sequence = segments.next_sequence();
+            let mut segment = SegmentHandle::open_at_level(
+                path.clone(),
+                0,
+                self.cipher.clone(),
+                self.prefix_bloom_length,
+                self.io.clone(),
+            )?;
+            segment.count_overwrites_in_file(segments.handles.iter())?;
+            self.metrics.flushed_bytes.add(segment.size());
+            segments.handles.push_back(segment.with_sequence(sequence));
+            segments.commit(&self.directory)?;
+            self.compaction_trigger.is_tripped(&segments)
+        };
+        if let Some(wakeup) = self.compaction_wakeup.as_ref().filter(|_| tripped) {
+            // The compaction loop only hangs up once it has been stopped.
+            _ = wakeup.send(());
+        }
+        Ok((path, written))
+    }
+
+    /// The files that make up the store right now, for a backup.
+    ///
+    /// Callers must make sure that no write or flush runs until this returns,
+    /// so that the WAL files match the manifest and hold only whole records.
+    pub fn snapshot_files(&self) -> Result<StoreSnapshot, Error> {
+        let segments = self.segments.read()?;
+        let mut pinned = Vec::with_capacity(segments.handles.len());
+        for segment in &segments.handles {
+            let filename = segment.path().file_name().map(|name| name.to_string_lossy());
+            let filename =
+                filename.ok_or_else(|| anyhow!("{:?} has no filename", segment.path()))?;
+            pinned.push((filename.into_owned(), segment.pin()));
+        }
+        Ok(StoreSnapshot {
+            segments: pinned,
+            wal: self.wal()?.read_from(segments.wal_start)?,
+            manifest: segments.manifest().serialize().into_bytes(),
+        })
+    }
+
+    /// Seed the `memtable` with the contents of the WAL, and report what was
+    /// replayed. A store in memory has nothing to replay.
+    pub fn replay_wal(&self, memtable: &mut Memtable) -> Result<RecoveryReport, Error> {
+        self.wal.as_ref().map_or(Ok(RecoveryReport::default()), |wal| wal.replay(memtable))
+    }
+
+    /// Read every entry of every segment file, and fail with the first damage
+    /// found. This catches what opening the store doesn't check, like keys that
+    /// are out of order, which would make lookups miss them. With
+    /// [`StoreArgs::quarantine_corrupt_segments`], damaged files are
+    /// quarantined instead.
+    pub fn verify(&self) -> Result<(), Error> {
+        let started_at = Instant::now();
+        let paths = self.list_segments()?;
+        let mut entries = 0;
+        for path in &paths {
+            let (readable, error) =
+                segment::read_sorted(path, self.cipher.as_ref(), |_, _| Ok(()))?;
+            if let Some(error) = error {
+                log::error!("{path:?} is damaged after {readable} entries: {error}");
+                if self.quarantine_corrupt_segments && self.quarantine(&error)? {
+                    continue;
+                }
+                return Err(error);
+            }
+            entries += readable;
+        }
+        log::info!(
+            "verified {entries} entries in {} segment files in {:?}",
+            paths.len(),
+            started_at.elapsed()
+        );
         Ok(())
     }
 
-    /// Seed the `memtable` with the contents of the WAL.
-    pub fn replay_wal(&mut self, memtable: &mut Memtable) -> Result<(), Error> {
-        Ok(EntryIter::from_start(&mut self.wal)?.for_each(|entry| {
-            match entry {
-                Entry::Assignment { key, value } => memtable.set(key, value),
-                Entry::Tombstone { key } => memtable.delete(&key),
-            };
+    pub fn list_segments(&self) -> Result<Vec<PathBuf>, Error> {
+        Ok(self.segments.read()?.handles.iter().map(|segment| segment.path().to_owned()).collect())
+    }
+
+    /// A snapshot of the state of the store.
+    pub fn stats(&self) -> Result<StoreStats, Error> {
+        let segments = self.segments.read()?.handles.iter().map(SegmentHandle::stats).collect();
+        let history = self.compaction_history.lock()?;
+        let probes = self.metrics.segment_probes.snapshot();
+        Ok(StoreStats {
+            segments,
+            wal_bytes: self.wal_size()?,
+            last_compaction: history.last_finished_at,
+            flushed_bytes: self.metrics.flushed_bytes.get(),
+            compacted_bytes: history.totals.bytes_written,
+            gets: probes.count(),
+            segment_probes: probes.sum,
+        })
+    }
+
+    /// The number of values and the number of tombstones across the live
+    /// segment files, as recorded when each one was opened.
+    pub fn entry_counts(&self) -> Result<(u64, u64), Error> {
+        let segments = self.segments.read()?;
+        Ok(segments.handles.iter().fold((0, 0), |(values, tombstones), segment| {
+            let segment_tombstones = segment.tombstone_count() as u64;
+            (
+                values + (segment.entry_count() as u64 - segment_tombstones),
+                tombstones + segment_tombstones,
+            )
         }))
     }
 
-    pub fn list_segments(&self) -> Result<Vec<PathBuf>, Error> {
-        Ok(self.segments.read()?.iter().cloned().collect())
+    /// The counters shared by the store and the engine that owns it.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The combined size of the WAL files, in bytes.
+    pub fn wal_size(&self) -> Result<u64, Error> {
+        self.wal.as_ref().map_or(Ok(0), Wal::size)
+    }
+
+    /// Statistics about the compactions that have run since the store was
+    /// opened.
+    pub fn compaction_stats(&self) -> Result<CompactionHistory, Error> {
+        Ok(self.compaction_history.lock()?.clone())
     }
 
     pub fn inspect_segment(&self, filename: &str) -> Result<(), Error> {
         let path = self.directory.join(filename);
         let guard = self.segments.read()?;
-        let Some(segment) = guard.iter().find(|segment| **segment == path) else {
+        let Some(segment) = guard.handles.iter().find(|segment| segment.path() == path) else {
             println!("Error: segment not found");
             return Ok(());
         };
-        _ = SegmentHandle::open(segment.to_owned())
-            .inspect_err(|error| println!("Error: could not open segment, reason: {error:?}"))
-            .inspect(|segment| segment.inspect());
+        segment.inspect();
         Ok(())
     }
+
+    /// Print every record in the WAL, which is what would be replayed into the
+    /// memtable if the store were opened now.
+    pub fn inspect_wal(&self) -> Result<(), Error> {
+        self.wal()?.inspect()
+    }
+}
+
+/// An ordered iterator over the entries in a store's segment files, created by
+/// [`Store::range`].
+///
+/// When several segments hold the same key, only the entry from the newest of
+/// them is yielded.
+pub struct StoreRange {
+    /// The pinned segment files along with readers of them, oldest first.
+    files: Vec<(Arc<SegmentFile>, Reader)>,
+
+    /// The next entry of each file, if it has any left.
+    heads: Vec<Option<Entry>>,
+
+    /// Whether an error has been yielded, after which the range ends.
+    failed: bool,
+}
+
+impl StoreRange {
+    fn step(&mut self) -> Result<Option<(String, Option<String>)>, Error> {
+        // Ties go to the later, and so newer, file.
+        let Some(newest) = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(source, head)| Some((head.as_ref()?.key(), source)))
+            .min_by(|(a_key, a_source), (b_key, b_source)| {
+                a_key.cmp(b_key).then(b_source.cmp(a_source))
+            })
+            .map(|(_, source)| source)
+        else {
+            return Ok(None);
+        };
+        let Some(entry) = self.heads[newest].take() else {
+            return Ok(None);
+        };
+        let (segment, file) = &mut self.files[newest];
+        self.heads[newest] = EntryIter::new(file).with_path(segment.path()).next().transpose()?;
+        // Older files may hold stale entries for the same key, and any file may hold
+        // older versions of it, all of which are skipped.
+        for ((segment, file), head) in self.files.iter_mut().zip(&mut self.heads) {
+            while head.as_ref().is_some_and(|stale| stale.key() == entry.key()) {
+                *head = EntryIter::new(file).with_path(segment.path()).next().transpose()?;
+            }
+        }
+        Ok(Some(match entry {
+            Entry::Assignment { key, value } => (key, Some(value)),
+            Entry::Tombstone { key } => (key, None),
+        }))
+    }
 }
 
+impl Iterator for StoreRange {
+    type Item = Result<(String, Option<String>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.step();
+        self.failed = result.is_err();
+        result.transpose()
+    }
+}
+
+/// A segment file that was quarantined as its store opened, where it was moved
+/// to, and what was wrong with it.
+type Quarantined = (PathBuf, PathBuf, Error);
+
 /// Creates a store directory at the given `path` if one does not already exist.
 ///
-/// If one does, it returns the existing segment files to seed the [`Store`].
-fn initialize_store_at_path(path: &PathBuf) -> Result<VecDeque<PathBuf>, io::Error> {
-    if !path.exists() {
+/// If one does, it opens the live segment files listed in the manifest, oldest
+/// first, to seed the [`Store`], spread over `args.open_threads` threads, and
+/// read through `io`. Either way, the store must be
+/// encrypted with `cipher`, or be unencrypted if that is `None`.
+///
+/// With `args.quarantine_corrupt_segments`, segment files that are too corrupt
+/// to open are quarantined, and returned along with where they were moved to
+/// and what was wrong with them.
+fn initialize_store_at_path(
+    path: &Path,
+    cipher: Option<&Cipher>,
+    io: &Arc<dyn IoBackend>,
+    args: &StoreArgs,
+) -> Result<(SegmentSet, Vec<Quarantined>), Error> {
+    let manifest = if !path.exists() {
         log::info!("no store detected at {path:?}, creating directory");
         create_dir_all(path)?;
-        Ok(VecDeque::new())
+        let manifest = Manifest::new(1, 1, []);
+        manifest.commit(path)?;
+        manifest
     } else {
         log::info!("existing store detected at {path:?}");
-        Ok(std::fs::read_dir(path)?
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let filetype = entry.file_type().ok()?;
-                let filename = entry.file_name();
-                let filename = filename.to_str()?;
-                (filetype.is_file() && is_segment_filename(filename)).then_some(entry)
+        let manifest = match Manifest::load(path)? {
+            Some(manifest) => manifest,
+            None => {
+                log::info!("no manifest found at {path:?}, building one from segment files");
+                let manifest = discover_segments(path)?;
+                manifest.commit(path)?;
+                manifest
+            },
+        };
+        remove_orphaned_files(path, manifest.next_segment_id)?;
+        manifest
+    };
+    let has_data = !manifest.segments.is_empty() || wal::has_records(path)?;
+    encryption::check_key(path, cipher, has_data)?;
+    let open = |entries: &[ManifestEntry]| {
+        entries
+            .iter()
+            .map(|entry| {
+                let path = path.join(segment_filename(entry.id));
+                let prefix_bloom_length = args.prefix_bloom_length;
+                SegmentHandle::open_at_level(
+                    path,
+                    entry.level,
+                    cipher.cloned(),
+                    prefix_bloom_length,
+                    io.clone(),
+                )
+                .map(|segment| {
+                    segment.with_sequence(entry.sequence).with_overwrite_count(entry.overwrites)
+                })
             })
-            .map(|entry| PathBuf::from(entry.path()))
-            .collect())
+            .collect::<Vec<_>>()
+    };
+    let started_at = Instant::now();
+    let threads = args.open_threads.clamp(1, manifest.segments.len().max(1));
+    let opened = if threads == 1 {
+        open(&manifest.segments)
+    } else {
+        // Each thread opens a run of consecutive segments, so that they can be put
+        // back together in order.
+        let chunk_size = manifest.segments.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let chunks: Vec<_> = manifest
+                .segments
+                .chunks(chunk_size)
+                .map(|entries| scope.spawn(move || open(entries)))
+                .collect();
+            let mut opened = Vec::with_capacity(manifest.segments.len());
+            for chunk in chunks {
+                opened.extend(
+                    chunk.join().map_err(|_| anyhow!("a thread opening segments panicked"))?,
+                );
+            }
+            Ok::<_, Error>(opened)
+        })?
+    };
+    let mut handles = VecDeque::with_capacity(opened.len());
+    let mut corrupt = Vec::new();
+    for (entry, segment) in manifest.segments.iter().zip(opened) {
+        match segment {
+            Ok(segment) => handles.push_back(segment),
+            Err(error @ Error::Corruption { .. }) if args.quarantine_corrupt_segments => {
+                corrupt.push((path.join(segment_filename(entry.id)), error));
+            },
+            Err(error) => return Err(error),
+        }
+    }
+    log::info!(
+        "opened {} segment files with {threads} threads in {:?}",
+        handles.len(),
+        started_at.elapsed()
+    );
+    let segments = SegmentSet {
+        handles,
+        next_segment_id: manifest.next_segment_id,
+        wal_start: manifest.wal_start,
+    };
+    // Like in `quarantine_segment`, the files are dropped from the manifest before
+    // they are moved.
+    let mut quarantined = Vec::with_capacity(corrupt.len());
+    if !corrupt.is_empty() {
+        segments.commit(path)?;
+    }
+    for (segment_path, error) in corrupt {
+        let destination = move_to_quarantine(path, &segment_path)?;
+        log::error!(
+            "quarantined {segment_path:?} in {destination:?}, since it is corrupt: {error}"
+        );
+        quarantined.push((segment_path, destination, error));
     }
+    Ok((segments, quarantined))
 }
 
-fn wal_path(store_path: &Path) -> PathBuf {
-    store_path.join("wal.dat")
+/// Remove the files that a flush or compaction which was cut short left behind
+/// in the store directory at `path`: compaction output that was never swapped
+/// in, and segment files from `next_segment_id` on, which were never added to
+/// the manifest. Their entries are still in the live segments or the WAL, and
+/// leaving them would stop their names from being used again.
+///
+/// Other segment files that the manifest doesn't list are left alone, since
+/// those include the originals of segment files that a repair replaced.
+fn remove_orphaned_files(path: &Path, next_segment_id: u32) -> Result<(), io::Error> {
+    let mut removed = false;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let filename = entry.file_name();
+        let Some(filename) = filename.to_str() else {
+            continue;
+        };
+        let orphaned = is_compaction_temp_filename(filename)
+            || segment_id(entry.path()).is_some_and(|id| id >= next_segment_id);
+        if orphaned && entry.file_type()?.is_file() {
+            log::info!("removing {filename}, which was left behind by an interrupted write");
+            remove_file(entry.path())?;
+            removed = true;
+        }
+    }
+    if removed {
+        sync_directory(path)?;
+    }
+    Ok(())
+}
+
+/// Build a manifest from the segment files in the store directory at `path`.
+///
+/// This is only used to upgrade stores that were created before the manifest
+/// existed; once a store has a manifest, its directory listing is never
+/// trusted again.
+fn discover_segments(path: &Path) -> Result<Manifest, io::Error> {
+    let mut ids: Vec<_> = std::fs::read_dir(path)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let filetype = entry.file_type().ok()?;
+            let filename = entry.file_name();
+            let filename = filename.to_str()?;
+            (filetype.is_file() && is_segment_filename(filename)).then_some(entry)
+        })
+        .filter_map(|entry| segment_id(entry.path()))
+        .collect();
+    ids.sort();
+    let next_segment_id = ids.last().map_or(1, |id| id + 1);
+    let entries = ids.into_iter().map(|id| ManifestEntry {
+        id,
+        level: 0,
+        sequence: u64::from(id),
+        overwrites: 0,
+    });
+    Ok(Manifest::new(next_segment_id, 1, entries))
 }
 
-fn open_wal(store_path: &Path) -> Result<File, io::Error> {
-    let path = wal_path(store_path);
-    OpenOptions::new().create(true).append(true).read(true).open(&path)
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::memtable::MemtableArgs;
+    use crate::quarantine::QUARANTINE_DIRECTORY;
+    use crate::test::StoreFixture;
+    use crate::wal::wal_filename;
+
+    fn args() -> StoreArgs {
+        StoreArgs { compaction_enabled: false, ..Default::default() }
+    }
+
+    #[test]
+    fn ignores_segments_missing_from_manifest() {
+        let fixture = StoreFixture::init("./test-db-store-manifest");
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        memtable.set("a", "1");
+        store.write_memtable(&memtable).unwrap();
+        store.stop().unwrap();
+
+        let mut stray = File::create(fixture.path().join(segment_filename(99))).unwrap();
+        segment::write(&mut stray, "a", "stray").unwrap();
+
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        assert_eq!(store.list_segments().unwrap(), [fixture.path().join(segment_filename(1))]);
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn removes_orphaned_files() {
+        let fixture = StoreFixture::init("./test-db-store-orphaned-files");
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        memtable.set("a", "1");
+        store.write_memtable(&memtable).unwrap();
+        store.stop().unwrap();
+
+        // An interrupted compaction's output, before and after it was renamed, and the
+        // name that older versions wrote it to.
+        let orphans = ["compaction-2.tmp", &segment_filename(2), "new-segment.dat"];
+        for orphan in orphans {
+            File::create(fixture.path().join(orphan)).unwrap();
+        }
+        File::create(fixture.path().join("compaction-notes.txt")).unwrap();
+
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        assert!(orphans.iter().all(|orphan| !fixture.path().join(orphan).exists()));
+        assert!(fixture.path().join("compaction-notes.txt").exists());
+        // The segment id is free to be used again.
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        memtable.set("b", "1");
+        store.write_memtable(&memtable).unwrap();
+        assert_eq!(store.get("b").unwrap().as_deref(), Some("1"));
+        store.stop().unwrap();
+    }
+
+    #[test]
+    fn quarantines_corrupt_segments() {
+        let fixture = StoreFixture::init("./test-db-store-quarantine");
+        let quarantining = || StoreArgs { quarantine_corrupt_segments: true, ..args() };
+        let flush = |store: &Store, value: &str| {
+            let mut memtable = Memtable::new(MemtableArgs::default());
+            memtable.set("a", value);
+            store.write_memtable(&memtable).unwrap();
+        };
+        let corrupt = |id| {
+            let path = fixture.path().join(segment_filename(id));
+            let mut contents = fs::read(&path).unwrap();
+            contents[0] = 9;
+            fs::write(path, contents).unwrap();
+        };
+        let quarantined = |id| fixture.path().join(QUARANTINE_DIRECTORY).join(segment_filename(id));
+
+        // Damage that a read runs into.
+        let store = Store::new(fixture.path().to_owned(), quarantining()).unwrap();
+        flush(&store, "1");
+        flush(&store, "2");
+        corrupt(2);
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(store.metrics().quarantined_segments.get(), 1);
+        assert_eq!(store.list_segments().unwrap().len(), 1);
+        assert!(quarantined(2).exists());
+        flush(&store, "3");
+        store.stop().unwrap();
+
+        // Damage that stops a segment file from being opened.
+        corrupt(3);
+        assert!(Store::new(fixture.path().to_owned(), args()).is_err());
+        let store = Store::new(fixture.path().to_owned(), quarantining()).unwrap();
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(store.metrics().quarantined_segments.get(), 1);
+        assert!(quarantined(3).exists());
+        store.stop().unwrap();
+        let store = Store::new(fixture.path().to_owned(), quarantining()).unwrap();
+        assert_eq!(store.list_segments().unwrap().len(), 1);
+        store.stop().unwrap();
+    }
+
+    #[test]
+    fn flush_wakes_compactor_under_pressure() {
+        let fixture = StoreFixture::init("./test-db-store-compaction-trigger");
+        let store = Store::new(fixture.path().to_owned(), StoreArgs {
+            compaction_interval: Duration::from_secs(3600),
+            compaction_trigger_segment_count: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        for key in ["a", "b"] {
+            let mut memtable = Memtable::new(MemtableArgs::default());
+            memtable.set(key, "1");
+            store.write_memtable(&memtable).unwrap();
+        }
+
+        let start = Instant::now();
+        while store.compaction_stats().unwrap().compactions == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10), "compactor never woke up");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(store.list_segments().unwrap().len(), 1);
+        let history = store.compaction_stats().unwrap();
+        assert_eq!(history.compactions, 1);
+        assert_eq!(history.totals.input_files, 2);
+        assert_eq!(history.totals.bytes_written, 22);
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.segment_count(), 1);
+        assert_eq!(stats.last_compaction, history.last_finished_at);
+        assert!(stats.last_compaction.is_some());
+        // Both flushed entries were written again by the compaction.
+        assert_eq!((stats.flushed_bytes, stats.compacted_bytes), (22, 22));
+        assert_eq!(stats.write_amplification(), Some(1.0));
+        store.stop().unwrap();
+    }
+
+    #[test]
+    fn estimated_dead_ratio() {
+        let fixture = StoreFixture::init("./test-db-store-dead-ratio");
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        assert_eq!(store.stats().unwrap().estimated_dead_ratio(), None);
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        for key in ["a", "b", "c"] {
+            memtable.set(key, "1");
+        }
+        store.write_memtable(&memtable).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        memtable.delete("a");
+        memtable.set("b", "2");
+        memtable.set("d", "1");
+        store.write_memtable(&memtable).unwrap();
+
+        // The tombstone, and the values of "a" and "b" that are shadowed, are dead
+        // out of six entries.
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.segments.iter().map(|segment| segment.tombstone_count).sum::<u32>(), 1);
+        assert_eq!(stats.segments[1].overwrite_count, 2);
+        assert_eq!(stats.estimated_dead_ratio(), Some(0.5));
+        assert_eq!(stats.space_amplification(), Some(2.0));
+        assert_eq!(stats.last_compaction, None);
+        assert_eq!(stats.write_amplification(), Some(0.0));
+
+        // "b" is found in the newest segment file and "c" in the oldest, while "e"
+        // is past the keys of both.
+        assert_eq!(stats.read_amplification(), None);
+        for key in ["b", "c", "e"] {
+            store.get(key).unwrap();
+        }
+        assert_eq!(store.stats().unwrap().read_amplification(), Some(2.0 / 3.0));
+        store.stop().unwrap();
+
+        // The overwrites are kept in the manifest.
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        assert_eq!(store.stats().unwrap().segments[1].overwrite_count, 2);
+        store.stop().unwrap();
+    }
+
+    #[test]
+    fn range_pins_compacted_segments() {
+        let fixture = StoreFixture::init("./test-db-store-range-pins");
+        let store = Store::new(fixture.path().to_owned(), StoreArgs {
+            compaction_interval: Duration::from_secs(3600),
+            compaction_trigger_segment_count: 3,
+            ..Default::default()
+        })
+        .unwrap();
+        for key in ["a", "b"] {
+            let mut memtable = Memtable::new(MemtableArgs::default());
+            memtable.set(key, "1");
+            store.write_memtable(&memtable).unwrap();
+        }
+        let pinned = store.list_segments().unwrap();
+        let mut range = store.range("").unwrap();
+        assert_eq!(range.next().unwrap().unwrap(), ("a".to_owned(), Some("1".to_owned())));
+
+        // This flush wakes the compactor, which merges all three segments.
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        memtable.set("c", "1");
+        store.write_memtable(&memtable).unwrap();
+        let start = Instant::now();
+        while store.compaction_stats().unwrap().compactions == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10), "compactor never woke up");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!fixture.path().join(segment_filename(3)).exists());
+        assert!(pinned.iter().all(|path| path.exists()));
+
+        // The range only covers the segments that were live when it was created.
+        assert_eq!(range.next().unwrap().unwrap(), ("b".to_owned(), Some("1".to_owned())));
+        assert!(range.next().is_none());
+        drop(range);
+        assert!(pinned.iter().all(|path| !path.exists()));
+        store.stop().unwrap();
+    }
+
+    #[test]
+    fn prefix_range_skips_segments() {
+        let fixture = StoreFixture::init("./test-db-store-prefix-range");
+        let store =
+            Store::new(fixture.path().to_owned(), StoreArgs { prefix_bloom_length: 5, ..args() })
+                .unwrap();
+        for keys in [["user:1", "user:3"], ["user:2", "zone:1"], ["temp:1", "zone:2"]] {
+            let mut memtable = Memtable::new(MemtableArgs::default());
+            for key in keys {
+                memtable.set(key, "1");
+            }
+            store.write_memtable(&memtable).unwrap();
+        }
+        // The last segment's key range covers the prefix, but its filter doesn't.
+        let range = store.prefix_range("user:").unwrap();
+        assert_eq!(range.files.len(), 2);
+        let keys: Vec<_> = range.map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys[..3], ["user:1", "user:2", "user:3"]);
+        assert_eq!(store.prefix_range("zone:").unwrap().files.len(), 2);
+        assert_eq!(store.prefix_range("a").unwrap().files.len(), 0);
+        store.stop().unwrap();
+    }
+
+    #[test]
+    fn opens_segments_in_parallel() {
+        let fixture = StoreFixture::init("./test-db-store-open-threads");
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        for value in ["1", "2", "3", "4", "5"] {
+            let mut memtable = Memtable::new(MemtableArgs::default());
+            memtable.set("a", value);
+            memtable.set(format!("b{value}"), value);
+            store.write_memtable(&memtable).unwrap();
+        }
+        let segments = store.list_segments().unwrap();
+        store.stop().unwrap();
+
+        let store =
+            Store::new(fixture.path().to_owned(), StoreArgs { open_threads: 3, ..args() }).unwrap();
+        assert_eq!(store.list_segments().unwrap(), segments);
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("5"));
+        assert_eq!(store.get("b2").unwrap().as_deref(), Some("2"));
+        store.stop().unwrap();
+    }
+
+    #[test]
+    fn flush_removes_only_flushed_wal_files() {
+        let fixture = StoreFixture::init("./test-db-store-wal-rotation");
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        // The write is still waiting to be committed, which the flush does first.
+        _ = store.set("a", "1", None).unwrap();
+        memtable.set("a", "1");
+        store.write_memtable(&memtable).unwrap();
+        store.commit(store.set("b", "2", None).unwrap()).unwrap();
+        store.stop().unwrap();
+
+        assert!(!fixture.path().join(wal_filename(1)).exists());
+        assert!(fixture.path().join(wal_filename(2)).exists());
+
+        let store = Store::new(fixture.path().to_owned(), args()).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        store.replay_wal(&mut memtable).unwrap();
+        assert_eq!(memtable.get("a"), None);
+        assert_eq!(memtable.get("b"), Some(Some("2".into())));
+    }
 }
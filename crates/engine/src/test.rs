@@ -15,22 +15,27 @@ impl StoreFixture {
         Self { path: PathBuf::from(path.as_ref()), segment_file_count: 0 }
     }
 
-    /// Create a new segment file with the given `pairs` as its data.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Create a new segment file with the given `pairs` as its data, and
+    /// return its path.
     ///
     /// This function will sort the pairs in ascending lexicographical order by
     /// key before it writes them.
-    pub fn create_segment_file(
+    pub fn write_segment_file(
         &mut self,
         pairs: impl IntoIterator<Item = (&'static str, &'static str)>,
-    ) -> File {
+    ) -> PathBuf {
         let path = self.allocate_segment_file();
-        let mut file = File::create_new(path).unwrap();
+        let mut file = File::create_new(&path).unwrap();
         let mut pairs: Vec<_> = pairs.into_iter().collect();
         pairs.sort_by_key(|pair| pair.0);
         pairs
             .into_iter()
             .for_each(|(key, value)| crate::segment::write(&mut file, key, value).unwrap());
-        file
+        path
     }
 
     /// Allocate an ID for a new file in the store and return its path.
@@ -1,4 +1,8 @@
 // TODO: The assignment code can probably move to the repl crate.
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::{env, io};
+
 use anyhow::{anyhow, Result};
 
 pub struct Assignment<'a> {
@@ -14,3 +18,39 @@ impl<'a> Assignment<'a> {
         Ok(Assignment { key, value })
     }
 }
+
+/// Flush the metadata of the directory at `path` to disk.
+///
+/// Creating, renaming, or deleting a file only changes the directory entry, so
+/// that change is only durable once the directory itself has been synced.
+pub fn sync_directory(path: &Path) -> Result<(), io::Error> {
+    File::open(path)?.sync_all()
+}
+
+/// A uniquely named directory in the system's temporary directory, which is
+/// removed along with everything in it when this is dropped.
+#[derive(Debug)]
+pub struct TempDirectory(PathBuf);
+
+impl TempDirectory {
+    pub fn new() -> Result<Self, io::Error> {
+        loop {
+            let path = env::temp_dir().join(format!("crunch-{:016x}", rand::random::<u64>()));
+            match fs::create_dir(&path) {
+                Ok(()) => return Ok(Self(path)),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDirectory {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.0);
+    }
+}
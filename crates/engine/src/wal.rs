@@ -0,0 +1,1140 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use crunch_common::env::FromEnv;
+
+use crate::batch::WriteBatch;
+use crate::encryption::Cipher;
+use crate::error::Error;
+use crate::io::{IoBackend, StdIo};
+use crate::memtable::Memtable;
+use crate::metrics::Metrics;
+use crate::segment::{self, Compression, Entry, EntryIter};
+use crate::util::sync_directory;
+
+/// The filename that the WAL used before it was split into numbered files.
+const LEGACY_WAL_FILENAME: &str = "wal.dat";
+
+/// Marks the start of a [`WriteBatch`] in a WAL file. It is followed by the
+/// length of the batch's encoded entries as a u32, and then the entries.
+///
+/// This doesn't clash with the indicators of individual entries, which are
+/// written to the WAL as is.
+const BATCH_INDICATOR: u8 = 3;
+
+/// The write-ahead log, split across numbered files in the store directory.
+///
+/// Records are only ever appended to the *active* file, which is the one with
+/// the highest id. Once it grows past the configured size cap, a new active
+/// file is started. Older files are kept around until their contents have been
+/// flushed to a segment file, at which point they can be removed.
+///
+/// Appends can be made from many threads at once, and are *group committed*:
+/// records that arrive while a write is in flight are buffered, and the next
+/// write persists all of them with a single write and fsync.
+pub struct Wal {
+    directory: PathBuf,
+    max_size: u64,
+
+    /// Whether to fsync the active file after every write to it.
+    sync: bool,
+
+    active: Mutex<ActiveFile>,
+    queue: Mutex<CommitQueue>,
+
+    /// Notified every time a batch of records has been committed.
+    committed: Condvar,
+    metrics: Arc<Metrics>,
+    recovery_mode: RecoveryMode,
+
+    /// Seals every write, if the store is encrypted.
+    cipher: Option<Cipher>,
+    compression: Option<Compression>,
+
+    /// What the active file is appended to with.
+    io: Arc<dyn IoBackend>,
+}
+
+/// What [`Wal::replay`] does with a record that is complete but can't be
+/// decoded. An incomplete record at the end of a file is always discarded,
+/// since that is left behind by a crash partway through a write.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RecoveryMode {
+    /// Fail with an [`Error::Corruption`], so the store doesn't open.
+    #[default]
+    Strict,
+
+    /// Truncate the WAL at the corrupt record, dropping it and everything
+    /// written after it, and replay what comes before.
+    Salvage,
+}
+
+impl FromEnv for RecoveryMode {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        match value.to_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "salvage" => Ok(Self::Salvage),
+            _ => Err(anyhow!("unknown WAL recovery mode {value:?}")),
+        }
+    }
+}
+
+/// How often [`Wal::replay`] logs how far it has got.
+const REPLAY_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What [`Wal::replay`] found in the WAL.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RecoveryReport {
+    /// The number of WAL files that were read.
+    pub files: usize,
+
+    /// The number of entries replayed into the memtable, counting each entry
+    /// of a batch.
+    pub records: u64,
+
+    /// The combined size of the WAL files that were read, in bytes.
+    pub bytes: u64,
+
+    pub duration: Duration,
+
+    /// The writes that a file ended partway through, which were discarded.
+    pub incomplete_tails: Vec<IncompleteTail>,
+
+    /// What was dropped, if a corrupt record was salvaged.
+    pub salvage: Option<Salvage>,
+}
+
+/// A write at the end of a WAL file that was cut short, most likely by a
+/// crash, and so never committed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IncompleteTail {
+    pub file: PathBuf,
+
+    /// Where the write starts in the file.
+    pub offset: u64,
+
+    /// The number of bytes of it that were written.
+    pub bytes: u64,
+}
+
+/// What a replay in [`RecoveryMode::Salvage`] dropped from the WAL.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Salvage {
+    /// The WAL file holding the corrupt record, which was truncated at
+    /// `offset`.
+    pub file: PathBuf,
+    pub offset: u64,
+
+    /// Why the record couldn't be read.
+    pub reason: String,
+
+    /// The later WAL files, which were removed.
+    pub removed_files: Vec<PathBuf>,
+
+    /// The number of bytes dropped, across the truncated file and the removed
+    /// ones.
+    pub dropped_bytes: u64,
+}
+
+struct ActiveFile {
+    file: File,
+    id: u32,
+    size: u64,
+}
+
+/// A record that has been added to the WAL's current batch, but isn't durable
+/// until [`Wal::commit`] returns for it.
+#[derive(Debug)]
+#[must_use = "a pending record isn't durable until it is committed"]
+pub struct Pending {
+    batch: u64,
+}
+
+impl Pending {
+    /// Stands in for a write that has nothing to commit, like one to a store
+    /// without a WAL.
+    pub fn none() -> Self {
+        Self { batch: 0 }
+    }
+}
+
+/// Records waiting to be written to the WAL.
+///
+/// Records are grouped into numbered batches. Writers append to the batch in
+/// `buffer` and then wait for it to be committed. Whichever writer finds no
+/// write in flight becomes the *leader*, taking the buffer and writing it out
+/// on behalf of every writer in the batch.
+#[derive(Default)]
+struct CommitQueue {
+    buffer: Vec<u8>,
+
+    /// The number of the batch that is being collected in `buffer`.
+    batch: u64,
+
+    /// The number of the last batch that was written out.
+    committed_batch: u64,
+
+    /// The number of the last batch that failed to be written out.
+    failed_batch: Option<u64>,
+
+    /// Whether a leader is currently writing a batch out.
+    writing: bool,
+}
+
+impl Wal {
+    /// Open the WAL in the store at `directory`.
+    ///
+    /// Any WAL file with an id lower than `start` is considered flushed, and
+    /// is removed.
+    pub fn open(directory: &Path, start: u32, max_size: u64, sync: bool) -> Result<Self, Error> {
+        migrate_legacy_wal(directory, start)?;
+        for id in wal_ids(directory)?.into_iter().filter(|id| *id < start) {
+            log::debug!("removing flushed WAL file {}", wal_filename(id));
+            fs::remove_file(directory.join(wal_filename(id)))?;
+        }
+        let id = wal_ids(directory)?.last().copied().unwrap_or(start).max(start);
+        let file = open_wal_file(directory, id)?;
+        sync_directory(directory)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            directory: directory.to_owned(),
+            max_size,
+            sync,
+            active: Mutex::new(ActiveFile { file, id, size }),
+            queue: Mutex::new(CommitQueue { batch: 1, ..Default::default() }),
+            committed: Condvar::new(),
+            metrics: Arc::default(),
+            recovery_mode: RecoveryMode::default(),
+            cipher: None,
+            compression: None,
+            io: Arc::new(StdIo),
+        })
+    }
+
+    /// Encrypt the WAL with `cipher`, if one is given. Each committed write
+    /// is sealed as a frame of its own.
+    pub fn with_cipher(mut self, cipher: Option<Cipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Compress the values of records according to `compression`.
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Append to the active file through `io`.
+    pub fn with_io(mut self, io: Arc<dyn IoBackend>) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// Handle corrupt records in [`Self::replay`] according to `mode`.
+    pub fn with_recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// Count the bytes appended to the WAL in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Append a `key`:`value` pair to the WAL, as the version written at
+    /// `sequence` if one is given.
+    pub fn set(&self, key: &str, value: &str, sequence: Option<u64>) -> Result<(), Error> {
+        let pending = self.enqueue_set(key, value, sequence)?;
+        self.commit(pending)
+    }
+
+    /// Like [`Self::set`], returning once the record is in the current batch,
+    /// rather than once it is committed.
+    pub fn enqueue_set(
+        &self,
+        key: &str,
+        value: &str,
+        sequence: Option<u64>,
+    ) -> Result<Pending, Error> {
+        let mut record = Vec::new();
+        if let Some(sequence) = sequence {
+            segment::write_version(&mut record, sequence)?;
+        }
+        segment::write_with(&mut record, key, value, self.compression)?;
+        self.enqueue(&record)
+    }
+
+    /// Append a tombstone for `key` to the WAL, as the version written at
+    /// `sequence` if one is given.
+    pub fn delete(&self, key: &str, sequence: Option<u64>) -> Result<(), Error> {
+        let pending = self.enqueue_delete(key, sequence)?;
+        self.commit(pending)
+    }
+
+    /// Like [`Self::delete`], returning once the record is in the current
+    /// batch, rather than once it is committed.
+    pub fn enqueue_delete(&self, key: &str, sequence: Option<u64>) -> Result<Pending, Error> {
+        let mut record = Vec::new();
+        if let Some(sequence) = sequence {
+            segment::write_version(&mut record, sequence)?;
+        }
+        segment::tombstone(&mut record, key)?;
+        self.enqueue(&record)
+    }
+
+    /// Append every write in `batch` to the WAL, as a single record. Each of
+    /// them is a version written at `sequence`, if one is given.
+    ///
+    /// If the record is only partially written when the process dies, none of
+    /// the batch is replayed.
+    pub fn write(&self, batch: &WriteBatch, sequence: Option<u64>) -> Result<(), Error> {
+        let pending = self.enqueue_write(batch, sequence)?;
+        self.commit(pending)
+    }
+
+    /// Like [`Self::write`], returning once the record is in the current
+    /// batch, rather than once it is committed.
+    pub fn enqueue_write(
+        &self,
+        batch: &WriteBatch,
+        sequence: Option<u64>,
+    ) -> Result<Pending, Error> {
+        let mut entries = Vec::new();
+        for entry in batch.entries() {
+            if let Some(sequence) = sequence {
+                segment::write_version(&mut entries, sequence)?;
+            }
+            entry.write_with(&mut entries, self.compression)?;
+        }
+        let size = u32::try_from(entries.len())
+            .map_err(|_| anyhow!("write batch of {} bytes is too large", entries.len()))?;
+        let mut record = Vec::with_capacity(entries.len() + 5);
+        record.push(BATCH_INDICATOR);
+        record.extend(size.to_be_bytes());
+        record.extend(entries);
+        self.enqueue(&record)
+    }
+
+    /// The combined size of every WAL file, in bytes.
+    pub fn size(&self) -> Result<u64, Error> {
+        let mut size = 0;
+        for id in wal_ids(&self.directory)? {
+            size += fs::metadata(self.directory.join(wal_filename(id)))?.len();
+        }
+        Ok(size)
+    }
+
+    /// The filename and contents of every WAL file with an id of at least
+    /// `start`, oldest first.
+    pub fn read_from(&self, start: u32) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        // Holding this keeps appends out while the files are read.
+        let _active = self.active.lock()?;
+        let mut files = Vec::new();
+        for id in wal_ids(&self.directory)?.into_iter().filter(|id| *id >= start) {
+            let filename = wal_filename(id);
+            let contents = fs::read(self.directory.join(&filename))?;
+            files.push((filename, contents));
+        }
+        Ok(files)
+    }
+
+    /// Start a new active file, and return its id.
+    ///
+    /// Records added before this call that are still waiting to be committed
+    /// are committed first, so every record added before it lives in a file
+    /// with a lower id than the one returned.
+    pub fn rotate(&self) -> Result<u32, Error> {
+        let (batch, empty) = {
+            let queue = self.queue.lock()?;
+            (queue.batch, queue.buffer.is_empty())
+        };
+        if empty {
+            // There is nothing to commit, but the batch before may still be in flight. Its
+            // writers hear whether it failed.
+            _ = self.commit(Pending { batch: batch - 1 });
+        } else {
+            self.commit(Pending { batch })?;
+        }
+        let mut active = self.active.lock()?;
+        self.rotate_active(&mut active)?;
+        Ok(active.id)
+    }
+
+    /// Remove every WAL file with an id lower than `id`.
+    ///
+    /// Only call this once the contents of those files are durable elsewhere.
+    pub fn remove_before(&self, id: u32) -> Result<(), Error> {
+        let ids: Vec<_> =
+            wal_ids(&self.directory)?.into_iter().filter(|wal_id| *wal_id < id).collect();
+        for id in &ids {
+            fs::remove_file(self.directory.join(wal_filename(*id)))?;
+            log::debug!("removed flushed WAL file {}", wal_filename(*id));
+        }
+        // Otherwise a crash could bring the files back, to be replayed over
+        // segments that already hold their writes, undoing any newer ones.
+        if !ids.is_empty() {
+            sync_directory(&self.directory)?;
+        }
+        Ok(())
+    }
+
+    /// Seed the `memtable` with the contents of the WAL, oldest record first,
+    /// and report what was replayed. How far the replay has got is logged as
+    /// it goes, since a large WAL can take a while.
+    ///
+    /// A corrupt record is handled according to the WAL's [`RecoveryMode`].
+    /// When it is salvaged, what was dropped is in the report.
+    pub fn replay(&self, memtable: &mut Memtable) -> Result<RecoveryReport, Error> {
+        let started_at = Instant::now();
+        let mut sizes = Vec::new();
+        for id in wal_ids(&self.directory)? {
+            sizes.push((id, fs::metadata(self.directory.join(wal_filename(id)))?.len()));
+        }
+        let mut report = RecoveryReport {
+            files: sizes.len(),
+            bytes: sizes.iter().map(|(_, size)| size).sum(),
+            ..RecoveryReport::default()
+        };
+        let size = |id| sizes.iter().find(|(file, _)| *file == id).map_or(0, |(_, size)| *size);
+        // The sizes of the files before the one being read.
+        let mut read_before = 0;
+        let mut current = None;
+        let mut last_logged_at = started_at;
+        let mut corruption = None;
+        self.walk(|id, position, record| {
+            if current != Some(id) {
+                read_before += current.map_or(0, size);
+                current = Some(id);
+            }
+            if last_logged_at.elapsed() >= REPLAY_PROGRESS_INTERVAL {
+                log::info!(
+                    "replaying the WAL: {} of {} bytes read, {} records replayed",
+                    read_before + position,
+                    report.bytes,
+                    report.records,
+                );
+                last_logged_at = Instant::now();
+            }
+            let tail = |position| IncompleteTail {
+                file: self.directory.join(wal_filename(id)),
+                offset: position,
+                bytes: size(id).saturating_sub(position),
+            };
+            match record {
+                WalRecord::Entry(entry, sequence) => {
+                    replay_entry(memtable, entry, sequence);
+                    report.records += 1;
+                },
+                WalRecord::Batch(entries, sequence) => {
+                    report.records += entries.len() as u64;
+                    entries.into_iter().for_each(|entry| replay_entry(memtable, entry, sequence))
+                },
+                WalRecord::IncompleteBatch => {
+                    log::warn!(
+                        "discarding incomplete write batch at the end of {}",
+                        wal_filename(id)
+                    );
+                    report.incomplete_tails.push(tail(position));
+                },
+                WalRecord::IncompleteEntry | WalRecord::IncompleteFrame => {
+                    log::warn!("discarding incomplete write at the end of {}", wal_filename(id));
+                    report.incomplete_tails.push(tail(position));
+                },
+                WalRecord::Corrupt { reason } => corruption = Some((id, position, reason)),
+            }
+        })?;
+        report.salvage = match corruption {
+            Some((id, offset, reason)) => Some(self.recover(id, offset, reason)?),
+            None => None,
+        };
+        report.duration = started_at.elapsed();
+        if report.files > 0 {
+            log::info!(
+                "replayed {} records from {} WAL files ({} bytes) in {:?}",
+                report.records,
+                report.files,
+                report.bytes,
+                report.duration,
+            );
+        }
+        Ok(report)
+    }
+
+    /// Deal with the corrupt record at `offset` in the WAL file with `id`
+    /// according to the WAL's [`RecoveryMode`].
+    fn recover(&self, id: u32, offset: u64, reason: String) -> Result<Salvage, Error> {
+        let file = self.directory.join(wal_filename(id));
+        match self.recovery_mode {
+            RecoveryMode::Strict => Err(Error::Corruption { file, offset, reason }),
+            RecoveryMode::Salvage => {
+                let salvage = self.salvage(id, offset, reason)?;
+                log::warn!(
+                    "salvaged the WAL by truncating {} at byte {offset} ({}), removing {} later \
+                     files and dropping {} bytes in all",
+                    wal_filename(id),
+                    salvage.reason,
+                    salvage.removed_files.len(),
+                    salvage.dropped_bytes,
+                );
+                Ok(salvage)
+            },
+        }
+    }
+
+    /// Truncate the WAL file with `id` at `offset` and remove every file after
+    /// it, leaving the truncated file as the active one.
+    fn salvage(&self, id: u32, offset: u64, reason: String) -> Result<Salvage, Error> {
+        let mut active = self.active.lock()?;
+        let file = self.directory.join(wal_filename(id));
+        let truncated = open_wal_file(&self.directory, id)?;
+        let mut dropped_bytes = truncated.metadata()?.len() - offset;
+        truncated.set_len(offset)?;
+        truncated.sync_all()?;
+
+        let mut removed_files = Vec::new();
+        for later in wal_ids(&self.directory)?.into_iter().filter(|later| *later > id) {
+            let path = self.directory.join(wal_filename(later));
+            dropped_bytes += fs::metadata(&path)?.len();
+            fs::remove_file(&path)?;
+            removed_files.push(path);
+        }
+        if !removed_files.is_empty() {
+            sync_directory(&self.directory)?;
+        }
+        *active = ActiveFile { file: truncated, id, size: offset };
+        Ok(Salvage { file, offset, reason, removed_files, dropped_bytes })
+    }
+
+    /// Print every record in the WAL, oldest first, along with the file and
+    /// offset that it is at. This reads the same records that [`Self::replay`]
+    /// would, without changing anything.
+    pub fn inspect(&self) -> Result<(), Error> {
+        self.walk(|id, offset, record| {
+            let location = format!("{} @ {offset}", wal_filename(id));
+            match record {
+                WalRecord::Entry(entry, sequence) => {
+                    println!("{location}: {}{}", describe(&entry), describe_version(sequence))
+                },
+                WalRecord::Batch(entries, sequence) => {
+                    let version = describe_version(sequence);
+                    println!("{location}: batch of {} entries{version}", entries.len());
+                    entries.iter().for_each(|entry| println!("    {}", describe(entry)));
+                },
+                WalRecord::IncompleteBatch => {
+                    println!("{location}: incomplete batch, which won't be replayed")
+                },
+                WalRecord::IncompleteEntry | WalRecord::IncompleteFrame => {
+                    println!("{location}: incomplete write, which won't be replayed")
+                },
+                WalRecord::Corrupt { reason } => println!("{location}: corrupt record, {reason}"),
+            }
+        })
+    }
+
+    /// Call `visit` with each record in the WAL, oldest first, along with the
+    /// id of the file that it is in and its offset in that file.
+    ///
+    /// Reading a file stops at the first record that is incomplete, since
+    /// that is left behind by a crash partway through a write. Reading stops
+    /// altogether at the first record that is complete but can't be decoded,
+    /// since the records after it can't be trusted to line up.
+    fn walk(&self, visit: impl FnMut(u32, u64, WalRecord)) -> Result<(), Error> {
+        walk_files(&self.directory, 0, self.cipher.as_ref(), visit)
+    }
+
+    /// Add an encoded `record` to the current batch. Records are written out in
+    /// the order that they are added.
+    fn enqueue(&self, record: &[u8]) -> Result<Pending, Error> {
+        let mut queue = self.queue.lock()?;
+        queue.buffer.extend_from_slice(record);
+        Ok(Pending { batch: queue.batch })
+    }
+
+    /// Return once the batch that `pending` was added to has been committed,
+    /// writing it out if no other writer is.
+    ///
+    /// Writers that add their records one after another, and only then commit
+    /// them, share a write and fsync.
+    pub fn commit(&self, pending: Pending) -> Result<(), Error> {
+        let Pending { batch } = pending;
+        let mut queue = self.queue.lock()?;
+        loop {
+            if queue.committed_batch >= batch {
+                return match queue.failed_batch {
+                    Some(failed) if failed == batch => {
+                        Err(Error::General(anyhow!("failed to commit WAL batch {batch}")))
+                    },
+                    _ => Ok(()),
+                };
+            }
+            if !queue.writing {
+                // No write is in flight, so this writer leads the batch. Any record that
+                // arrives while it writes goes into the next batch.
+                queue.writing = true;
+                queue.batch += 1;
+                let buffer = mem::take(&mut queue.buffer);
+                drop(queue);
+
+                let result = self.write_batch(&buffer);
+                log::trace!("committed WAL batch {batch} ({} bytes)", buffer.len());
+
+                let mut queue = self.queue.lock()?;
+                queue.writing = false;
+                queue.committed_batch = batch;
+                if result.is_err() {
+                    queue.failed_batch = Some(batch);
+                }
+                self.committed.notify_all();
+                return result;
+            }
+            queue = self.committed.wait(queue)?;
+        }
+    }
+
+    fn write_batch(&self, buffer: &[u8]) -> Result<(), Error> {
+        let mut active = self.active.lock()?;
+        if active.size >= self.max_size {
+            self.rotate_active(&mut active)?;
+        }
+        let frame;
+        let buffer = match &self.cipher {
+            Some(cipher) => {
+                let sealed = cipher.seal(&frame_associated_data(active.id, active.size), buffer)?;
+                frame = [&(sealed.len() as u32).to_be_bytes()[..], &sealed].concat();
+                &frame
+            },
+            None => buffer,
+        };
+        self.io.write_all_at(&active.file, buffer, active.size, self.sync)?;
+        active.size += buffer.len() as u64;
+        self.metrics.wal_bytes.add(buffer.len() as u64);
+        self.metrics.wal_batches.increment();
+        Ok(())
+    }
+
+    fn rotate_active(&self, active: &mut ActiveFile) -> Result<(), Error> {
+        let id = active.id + 1;
+        let file = open_wal_file(&self.directory, id)?;
+        // Writes to the new file are only durable once the file itself is.
+        sync_directory(&self.directory)?;
+        *active = ActiveFile { file, id, size: 0 };
+        log::debug!("rotated WAL to {}", wal_filename(id));
+        Ok(())
+    }
+}
+
+/// A record read back from the WAL by [`Wal::walk`].
+#[derive(Debug, Eq, PartialEq)]
+enum WalRecord {
+    /// An entry, along with the sequence number it was written at, if it is a
+    /// version.
+    Entry(Entry, Option<u64>),
+
+    /// The entries of a batch, along with the sequence number they were all
+    /// written at, if they are versions.
+    Batch(Vec<Entry>, Option<u64>),
+
+    /// A batch that the file ends partway through, which was never committed.
+    IncompleteBatch,
+
+    /// An entry that the file ends partway through, which was never committed.
+    IncompleteEntry,
+
+    /// An encrypted write that the file ends partway through, which was never
+    /// committed.
+    IncompleteFrame,
+
+    /// A complete record that can't be decoded. Nothing is read after it.
+    Corrupt { reason: String },
+}
+
+fn describe(entry: &Entry) -> String {
+    match entry {
+        Entry::Assignment { key, value } => format!("set {key} = {value}"),
+        Entry::Tombstone { key } => format!("delete {key}"),
+    }
+}
+
+fn describe_version(sequence: Option<u64>) -> String {
+    sequence.map(|sequence| format!(", at version {sequence}")).unwrap_or_default()
+}
+
+fn replay_entry(memtable: &mut Memtable, entry: Entry, sequence: Option<u64>) {
+    match (entry, sequence) {
+        (Entry::Assignment { key, value }, Some(sequence)) => {
+            memtable.write_version(&key, Some(value.into()), sequence)
+        },
+        (Entry::Tombstone { key }, Some(sequence)) => memtable.write_version(&key, None, sequence),
+        (Entry::Assignment { key, value }, None) => memtable.set(key, value),
+        (Entry::Tombstone { key }, None) => memtable.delete(&key),
+    };
+}
+
+/// Call `visit` with each record in the WAL files in `directory` with an id of
+/// at least `start`, like [`Wal::walk`].
+fn walk_files(
+    directory: &Path,
+    start: u32,
+    cipher: Option<&Cipher>,
+    mut visit: impl FnMut(u32, u64, WalRecord),
+) -> Result<(), Error> {
+    for id in wal_ids(directory)?.into_iter().filter(|id| *id >= start) {
+        let path = directory.join(wal_filename(id));
+        let mut file = File::open(&path)?;
+        let mut visit = |position, record| visit(id, position, record);
+        let stopped = match cipher {
+            Some(cipher) => walk_frames(&mut file, &path, id, cipher, visit)?,
+            None => walk_records(&mut file, &path, &mut visit)?,
+        };
+        if stopped {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Read through the WAL files in `directory` with an id of at least `start`,
+/// without changing them, and return the number of files along with the first
+/// complete record that can't be decoded, if there is one.
+pub fn check(
+    directory: &Path,
+    start: u32,
+    cipher: Option<&Cipher>,
+) -> Result<(usize, Option<Error>), Error> {
+    let files = wal_ids(directory)?.into_iter().filter(|id| *id >= start).count();
+    let mut corruption = None;
+    walk_files(directory, start, cipher, |id, offset, record| {
+        if let WalRecord::Corrupt { reason } = record {
+            let file = directory.join(wal_filename(id));
+            corruption = Some(Error::Corruption { file, offset, reason });
+        }
+    })?;
+    Ok((files, corruption))
+}
+
+/// Call `visit` with each record in `reader`, which is the WAL file at `path`
+/// or the plaintext of one of its encrypted frames, along with its offset.
+/// Returns whether a corrupt record stopped the walk.
+fn walk_records(
+    reader: &mut (impl Read + Seek),
+    path: &Path,
+    visit: &mut impl FnMut(u64, WalRecord),
+) -> Result<bool, Error> {
+    loop {
+        let position = reader.stream_position()?;
+        let mut indicator = [0; 1];
+        match reader.read_exact(&mut indicator) {
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+        if indicator[0] != BATCH_INDICATOR {
+            reader.seek(SeekFrom::Start(position))?;
+            let mut entries = EntryIter::new(reader).with_path(path);
+            match entries.next() {
+                Some(Ok(entry)) => visit(position, WalRecord::Entry(entry, entries.last_version())),
+                Some(Err(_)) if entries.truncated() => {
+                    visit(position, WalRecord::IncompleteEntry);
+                    return Ok(false);
+                },
+                Some(Err(Error::Corruption { reason, .. })) => {
+                    visit(position, WalRecord::Corrupt { reason });
+                    return Ok(true);
+                },
+                Some(Err(error)) => return Err(error),
+                None => return Ok(false),
+            }
+            continue;
+        }
+        let Some(entries) = read_sized(reader)? else {
+            visit(position, WalRecord::IncompleteBatch);
+            return Ok(false);
+        };
+        // The batch was read in full, so an entry that is cut short within it is
+        // corrupt, rather than an interrupted write.
+        let mut reader = Cursor::new(entries);
+        let mut entries = EntryIter::new(&mut reader);
+        let mut batch = Vec::new();
+        let mut sequence = None;
+        let batch = loop {
+            match entries.next() {
+                Some(Ok(entry)) => {
+                    sequence = sequence.or(entries.last_version());
+                    batch.push(entry);
+                },
+                Some(Err(error)) => break Err(error),
+                None => break Ok(batch),
+            }
+        };
+        match batch {
+            Ok(batch) => visit(position, WalRecord::Batch(batch, sequence)),
+            Err(Error::Corruption { offset, reason, .. }) => {
+                let reason = format!("entry at byte {offset} of the batch: {reason}");
+                visit(position, WalRecord::Corrupt { reason });
+                return Ok(true);
+            },
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Like [`walk_records`], for the encrypted WAL file with `id`. Each of its
+/// frames is a length prefixed sealed write, and every record in that write is
+/// reported at the offset of the frame.
+fn walk_frames(
+    file: &mut File,
+    path: &Path,
+    id: u32,
+    cipher: &Cipher,
+    mut visit: impl FnMut(u64, WalRecord),
+) -> Result<bool, Error> {
+    let len = file.metadata()?.len();
+    loop {
+        let position = file.stream_position()?;
+        if position == len {
+            return Ok(false);
+        }
+        let Some(sealed) = read_sized(file)? else {
+            visit(position, WalRecord::IncompleteFrame);
+            return Ok(false);
+        };
+        let Some(plaintext) = cipher.open(&frame_associated_data(id, position), &sealed) else {
+            let reason = "the write can't be decrypted, so the key is wrong or the data is damaged";
+            visit(position, WalRecord::Corrupt { reason: reason.to_owned() });
+            return Ok(true);
+        };
+        let mut records = Vec::new();
+        walk_records(&mut Cursor::new(plaintext), path, &mut |_, record| records.push(record))?;
+        for record in records {
+            // The frame was sealed whole, so a record can only be cut short within it
+            // if it was written that way.
+            let record = match record {
+                WalRecord::IncompleteBatch | WalRecord::IncompleteEntry => WalRecord::Corrupt {
+                    reason: "a write is cut short within its frame".to_owned(),
+                },
+                record => record,
+            };
+            let corrupt = matches!(record, WalRecord::Corrupt { .. });
+            visit(position, record);
+            if corrupt {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// The associated data of the frame at `position` of the WAL file with `id`,
+/// which stops frames from being moved around.
+fn frame_associated_data(id: u32, position: u64) -> [u8; 12] {
+    let mut data = [0; 12];
+    data[..4].copy_from_slice(&id.to_be_bytes());
+    data[4..].copy_from_slice(&position.to_be_bytes());
+    data
+}
+
+/// Read a u32 length followed by that many bytes, as the encoded entries of a
+/// batch follow its indicator, and as each frame of an encrypted WAL file is
+/// written. Returns `None` if the reader ends before the whole thing has been
+/// read.
+fn read_sized(file: &mut impl Read) -> Result<Option<Vec<u8>>, io::Error> {
+    let mut size = [0; 4];
+    let mut entries = Vec::new();
+    let result = file.read_exact(&mut size).and_then(|_| {
+        entries.resize(u32::from_be_bytes(size) as usize, 0);
+        file.read_exact(&mut entries)
+    });
+    match result {
+        Ok(()) => Ok(Some(entries)),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+pub fn wal_filename(id: u32) -> String {
+    format!("wal-{id}.dat")
+}
+
+pub fn wal_id(path: impl AsRef<Path>) -> Option<u32> {
+    path.as_ref().file_name()?.to_str()?.strip_prefix("wal-")?.strip_suffix(".dat")?.parse().ok()
+}
+
+/// The ids of every WAL file in the store at `directory`, in ascending order.
+fn wal_ids(directory: &Path) -> Result<Vec<u32>, io::Error> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        if let Some(id) = wal_id(entry?.path()) {
+            ids.push(id);
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Whether the store at `directory` has anything in its WAL, flushed or not.
+pub fn has_records(directory: &Path) -> Result<bool, io::Error> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let is_wal =
+            wal_id(&path).is_some() || path.file_name() == Some(LEGACY_WAL_FILENAME.as_ref());
+        if is_wal && fs::metadata(&path)?.len() > 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn open_wal_file(directory: &Path, id: u32) -> Result<File, io::Error> {
+    OpenOptions::new().create(true).append(true).read(true).open(directory.join(wal_filename(id)))
+}
+
+/// Stores created before the WAL was split have a single unnumbered WAL file,
+/// which becomes the first numbered one.
+fn migrate_legacy_wal(directory: &Path, start: u32) -> Result<(), io::Error> {
+    let legacy_path = directory.join(LEGACY_WAL_FILENAME);
+    if legacy_path.exists() && wal_ids(directory)?.is_empty() {
+        log::info!("migrating {legacy_path:?} to {}", wal_filename(start));
+        fs::rename(legacy_path, directory.join(wal_filename(start)))?;
+        sync_directory(directory)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+    use crate::memtable::MemtableArgs;
+    use crate::segment::Codec;
+    use crate::test::StoreFixture;
+
+    #[test]
+    fn rotates_at_size_cap() {
+        let fixture = StoreFixture::init("./test-db-wal-rotation");
+        // Each of these records is 11 bytes, so every file fits two of them.
+        let wal = Wal::open(fixture.path(), 1, 20, false).unwrap();
+        for key in ["a", "b", "c", "d", "e"] {
+            wal.set(key, "1", None).unwrap();
+        }
+        assert_eq!(wal_ids(fixture.path()).unwrap(), [1, 2, 3]);
+
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        wal.replay(&mut memtable).unwrap();
+        assert_eq!(memtable.iter().count(), 5);
+    }
+
+    #[test]
+    fn remove_before() {
+        let fixture = StoreFixture::init("./test-db-wal-remove-before");
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        wal.set("a", "1", None).unwrap();
+        let start = wal.rotate().unwrap();
+        wal.set("b", "2", None).unwrap();
+        wal.remove_before(start).unwrap();
+        assert_eq!(wal_ids(fixture.path()).unwrap(), [start]);
+
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        wal.replay(&mut memtable).unwrap();
+        assert_eq!(memtable.get("a"), None);
+        assert_eq!(memtable.get("b"), Some(Some("2".into())));
+    }
+
+    #[test]
+    fn open_discards_flushed_files() {
+        let fixture = StoreFixture::init("./test-db-wal-open");
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        wal.set("a", "1", None).unwrap();
+        let start = wal.rotate().unwrap();
+        drop(wal);
+
+        Wal::open(fixture.path(), start, u64::MAX, false).unwrap();
+        assert_eq!(wal_ids(fixture.path()).unwrap(), [start]);
+    }
+
+    #[test]
+    fn walk_reports_offsets() {
+        let fixture = StoreFixture::init("./test-db-wal-walk");
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        wal.set("a", "1", None).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete("a");
+        wal.write(&batch, None).unwrap();
+        wal.set("b", "2", None).unwrap();
+        drop(wal);
+        // Cut the last entry short.
+        let path = fixture.path().join(wal_filename(1));
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(fs::metadata(&path).unwrap().len() - 1).unwrap();
+
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        let mut records = Vec::new();
+        wal.walk(|id, offset, record| records.push((id, offset, record))).unwrap();
+        // The set takes 11 bytes, and the batch 5 bytes of framing around a 6 byte
+        // tombstone.
+        assert_eq!(records, [
+            (
+                1,
+                0,
+                WalRecord::Entry(Entry::Assignment { key: "a".into(), value: "1".into() }, None)
+            ),
+            (1, 11, WalRecord::Batch(vec![Entry::Tombstone { key: "a".into() }], None)),
+            (1, 22, WalRecord::IncompleteEntry),
+        ]);
+    }
+
+    #[test]
+    fn incomplete_batch_is_discarded() {
+        let fixture = StoreFixture::init("./test-db-wal-batch");
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        wal.set("a", "1", None).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("b", "2");
+        batch.delete("a");
+        wal.write(&batch, None).unwrap();
+        let path = fixture.path().join(wal_filename(1));
+        let complete = fs::metadata(&path).unwrap().len();
+
+        let mut batch = WriteBatch::new();
+        batch.set("c", "3");
+        batch.set("d", "4");
+        wal.write(&batch, None).unwrap();
+        drop(wal);
+        // Cut the second batch off partway through its last entry.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(fs::metadata(&path).unwrap().len() - 3).unwrap();
+
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        let report = wal.replay(&mut memtable).unwrap();
+        assert_eq!(memtable.get("a"), Some(None));
+        assert_eq!(memtable.get("b"), Some(Some("2".into())));
+        assert_eq!(memtable.get("c"), None);
+        assert_eq!(memtable.len(), 2);
+        let size = fs::metadata(&path).unwrap().len();
+        assert!(complete < size);
+
+        // The set, and both entries of the first batch.
+        assert_eq!((report.files, report.records, report.bytes), (1, 3, size));
+        assert_eq!(report.incomplete_tails, [IncompleteTail {
+            file: path,
+            offset: complete,
+            bytes: size - complete
+        }]);
+        assert_eq!(report.salvage, None);
+    }
+
+    #[test]
+    fn corrupt_record_fails_replay() {
+        let fixture = StoreFixture::init("./test-db-wal-corruption");
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        wal.set("a", "1", None).unwrap();
+        wal.set("b", "2", None).unwrap();
+        drop(wal);
+        // Overwrite the indicator of the second entry.
+        let path = fixture.path().join(wal_filename(1));
+        let mut contents = fs::read(&path).unwrap();
+        contents[11] = 9;
+        fs::write(&path, contents).unwrap();
+
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        let error = wal.replay(&mut memtable).unwrap_err();
+        assert!(
+            matches!(error, Error::Corruption { ref file, offset: 11, .. } if *file == path),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn compressed_records() {
+        let fixture = StoreFixture::init("./test-db-wal-compressed-records");
+        let compression = Some(Compression { codec: Codec::Lz4, threshold: 16 });
+        let wal =
+            Wal::open(fixture.path(), 1, u64::MAX, false).unwrap().with_compression(compression);
+        let large = "a".repeat(1000);
+        wal.set("a", &large, None).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("b", &large);
+        batch.delete("c");
+        wal.write(&batch, None).unwrap();
+        drop(wal);
+        assert!(fs::metadata(fixture.path().join(wal_filename(1))).unwrap().len() < 200);
+
+        // Reading doesn't depend on the setting.
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        assert_eq!(wal.replay(&mut memtable).unwrap().salvage, None);
+        assert_eq!(memtable.get("a"), Some(Some(large.as_str().into())));
+        assert_eq!(memtable.get("b"), Some(Some(large.into())));
+        assert_eq!(memtable.get("c"), Some(None));
+    }
+
+    #[test]
+    fn salvage_truncates_at_corruption() {
+        let fixture = StoreFixture::init("./test-db-wal-salvage");
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        wal.set("a", "1", None).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("b", "2");
+        wal.write(&batch, None).unwrap();
+        wal.set("c", "3", None).unwrap();
+        wal.rotate().unwrap();
+        wal.set("d", "4", None).unwrap();
+        drop(wal);
+        // Overwrite the indicator of the entry in the batch.
+        let path = fixture.path().join(wal_filename(1));
+        let mut contents = fs::read(&path).unwrap();
+        contents[16] = 9;
+        fs::write(&path, contents).unwrap();
+
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false)
+            .unwrap()
+            .with_recovery_mode(RecoveryMode::Salvage);
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        let salvage = wal.replay(&mut memtable).unwrap().salvage.unwrap();
+        assert_eq!((salvage.file, salvage.offset), (path.clone(), 11));
+        assert_eq!(salvage.reason, "entry at byte 0 of the batch: unknown entry indicator 9");
+        assert_eq!(salvage.removed_files, [fixture.path().join(wal_filename(2))]);
+        // The batch and the set after it in the first file, and the set in the
+        // second.
+        assert_eq!(salvage.dropped_bytes, 16 + 11 + 11);
+        assert_eq!(memtable.get("a"), Some(Some("1".into())));
+        assert_eq!(memtable.len(), 1);
+
+        // The truncated file is where writes go from now on, and it replays
+        // cleanly.
+        wal.set("e", "5", None).unwrap();
+        drop(wal);
+        assert_eq!(wal_ids(fixture.path()).unwrap(), [1]);
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, false).unwrap();
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        assert_eq!(wal.replay(&mut memtable).unwrap().salvage, None);
+        assert_eq!(memtable.get("e"), Some(Some("5".into())));
+        assert_eq!(memtable.len(), 2);
+    }
+
+    #[test]
+    fn concurrent_appends() {
+        let fixture = StoreFixture::init("./test-db-wal-group-commit");
+        let wal = Wal::open(fixture.path(), 1, u64::MAX, true).unwrap();
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let wal = &wal;
+                scope.spawn(move || {
+                    for n in 0..25 {
+                        wal.set(&format!("{thread}-{n}"), "1", None).unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut memtable = Memtable::new(MemtableArgs::default());
+        wal.replay(&mut memtable).unwrap();
+        assert_eq!(memtable.iter().count(), 200);
+    }
+}
@@ -0,0 +1,77 @@
+/*
+ * C bindings for the Crunch storage engine, from the crunch-ffi crate.
+ *
+ * Strings passed in are NUL-terminated UTF-8, and are only read for the
+ * length of the call. A handle can be used from any number of threads at
+ * once, but not after it is passed to crunch_close. Passing a NULL handle or
+ * string fails with CRUNCH_ERROR, as does a panic inside the engine.
+ */
+
+#ifndef CRUNCH_H
+#define CRUNCH_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* What every function other than crunch_open returns. */
+#define CRUNCH_OK 0
+#define CRUNCH_NOT_FOUND 1
+#define CRUNCH_ERROR -1
+
+typedef struct crunch_engine crunch_engine;
+
+/*
+ * Called for each pair that crunch_scan visits, with the context passed to
+ * it. The strings are only valid during the call. Returning anything other
+ * than 0 stops the scan.
+ */
+typedef int (*crunch_scan_callback)(void *context, const char *key, const char *value);
+
+/*
+ * Open the engine at path, with its settings read from the environment.
+ * Returns NULL on failure.
+ */
+crunch_engine *crunch_open(const char *path);
+
+/*
+ * Store a copy of the value of key in *value, to be released with
+ * crunch_free_string. Returns CRUNCH_NOT_FOUND, leaving *value alone, if the
+ * key doesn't exist.
+ */
+int crunch_get(const crunch_engine *engine, const char *key, char **value);
+
+int crunch_set(const crunch_engine *engine, const char *key, const char *value);
+
+int crunch_delete(const crunch_engine *engine, const char *key);
+
+/*
+ * Call callback for every pair whose key starts with prefix, in key order,
+ * until it returns something other than 0. A NULL callback is an error.
+ */
+int crunch_scan(
+    const crunch_engine *engine,
+    const char *prefix,
+    crunch_scan_callback callback,
+    void *context
+);
+
+/* Shut the engine down, and release the handle, even on failure. */
+int crunch_close(crunch_engine *engine);
+
+/* Release a string returned by crunch_get. Passing NULL does nothing. */
+void crunch_free_string(char *string);
+
+/*
+ * Why the last call on this thread that failed did so, or NULL if none has.
+ * The message is valid until another call on this thread fails.
+ */
+const char *crunch_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
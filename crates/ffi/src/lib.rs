@@ -0,0 +1,316 @@
+//! C bindings for the engine, so that applications in other languages can
+//! embed it instead of going through `crunch-kv`.
+//!
+//! The functions are declared in `include/crunch.h`, which documents how
+//! they are called. Every one but [`crunch_open`] returns [`CRUNCH_OK`],
+//! [`CRUNCH_NOT_FOUND`] or [`CRUNCH_ERROR`], and the message for an error is
+//! kept for [`crunch_last_error`]. A panic is caught and reported as an error,
+//! since unwinding into the caller's frames isn't allowed.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::fmt::Display;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crunch_engine::engine::Engine;
+
+pub const CRUNCH_OK: c_int = 0;
+pub const CRUNCH_NOT_FOUND: c_int = 1;
+pub const CRUNCH_ERROR: c_int = -1;
+
+pub type ScanCallback =
+    extern "C" fn(context: *mut c_void, key: *const c_char, value: *const c_char) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Keep `error` for [`crunch_last_error`], and return the status for it.
+fn fail(error: impl Display) -> c_int {
+    // A message can only hold a NUL if a key or value did, which is replaced
+    // rather than losing the rest of the message.
+    let message = error.to_string().replace('\0', "\u{FFFD}");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+    CRUNCH_ERROR
+}
+
+/// Run `body`, reporting a panic in it as an error.
+fn guard(body: impl FnOnce() -> c_int) -> c_int {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("no message");
+        fail(format!("the engine panicked: {message}"))
+    })
+}
+
+/// The engine behind the handle `engine`, if it isn't null.
+///
+/// # Safety
+///
+/// `engine` must be null or a handle from [`crunch_open`] that outlives `'a`.
+unsafe fn handle<'a>(engine: *const Engine) -> Result<&'a Engine, String> {
+    engine.as_ref().ok_or_else(|| "engine is NULL".to_owned())
+}
+
+/// Read the string that `string` points to, if it can be.
+///
+/// # Safety
+///
+/// `string` must be null or point to a NUL-terminated string that outlives
+/// `'a`.
+unsafe fn text<'a>(string: *const c_char, name: &str) -> Result<&'a str, String> {
+    if string.is_null() {
+        return Err(format!("{name} is NULL"));
+    }
+    CStr::from_ptr(string).to_str().map_err(|error| format!("{name} isn't UTF-8: {error}"))
+}
+
+/// # Safety
+///
+/// `path` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn crunch_open(path: *const c_char) -> *mut Engine {
+    let mut engine = ptr::null_mut();
+    guard(|| {
+        let path = match text(path, "path") {
+            Ok(path) => path,
+            Err(error) => return fail(error),
+        };
+        match Engine::new(path.into()) {
+            Ok(opened) => {
+                engine = Box::into_raw(Box::new(opened));
+                CRUNCH_OK
+            },
+            Err(error) => fail(error),
+        }
+    });
+    engine
+}
+
+/// # Safety
+///
+/// `engine` must be null or a handle from [`crunch_open`] that hasn't been
+/// closed, `key` null or a NUL-terminated string, and `value` null or valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn crunch_get(
+    engine: *const Engine,
+    key: *const c_char,
+    value: *mut *mut c_char,
+) -> c_int {
+    guard(|| {
+        let (engine, key) = match (handle(engine), text(key, "key")) {
+            (Ok(engine), Ok(key)) => (engine, key),
+            (Err(error), _) | (_, Err(error)) => return fail(error),
+        };
+        if value.is_null() {
+            return fail("value is NULL");
+        }
+        match engine.get(key) {
+            Ok(Some(found)) => match CString::new(found.as_bytes()) {
+                Ok(found) => {
+                    *value = found.into_raw();
+                    CRUNCH_OK
+                },
+                Err(_) => fail(format!("the value of {key} holds a NUL, so it can't be returned")),
+            },
+            Ok(None) => CRUNCH_NOT_FOUND,
+            Err(error) => fail(error),
+        }
+    })
+}
+
+/// # Safety
+///
+/// `engine` must be null or a handle from [`crunch_open`] that hasn't been
+/// closed, and `key` and `value` each null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn crunch_set(
+    engine: *const Engine,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    guard(|| {
+        let engine = match handle(engine) {
+            Ok(engine) => engine,
+            Err(error) => return fail(error),
+        };
+        let (key, value) = match (text(key, "key"), text(value, "value")) {
+            (Ok(key), Ok(value)) => (key, value),
+            (Err(error), _) | (_, Err(error)) => return fail(error),
+        };
+        match engine.set(key, value) {
+            Ok(()) => CRUNCH_OK,
+            Err(error) => fail(error),
+        }
+    })
+}
+
+/// # Safety
+///
+/// `engine` must be null or a handle from [`crunch_open`] that hasn't been
+/// closed, and `key` null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn crunch_delete(engine: *const Engine, key: *const c_char) -> c_int {
+    guard(|| {
+        let (engine, key) = match (handle(engine), text(key, "key")) {
+            (Ok(engine), Ok(key)) => (engine, key),
+            (Err(error), _) | (_, Err(error)) => return fail(error),
+        };
+        match engine.delete(key) {
+            Ok(()) => CRUNCH_OK,
+            Err(error) => fail(error),
+        }
+    })
+}
+
+/// # Safety
+///
+/// `engine` must be null or a handle from [`crunch_open`] that hasn't been
+/// closed, and `prefix` null or a NUL-terminated string. `context` is only
+/// handed to `callback`.
+#[no_mangle]
+pub unsafe extern "C" fn crunch_scan(
+    engine: *const Engine,
+    prefix: *const c_char,
+    callback: Option<ScanCallback>,
+    context: *mut c_void,
+) -> c_int {
+    guard(|| {
+        let (engine, prefix) = match (handle(engine), text(prefix, "prefix")) {
+            (Ok(engine), Ok(prefix)) => (engine, prefix),
+            (Err(error), _) | (_, Err(error)) => return fail(error),
+        };
+        let Some(callback) = callback else {
+            return fail("callback is NULL");
+        };
+        let entries = match engine.scan_prefix(prefix) {
+            Ok(entries) => entries,
+            Err(error) => return fail(error),
+        };
+        for entry in entries {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(error) => return fail(error),
+            };
+            let (Ok(key), Ok(value)) = (CString::new(key), CString::new(value)) else {
+                return fail("a key or value in the scan holds a NUL, so it can't be passed on");
+            };
+            if callback(context, key.as_ptr(), value.as_ptr()) != 0 {
+                break;
+            }
+        }
+        CRUNCH_OK
+    })
+}
+
+/// # Safety
+///
+/// `engine` must be null or a handle from [`crunch_open`] that hasn't been
+/// closed, and isn't in use on another thread.
+#[no_mangle]
+pub unsafe extern "C" fn crunch_close(engine: *mut Engine) -> c_int {
+    guard(|| {
+        if engine.is_null() {
+            return fail("engine is NULL");
+        }
+        match Box::from_raw(engine).stop() {
+            Ok(()) => CRUNCH_OK,
+            Err(_) => fail("a background thread of the engine panicked"),
+        }
+    })
+}
+
+/// # Safety
+///
+/// `string` must be null or a string from [`crunch_get`] that hasn't been
+/// freed.
+#[no_mangle]
+pub unsafe extern "C" fn crunch_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn crunch_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |error| error.as_ptr()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    extern "C" fn collect(context: *mut c_void, key: *const c_char, value: *const c_char) -> c_int {
+        let entries = unsafe { &mut *(context as *mut Vec<(String, String)>) };
+        let (key, value) = unsafe { (CStr::from_ptr(key), CStr::from_ptr(value)) };
+        entries.push((key.to_str().unwrap().to_owned(), value.to_str().unwrap().to_owned()));
+        (entries.len() == 2) as c_int
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join("crunch-ffi-test-round-trip");
+        _ = fs::remove_dir_all(&path);
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let engine = crunch_open(c_path.as_ptr());
+            assert!(!engine.is_null());
+            for (key, value) in
+                [(c"a:1", c"one"), (c"a:2", c"two"), (c"a:3", c"three"), (c"b", c"")]
+            {
+                assert_eq!(crunch_set(engine, key.as_ptr(), value.as_ptr()), CRUNCH_OK);
+            }
+            let mut value = ptr::null_mut();
+            assert_eq!(crunch_get(engine, c"a:2".as_ptr(), &mut value), CRUNCH_OK);
+            assert_eq!(CStr::from_ptr(value), c"two");
+            crunch_free_string(value);
+
+            assert_eq!(crunch_delete(engine, c"a:2".as_ptr()), CRUNCH_OK);
+            let mut value = ptr::null_mut();
+            assert_eq!(crunch_get(engine, c"a:2".as_ptr(), &mut value), CRUNCH_NOT_FOUND);
+            assert!(value.is_null());
+
+            // The callback stops the scan once it has two entries.
+            let mut entries = Vec::<(String, String)>::new();
+            let context = &mut entries as *mut _ as *mut c_void;
+            assert_eq!(crunch_scan(engine, c"".as_ptr(), Some(collect), context), CRUNCH_OK);
+            assert_eq!(entries, [
+                ("a:1".to_owned(), "one".to_owned()),
+                ("a:3".to_owned(), "three".to_owned())
+            ]);
+
+            assert_eq!(crunch_set(engine, ptr::null(), c"value".as_ptr()), CRUNCH_ERROR);
+            assert_eq!(CStr::from_ptr(crunch_last_error()), c"key is NULL");
+            assert_eq!(crunch_scan(engine, c"".as_ptr(), None, context), CRUNCH_ERROR);
+            assert_eq!(CStr::from_ptr(crunch_last_error()), c"callback is NULL");
+            assert_eq!(crunch_close(engine), CRUNCH_OK);
+
+            // A null handle is an error rather than a crash.
+            assert_eq!(crunch_set(ptr::null(), c"a".as_ptr(), c"1".as_ptr()), CRUNCH_ERROR);
+            assert_eq!(CStr::from_ptr(crunch_last_error()), c"engine is NULL");
+            assert_eq!(crunch_get(ptr::null(), c"a".as_ptr(), &mut value), CRUNCH_ERROR);
+            assert_eq!(crunch_delete(ptr::null(), c"a".as_ptr()), CRUNCH_ERROR);
+            assert_eq!(
+                crunch_scan(ptr::null(), c"".as_ptr(), Some(collect), context),
+                CRUNCH_ERROR
+            );
+            assert_eq!(crunch_close(ptr::null_mut()), CRUNCH_ERROR);
+        }
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn panics_are_errors() {
+        assert_eq!(guard(|| panic!("oops")), CRUNCH_ERROR);
+        let error = unsafe { CStr::from_ptr(crunch_last_error()) };
+        assert_eq!(error, c"the engine panicked: oops");
+        assert_eq!(guard(|| CRUNCH_NOT_FOUND), CRUNCH_NOT_FOUND);
+    }
+}
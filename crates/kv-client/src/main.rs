@@ -1,15 +1,17 @@
 use std::fmt::Display;
 use std::io::Write;
-use std::net::TcpStream;
+use std::process::ExitCode;
+use std::time::Duration;
 
 use clap::Parser;
+use crunch_client::{AckLevel, Change, Client, ClientArgs, Event, Reply};
 use nom::branch::alt;
 use nom::bytes::complete::{is_not, tag, tag_no_case};
 use nom::character::complete::space1;
 use nom::sequence::separated_pair;
 use nom::IResult;
-
-mod protocol;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 
 /// Command line client for CrunchKV
 #[derive(Parser)]
@@ -18,28 +20,173 @@ struct Cli {
     /// The server port
     #[arg(short, long)]
     port: Option<u16>,
+
+    /// The password to authenticate with, if the server requires one
+    #[arg(long)]
+    password: Option<String>,
+
+    /// The user to authenticate as, with the password from `--password`
+    #[arg(long, requires = "password")]
+    user: Option<String>,
+
+    /// How long to wait for the connection to the server, in seconds, or 0 to
+    /// wait forever
+    #[arg(long, default_value_t = 5)]
+    connect_timeout: u64,
+
+    /// How long to wait for the server to respond to each command, in seconds,
+    /// or 0 to wait forever
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+
+    /// The database to select before running any commands
+    #[arg(long)]
+    database: Option<u32>,
+
+    /// A command to run, instead of starting the interactive prompt. The exit
+    /// code is 0 if it succeeds, 1 if the key isn't found, and 2 otherwise
+    #[arg(trailing_var_arg = true)]
+    command: Vec<String>,
 }
 
 enum Command<'a> {
-    Get { key: &'a str },
-    Set { key: &'a str, value: &'a str },
-    Delete { key: &'a str },
+    Get {
+        key: &'a str,
+    },
+    Set {
+        key: &'a str,
+        value: &'a str,
+    },
+
+    /// Set `key` to `value`, printing the value it had before.
+    GetSet {
+        key: &'a str,
+        value: &'a str,
+    },
+
+    /// Set `key` to `value` if it has no value, printing whether it was set.
+    SetNx {
+        key: &'a str,
+        value: &'a str,
+    },
+
+    /// Add `suffix` to the end of the value of `key`, printing the length of
+    /// the new value.
+    Append {
+        key: &'a str,
+        suffix: &'a str,
+    },
+    Delete {
+        key: &'a str,
+    },
+
+    /// Delete `key`, printing the value it had.
+    GetDel {
+        key: &'a str,
+    },
+    Exists {
+        key: &'a str,
+    },
+    Ping,
+
+    /// List the keys that start with `prefix`, and their values if
+    /// `with_values` is set.
+    Scan {
+        prefix: &'a str,
+        with_values: bool,
+    },
+    Info,
+    Config,
+    DbSize,
+
+    /// Print which node of the slot cluster owns each range of slots.
+    Cluster,
+    Watch {
+        keys: Vec<&'a str>,
+    },
+
+    /// Print the keyspace events for every key that matches `pattern`.
+    Subscribe {
+        pattern: &'a str,
+    },
+    Auth {
+        password: &'a str,
+    },
+    AuthUser {
+        user: &'a str,
+        password: &'a str,
+    },
+
+    /// Run a Lua script on the server, printing its result.
+    Eval {
+        script: &'a str,
+    },
+    Select {
+        index: u32,
+    },
+
+    /// Set how many servers must apply this connection's writes before they
+    /// are acknowledged, or `None` for the server's own setting.
+    Acks {
+        level: Option<AckLevel>,
+    },
+
+    /// Start queueing commands, to send to the server all at once.
+    Multi,
+
+    /// Send the queued commands.
+    Exec,
+
+    /// Throw away the queued commands.
+    Discard,
     Exit,
 }
 
 impl<'a> Command<'a> {
-    fn parse(input: &'a str) -> Self {
-        alt((parse_get, parse_set, parse_delete, parse_exit))(input).unwrap().1
+    /// Parse a line of input, returning `None` if it isn't a valid command.
+    fn parse(input: &'a str) -> Option<Self> {
+        alt((
+            parse_get,
+            parse_set,
+            parse_getset,
+            parse_setnx,
+            parse_append,
+            parse_delete,
+            parse_getdel,
+            parse_exists,
+            parse_ping,
+            parse_scan,
+            parse_info,
+            parse_config,
+            parse_dbsize,
+            parse_watch,
+            parse_subscribe,
+            parse_auth,
+            parse_select,
+            parse_eval,
+            // `alt` only takes so many parsers at once.
+            alt((
+                parse_auth_user,
+                parse_cluster,
+                parse_acks,
+                parse_multi,
+                parse_exec,
+                parse_discard,
+                parse_exit,
+            )),
+        ))(input)
+        .ok()
+        .map(|(_, command)| command)
     }
 }
 
-fn parse_get(input: &str) -> IResult<&str, Command> {
+fn parse_get(input: &str) -> IResult<&str, Command<'_>> {
     let (rest, _) = tag_no_case("get")(input)?;
     let (rest, _) = space1(rest)?;
     Ok(("", Command::Get { key: rest.trim() }))
 }
 
-fn parse_set(input: &str) -> IResult<&str, Command> {
+fn parse_set(input: &str) -> IResult<&str, Command<'_>> {
     let (rest, _) = tag_no_case("set")(input)?;
     let (rest, _) = space1(rest)?;
     let (_, (key, value)) = separated_pair(is_not("="), tag("="), is_not("="))(rest)?;
@@ -47,59 +194,474 @@ fn parse_set(input: &str) -> IResult<&str, Command> {
     Ok(("", Command::Set { key: key.trim(), value: value.trim() }))
 }
 
-fn parse_delete(input: &str) -> IResult<&str, Command> {
-    let (rest, _) = tag_no_case("delete")(input)?;
+fn parse_getset(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("getset")(input)?;
+    let (rest, _) = space1(rest)?;
+    let (_, (key, value)) = separated_pair(is_not("="), tag("="), is_not("="))(rest)?;
+    Ok(("", Command::GetSet { key: key.trim(), value: value.trim() }))
+}
+
+fn parse_setnx(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("setnx")(input)?;
+    let (rest, _) = space1(rest)?;
+    let (_, (key, value)) = separated_pair(is_not("="), tag("="), is_not("="))(rest)?;
+    Ok(("", Command::SetNx { key: key.trim(), value: value.trim() }))
+}
+
+fn parse_append(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("append")(input)?;
+    let (rest, _) = space1(rest)?;
+    let (_, (key, suffix)) = separated_pair(is_not("="), tag("="), is_not("="))(rest)?;
+    Ok(("", Command::Append { key: key.trim(), suffix: suffix.trim() }))
+}
+
+fn parse_delete(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = alt((tag_no_case("delete"), tag_no_case("del")))(input)?;
     let (rest, _) = space1(rest)?;
     Ok(("", Command::Delete { key: rest.trim() }))
 }
 
-fn parse_exit(input: &str) -> IResult<&str, Command> {
+fn parse_getdel(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("getdel")(input)?;
+    let (rest, _) = space1(rest)?;
+    Ok(("", Command::GetDel { key: rest.trim() }))
+}
+
+fn parse_exists(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("exists")(input)?;
+    let (rest, _) = space1(rest)?;
+    Ok(("", Command::Exists { key: rest.trim() }))
+}
+
+fn parse_ping(input: &str) -> IResult<&str, Command<'_>> {
+    _ = tag_no_case("ping")(input)?;
+    Ok(("", Command::Ping))
+}
+
+fn parse_scan(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("scan")(input)?;
+    let mut words: Vec<_> = rest.split_whitespace().collect();
+    let with_values = words.last().is_some_and(|word| word.eq_ignore_ascii_case("withvalues"));
+    if with_values {
+        words.pop();
+    }
+    match words[..] {
+        [] => Ok(("", Command::Scan { prefix: "", with_values })),
+        [prefix] => Ok(("", Command::Scan { prefix, with_values })),
+        _ => Err(nom::Err::Error(nom::error::Error::new(rest, nom::error::ErrorKind::Verify))),
+    }
+}
+
+fn parse_info(input: &str) -> IResult<&str, Command<'_>> {
+    _ = tag_no_case("info")(input)?;
+    Ok(("", Command::Info))
+}
+
+fn parse_config(input: &str) -> IResult<&str, Command<'_>> {
+    _ = tag_no_case("config")(input)?;
+    Ok(("", Command::Config))
+}
+
+fn parse_dbsize(input: &str) -> IResult<&str, Command<'_>> {
+    _ = tag_no_case("dbsize")(input)?;
+    Ok(("", Command::DbSize))
+}
+
+fn parse_cluster(input: &str) -> IResult<&str, Command<'_>> {
+    _ = tag_no_case("cluster")(input)?;
+    Ok(("", Command::Cluster))
+}
+
+fn parse_watch(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("watch")(input)?;
+    let (rest, _) = space1(rest)?;
+    Ok(("", Command::Watch { keys: rest.split_whitespace().collect() }))
+}
+
+fn parse_subscribe(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("subscribe")(input)?;
+    let (rest, _) = space1(rest)?;
+    Ok(("", Command::Subscribe { pattern: rest.trim() }))
+}
+
+fn parse_auth(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("auth")(input)?;
+    let (rest, _) = space1(rest)?;
+    Ok(("", Command::Auth { password: rest.trim() }))
+}
+
+fn parse_auth_user(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("authuser")(input)?;
+    let (rest, _) = space1(rest)?;
+    let (password, user) = is_not(" ")(rest)?;
+    let (password, _) = space1(password)?;
+    Ok(("", Command::AuthUser { user, password: password.trim() }))
+}
+
+fn parse_select(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("select")(input)?;
+    let (rest, _) = space1(rest)?;
+    let index = rest
+        .trim()
+        .parse()
+        .map_err(|_| nom::Err::Error(nom::error::Error::new(rest, nom::error::ErrorKind::Digit)))?;
+    Ok(("", Command::Select { index }))
+}
+
+fn parse_acks(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("acks")(input)?;
+    let (rest, _) = space1(rest)?;
+    let level = match rest.trim().to_lowercase().as_str() {
+        "default" => None,
+        "local" => Some(AckLevel::Local),
+        "leader+1" => Some(AckLevel::LeaderPlusOne),
+        "majority" => Some(AckLevel::Majority),
+        _ => {
+            let kind = nom::error::ErrorKind::Tag;
+            return Err(nom::Err::Error(nom::error::Error::new(rest, kind)));
+        },
+    };
+    Ok(("", Command::Acks { level }))
+}
+
+fn parse_eval(input: &str) -> IResult<&str, Command<'_>> {
+    let (rest, _) = tag_no_case("eval")(input)?;
+    let (rest, _) = space1(rest)?;
+    Ok(("", Command::Eval { script: rest.trim() }))
+}
+
+fn parse_multi(input: &str) -> IResult<&str, Command<'_>> {
+    _ = tag_no_case("multi")(input)?;
+    Ok(("", Command::Multi))
+}
+
+fn parse_exec(input: &str) -> IResult<&str, Command<'_>> {
+    _ = tag_no_case("exec")(input)?;
+    Ok(("", Command::Exec))
+}
+
+fn parse_discard(input: &str) -> IResult<&str, Command<'_>> {
+    _ = tag_no_case("discard")(input)?;
+    Ok(("", Command::Discard))
+}
+
+fn parse_exit(input: &str) -> IResult<&str, Command<'_>> {
     _ = tag_no_case("exit")(input)?;
     Ok(("", Command::Exit))
 }
 
+/// Print an error to stderr, so that it doesn't end up in the output of a
+/// one-shot command that is being captured by a script.
 fn error(message: impl Display) {
-    println!("Error: {message}");
+    eprintln!("Error: {message}");
 }
 
-fn main() {
-    env_logger::init();
-    let args = Cli::parse();
-    let port = args.port.unwrap_or(6210);
-    let mut stream = protocol::Stream(TcpStream::connect(("127.0.0.1", port)).unwrap());
-    let stdin = std::io::stdin();
+/// How a command went, which is what the exit code reports in one-shot mode.
+#[derive(Clone, Copy)]
+enum Outcome {
+    Ok,
+    NotFound,
+    Failed,
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::Ok => Self::SUCCESS,
+            Outcome::NotFound => Self::from(1),
+            Outcome::Failed => Self::from(2),
+        }
+    }
+}
+
+/// Print `result`'s error, if it has one.
+fn report(result: crunch_client::Result<()>) -> Outcome {
+    match result {
+        Ok(()) => Outcome::Ok,
+        Err(err) => {
+            error(err);
+            Outcome::Failed
+        },
+    }
+}
+
+/// Send the commands on each of `lines` to the server in one pipeline, and
+/// print their replies.
+fn exec(client: &mut Client, lines: &[String]) -> crunch_client::Result<()> {
+    let mut pipeline = client.pipeline();
+    for line in lines {
+        match Command::parse(line) {
+            Some(Command::Get { key }) => pipeline.get(key.as_bytes()),
+            Some(Command::Set { key, value }) => pipeline.set(key.as_bytes(), value.as_bytes()),
+            Some(Command::GetSet { key, value }) => {
+                pipeline.getset(key.as_bytes(), value.as_bytes())
+            },
+            Some(Command::Delete { key }) => pipeline.delete(key.as_bytes()),
+            Some(Command::GetDel { key }) => pipeline.getdel(key.as_bytes()),
+            Some(Command::Exists { key }) => pipeline.exists(key.as_bytes()),
+            Some(Command::Ping) => pipeline.ping(),
+            _ => unreachable!("only pipelinable commands are queued"),
+        };
+    }
+    for (index, reply) in pipeline.exec()?.into_iter().enumerate() {
+        match reply {
+            Ok(Reply::Ok) => println!("{}) OK", index + 1),
+            Ok(Reply::Value(Some(value))) => {
+                println!("{}) {}", index + 1, String::from_utf8_lossy(&value))
+            },
+            Ok(Reply::Value(None)) => println!("{}) (not found)", index + 1),
+            Ok(Reply::Bool(value)) => println!("{}) {value}", index + 1),
+            Err(err) => println!("{}) Error: {err}", index + 1),
+        }
+    }
+    Ok(())
+}
+
+/// How many keys to fetch from the server at a time, when scanning.
+const SCAN_PAGE_SIZE: u32 = 100;
+
+/// Print every key that starts with `prefix`, a page at a time.
+fn scan(client: &mut Client, prefix: &str, with_values: bool) -> crunch_client::Result<()> {
+    let mut cursor = prefix.as_bytes().to_vec();
     loop {
-        print!("> ");
-        std::io::stdout().flush().unwrap();
-        let mut line = String::new();
-        stdin.read_line(&mut line).unwrap();
-        match Command::parse(&line) {
-            Command::Get { key } => {
-                let Some(value) = stream.get(key.as_bytes()).unwrap() else {
-                    error("not found");
-                    continue;
-                };
-                match std::str::from_utf8(&value) {
-                    Ok(value) => {
-                        println!("{value}");
-                        std::io::stdout().flush().unwrap();
+        let page = client.scan(&cursor, SCAN_PAGE_SIZE, with_values)?;
+        for (key, value) in page.entries {
+            // Keys come back in order, so once one doesn't match, none of the
+            // rest will either.
+            if !key.starts_with(prefix.as_bytes()) {
+                return Ok(());
+            }
+            match value {
+                Some(value) => println!(
+                    "{} = {}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                ),
+                None => println!("{}", String::from_utf8_lossy(&key)),
+            }
+        }
+        match page.next {
+            Some(next) => cursor = next,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Run a command that talks to the server, and print its result.
+///
+/// The commands that manage a `multi` are left to the prompt.
+/// Print `name value` pairs with the values lined up.
+fn print_fields(fields: Vec<(String, String)>) {
+    let width = fields.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, value) in fields {
+        println!("{name:width$}  {value}");
+    }
+}
+
+/// Print a value read from the server, like the response to a get.
+fn print_value(result: crunch_client::Result<Option<Vec<u8>>>) -> Outcome {
+    match result {
+        Ok(Some(value)) => match std::str::from_utf8(&value) {
+            Ok(value) => {
+                println!("{value}");
+                std::io::stdout().flush().unwrap();
+                Outcome::Ok
+            },
+            Err(err) => {
+                error(err);
+                Outcome::Failed
+            },
+        },
+        Ok(None) => {
+            error("not found");
+            Outcome::NotFound
+        },
+        Err(err) => report(Err(err)),
+    }
+}
+
+fn run(client: &mut Client, command: Command) -> Outcome {
+    match command {
+        Command::Get { key } => print_value(client.get(key.as_bytes())),
+        Command::Set { key, value } => report(client.set(key.as_bytes(), value.as_bytes())),
+        Command::GetSet { key, value } => {
+            print_value(client.getset(key.as_bytes(), value.as_bytes()))
+        },
+        Command::SetNx { key, value } => {
+            report(client.setnx(key.as_bytes(), value.as_bytes()).map(|set| println!("{set}")))
+        },
+        Command::Append { key, suffix } => {
+            report(client.append(key.as_bytes(), suffix.as_bytes()).map(|len| println!("{len}")))
+        },
+        Command::Delete { key } => report(client.delete(key.as_bytes())),
+        Command::GetDel { key } => print_value(client.getdel(key.as_bytes())),
+        Command::Exists { key } => match client.exists(key.as_bytes()) {
+            Ok(exists) => {
+                println!("{exists}");
+                Outcome::Ok
+            },
+            Err(err) => report(Err(err)),
+        },
+        Command::Ping => report(client.ping().map(|()| println!("PONG"))),
+        Command::Scan { prefix, with_values } => report(scan(client, prefix, with_values)),
+        Command::Info => report(client.info().map(print_fields)),
+        Command::Config => report(client.config().map(print_fields)),
+        Command::DbSize => report(client.dbsize().map(|len| println!("{len}"))),
+        Command::Cluster => report(client.cluster_slots().map(|slots| {
+            for range in slots {
+                println!("{}-{} {}", range.start, range.end, range.address);
+            }
+        })),
+        Command::Watch { keys } => {
+            if let Err(err) = client.watch(keys.iter().map(|key| key.as_bytes())) {
+                return report(Err(err));
+            }
+            // The connection is only good for watching from here on, so this
+            // only returns once it fails.
+            loop {
+                match client.next_change() {
+                    Ok(Change::Set { key, value }) => println!(
+                        "set {} = {}",
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(&value)
+                    ),
+                    Ok(Change::Delete { key }) => {
+                        println!("delete {}", String::from_utf8_lossy(&key))
                     },
-                    Err(err) => error(err),
+                    Err(err) => return report(Err(err)),
                 }
-            },
-            Command::Set { key, value } => {
-                if let Err(err) = stream.set(key.as_bytes(), value.as_bytes()) {
-                    error(err);
+            }
+        },
+        Command::Subscribe { pattern } => {
+            if let Err(err) = client.subscribe(pattern.as_bytes()) {
+                return report(Err(err));
+            }
+            // Like watching, this only returns once the connection fails.
+            loop {
+                match client.next_event() {
+                    Ok(Event { name, key, value: Some(value) }) => println!(
+                        "{name} {} = {}",
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(&value)
+                    ),
+                    Ok(Event { name, key, value: None }) => {
+                        println!("{name} {}", String::from_utf8_lossy(&key))
+                    },
+                    Err(err) => return report(Err(err)),
                 }
+            }
+        },
+        Command::Auth { password } => report(client.auth(password.as_bytes())),
+        Command::AuthUser { user, password } => {
+            report(client.auth_user(user.as_bytes(), password.as_bytes()))
+        },
+        Command::Eval { script } => print_value(client.eval(script.as_bytes(), &[])),
+        Command::Select { index } => report(client.select(index)),
+        Command::Acks { level } => report(client.acks(level)),
+        Command::Multi | Command::Exec | Command::Discard | Command::Exit => {
+            error("this command can only be used at the prompt");
+            Outcome::Failed
+        },
+    }
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    let args = Cli::parse();
+    let port = args.port.unwrap_or(6210);
+    let client_args = ClientArgs {
+        connect_timeout: (args.connect_timeout > 0)
+            .then(|| Duration::from_secs(args.connect_timeout)),
+        request_timeout: (args.timeout > 0).then(|| Duration::from_secs(args.timeout)),
+    };
+    let mut client = match Client::connect_with(("127.0.0.1", port), &client_args) {
+        Ok(client) => client,
+        Err(err) => {
+            error(err);
+            return Outcome::Failed.into();
+        },
+    };
+    if let Some(password) = &args.password {
+        let result = match &args.user {
+            Some(user) => client.auth_user(user.as_bytes(), password.as_bytes()),
+            None => client.auth(password.as_bytes()),
+        };
+        if let Err(err) = result {
+            error(err);
+        }
+    }
+    if let Some(index) = args.database {
+        if let Err(err) = client.select(index) {
+            error(err);
+            return Outcome::Failed.into();
+        }
+    }
+
+    if !args.command.is_empty() {
+        let line = args.command.join(" ");
+        return match Command::parse(&line) {
+            Some(command) => run(&mut client, command).into(),
+            None => {
+                error(format!("unknown command: {line}"));
+                Outcome::Failed.into()
             },
-            Command::Delete { key } => {
-                if let Err(err) = stream.delete(key.as_bytes()) {
-                    error(err);
-                }
+        };
+    }
+
+    let mut editor = DefaultEditor::new().unwrap();
+    // The lines that have been queued since a `multi`, if there was one.
+    let mut queued: Option<Vec<String>> = None;
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            // Ctrl-C abandons the current line, and Ctrl-D exits.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return ExitCode::SUCCESS,
+            Err(err) => {
+                error(err);
+                return Outcome::Failed.into();
             },
-            Command::Exit => {
-                return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        _ = editor.add_history_entry(&line);
+        let Some(command) = Command::parse(&line) else {
+            error(format!("unknown command: {}", line.trim()));
+            continue;
+        };
+        if let Some(lines) = &mut queued {
+            match command {
+                Command::Get { .. }
+                | Command::Set { .. }
+                | Command::GetSet { .. }
+                | Command::Delete { .. }
+                | Command::GetDel { .. }
+                | Command::Exists { .. }
+                | Command::Ping => {
+                    lines.push(line.clone());
+                    println!("QUEUED");
+                },
+                Command::Exec => {
+                    if let Err(err) = exec(&mut client, lines) {
+                        error(err);
+                    }
+                    queued = None;
+                },
+                Command::Discard => queued = None,
+                _ => error("only get, set, getset, delete, getdel, exists and ping can be queued"),
+            }
+            continue;
+        }
+        match command {
+            Command::Multi => queued = Some(Vec::new()),
+            Command::Exec | Command::Discard => error("there is no multi to end"),
+            Command::Exit => return ExitCode::SUCCESS,
+            // Watching and subscribing only end once the connection fails.
+            Command::Watch { .. } | Command::Subscribe { .. } => {
+                return run(&mut client, command).into()
             },
+            command => _ = run(&mut client, command),
         }
     }
 }
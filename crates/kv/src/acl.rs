@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crunch_common::config::Config;
+
+use crate::protocol::{self, Command};
+use crate::watch;
+
+/// What a connection is allowed to do, once it has authenticated.
+///
+/// Keys are allowed by prefix, so that applications sharing a server can each
+/// be kept to their own part of the keyspace.
+#[derive(Debug, Default)]
+pub struct Permissions {
+    /// The prefixes of the keys that can be read. Keys that can be written can
+    /// also be read.
+    read: Vec<String>,
+
+    /// The prefixes of the keys that can be written.
+    write: Vec<String>,
+
+    /// The databases that can be selected, or `None` for every one.
+    databases: Option<Vec<usize>>,
+}
+
+impl Permissions {
+    /// The permissions to do anything, which connections have when the server
+    /// has no users, or they authenticate with `CRUNCH_KV__PASSWORD`.
+    pub fn full() -> Self {
+        Self { read: Vec::new(), write: vec![String::new()], databases: None }
+    }
+
+    pub fn can_read(&self, key: &str) -> bool {
+        self.can_write(key) || self.read.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    pub fn can_write(&self, key: &str) -> bool {
+        self.write.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    /// Whether every key can be read, which only the empty prefix allows.
+    pub fn can_read_all(&self) -> bool {
+        self.can_read("")
+    }
+
+    pub fn can_write_all(&self) -> bool {
+        self.can_write("")
+    }
+
+    pub fn can_select(&self, database: usize) -> bool {
+        self.databases.as_ref().is_none_or(|databases| databases.contains(&database))
+    }
+
+    /// Whether `command` can be run with `args`, of which the leading ones are
+    /// also given as `text`. Arguments that can't be parsed are allowed, so
+    /// that the command itself rejects them as malformed.
+    ///
+    /// Commands that can't be kept to a set of keys need access to all of
+    /// them: a script can touch any key, and a follower copies every write
+    /// and compares every key with its leader's.
+    /// A SUBSCRIBE is allowed, since its events are filtered instead.
+    pub fn allow(&self, command: Command, text: &[&str], args: &[Vec<u8>]) -> bool {
+        match command {
+            Command::Get | Command::Exists | Command::Scan => self.can_read(text[0]),
+            Command::Set
+            | Command::Delete
+            | Command::GetSet
+            | Command::Append
+            | Command::SetNx
+            | Command::GetDel => self.can_write(text[0]),
+            Command::Batch => protocol::parse_batch(&args[0]).is_none_or(|batch| {
+                batch.entries().iter().all(|entry| self.can_write(entry.key()))
+            }),
+            Command::Watch => watch::parse_keys(&args[0])
+                .is_none_or(|keys| keys.iter().all(|key| self.can_read(key))),
+            Command::Select => <[u8; 4]>::try_from(args[0].as_slice())
+                .map_or(true, |index| self.can_select(u32::from_be_bytes(index) as usize)),
+            Command::Replicate | Command::Digest | Command::Repair => self.can_read_all(),
+            Command::Eval => self.can_write_all(),
+            Command::Ping
+            | Command::Auth
+            | Command::AuthUser
+            | Command::Info
+            | Command::DbSize
+            | Command::Config
+            | Command::Cluster
+            | Command::Acks
+            | Command::Subscribe => true,
+        }
+    }
+}
+
+struct User {
+    password: String,
+    permissions: Arc<Permissions>,
+}
+
+/// The user accounts that connections can authenticate as, from the tables
+/// under `kv.users` in the config file, like:
+///
+/// ```toml
+/// [kv.users.reports]
+/// password = "secret"
+/// read = "orders/,customers/"
+///
+/// [kv.users.orders]
+/// password = "another secret"
+/// write = "orders/"
+/// databases = "0"
+/// ```
+///
+/// `read` and `write` are comma separated key prefixes, where `*` stands for
+/// every key, and `databases` limits the databases that the user can select.
+#[derive(Default)]
+pub struct Users(HashMap<String, User>);
+
+impl Users {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for name in config.tables("kv.users") {
+            let namespace = format!("users.{name}");
+            let setting = |setting| config.get("kv", Some(&namespace), setting, None::<String>);
+            let password =
+                setting("password").ok_or_else(|| format!("user {name} has no password"))?;
+            let databases = setting("databases")
+                .map(|databases| {
+                    databases
+                        .split(',')
+                        .map(|index| {
+                            index.trim().parse().map_err(|_| {
+                                format!("user {name} has an invalid database {index:?}")
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?;
+            let permissions = Permissions {
+                read: setting("read").as_deref().map(parse_prefixes).unwrap_or_default(),
+                write: setting("write").as_deref().map(parse_prefixes).unwrap_or_default(),
+                databases,
+            };
+            users.insert(name, User { password, permissions: Arc::new(permissions) });
+        }
+        Ok(Self(users))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The permissions of user `name`, if `password` is theirs.
+    pub fn authenticate(&self, name: &[u8], password: &[u8]) -> Option<Arc<Permissions>> {
+        let user = self.0.get(std::str::from_utf8(name).ok()?)?;
+        constant_time_eq(user.password.as_bytes(), password).then(|| user.permissions.clone())
+    }
+}
+
+/// Whether `a` and `b` are equal, comparing every byte however early they
+/// differ, so that how long a password check takes doesn't give away how much
+/// of the password was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b));
+    std::hint::black_box(difference) == 0
+}
+
+fn parse_prefixes(prefixes: &str) -> Vec<String> {
+    prefixes
+        .split(',')
+        .map(str::trim)
+        .filter(|prefix| !prefix.is_empty())
+        .map(|prefix| if prefix == "*" { String::new() } else { prefix.to_owned() })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn users() {
+        let mut config = Config::from_toml(
+            r#"
+            [kv.users.reports]
+            password = "a"
+            read = "orders/, customers/"
+
+            [kv.users.orders]
+            password = "b"
+            write = "orders/"
+            databases = "0, 2"
+            "#,
+        )
+        .unwrap();
+        config.set_override("kv.users.admin.password=c").unwrap();
+        config.set_override("kv.users.admin.write=*").unwrap();
+        let users = Users::from_config(&config).unwrap();
+        assert_eq!(users.len(), 3);
+        assert!(users.authenticate(b"reports", b"b").is_none());
+        assert!(users.authenticate(b"nobody", b"a").is_none());
+
+        let reports = users.authenticate(b"reports", b"a").unwrap();
+        assert!(reports.can_read("customers/1") && !reports.can_write("customers/1"));
+        assert!(!reports.can_read("other") && !reports.can_read_all());
+        assert!(reports.can_select(5));
+
+        let orders = users.authenticate(b"orders", b"b").unwrap();
+        assert!(orders.can_read("orders/1") && orders.can_write("orders/1"));
+        assert!(!orders.can_read("customers/1"));
+        assert!(orders.can_select(2) && !orders.can_select(1));
+        let batch = |key: &str| {
+            let mut data = vec![2];
+            data.extend((key.len() as u32).to_be_bytes());
+            data.extend(key.as_bytes());
+            vec![data]
+        };
+        assert!(orders.allow(Command::Batch, &[], &batch("orders/1")));
+        assert!(!orders.allow(Command::Batch, &[], &batch("customers/1")));
+        assert!(!orders.allow(Command::Eval, &["return 1"], &[vec![], vec![]]));
+
+        let admin = users.authenticate(b"admin", b"c").unwrap();
+        assert!(admin.can_read_all() && admin.can_write_all());
+        assert!(admin.allow(Command::Replicate, &[], &[vec![0; 8]]));
+
+        let mut config = Config::default();
+        config.set_override("kv.users.broken.read=*").unwrap();
+        assert!(Users::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn constant_time_eq() {
+        assert!(super::constant_time_eq(b"secret", b"secret"));
+        assert!(!super::constant_time_eq(b"secret", b"secreT"));
+        assert!(!super::constant_time_eq(b"secret", b"secrets"));
+        assert!(super::constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,620 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use crate::protocol::MAX_DATA_SIZE;
+use crate::raft::{self, Envelope, FileStorage, LogEntry, Message, NodeId, RaftNode};
+use crate::replication::Record;
+use crate::Server;
+
+/// How often the Raft clock ticks.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a write waits to be committed before giving up.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait before reconnecting to a peer that can't be reached.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// How long a peer that connects has to authenticate.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The size of the random challenge that a peer that connects is sent, which
+/// it proves that it knows the cluster's secret with.
+const CHALLENGE_SIZE: usize = 32;
+
+/// The largest frame that is accepted from a peer. An `AppendEntries` message
+/// only goes past [`raft::MAX_BYTES_PER_MESSAGE`] by its last entry, whose key
+/// and value are each at most [`MAX_DATA_SIZE`], and the rest of the message
+/// is far smaller than the headroom left for it.
+const MAX_FRAME_SIZE: u32 = raft::MAX_BYTES_PER_MESSAGE as u32 * 2 + MAX_DATA_SIZE * 2;
+
+const RAFT_LOG_FILENAME: &str = "raft.log";
+
+/// Settings for running the server as a member of a Raft cluster.
+#[derive(Debug)]
+pub struct ClusterArgs {
+    /// The id of this server.
+    pub id: NodeId,
+
+    /// The address that every member of the cluster, including this one,
+    /// listens for Raft messages on.
+    pub members: HashMap<NodeId, String>,
+
+    /// What members authenticate each other with, which they all share.
+    pub secret: String,
+}
+
+impl ClusterArgs {
+    /// Parse a list of members in the form `1=host:port,2=host:port,...`. Ids
+    /// start from 1, since the Raft log records a vote for no one as 0.
+    pub fn parse(id: NodeId, members: &str, secret: String) -> Result<Self, String> {
+        let members = members
+            .split(',')
+            .map(|member| {
+                let (id, address) =
+                    member.split_once('=').ok_or_else(|| format!("invalid member {member:?}"))?;
+                let id =
+                    id.trim().parse().ok().filter(|id| *id != 0).ok_or_else(|| {
+                        format!("invalid member id {id:?}, which must be at least 1")
+                    })?;
+                Ok((id, address.trim().to_owned()))
+            })
+            .collect::<Result<HashMap<_, _>, String>>()?;
+        if !members.contains_key(&id) {
+            return Err(format!("this server's id ({id}) is not one of the members"));
+        }
+        Ok(Self { id, members, secret })
+    }
+}
+
+/// The Raft state of a clustered server.
+///
+/// In clustered mode, writes are only applied to the engine once they have been
+/// committed to a majority of the members' Raft logs, so the cluster keeps
+/// every acknowledged write as long as a majority of it survives. Only the
+/// leader accepts writes. Reads are served from the local engine, so they may
+/// lag behind the leader on other members. Members only take messages from
+/// each other once the sender has proven that it knows the cluster's secret.
+///
+/// The Raft log is never compacted, and on startup every committed entry is
+/// applied to the engine again, in order.
+///
+/// If the Raft log can't be written to, the server stops taking part in the
+/// cluster, and every write to it fails from then on.
+pub struct Cluster {
+    /// The node, until it fails to write to the Raft log.
+    node: Mutex<Option<RaftNode<FileStorage>>>,
+
+    /// Writes waiting to be committed, keyed by the index of their entry.
+    waiters: Mutex<HashMap<u64, Waiter>>,
+
+    /// A queue of outgoing messages for each peer.
+    outgoing: HashMap<NodeId, mpsc::UnboundedSender<Envelope>>,
+
+    /// Wakes the driver after a proposal, so that it is sent out without
+    /// waiting for the next tick.
+    proposed: Notify,
+}
+
+/// A write waiting to be committed: the term it was proposed in, and where to
+/// send how it went.
+type Waiter = (u64, oneshot::Sender<Result<(), ProposeError>>);
+
+/// The reasons that a write can fail to commit.
+#[derive(Debug)]
+pub enum ProposeError {
+    /// This server isn't the leader. Holds the leader's id, if it is known.
+    NotLeader(Option<NodeId>),
+
+    /// Leadership changed before the write was committed, so it was discarded.
+    Discarded,
+
+    /// The write was committed, but this server's engine failed to apply it, so
+    /// the engine no longer matches the rest of the cluster.
+    NotApplied(String),
+
+    TimedOut,
+
+    /// The Raft log couldn't be written to, so this server has stopped taking
+    /// part in the cluster.
+    Stopped,
+}
+
+/// The Raft log couldn't be written to, so the node has been shut down.
+#[derive(Debug)]
+struct Stopped;
+
+impl Cluster {
+    /// Open the Raft log in the store directory at `path`, and start listening
+    /// for messages from the rest of the cluster. Call [`run`] to start taking
+    /// part in it.
+    pub async fn start(
+        args: ClusterArgs,
+        path: &Path,
+    ) -> Result<(Arc<Self>, mpsc::UnboundedReceiver<Envelope>), io::Error> {
+        let (storage, state) = FileStorage::open(&path.join(RAFT_LOG_FILENAME))?;
+        let peers: Vec<_> = args.members.keys().copied().filter(|id| *id != args.id).collect();
+        log::info!("joining cluster as node {} with peers {peers:?}", args.id);
+
+        let secret: Arc<[u8]> = args.secret.as_bytes().into();
+        let (inbound_sender, inbound) = mpsc::unbounded_channel();
+        let listener = TcpListener::bind(&args.members[&args.id]).await?;
+        tokio::task::spawn(listen(listener, inbound_sender, peers.clone(), secret.clone()));
+
+        let mut outgoing = HashMap::new();
+        for peer in &peers {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let address = args.members[peer].clone();
+            tokio::task::spawn(send_to_peer(address, receiver, args.id, secret.clone()));
+            outgoing.insert(*peer, sender);
+        }
+
+        let cluster = Self {
+            node: Mutex::new(Some(RaftNode::new(args.id, peers, storage, state))),
+            waiters: Mutex::new(HashMap::new()),
+            outgoing,
+            proposed: Notify::new(),
+        };
+        Ok((Arc::new(cluster), inbound))
+    }
+
+    /// Commit `record` to the cluster, and return once it has been applied to
+    /// this server's engine.
+    pub async fn propose(&self, record: Record) -> Result<(), ProposeError> {
+        let (sender, receiver) = oneshot::channel();
+        let (index, term) = self
+            .with_node(|node| {
+                let proposed = node.propose(record)?;
+                // The waiter is registered before the node is unlocked, so the driver
+                // can't apply the entry before it is there.
+                if let Ok((index, term)) = proposed {
+                    self.waiters
+                        .lock()
+                        .expect("raft waiters lock is poisoned")
+                        .insert(index, (term, sender));
+                }
+                Ok(proposed)
+            })
+            .map_err(|Stopped| ProposeError::Stopped)?
+            .map_err(|raft::NotLeader(leader)| ProposeError::NotLeader(leader))?;
+        self.proposed.notify_one();
+        match tokio::time::timeout(COMMIT_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(ProposeError::Discarded),
+            Err(_) => {
+                // The entry may still be committed later, but nothing is waiting for it.
+                // A waiter of another term at its index belongs to a later proposal.
+                let mut waiters = self.waiters.lock().expect("raft waiters lock is poisoned");
+                if waiters.get(&index).is_some_and(|(proposed, _)| *proposed == term) {
+                    waiters.remove(&index);
+                }
+                Err(ProposeError::TimedOut)
+            },
+        }
+    }
+
+    /// Run `operation` on the Raft node, which may write to the Raft log, so
+    /// it is run outside of the async runtime.
+    ///
+    /// If the log can't be written to, the node is dropped without sending
+    /// anything that it produced, since it may have changed state that isn't
+    /// durable, and every write waiting to be committed is discarded.
+    fn with_node<T>(
+        &self,
+        operation: impl FnOnce(&mut RaftNode<FileStorage>) -> Result<T, io::Error>,
+    ) -> Result<T, Stopped> {
+        tokio::task::block_in_place(|| {
+            let mut node = self.node.lock().expect("raft node lock is poisoned");
+            let result = operation(node.as_mut().ok_or(Stopped)?);
+            result.map_err(|error| {
+                log::error!("failed to write to the raft log, leaving the cluster: {error}");
+                *node = None;
+                self.waiters.lock().expect("raft waiters lock is poisoned").clear();
+                Stopped
+            })
+        })
+    }
+}
+
+/// Drive the Raft node of `server`'s cluster: tick its clock, feed it messages
+/// from `inbound`, send out the messages it produces, and apply the entries it
+/// commits to the engine. Returns once the node has stopped.
+pub async fn run(server: Arc<Server>, mut inbound: mpsc::UnboundedReceiver<Envelope>) {
+    let cluster = server.cluster.clone().expect("server is not clustered");
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    let mut last_state = None;
+    loop {
+        let stepped = tokio::select! {
+            _ = interval.tick() => cluster.with_node(|node| node.tick()),
+            Some(envelope) = inbound.recv() => cluster.with_node(|node| node.step(envelope)),
+            _ = cluster.proposed.notified() => Ok(()),
+        };
+        let taken = stepped.and_then(|()| {
+            cluster.with_node(|node| {
+                let state = (node.role(), node.term(), node.leader());
+                if last_state != Some(state) {
+                    log::debug!(
+                        "node {} is {:?} in term {}, leader is {:?}",
+                        node.id(),
+                        state.0,
+                        state.1,
+                        state.2
+                    );
+                    last_state = Some(state);
+                }
+                Ok((node.take_messages(), node.take_committed()))
+            })
+        });
+        let Ok((messages, committed)) = taken else {
+            return;
+        };
+        for envelope in messages {
+            if let Some(outgoing) = cluster.outgoing.get(&envelope.to) {
+                _ = outgoing.send(envelope);
+            }
+        }
+        if committed.is_empty() {
+            continue;
+        }
+        let _writes = server.writes.lock().await;
+        // A clustered server only has the one database.
+        let database = &server.databases[0];
+        let engine = &database.engine;
+        for (index, entry) in committed {
+            let result = match &entry.record {
+                Some(Record::Set { key, value }) => engine.set(key, value),
+                Some(Record::Delete { key }) => engine.delete(key),
+                None => Ok(()),
+            };
+            let result = match (result, &entry.record) {
+                (Err(error), _) => {
+                    log::error!(
+                        "failed to apply raft entry {index}, so this server has diverged from \
+                         the cluster: {error:?}"
+                    );
+                    Err(ProposeError::NotApplied(error.to_string()))
+                },
+                (Ok(()), Some(record)) => {
+                    database.notify_watchers(record);
+                    Ok(())
+                },
+                (Ok(()), None) => Ok(()),
+            };
+            let waiter =
+                cluster.waiters.lock().expect("raft waiters lock is poisoned").remove(&index);
+            if let Some((term, waiter)) = waiter {
+                // Another leader may have replaced the proposed entry with its own.
+                let result = if term == entry.term { result } else { Err(ProposeError::Discarded) };
+                _ = waiter.send(result);
+            }
+        }
+    }
+}
+
+async fn listen(
+    listener: TcpListener,
+    inbound: mpsc::UnboundedSender<Envelope>,
+    peers: Vec<NodeId>,
+    secret: Arc<[u8]>,
+) {
+    let peers = Arc::new(peers);
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                log::warn!("raft connection error: {error}");
+                continue;
+            },
+        };
+        let (inbound, peers, secret) = (inbound.clone(), peers.clone(), secret.clone());
+        tokio::task::spawn(async move {
+            match receive_from_peer(stream, inbound, &peers, &secret).await {
+                Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                    log::warn!("refused raft connection from {address}: {error}");
+                },
+                Err(error) => log::debug!("raft peer disconnected: {error}"),
+                Ok(()) => {},
+            }
+        });
+    }
+}
+
+/// Authenticate the peer on the other end of `stream`, and then pass on every
+/// message that it sends to `inbound`. The peer must be one of `peers`, and
+/// only send messages from itself.
+async fn receive_from_peer(
+    mut stream: TcpStream,
+    inbound: mpsc::UnboundedSender<Envelope>,
+    peers: &[NodeId],
+    secret: &[u8],
+) -> Result<(), io::Error> {
+    let challenge: [u8; CHALLENGE_SIZE] = rand::random();
+    let peer = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        stream.write_all(&challenge).await?;
+        let peer = stream.read_u64().await?;
+        let mut tag = [0; 32];
+        stream.read_exact(&mut tag).await?;
+        let denied = |message: &str| io::Error::new(io::ErrorKind::PermissionDenied, message);
+        if !peers.contains(&peer) {
+            return Err(denied(&format!("{peer} isn't a member of the cluster")));
+        }
+        handshake_mac(secret, &challenge, peer)
+            .verify_slice(&tag)
+            .map_err(|_| denied(&format!("node {peer} doesn't know the cluster's secret")))?;
+        Ok(peer)
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "raft peer didn't authenticate"))??;
+    loop {
+        let size = stream.read_u32().await?;
+        if size > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("raft frame of {size} bytes is larger than allowed"),
+            ));
+        }
+        let mut frame = vec![0; size as usize];
+        stream.read_exact(&mut frame).await?;
+        let envelope = decode(&mut frame.as_slice())?;
+        if envelope.from != peer {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("node {peer} sent a message from node {}", envelope.from),
+            ));
+        }
+        if inbound.send(envelope).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// The MAC that proves that node `id` knows `secret`, in answer to
+/// `challenge`.
+fn handshake_mac(secret: &[u8], challenge: &[u8], id: NodeId) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(challenge);
+    mac.update(&id.to_be_bytes());
+    mac
+}
+
+/// Send every message on `outgoing` to the peer at `address`, reconnecting
+/// whenever the connection drops.
+///
+/// Each connection starts by proving that this node, `id`, knows the
+/// cluster's `secret`.
+///
+/// Messages are dropped while the peer can't be reached. Raft copes with lost
+/// messages, and this keeps the queue from growing without bound.
+async fn send_to_peer(
+    address: String,
+    mut outgoing: mpsc::UnboundedReceiver<Envelope>,
+    id: NodeId,
+    secret: Arc<[u8]>,
+) {
+    loop {
+        let connected = async {
+            let mut stream = TcpStream::connect(&address).await?;
+            let mut challenge = [0; CHALLENGE_SIZE];
+            stream.read_exact(&mut challenge).await?;
+            let tag = handshake_mac(&secret, &challenge, id).finalize().into_bytes();
+            stream.write_u64(id).await?;
+            stream.write_all(&tag).await?;
+            Ok::<_, io::Error>(stream)
+        };
+        let connected =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, connected).await.unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "the handshake timed out"))
+            });
+        let mut stream = match connected {
+            Ok(stream) => stream,
+            Err(error) => {
+                log::trace!("failed to connect to raft peer at {address}: {error}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                while outgoing.try_recv().is_ok() {}
+                continue;
+            },
+        };
+        while let Some(envelope) = outgoing.recv().await {
+            let mut frame = Vec::new();
+            encode(&mut frame, &envelope);
+            let mut buffer = (frame.len() as u32).to_be_bytes().to_vec();
+            buffer.extend(frame);
+            if let Err(error) = stream.write_all(&buffer).await {
+                log::debug!("lost connection to raft peer at {address}: {error}");
+                break;
+            }
+        }
+    }
+}
+
+fn encode(buffer: &mut Vec<u8>, envelope: &Envelope) {
+    buffer.extend(envelope.from.to_be_bytes());
+    buffer.extend(envelope.to.to_be_bytes());
+    match &envelope.message {
+        Message::RequestVote { term, last_log_index, last_log_term } => {
+            buffer.push(1);
+            for field in [term, last_log_index, last_log_term] {
+                buffer.extend(field.to_be_bytes());
+            }
+        },
+        Message::Vote { term, granted } => {
+            buffer.push(2);
+            buffer.extend(term.to_be_bytes());
+            buffer.push(*granted as u8);
+        },
+        Message::AppendEntries { term, prev_log_index, prev_log_term, entries, leader_commit } => {
+            buffer.push(3);
+            for field in [term, prev_log_index, prev_log_term, leader_commit] {
+                buffer.extend(field.to_be_bytes());
+            }
+            buffer.extend((entries.len() as u32).to_be_bytes());
+            for entry in entries {
+                buffer.extend(entry.term.to_be_bytes());
+                raft::write_record(buffer, entry.record.as_ref());
+            }
+        },
+        Message::AppendResponse { term, success, match_index } => {
+            buffer.push(4);
+            buffer.extend(term.to_be_bytes());
+            buffer.push(*success as u8);
+            buffer.extend(match_index.to_be_bytes());
+        },
+    }
+}
+
+fn decode(reader: &mut impl Read) -> Result<Envelope, io::Error> {
+    let read_u8 = |reader: &mut dyn Read| -> Result<u8, io::Error> {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        Ok(byte[0])
+    };
+    let from = raft::read_u64(reader)?;
+    let to = raft::read_u64(reader)?;
+    let message = match read_u8(reader)? {
+        1 => Message::RequestVote {
+            term: raft::read_u64(reader)?,
+            last_log_index: raft::read_u64(reader)?,
+            last_log_term: raft::read_u64(reader)?,
+        },
+        2 => Message::Vote { term: raft::read_u64(reader)?, granted: read_u8(reader)? == 1 },
+        3 => {
+            let term = raft::read_u64(reader)?;
+            let prev_log_index = raft::read_u64(reader)?;
+            let prev_log_term = raft::read_u64(reader)?;
+            let leader_commit = raft::read_u64(reader)?;
+            let mut count = [0; 4];
+            reader.read_exact(&mut count)?;
+            let entries = (0..u32::from_be_bytes(count))
+                .map(|_| {
+                    Ok(LogEntry {
+                        term: raft::read_u64(reader)?,
+                        record: raft::read_record(reader)?,
+                    })
+                })
+                .collect::<Result<_, io::Error>>()?;
+            Message::AppendEntries { term, prev_log_index, prev_log_term, entries, leader_commit }
+        },
+        4 => Message::AppendResponse {
+            term: raft::read_u64(reader)?,
+            success: read_u8(reader)? == 1,
+            match_index: raft::read_u64(reader)?,
+        },
+        tag => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid raft message {tag}"),
+            ))
+        },
+    };
+    Ok(Envelope { from, to, message })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let entries = vec![
+            LogEntry { term: 1, record: None },
+            LogEntry { term: 2, record: Some(Record::Set { key: "a".into(), value: "1".into() }) },
+            LogEntry { term: 2, record: Some(Record::Delete { key: "b".into() }) },
+        ];
+        let messages = [
+            Message::RequestVote { term: 3, last_log_index: 4, last_log_term: 2 },
+            Message::Vote { term: 3, granted: true },
+            Message::AppendEntries {
+                term: 3,
+                prev_log_index: 1,
+                prev_log_term: 1,
+                entries,
+                leader_commit: 1,
+            },
+            Message::AppendResponse { term: 3, success: false, match_index: 7 },
+        ];
+        for message in messages {
+            let envelope = Envelope { from: 1, to: 2, message };
+            let mut buffer = Vec::new();
+            encode(&mut buffer, &envelope);
+            assert_eq!(decode(&mut buffer.as_slice()).unwrap(), envelope);
+        }
+    }
+
+    /// Connect to `address` and answer its challenge as node `id`, with
+    /// `secret`.
+    async fn handshake(address: std::net::SocketAddr, id: NodeId, secret: &[u8]) -> TcpStream {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut challenge = [0; CHALLENGE_SIZE];
+        stream.read_exact(&mut challenge).await.unwrap();
+        stream.write_u64(id).await.unwrap();
+        let tag = handshake_mac(secret, &challenge, id).finalize().into_bytes();
+        stream.write_all(&tag).await.unwrap();
+        stream
+    }
+
+    async fn send(stream: &mut TcpStream, envelope: &Envelope) {
+        let mut frame = Vec::new();
+        encode(&mut frame, envelope);
+        stream.write_u32(frame.len() as u32).await.unwrap();
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    /// Whether the other end of `stream` has closed it.
+    async fn closed(stream: &mut TcpStream) -> bool {
+        matches!(stream.read(&mut [0; 1]).await, Ok(0) | Err(_))
+    }
+
+    #[tokio::test]
+    async fn peers_must_authenticate() {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let (sender, mut inbound) = mpsc::unbounded_channel();
+            tokio::task::spawn(listen(listener, sender, vec![2, 3], b"secret".as_slice().into()));
+            let vote =
+                |from| Envelope { from, to: 1, message: Message::Vote { term: 1, granted: true } };
+
+            // A peer that doesn't know the secret, or isn't a member, is cut off before
+            // anything it sends is passed on.
+            let mut stream = handshake(address, 2, b"guess").await;
+            send(&mut stream, &vote(2)).await;
+            assert!(closed(&mut stream).await);
+            let mut stream = handshake(address, 4, b"secret").await;
+            send(&mut stream, &vote(4)).await;
+            assert!(closed(&mut stream).await);
+            assert!(inbound.try_recv().is_err());
+
+            // A member can only send messages from itself, in frames of a bounded size.
+            let mut stream = handshake(address, 2, b"secret").await;
+            send(&mut stream, &vote(2)).await;
+            assert_eq!(inbound.recv().await.unwrap(), vote(2));
+            send(&mut stream, &vote(3)).await;
+            assert!(closed(&mut stream).await);
+            let mut stream = handshake(address, 3, b"secret").await;
+            stream.write_u32(MAX_FRAME_SIZE + 1).await.unwrap();
+            assert!(closed(&mut stream).await);
+            assert!(inbound.try_recv().is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn parse_args() {
+        let parse = |id, members| ClusterArgs::parse(id, members, "secret".to_owned());
+        let args = parse(2, "1=127.0.0.1:7201, 2=127.0.0.1:7202").unwrap();
+        assert_eq!(args.members[&2], "127.0.0.1:7202");
+        assert!(parse(3, "1=127.0.0.1:7201").is_err());
+        assert!(parse(1, "1:127.0.0.1:7201").is_err());
+        assert!(parse(1, "0=127.0.0.1:7200,1=127.0.0.1:7201").is_err());
+    }
+}
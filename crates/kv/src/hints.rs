@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::replication::Record;
+
+/// The writes that named followers miss while they are disconnected, which a
+/// leader keeps on disk to replay when they reconnect, even once they have
+/// dropped out of the in-memory replication log.
+///
+/// A follower is only hinted for so long after it disconnects, and for so many
+/// bytes of writes. Past either, its hints are dropped, and it has to be
+/// repaired instead. Hints are also dropped when the leader restarts, since
+/// the sequences of its writes start over.
+pub struct Hints {
+    directory: PathBuf,
+    window: Duration,
+    max_bytes: u64,
+
+    /// The followers that are being hinted, by name.
+    absent: Mutex<HashMap<String, HintFile>>,
+}
+
+struct HintFile {
+    writer: BufWriter<File>,
+
+    /// The sequence of the first record that the follower missed.
+    first: u64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl Hints {
+    /// Keep hints in `directory`, dropping any that an earlier run left there.
+    pub fn open(directory: PathBuf, window: Duration, max_bytes: u64) -> io::Result<Self> {
+        match fs::remove_dir_all(&directory) {
+            Ok(()) => log::info!("dropped the hints left by the last run"),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {},
+            Err(error) => return Err(error),
+        }
+        fs::create_dir_all(&directory)?;
+        Ok(Self { directory, window, max_bytes, absent: Mutex::new(HashMap::new()) })
+    }
+
+    /// Start hinting the writes that follower `name` misses, from the record at
+    /// sequence `first`, now that it has disconnected. `missed` are the records
+    /// from `first` on that have already been made.
+    pub fn start(&self, name: &str, first: u64, missed: &[(u64, Record)]) {
+        let mut absent = self.absent.lock().expect("hints lock is poisoned");
+        let file = match File::create(self.path(name)) {
+            Ok(file) => file,
+            Err(error) => {
+                log::warn!("failed to start hinting follower {name}: {error}");
+                return;
+            },
+        };
+        let writer = BufWriter::new(file);
+        let mut hints = HintFile { writer, first, bytes: 0, since: Instant::now() };
+        for (sequence, record) in missed {
+            if let Err(error) = self.write(&mut hints, *sequence, record) {
+                log::warn!("dropping the hints for follower {name}: {error}");
+                _ = fs::remove_file(self.path(name));
+                return;
+            }
+        }
+        log::info!("hinting the writes that follower {name} misses, from sequence {first}");
+        absent.insert(name.to_owned(), hints);
+    }
+
+    /// Hint the record at `sequence` for every follower that is being hinted.
+    pub fn append(&self, sequence: u64, record: &Record) {
+        let mut absent = self.absent.lock().expect("hints lock is poisoned");
+        absent.retain(|name, hints| {
+            let dropped = if hints.since.elapsed() > self.window {
+                "it has been disconnected for longer than the hint window".to_owned()
+            } else {
+                match self.write(hints, sequence, record) {
+                    Ok(()) if hints.bytes <= self.max_bytes => return true,
+                    Ok(()) => "its hints are over CRUNCH_KV__HINT_MAX_BYTES".to_owned(),
+                    Err(error) => error.to_string(),
+                }
+            };
+            log::warn!("dropping the hints for follower {name}, since {dropped}");
+            _ = fs::remove_file(self.path(name));
+            false
+        });
+    }
+
+    /// Stop hinting follower `name`, which has reconnected, and return the
+    /// records that it missed from `from` on, if they were hinted.
+    pub fn take(&self, name: &str, from: u64) -> Option<Vec<(u64, Record)>> {
+        let mut hints = self.absent.lock().expect("hints lock is poisoned").remove(name)?;
+        let path = self.path(name);
+        let records = hints.writer.flush().and_then(|()| read(&path));
+        _ = fs::remove_file(&path);
+        if hints.first > from {
+            log::debug!("follower {name} asked for sequence {from}, before its hints start");
+            return None;
+        }
+        match records {
+            Ok(records) => {
+                Some(records.into_iter().filter(|(sequence, _)| *sequence >= from).collect())
+            },
+            Err(error) => {
+                log::warn!("failed to read the hints for follower {name}: {error}");
+                None
+            },
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.directory.join(format!("{name}.hints"))
+    }
+
+    fn write(&self, hints: &mut HintFile, sequence: u64, record: &Record) -> io::Result<()> {
+        let encoded = encode(sequence, record);
+        hints.writer.write_all(&encoded)?;
+        hints.bytes += encoded.len() as u64;
+        Ok(())
+    }
+}
+
+/// Whether `name` can be given by a follower, which it can if it is made up of
+/// letters, digits, `-`, `_` and `.`, since it names the follower's hint file.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|char| char.is_ascii_alphanumeric() || "-_.".contains(char))
+}
+
+/// Encode a hinted record as its sequence, then the record as it is sent to
+/// followers.
+fn encode(sequence: u64, record: &Record) -> Vec<u8> {
+    let mut encoded = sequence.to_be_bytes().to_vec();
+    encoded.push(record.indicator());
+    let mut push = |data: &str| {
+        encoded.extend((data.len() as u32).to_be_bytes());
+        encoded.extend(data.as_bytes());
+    };
+    match record {
+        Record::Set { key, value } => {
+            push(key);
+            push(value);
+        },
+        Record::Delete { key } => push(key),
+    }
+    encoded
+}
+
+fn read(path: &Path) -> io::Result<Vec<(u64, Record)>> {
+    fn read_string(reader: &mut impl Read) -> io::Result<String> {
+        let mut size = [0; 4];
+        reader.read_exact(&mut size)?;
+        let mut data = vec![0; u32::from_be_bytes(size) as usize];
+        reader.read_exact(&mut data)?;
+        String::from_utf8(data).map_err(io::Error::other)
+    }
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    loop {
+        let mut sequence = [0; 8];
+        match reader.read_exact(&mut sequence) {
+            Ok(()) => {},
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(error) => return Err(error),
+        }
+        let mut indicator = [0; 1];
+        reader.read_exact(&mut indicator)?;
+        let key = read_string(&mut reader)?;
+        let record = match indicator[0] {
+            1 => Record::Set { key, value: read_string(&mut reader)? },
+            _ => Record::Delete { key },
+        };
+        records.push((u64::from_be_bytes(sequence), record));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set(key: &str) -> Record {
+        Record::Set { key: key.to_owned(), value: "1".to_owned() }
+    }
+
+    fn open(name: &str, window: Duration, max_bytes: u64) -> (Hints, PathBuf) {
+        let path = std::env::temp_dir().join(format!("crunch-kv-test-hints-{name}"));
+        (Hints::open(path.clone(), window, max_bytes).unwrap(), path)
+    }
+
+    #[test]
+    fn take() {
+        let (hints, path) = open("take", Duration::from_secs(60), 1024);
+        hints.start("a", 2, &[(2, set("b")), (3, Record::Delete { key: "c".to_owned() })]);
+        hints.start("other", 4, &[]);
+        hints.append(4, &set("d"));
+        assert!(path.join("a.hints").exists());
+
+        assert_eq!(hints.take("a", 3).unwrap(), [
+            (3, Record::Delete { key: "c".to_owned() }),
+            (4, set("d"))
+        ]);
+        assert!(!path.join("a.hints").exists());
+        assert_eq!(hints.take("a", 3), None);
+        // The hints don't reach back to sequence 3.
+        assert_eq!(hints.take("other", 3), None);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn limits() {
+        let (hints, path) = open("limits", Duration::from_secs(60), 30);
+        hints.start("a", 1, &[]);
+        hints.append(1, &set("b"));
+        hints.append(2, &set("c"));
+        assert_eq!(hints.take("a", 1), None);
+        fs::remove_dir_all(path).unwrap();
+
+        let (hints, path) = open("window", Duration::ZERO, 1024);
+        hints.start("a", 1, &[]);
+        std::thread::sleep(Duration::from_millis(1));
+        hints.append(1, &set("b"));
+        assert_eq!(hints.take("a", 1), None);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn valid_name() {
+        assert!(super::valid_name("replica-1.east_2"));
+        assert!(!super::valid_name(""));
+        assert!(!super::valid_name("../leader"));
+        assert!(!super::valid_name("a/b"));
+    }
+}
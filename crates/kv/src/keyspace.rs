@@ -0,0 +1,141 @@
+use tokio::io;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::acl::Permissions;
+use crate::protocol;
+use crate::replication::Record;
+
+/// A glob pattern that keys are matched against, in which `*` matches any
+/// run of characters, `?` matches any single character, and `\` matches the
+/// character after it literally.
+#[derive(Debug)]
+pub struct Pattern(Vec<Token>);
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Literal(char),
+    AnyChar,
+    AnyRun,
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(char) = chars.next() {
+            tokens.push(match char {
+                '*' => Token::AnyRun,
+                '?' => Token::AnyChar,
+                // A trailing backslash has nothing to escape, so it stands for itself.
+                '\\' => Token::Literal(chars.next().unwrap_or('\\')),
+                char => Token::Literal(char),
+            });
+        }
+        Self(tokens)
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let (mut token, mut char) = (0, 0);
+        // Where to pick up from if the rest of the key doesn't match: just after the
+        // last `*`, with it taking one more character than it did.
+        let mut resume = None;
+        while char < key.len() {
+            match self.0.get(token) {
+                Some(Token::AnyRun) => {
+                    resume = Some((token + 1, char));
+                    token += 1;
+                    continue;
+                },
+                Some(Token::AnyChar) => {
+                    token += 1;
+                    char += 1;
+                    continue;
+                },
+                Some(Token::Literal(literal)) if *literal == key[char] => {
+                    token += 1;
+                    char += 1;
+                    continue;
+                },
+                _ => {},
+            }
+            let Some((after_run, start)) = resume else {
+                return false;
+            };
+            token = after_run;
+            char = start + 1;
+            resume = Some((after_run, start + 1));
+        }
+        self.0[token..].iter().all(|token| *token == Token::AnyRun)
+    }
+}
+
+/// Push an event for every change to a key that matches `pattern` from
+/// `changes` to a subscriber on `stream`, until the connection is closed.
+/// Changes to keys that the subscriber's `permissions` don't let it read are
+/// left out.
+///
+/// Unlike a watcher, which names the keys it wants, a subscriber is told
+/// about every key that matches, including ones that don't exist yet. Each
+/// event is a success status, then the name of the event (`set` or
+/// `delete`), then the key, and then the new value for a `set`. If the
+/// subscriber falls too far behind, a failure outcome is written in place of
+/// the events that were missed, and the connection is closed.
+pub async fn serve_subscriber(
+    mut changes: broadcast::Receiver<Record>,
+    pattern: Pattern,
+    permissions: &Permissions,
+    stream: &mut protocol::Stream,
+) -> Result<(), io::Error> {
+    stream.write_success().await?;
+    log::info!("subscribed to keyspace events for {pattern:?}");
+    loop {
+        let record = match changes.recv().await {
+            Ok(record) => record,
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("subscriber missed {missed} events, disconnecting it");
+                return stream.write_failure().await;
+            },
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let (name, key, value) = match &record {
+            Record::Set { key, value } => ("set", key, Some(value)),
+            Record::Delete { key } => ("delete", key, None),
+        };
+        if !pattern.matches(key) || !permissions.can_read(key) {
+            continue;
+        }
+        stream.write_success().await?;
+        stream.write_data(name.as_bytes()).await?;
+        stream.write_data(key.as_bytes()).await?;
+        if let Some(value) = value {
+            stream.write_data(value.as_bytes()).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pattern() {
+        let matches = |pattern: &str, key: &str| Pattern::parse(pattern).matches(key);
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:1"));
+        assert!(matches("user:*", "user:"));
+        assert!(!matches("user:*", "users:1"));
+        assert!(matches("*:name", "user:1:name"));
+        assert!(matches("a*b*c", "axxbyybzc"));
+        assert!(!matches("a*b*c", "axxbyyb"));
+        assert!(matches("?at", "cat"));
+        assert!(!matches("?at", "at"));
+        assert!(matches("é?", "éa"));
+        assert!(matches(r"a\*", "a*"));
+        assert!(!matches(r"a\*", "ab"));
+        assert!(matches("exact", "exact"));
+        assert!(!matches("exact", "exactly"));
+    }
+}
@@ -1,23 +1,276 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crunch_common::env::parse_env;
-use crunch_engine::engine::Engine;
-use protocol::Command;
+use acl::{Permissions, Users};
+use clap::Parser;
+use cluster::{Cluster, ClusterArgs, ProposeError};
+use crunch_common::config::Config;
+use crunch_common::env::ByteSize;
+use crunch_common::registry;
+use crunch_engine::engine::{Engine, EngineArgs};
+use crunch_engine::error::{Error as EngineError, PairComponent};
+use crunch_engine::metrics::{BloomFilterStats, Histogram};
+use crunch_engine::segment::Entry;
+use hints::Hints;
+use protocol::{Command, Status};
+use replication::{AckLevel, Record, ReplicationLog};
+use scripting::ScriptError;
+use slots::SlotMap;
 use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, Mutex};
 
+mod acl;
+mod cluster;
+mod hints;
+mod keyspace;
 mod protocol;
+mod raft;
+mod repair;
+mod replication;
+mod scripting;
+mod slots;
+#[cfg(test)]
+mod test;
+mod watch;
+
+/// The CrunchKV server
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// A TOML file to read settings from. Environment variables take
+    /// precedence over it
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Override a setting, such as `kv.port=6211` or
+    /// `engine.store.wal_sync=false`. Can be given more than once
+    #[arg(long = "set", value_name = "NAME=VALUE")]
+    overrides: Vec<String>,
+
+    /// Print every setting with its effective value and where it came from,
+    /// then exit
+    #[arg(long)]
+    print_config: bool,
+}
+
+/// The most keys that a single SCAN will return.
+const MAX_SCAN_COUNT: usize = 1000;
+
+/// The upper bounds of the buckets for how long commands take, in
+/// microseconds.
+const LATENCY_BOUNDS: &[u64] = &[
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000,
+];
+
+/// One of the server's numbered databases, which connections pick between
+/// with SELECT. Each has an engine of its own, so their keys are kept apart.
+pub struct Database {
+    engine: Engine,
+
+    /// Every write applied to the engine, for connections that are watching
+    /// keys in this database or are subscribed to its keyspace events.
+    changes: broadcast::Sender<Record>,
+}
+
+impl Database {
+    fn open(path: PathBuf, config: &Config) -> Result<Self, EngineError> {
+        Ok(Self {
+            engine: Engine::with_args(path, EngineArgs::from_config(config))?,
+            changes: broadcast::Sender::new(watch::WATCH_BACKLOG),
+        })
+    }
+
+    /// Tell any watchers that `record` has been applied to the engine.
+    ///
+    /// Callers must hold the server's `writes` lock, so that watchers see
+    /// writes in the order that they were applied.
+    fn notify_watchers(&self, record: &Record) {
+        if self.changes.receiver_count() > 0 {
+            _ = self.changes.send(record.clone());
+        }
+    }
+}
+
+/// State shared by every connection to the server.
+pub struct Server {
+    /// Connections start out using the first database. Only it is replicated
+    /// or clustered, so a server with more than one does neither.
+    databases: Vec<Database>,
+
+    /// Held across every write to the engine, so that the replication log and
+    /// watchers see writes in the order that they were applied. Reads don't
+    /// take it.
+    writes: Mutex<()>,
+    password: Option<String>,
+
+    /// The accounts that connections can authenticate as with AUTHUSER, each
+    /// kept to some of the keys.
+    users: Users,
+
+    /// Recent writes, for followers to replicate.
+    replication_log: ReplicationLog,
+
+    /// Whether this server is following a leader. Followers only accept writes
+    /// from their leader.
+    follower: bool,
+
+    /// How many followers must apply a write before it is acknowledged, for
+    /// connections that haven't asked for another level with ACKS.
+    write_acks: AckLevel,
+
+    /// The number of followers that this server leads, which a majority is
+    /// counted out of.
+    followers: usize,
+
+    /// How long a write waits for its followers to acknowledge it.
+    write_ack_timeout: Duration,
+
+    /// On a follower, the sequence of the last record applied from the leader.
+    replicated: AtomicU64,
+
+    /// The name that this server gives its leader, if it is a follower, so
+    /// that the leader hints the writes it misses.
+    follower_name: Option<String>,
+
+    /// The Raft cluster this server is a member of, if it is clustered. Writes
+    /// are committed to the cluster before they are applied.
+    cluster: Option<Arc<Cluster>>,
+
+    started: Instant,
+
+    /// The slots of the keyspace that this server owns, if it is a node of a
+    /// slot cluster.
+    slots: Option<SlotMap>,
+
+    /// The number of times each command has been run, indexed by indicator.
+    command_counts: [AtomicU64; Command::COUNT],
+
+    /// How long each command has taken to run, indexed by indicator, from once
+    /// its arguments are read until its response is written.
+    command_latencies: Vec<Histogram>,
+
+    /// The most bytes that a script run with EVAL can allocate.
+    script_memory_limit: usize,
+
+    /// The settings that the server was started with, for CONFIG.
+    config: Config,
+}
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let port: u16 = parse_env("kv", None, "port", 6210);
-    let path: PathBuf = parse_env("kv", None, "path", "./data".into());
-    let engine = Arc::new(RwLock::new(Engine::new(path).unwrap()));
+    let args = Cli::parse();
+    let mut config = match &args.config {
+        Some(path) => Config::from_file(path).unwrap(),
+        None => Config::default(),
+    };
+    for assignment in &args.overrides {
+        config.set_override(assignment).unwrap();
+    }
+    if args.print_config {
+        print_config(&config);
+        return;
+    }
+    let port: u16 = config.get("kv", None, "port", 6210);
+    let path: PathBuf = config.get("kv", None, "path", "./data".into());
+    let password: Option<String> = config.get("kv", None, "password", None);
+    let leader: Option<String> = config.get("kv", None, "replicate_from", None);
+    let replication_backlog = config.get("kv", None, "replication_backlog", 100_000);
+    let write_acks: String = config.get("kv", None, "write_acks", "local".to_owned());
+    let write_acks = AckLevel::parse(&write_acks).unwrap();
+    let followers = config.get("kv", None, "followers", 0);
+    let write_ack_timeout = config.get("kv", None, "write_ack_timeout", Duration::from_secs(1));
+    let repair_interval = config.get("kv", None, "repair_interval", Duration::from_secs(10 * 60));
+    let follower_name: Option<String> = config.get("kv", None, "follower_name", None);
+    if let Some(name) = &follower_name {
+        assert!(
+            hints::valid_name(name),
+            "CRUNCH_KV__FOLLOWER_NAME must be made up of letters, digits, '-', '_' and '.'"
+        );
+    }
+    let hint_window = config.get("kv", None, "hint_window", Duration::from_secs(3 * 60 * 60));
+    let hint_max_bytes = config.get("kv", None, "hint_max_bytes", ByteSize(64 * 1024 * 1024)).0;
+    let script_memory_limit =
+        config.get("kv", None, "script_memory_limit", ByteSize(64 * 1024 * 1024)).0;
+    let raft_id: Option<u64> = config.get("kv", None, "raft_id", None);
+    let raft_members: Option<String> = config.get("kv", None, "raft_members", None);
+    let raft_secret: Option<String> = config.get("kv", None, "raft_secret", None);
+    let users = Users::from_config(&config).unwrap();
+    let slots: Option<String> = config.get("kv", None, "slots", None);
+    let slots = slots.map(|slots| {
+        let address = config.get("kv", None, "slot_address", format!("127.0.0.1:{port}"));
+        assert!(raft_id.is_none(), "a slot cluster node can't also be a Raft cluster member");
+        SlotMap::parse(&slots, &address).unwrap()
+    });
+    let database_count: usize = config.get("kv", None, "databases", 1);
+    assert!(database_count > 0, "CRUNCH_KV__DATABASES must be at least 1");
+    assert!(
+        database_count == 1 || (leader.is_none() && raft_id.is_none()),
+        "a server with more than one database can't be replicated or clustered"
+    );
+    let databases = (0..database_count)
+        .map(|index| Database::open(database_directory(&path, index), &config).unwrap())
+        .collect();
+    let (cluster, inbound) = match (raft_id, raft_members) {
+        (Some(id), Some(members)) => {
+            assert!(leader.is_none(), "a clustered server can't also follow a leader");
+            let secret = raft_secret.or_else(|| password.clone()).expect(
+                "a Raft cluster member needs CRUNCH_KV__RAFT_SECRET or CRUNCH_KV__PASSWORD to \
+                 authenticate its peers",
+            );
+            let args = ClusterArgs::parse(id, &members, secret).unwrap();
+            let (cluster, inbound) = Cluster::start(args, &path).await.unwrap();
+            (Some(cluster), Some(inbound))
+        },
+        (None, None) => (None, None),
+        _ => panic!("CRUNCH_KV__RAFT_ID and CRUNCH_KV__RAFT_MEMBERS must be set together"),
+    };
+    // Only a server that can be followed hints its followers.
+    let hints = (leader.is_none() && database_count == 1 && !hint_window.is_zero())
+        .then(|| Hints::open(path.join("hints"), hint_window, hint_max_bytes).unwrap());
+    let server = Arc::new(Server {
+        databases,
+        writes: Mutex::new(()),
+        password,
+        users,
+        replication_log: ReplicationLog::new(replication_backlog, hints),
+        follower: leader.is_some(),
+        write_acks,
+        followers,
+        write_ack_timeout,
+        replicated: AtomicU64::new(0),
+        follower_name,
+        cluster,
+        slots,
+        started: Instant::now(),
+        command_counts: Default::default(),
+        command_latencies: (0..Command::COUNT).map(|_| Histogram::new(LATENCY_BOUNDS)).collect(),
+        script_memory_limit: script_memory_limit as usize,
+        config,
+    });
     let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
     log::info!("CrunchKV server listening on port {port}");
+    if server.password.is_some() || !server.users.is_empty() {
+        log::info!("connections must authenticate before running commands");
+    }
+    if !server.users.is_empty() {
+        log::info!("{} users can authenticate", server.users.len());
+    }
+    if let Some(leader) = leader {
+        log::info!("following leader at {leader}");
+        let state_path = path.join(replication::STATE_FILENAME);
+        tokio::task::spawn(replication::follow(server.clone(), leader.clone(), state_path));
+        if !repair_interval.is_zero() {
+            tokio::task::spawn(repair::run(server.clone(), leader, repair_interval));
+        }
+    }
+    if let Some(inbound) = inbound {
+        tokio::task::spawn(cluster::run(server.clone(), inbound));
+    }
     loop {
         let stream = match listener.accept().await {
             Ok((stream, _)) => stream,
@@ -26,54 +279,864 @@ async fn main() {
                 continue;
             },
         };
-        tokio::task::spawn(handle_client(engine.clone(), stream));
+        // Responses are written in a few pieces, and Nagle's algorithm would hold
+        // back all but the first until the client acknowledges it.
+        if let Err(error) = stream.set_nodelay(true) {
+            log::warn!("failed to disable Nagle's algorithm: {error}");
+        }
+        tokio::task::spawn(handle_client(server.clone(), stream));
+    }
+}
+
+/// The engine directory of the database at `index`. The first database is
+/// kept in the data directory itself, so that a server which gains more
+/// databases keeps its data, and the rest are in its `databases` directory.
+fn database_directory(path: &Path, index: usize) -> PathBuf {
+    match index {
+        0 => path.to_owned(),
+        index => path.join("databases").join(index.to_string()),
+    }
+}
+
+/// Records how long a command took when it is dropped, so that the time is
+/// recorded however the command's handler finishes.
+struct LatencyTimer<'a> {
+    histogram: &'a Histogram,
+    started: Instant,
+}
+
+impl<'a> LatencyTimer<'a> {
+    fn start(histogram: &'a Histogram) -> Self {
+        Self { histogram, started: Instant::now() }
+    }
+}
+
+impl Drop for LatencyTimer<'_> {
+    fn drop(&mut self) {
+        self.histogram.record(self.started.elapsed().as_micros() as u64);
+    }
+}
+
+fn print_config(config: &Config) {
+    for value in registry::effective_values(config) {
+        println!("# {}", value.setting.description);
+        match value.value {
+            Some(setting) => {
+                println!(
+                    "{} = {setting}  # from {}",
+                    value.setting.dotted_name(),
+                    value.source.name()
+                )
+            },
+            None => println!("# {} is unset", value.setting.dotted_name()),
+        }
+        println!();
     }
 }
 
-// TODO: Don't unwrap, and don't swallow errors.
-async fn handle_client(engine: Arc<RwLock<Engine>>, stream: TcpStream) -> Result<(), io::Error> {
+async fn handle_client(server: Arc<Server>, stream: TcpStream) {
     let mut stream = protocol::Stream(stream);
+    match serve_client(&server, &mut stream).await {
+        Ok(()) => {},
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            log::trace!("client disconnected");
+        },
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+            // The rest of the stream can't be framed, so the connection is closed after
+            // telling the client why.
+            log::debug!("closing connection after protocol error: {error}");
+            _ = stream.write_error(Status::Invalid, &error.to_string()).await;
+        },
+        Err(error) => log::warn!("connection failed: {error}"),
+    }
+}
+
+async fn serve_client(
+    server: &Arc<Server>,
+    stream: &mut protocol::Stream,
+) -> Result<(), io::Error> {
+    // What the connection is allowed to do, once it has authenticated.
+    let mut permissions = (server.password.is_none() && server.users.is_empty())
+        .then(|| Arc::new(Permissions::full()));
+    // The index of the database that the connection has selected.
+    let mut selected = 0;
+    // The acknowledgement level that the connection has asked for with ACKS.
+    let mut acks = None;
     loop {
-        let Some(command) = stream.read_command_indicator().await? else {
+        let write_acks = acks.unwrap_or(server.write_acks);
+        let database = &server.databases[selected];
+        let engine = &database.engine;
+        let command = stream.read_command_indicator().await?;
+        // Arguments are read before the connection is checked for authentication, so
+        // that a rejected command doesn't leave them behind on the stream.
+        // Keys and values over the engine's limits are skipped rather than read in,
+        // and the command refused.
+        let limits = engine.size_limits();
+        let mut args = Vec::with_capacity(command.arg_count());
+        let mut too_large = None;
+        for index in 0..command.arg_count() {
+            let (component, limit) = match command.size_limited_arg(index) {
+                Some(PairComponent::Key) => (PairComponent::Key, limits.max_key_bytes),
+                Some(PairComponent::Value) => (PairComponent::Value, limits.max_value_bytes),
+                None => {
+                    args.push(stream.read_data().await?);
+                    continue;
+                },
+            };
+            match stream.read_data_within(limit).await? {
+                Ok(arg) => args.push(arg),
+                Err(size) => {
+                    too_large.get_or_insert(EngineError::TooLarge(component, size, limit));
+                    args.push(Vec::new());
+                },
+            }
+        }
+        if permissions.is_none() && !command.allowed_unauthenticated() {
+            log::trace!("rejecting {command:?} from unauthenticated connection");
+            stream.write_unauthenticated().await?;
+            continue;
+        }
+        if let Some(error) = too_large {
+            write_engine_error(stream, error).await?;
+            continue;
+        }
+        server.command_counts[command as usize - 1].fetch_add(1, Ordering::Relaxed);
+        // Commands that take over the connection run for as long as it is open, which
+        // isn't a latency.
+        let _timer = (!command.takes_over_connection())
+            .then(|| LatencyTimer::start(&server.command_latencies[command as usize - 1]));
+        let Ok(text) = args[..command.text_arg_count()]
+            .iter()
+            .map(|arg| std::str::from_utf8(arg))
+            .collect::<Result<Vec<_>, _>>()
+        else {
+            log::debug!("rejecting {command:?}, since its arguments aren't valid UTF-8");
+            stream.write_error(Status::Invalid, "arguments must be valid UTF-8").await?;
             continue;
         };
+        if server.follower && command.is_write() {
+            log::trace!("rejecting {command:?}, since this server is a follower");
+            stream
+                .write_error(
+                    Status::Refused,
+                    "this server is a follower, and doesn't accept writes",
+                )
+                .await?;
+            continue;
+        }
+        if let Some(permissions) = &permissions {
+            if !permissions.allow(command, &text, &args) {
+                log::debug!("rejecting {command:?}, which the connection's user can't run");
+                let message = format!("not allowed to run {} on these keys", command.name());
+                stream.write_error(Status::Forbidden, &message).await?;
+                continue;
+            }
+        }
+        if let Some((slot, owner)) =
+            server.slots.as_ref().and_then(|slots| slots.misplaced_key(command, &text, &args))
+        {
+            log::trace!("redirecting {command:?} to {owner}, which owns slot {slot}");
+            stream.write_error(Status::Moved, &format!("{slot} {owner}")).await?;
+            continue;
+        }
         match command {
             Command::Get => {
-                let data = stream.read_data().await?;
-                let key = std::str::from_utf8(&data).unwrap();
+                let key = text[0];
                 log::trace!("GET {key}");
-                match engine.read().await.get(key).unwrap() {
-                    Some(value) => {
+                let value = engine.get(key);
+                match value {
+                    Ok(Some(value)) => {
                         log::trace!("got {key} = {value}");
                         stream.write_success().await?;
                         stream.write_data(value.as_bytes()).await?;
                     },
-                    None => {
+                    Ok(None) => {
                         log::trace!("{key} not found");
-                        stream.write_outcome(2).await?;
+                        stream.write_not_found().await?;
                     },
+                    Err(error) => write_engine_error(stream, error).await?,
+                }
+            },
+            Command::Exists => {
+                let key = text[0];
+                log::trace!("EXISTS {key}");
+                let exists = engine.exists(key);
+                match exists {
+                    Ok(true) => stream.write_success().await?,
+                    Ok(false) => stream.write_not_found().await?,
+                    Err(error) => write_engine_error(stream, error).await?,
                 }
             },
             Command::Set => {
-                let key = stream.read_data().await?;
-                let val = stream.read_data().await?;
-                let key = std::str::from_utf8(&key).unwrap();
-                let val = std::str::from_utf8(&val).unwrap();
+                let key = text[0];
+                let val = text[1];
                 log::trace!("SET {key}={val}");
-                match engine.write().await.set(key, val) {
-                    Ok(_) => stream.write_success().await?,
-                    Err(_) => stream.write_failure().await?,
+                if let Some(cluster) = &server.cluster {
+                    let record = Record::Set { key: key.to_owned(), value: val.to_owned() };
+                    write_clustered(cluster, stream, record).await?;
+                    continue;
+                }
+                let writes = server.writes.lock().await;
+                match engine.set(key, val) {
+                    Ok(_) => {
+                        let record = Record::Set { key: key.to_owned(), value: val.to_owned() };
+                        database.notify_watchers(&record);
+                        let sequence = server.replication_log.append(record);
+                        drop(writes);
+                        if await_acks(server, stream, write_acks, sequence).await? {
+                            stream.write_success().await?
+                        }
+                    },
+                    Err(error) => write_engine_error(stream, error).await?,
+                }
+            },
+            Command::GetSet | Command::Append => {
+                let key = text[0];
+                let operand = text[1];
+                log::trace!("{} {key} {operand}", command.name().to_uppercase());
+                if server.cluster.is_some() {
+                    write_unsupported_clustered(stream, command).await?;
+                    continue;
+                }
+                let writes = server.writes.lock().await;
+                // Both are replicated as a set of the value that the key ends up with.
+                let result = match command {
+                    Command::GetSet => engine
+                        .get_set(key, operand)
+                        .map(|previous| (Arc::from(operand), Some(previous))),
+                    _ => engine.append(key, operand).map(|value| (value, None)),
+                };
+                match result {
+                    Ok((value, previous)) => {
+                        let record = Record::Set { key: key.to_owned(), value: value.to_string() };
+                        database.notify_watchers(&record);
+                        let sequence = server.replication_log.append(record);
+                        drop(writes);
+                        if !await_acks(server, stream, write_acks, sequence).await? {
+                            continue;
+                        }
+                        match previous {
+                            // GETSET responds like GET, with the value that was replaced.
+                            Some(Some(previous)) => {
+                                stream.write_success().await?;
+                                stream.write_data(previous.as_bytes()).await?;
+                            },
+                            Some(None) => stream.write_not_found().await?,
+                            // APPEND responds with the length of the new value, in bytes.
+                            None => {
+                                stream.write_success().await?;
+                                stream.write_u64(value.len() as u64).await?;
+                            },
+                        }
+                    },
+                    Err(error) => write_engine_error(stream, error).await?,
+                }
+            },
+            Command::SetNx => {
+                let key = text[0];
+                let val = text[1];
+                log::trace!("SETNX {key}={val}");
+                if server.cluster.is_some() {
+                    write_unsupported_clustered(stream, command).await?;
+                    continue;
+                }
+                let writes = server.writes.lock().await;
+                match engine.set_if_absent(key, val) {
+                    Ok(true) => {
+                        let record = Record::Set { key: key.to_owned(), value: val.to_owned() };
+                        database.notify_watchers(&record);
+                        let sequence = server.replication_log.append(record);
+                        drop(writes);
+                        if await_acks(server, stream, write_acks, sequence).await? {
+                            stream.write_success().await?
+                        }
+                    },
+                    // The key already has a value, which is left as it is.
+                    Ok(false) => stream.write_failure().await?,
+                    Err(error) => write_engine_error(stream, error).await?,
                 }
             },
             Command::Delete => {
-                let data = stream.read_data().await?;
-                let key = std::str::from_utf8(&data).unwrap();
+                let key = text[0];
                 log::trace!("DELETE {key}");
-                match engine.write().await.delete(key) {
-                    Ok(_) => stream.write_success().await?,
-                    Err(_) => stream.write_failure().await?,
+                if let Some(cluster) = &server.cluster {
+                    let record = Record::Delete { key: key.to_owned() };
+                    write_clustered(cluster, stream, record).await?;
+                    continue;
+                }
+                let writes = server.writes.lock().await;
+                match engine.delete(key) {
+                    Ok(_) => {
+                        let record = Record::Delete { key: key.to_owned() };
+                        database.notify_watchers(&record);
+                        let sequence = server.replication_log.append(record);
+                        drop(writes);
+                        if await_acks(server, stream, write_acks, sequence).await? {
+                            stream.write_success().await?
+                        }
+                    },
+                    Err(error) => write_engine_error(stream, error).await?,
+                }
+            },
+            Command::GetDel => {
+                let key = text[0];
+                log::trace!("GETDEL {key}");
+                if server.cluster.is_some() {
+                    write_unsupported_clustered(stream, command).await?;
+                    continue;
+                }
+                let writes = server.writes.lock().await;
+                match engine.get_delete(key) {
+                    Ok(Some(value)) => {
+                        let record = Record::Delete { key: key.to_owned() };
+                        database.notify_watchers(&record);
+                        let sequence = server.replication_log.append(record);
+                        drop(writes);
+                        if await_acks(server, stream, write_acks, sequence).await? {
+                            stream.write_success().await?;
+                            stream.write_data(value.as_bytes()).await?;
+                        }
+                    },
+                    // Nothing was deleted, so there is nothing to replicate.
+                    Ok(None) => stream.write_not_found().await?,
+                    Err(error) => write_engine_error(stream, error).await?,
                 }
             },
+            Command::Eval => {
+                let script = text[0];
+                let Some(script_args) = scripting::parse_args(&args[1]) else {
+                    stream.write_error(Status::Invalid, "malformed script arguments").await?;
+                    continue;
+                };
+                log::trace!("EVAL with {} arguments", script_args.len());
+                if server.slots.is_some() {
+                    // A script's keys aren't known until it runs, so it can't be redirected.
+                    log::debug!("rejecting EVAL, which isn't supported in a slot cluster");
+                    stream
+                        .write_error(Status::Refused, "eval isn't supported in a slot cluster")
+                        .await?;
+                    continue;
+                }
+                if server.cluster.is_some() {
+                    write_unsupported_clustered(stream, command).await?;
+                    continue;
+                }
+                let writes = server.writes.lock().await;
+                let eval = {
+                    let server = server.clone();
+                    let script = script.to_owned();
+                    move || {
+                        let engine = &server.databases[selected].engine;
+                        scripting::eval(engine, &script, script_args, server.script_memory_limit)
+                    }
+                };
+                let (records, result) =
+                    tokio::task::spawn_blocking(eval).await.map_err(io::Error::other)?;
+                let mut sequence = None;
+                for record in records {
+                    database.notify_watchers(&record);
+                    sequence = Some(server.replication_log.append(record));
+                }
+                drop(writes);
+                // Even a script that fails may have written before it did.
+                if let Some(sequence) = sequence {
+                    if !await_acks(server, stream, write_acks, sequence).await? {
+                        continue;
+                    }
+                }
+                match result {
+                    Ok(Some(value)) => {
+                        stream.write_success().await?;
+                        stream.write_data(value.as_bytes()).await?;
+                    },
+                    Ok(None) => stream.write_not_found().await?,
+                    Err(ScriptError::Engine(error)) => write_engine_error(stream, error).await?,
+                    Err(ScriptError::Lua(message)) => {
+                        log::debug!("script failed: {message}");
+                        stream.write_error(Status::Invalid, &message).await?
+                    },
+                }
+            },
+            Command::Batch => {
+                let Some(batch) = protocol::parse_batch(&args[0]) else {
+                    stream.write_error(Status::Invalid, "malformed batch").await?;
+                    continue;
+                };
+                log::trace!("BATCH of {} operations", batch.len());
+                if server.cluster.is_some() {
+                    // TODO: Commit batches to the Raft log as a single entry.
+                    log::warn!("rejecting BATCH, which isn't supported in clustered mode");
+                    stream
+                        .write_error(Status::Refused, "BATCH isn't supported in clustered mode")
+                        .await?;
+                    continue;
+                }
+                let writes = server.writes.lock().await;
+                match engine.apply(&batch) {
+                    Ok(_) => {
+                        // Followers apply the operations one at a time, so they can briefly
+                        // observe part of a batch.
+                        let mut sequence = None;
+                        for entry in batch.entries() {
+                            let record = match entry.clone() {
+                                Entry::Assignment { key, value } => Record::Set { key, value },
+                                Entry::Tombstone { key } => Record::Delete { key },
+                            };
+                            database.notify_watchers(&record);
+                            sequence = Some(server.replication_log.append(record));
+                        }
+                        drop(writes);
+                        let acknowledged = match sequence {
+                            Some(sequence) => {
+                                await_acks(server, stream, write_acks, sequence).await?
+                            },
+                            None => true,
+                        };
+                        if acknowledged {
+                            stream.write_success().await?
+                        }
+                    },
+                    Err(error) => write_engine_error(stream, error).await?,
+                }
+            },
+            Command::Scan => {
+                // The arguments are the cursor to start from (empty for the start of the
+                // keyspace), the number of keys to return as a u32, and a flag byte that is 1
+                // if values should be returned along with the keys.
+                let cursor = text[0];
+                let (Ok(count), [with_values]) =
+                    (<[u8; 4]>::try_from(args[1].as_slice()), args[2].as_slice())
+                else {
+                    stream.write_error(Status::Invalid, "malformed scan count or flags").await?;
+                    continue;
+                };
+                let count = (u32::from_be_bytes(count) as usize).clamp(1, MAX_SCAN_COUNT);
+                log::trace!("SCAN {cursor} {count}");
+                let page = engine.scan(cursor, count);
+                let mut page = match page {
+                    Ok(page) => page,
+                    Err(error) => {
+                        write_engine_error(stream, error).await?;
+                        continue;
+                    },
+                };
+                // A user that can only read some keys scans each of its prefixes on its own,
+                // so the scan ends at the first key that it can't read.
+                if let Some(permissions) = &permissions {
+                    let readable = |key: &String| permissions.can_read(key);
+                    if let Some(end) = page.entries.iter().position(|(key, _)| !readable(key)) {
+                        page.entries.truncate(end);
+                        page.next = None;
+                    } else if !page.next.as_ref().is_none_or(readable) {
+                        page.next = None;
+                    }
+                }
+                // The response is the number of keys, then each key (followed by its value, if
+                // requested), and finally the cursor for the next page, which is empty once
+                // the scan is complete.
+                stream.write_success().await?;
+                stream.write_count(page.entries.len() as u32).await?;
+                for (key, value) in &page.entries {
+                    stream.write_data(key.as_bytes()).await?;
+                    if *with_values == 1 {
+                        stream.write_data(value.as_bytes()).await?;
+                    }
+                }
+                stream.write_data(page.next.unwrap_or_default().as_bytes()).await?;
+            },
+            Command::Info => {
+                log::trace!("INFO");
+                let stats = engine.stats();
+                let stats = match stats {
+                    Ok(stats) => stats,
+                    Err(error) => {
+                        write_engine_error(stream, error).await?;
+                        continue;
+                    },
+                };
+                // The response is the number of fields, followed by the name and value of
+                // each one.
+                let mut fields = vec![
+                    ("uptime_seconds".to_owned(), server.started.elapsed().as_secs().to_string()),
+                    ("database".to_owned(), selected.to_string()),
+                    ("databases".to_owned(), server.databases.len().to_string()),
+                    ("segment_count".to_owned(), stats.store.segment_count().to_string()),
+                    ("segment_bytes".to_owned(), stats.store.segment_bytes().to_string()),
+                    ("memtable_len".to_owned(), stats.memtable_len.to_string()),
+                    ("memtable_capacity".to_owned(), stats.memtable_capacity.to_string()),
+                    ("wal_bytes".to_owned(), stats.store.wal_bytes.to_string()),
+                    (
+                        "replication.followers".to_owned(),
+                        server.replication_log.follower_count().to_string(),
+                    ),
+                    ("replication.epoch".to_owned(), server.replication_log.epoch().to_string()),
+                ];
+                if let Some(ratio) = stats.store.estimated_dead_ratio() {
+                    fields.push(("estimated_dead_ratio".to_owned(), format!("{ratio:.3}")));
+                }
+                let amplification = [
+                    ("space_amplification", stats.store.space_amplification()),
+                    ("read_amplification", stats.store.read_amplification()),
+                    ("write_amplification", stats.store.write_amplification()),
+                ];
+                for (name, amplification) in amplification {
+                    if let Some(amplification) = amplification {
+                        fields.push((name.to_owned(), format!("{amplification:.3}")));
+                    }
+                }
+                // Given as a Unix timestamp, in seconds.
+                let last_compaction = stats.store.last_compaction.and_then(|time| {
+                    time.duration_since(std::time::UNIX_EPOCH).ok().map(|since| since.as_secs())
+                });
+                if let Some(last_compaction) = last_compaction {
+                    fields.push(("last_compaction".to_owned(), last_compaction.to_string()));
+                }
+                let mut bloom_filter = BloomFilterStats::default();
+                for segment in &stats.store.segments {
+                    bloom_filter += segment.bloom_filter;
+                    let name = segment.path.file_name().unwrap_or_default().to_string_lossy();
+                    fields.extend([
+                        (format!("segment.{name}.bytes"), segment.size.to_string()),
+                        (format!("segment.{name}.entries"), segment.entry_count.to_string()),
+                    ]);
+                }
+                fields.extend([
+                    ("bloom_filter.checks".to_owned(), bloom_filter.checks.to_string()),
+                    ("bloom_filter.negatives".to_owned(), bloom_filter.negatives.to_string()),
+                    (
+                        "bloom_filter.false_positives".to_owned(),
+                        bloom_filter.false_positives.to_string(),
+                    ),
+                ]);
+                let metrics = engine.metrics();
+                fields.extend([
+                    ("metrics.gets".to_owned(), metrics.gets.to_string()),
+                    ("metrics.sets".to_owned(), metrics.sets.to_string()),
+                    ("metrics.deletes".to_owned(), metrics.deletes.to_string()),
+                    (
+                        "metrics.bloom_filter_skips".to_owned(),
+                        metrics.bloom_filter_skips.to_string(),
+                    ),
+                    ("metrics.row_cache_hits".to_owned(), metrics.row_cache_hits.to_string()),
+                    ("metrics.row_cache_misses".to_owned(), metrics.row_cache_misses.to_string()),
+                    ("metrics.flushes".to_owned(), metrics.flushes.to_string()),
+                    ("metrics.flushed_bytes".to_owned(), metrics.flushed_bytes.to_string()),
+                    ("metrics.wal_bytes".to_owned(), metrics.wal_bytes.to_string()),
+                    ("metrics.wal_batches".to_owned(), metrics.wal_batches.to_string()),
+                    (
+                        "metrics.quarantined_segments".to_owned(),
+                        metrics.quarantined_segments.to_string(),
+                    ),
+                ]);
+                // The histogram is given in the cumulative form that Prometheus uses.
+                let probes = &metrics.segment_probes;
+                for (bound, count) in probes.cumulative_counts() {
+                    let bound = bound.map_or("inf".to_owned(), |bound| bound.to_string());
+                    fields.push((format!("metrics.segment_probes.le_{bound}"), count.to_string()));
+                }
+                fields.push(("metrics.segment_probes.sum".to_owned(), probes.sum.to_string()));
+                for indicator in 1..=Command::COUNT as u8 {
+                    let command = Command::from_u8_opt(indicator).unwrap();
+                    let count =
+                        server.command_counts[indicator as usize - 1].load(Ordering::Relaxed);
+                    fields.push((format!("commands.{}", command.name()), count.to_string()));
+                }
+                // Latencies are given for the commands that have been run, in microseconds,
+                // as estimated percentiles and like the segment probes.
+                for indicator in 1..=Command::COUNT as u8 {
+                    let name = Command::from_u8_opt(indicator).unwrap().name();
+                    let latency = server.command_latencies[indicator as usize - 1].snapshot();
+                    if latency.count() == 0 {
+                        continue;
+                    }
+                    let bound =
+                        |bound: Option<u64>| bound.map_or("inf".to_owned(), |b| b.to_string());
+                    for (percentile, quantile) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+                        fields.push((
+                            format!("latency.{name}.{percentile}_us"),
+                            bound(latency.quantile(quantile)),
+                        ));
+                    }
+                    for (le, count) in latency.cumulative_counts() {
+                        fields
+                            .push((format!("latency.{name}.le_{}", bound(le)), count.to_string()));
+                    }
+                    fields.push((format!("latency.{name}.sum"), latency.sum.to_string()));
+                }
+                stream.write_success().await?;
+                stream.write_count(fields.len() as u32).await?;
+                for (name, value) in fields {
+                    stream.write_data(name.as_bytes()).await?;
+                    stream.write_data(value.as_bytes()).await?;
+                }
+            },
+            Command::Config => {
+                log::trace!("CONFIG");
+                // The response is the number of settings, followed by the name and value of
+                // each one that is set, like INFO.
+                let values: Vec<_> = registry::effective_values(&server.config)
+                    .into_iter()
+                    .filter_map(|value| Some((value.setting.dotted_name(), value.value?)))
+                    .collect();
+                stream.write_success().await?;
+                stream.write_count(values.len() as u32).await?;
+                for (name, value) in values {
+                    stream.write_data(name.as_bytes()).await?;
+                    stream.write_data(value.as_bytes()).await?;
+                }
+            },
+            Command::DbSize => {
+                log::trace!("DBSIZE");
+                let len = engine.approximate_len();
+                match len {
+                    Ok(len) => {
+                        stream.write_success().await?;
+                        stream.write_u64(len).await?;
+                    },
+                    Err(error) => write_engine_error(stream, error).await?,
+                }
+            },
+            Command::Ping => {
+                log::trace!("PING");
+                stream.write_success().await?;
+            },
+            Command::Auth => {
+                log::trace!("AUTH");
+                // Without a password or users, every connection is already authenticated.
+                let accepted = match server.password.as_deref() {
+                    Some(password) => acl::constant_time_eq(password.as_bytes(), &args[0]),
+                    None => server.users.is_empty(),
+                };
+                if accepted {
+                    permissions = Some(Arc::new(Permissions::full()));
+                    stream.write_success().await?;
+                } else {
+                    stream.write_failure().await?;
+                }
+            },
+            Command::AuthUser => {
+                log::trace!("AUTHUSER {}", String::from_utf8_lossy(&args[0]));
+                match server.users.authenticate(&args[0], &args[1]) {
+                    Some(user) => {
+                        permissions = Some(user);
+                        stream.write_success().await?;
+                    },
+                    None => stream.write_failure().await?,
+                }
+            },
+            Command::Watch => {
+                let Some(keys) = watch::parse_keys(&args[0]) else {
+                    stream.write_error(Status::Invalid, "malformed keys").await?;
+                    continue;
+                };
+                log::trace!("WATCH {keys:?}");
+                // The connection only carries notifications from here on.
+                let changes = database.changes.subscribe();
+                return watch::serve_watcher(changes, keys, stream).await;
+            },
+            Command::Subscribe => {
+                let pattern = keyspace::Pattern::parse(text[0]);
+                log::trace!("SUBSCRIBE {pattern:?}");
+                // Like WATCH, the connection only carries events from here on.
+                let changes = database.changes.subscribe();
+                let permissions = permissions.unwrap_or_default();
+                return keyspace::serve_subscriber(changes, pattern, &permissions, stream).await;
+            },
+            Command::Replicate => {
+                if server.databases.len() > 1 {
+                    log::debug!("rejecting REPLICATE, since this server has several databases");
+                    stream
+                        .write_error(
+                            Status::Refused,
+                            "a server with more than one database can't be replicated",
+                        )
+                        .await?;
+                    continue;
+                }
+                // The argument is the epoch and sequence to start from, followed by the
+                // follower's name if it has one.
+                let Some((start, name)) = args[0].split_first_chunk::<16>() else {
+                    stream.write_error(Status::Invalid, "malformed sequence").await?;
+                    continue;
+                };
+                let (epoch, from) = start.split_at(8);
+                let epoch = u64::from_be_bytes(epoch.try_into().unwrap());
+                let from = u64::from_be_bytes(from.try_into().unwrap());
+                let name = match std::str::from_utf8(name) {
+                    Ok("") => None,
+                    Ok(name) if hints::valid_name(name) => Some(name.to_owned()),
+                    _ => {
+                        stream.write_error(Status::Invalid, "invalid follower name").await?;
+                        continue;
+                    },
+                };
+                log::trace!("REPLICATE {epoch} {from} {name:?}");
+                // The connection belongs to the follower from here on.
+                return replication::serve_follower(server, stream, epoch, from, name).await;
+            },
+            Command::Digest | Command::Repair => {
+                if server.databases.len() > 1 {
+                    log::debug!("rejecting {command:?}, since this server has several databases");
+                    stream
+                        .write_error(
+                            Status::Refused,
+                            "a server with more than one database can't be repaired from",
+                        )
+                        .await?;
+                    continue;
+                }
+                if let Command::Digest = command {
+                    log::trace!("DIGEST");
+                    repair::serve_digest(server, stream).await?;
+                    continue;
+                }
+                let Some(leaves) = repair::parse_leaves(&args[0]) else {
+                    stream.write_error(Status::Invalid, "malformed leaves").await?;
+                    continue;
+                };
+                log::trace!("REPAIR of {} leaves", leaves.len());
+                repair::serve_repair(server, stream, &leaves).await?;
+            },
+            Command::Cluster => {
+                log::trace!("CLUSTER");
+                let Some(slots) = &server.slots else {
+                    stream
+                        .write_error(Status::Refused, "this server isn't part of a slot cluster")
+                        .await?;
+                    continue;
+                };
+                // The response is the number of slot ranges, followed by the first and last
+                // slot of each one, and the address of the node that owns it.
+                stream.write_success().await?;
+                stream.write_count(slots.ranges().len() as u32).await?;
+                for range in slots.ranges() {
+                    stream.write_count(range.start as u32).await?;
+                    stream.write_count(range.end as u32).await?;
+                    stream.write_data(range.address.as_bytes()).await?;
+                }
+            },
+            Command::Acks => {
+                let Some(level) = (match args[0].as_slice() {
+                    [indicator] => AckLevel::from_indicator(*indicator),
+                    _ => None,
+                }) else {
+                    stream.write_error(Status::Invalid, "malformed acknowledgement level").await?;
+                    continue;
+                };
+                log::trace!("ACKS {level:?}");
+                acks = level;
+                stream.write_success().await?;
+            },
+            Command::Select => {
+                let Ok(index) = <[u8; 4]>::try_from(args[0].as_slice()) else {
+                    stream.write_error(Status::Invalid, "malformed database index").await?;
+                    continue;
+                };
+                let index = u32::from_be_bytes(index) as usize;
+                log::trace!("SELECT {index}");
+                if index >= server.databases.len() {
+                    let message = format!(
+                        "there is no database {index}, the server has {}",
+                        server.databases.len()
+                    );
+                    stream.write_error(Status::Invalid, &message).await?;
+                    continue;
+                }
+                selected = index;
+                stream.write_success().await?;
+            },
         }
     }
 }
+
+/// Wait for as many followers as `acks` asks for to apply the write at
+/// `sequence`. If too few do before the timeout, the client is told so in
+/// place of the write's response, and this returns false.
+async fn await_acks(
+    server: &Server,
+    stream: &mut protocol::Stream,
+    acks: AckLevel,
+    sequence: u64,
+) -> Result<bool, io::Error> {
+    let required = acks.required(server.followers);
+    if required == 0 {
+        return Ok(true);
+    }
+    let waited =
+        server.replication_log.wait_for_acks(sequence, required, server.write_ack_timeout).await;
+    let Err(applied) = waited else {
+        return Ok(true);
+    };
+    log::debug!("only {applied} of {required} followers acknowledged write {sequence} in time");
+    let message = format!(
+        "the write was applied, but only {applied} of the {required} followers that it needed \
+         acknowledged it"
+    );
+    stream.write_error(Status::Unreplicated, &message).await?;
+    Ok(false)
+}
+
+/// Tell the client that the engine failed to carry out its command.
+async fn write_engine_error(
+    stream: &mut protocol::Stream,
+    error: EngineError,
+) -> Result<(), io::Error> {
+    let status = match error {
+        EngineError::TooLarge(..) => {
+            log::debug!("engine error: {error}");
+            Status::TooLarge
+        },
+        EngineError::Corruption { .. } => {
+            log::error!("engine error: {error}");
+            Status::Corruption
+        },
+        EngineError::General(_) | EngineError::Io(_) | EngineError::Poison => {
+            log::warn!("engine error: {error}");
+            Status::Internal
+        },
+    };
+    stream.write_error(status, &error.to_string()).await
+}
+
+/// Tell the client that `command` can't be run in clustered mode, since it
+/// reads a key before writing it, and the cluster only commits plain writes.
+async fn write_unsupported_clustered(
+    stream: &mut protocol::Stream,
+    command: Command,
+) -> Result<(), io::Error> {
+    // TODO: Commit reads and writes to the Raft log together.
+    log::warn!("rejecting {command:?}, which isn't supported in clustered mode");
+    let message = format!("{} isn't supported in clustered mode", command.name());
+    stream.write_error(Status::Refused, &message).await
+}
+
+/// Commit a write to the cluster, and tell the client whether it succeeded.
+async fn write_clustered(
+    cluster: &Cluster,
+    stream: &mut protocol::Stream,
+    record: Record,
+) -> Result<(), io::Error> {
+    match cluster.propose(record).await {
+        Ok(()) => stream.write_success().await,
+        Err(error) => {
+            let (status, message) = match error {
+                ProposeError::NotLeader(leader) => {
+                    log::trace!("rejecting write, since the leader is {leader:?}");
+                    let message = match leader {
+                        Some(leader) => format!("this server isn't the leader, node {leader} is"),
+                        None => "this server isn't the leader".to_owned(),
+                    };
+                    (Status::Refused, message)
+                },
+                ProposeError::NotApplied(error) => {
+                    (Status::Internal, format!("the write was committed, but not applied: {error}"))
+                },
+                error => {
+                    log::warn!("failed to commit write: {error:?}");
+                    (Status::Internal, format!("failed to commit write: {error:?}"))
+                },
+            };
+            stream.write_error(status, &message).await
+        },
+    }
+}
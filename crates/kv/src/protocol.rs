@@ -1,60 +1,495 @@
+use crunch_engine::batch::WriteBatch;
+use crunch_engine::error::PairComponent;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
 pub enum Command {
-    Get,
+    Get = 1,
     Set,
     Delete,
+    Ping,
+    Auth,
+    Scan,
+    Exists,
+    Replicate,
+    Batch,
+    Info,
+    DbSize,
+    Watch,
+    Config,
+    Select,
+    GetSet,
+    Append,
+    SetNx,
+    GetDel,
+    Subscribe,
+    Eval,
+    AuthUser,
+    Cluster,
+    Acks,
+    Digest,
+    Repair,
 }
 
 impl Command {
+    /// The number of commands, whose indicators run from 1 up to this.
+    pub const COUNT: usize = 25;
+
     pub fn from_u8_opt(indicator: u8) -> Option<Self> {
         match indicator {
             1 => Some(Self::Get),
             2 => Some(Self::Set),
             3 => Some(Self::Delete),
+            4 => Some(Self::Ping),
+            5 => Some(Self::Auth),
+            6 => Some(Self::Scan),
+            7 => Some(Self::Exists),
+            8 => Some(Self::Replicate),
+            9 => Some(Self::Batch),
+            10 => Some(Self::Info),
+            11 => Some(Self::DbSize),
+            12 => Some(Self::Watch),
+            13 => Some(Self::Config),
+            14 => Some(Self::Select),
+            15 => Some(Self::GetSet),
+            16 => Some(Self::Append),
+            17 => Some(Self::SetNx),
+            18 => Some(Self::GetDel),
+            19 => Some(Self::Subscribe),
+            20 => Some(Self::Eval),
+            21 => Some(Self::AuthUser),
+            22 => Some(Self::Cluster),
+            23 => Some(Self::Acks),
+            24 => Some(Self::Digest),
+            25 => Some(Self::Repair),
+            _ => None,
+        }
+    }
+
+    /// The number of data arguments that follow the command indicator.
+    pub fn arg_count(&self) -> usize {
+        match self {
+            Self::Ping
+            | Self::Info
+            | Self::DbSize
+            | Self::Config
+            | Self::Cluster
+            | Self::Digest => 0,
+            Self::Get
+            | Self::Delete
+            | Self::Auth
+            | Self::Exists
+            | Self::Replicate
+            | Self::Batch
+            | Self::Watch
+            | Self::Select
+            | Self::GetDel
+            | Self::Subscribe
+            | Self::Acks
+            | Self::Repair => 1,
+            Self::Set | Self::GetSet | Self::Append | Self::SetNx | Self::Eval | Self::AuthUser => {
+                2
+            },
+            Self::Scan => 3,
+        }
+    }
+
+    /// The number of leading arguments that must be valid UTF-8, since the
+    /// engine only stores strings.
+    pub fn text_arg_count(&self) -> usize {
+        match self {
+            Self::Get
+            | Self::Delete
+            | Self::Exists
+            | Self::Scan
+            | Self::GetDel
+            | Self::Subscribe
+            | Self::Eval => 1,
+            Self::Set | Self::GetSet | Self::Append | Self::SetNx => 2,
+            _ => 0,
+        }
+    }
+
+    /// Which of the engine's size limits argument `index` is held to as it is
+    /// read, if any: the key limit for the key that the command is about, and
+    /// the value limit for the value that it writes.
+    pub fn size_limited_arg(&self, index: usize) -> Option<PairComponent> {
+        match (self, index) {
+            (
+                Self::Get
+                | Self::Delete
+                | Self::Exists
+                | Self::GetDel
+                | Self::Set
+                | Self::GetSet
+                | Self::Append
+                | Self::SetNx,
+                0,
+            ) => Some(PairComponent::Key),
+            (Self::Set | Self::GetSet | Self::Append | Self::SetNx, 1) => {
+                Some(PairComponent::Value)
+            },
             _ => None,
         }
     }
+
+    /// Whether the command modifies the database.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Self::Set
+                | Self::Delete
+                | Self::Batch
+                | Self::GetSet
+                | Self::Append
+                | Self::SetNx
+                | Self::GetDel
+                | Self::Eval
+        )
+    }
+
+    /// Whether the connection only carries the command's pushes or stream once
+    /// it is run, rather than going on to read more commands.
+    pub fn takes_over_connection(&self) -> bool {
+        matches!(self, Self::Replicate | Self::Watch | Self::Subscribe)
+    }
+
+    /// Whether the command can be run before the connection has authenticated.
+    pub fn allowed_unauthenticated(&self) -> bool {
+        matches!(self, Self::Ping | Self::Auth | Self::AuthUser)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Get => "get",
+            Self::Set => "set",
+            Self::Delete => "delete",
+            Self::Ping => "ping",
+            Self::Auth => "auth",
+            Self::Scan => "scan",
+            Self::Exists => "exists",
+            Self::Replicate => "replicate",
+            Self::Batch => "batch",
+            Self::Info => "info",
+            Self::DbSize => "dbsize",
+            Self::Watch => "watch",
+            Self::Config => "config",
+            Self::Select => "select",
+            Self::GetSet => "getset",
+            Self::Append => "append",
+            Self::SetNx => "setnx",
+            Self::GetDel => "getdel",
+            Self::Subscribe => "subscribe",
+            Self::Eval => "eval",
+            Self::AuthUser => "authuser",
+            Self::Cluster => "cluster",
+            Self::Acks => "acks",
+            Self::Digest => "digest",
+            Self::Repair => "repair",
+        }
+    }
 }
 
+/// The status that starts every response. Those from [`Status::Internal`] up,
+/// including any that are added later, are errors, and are followed by a
+/// message explaining them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Status {
+    /// The command ran, but didn't do what it asked, like an AUTH with the
+    /// wrong password.
+    Failed = 0,
+    Ok = 1,
+    NotFound = 2,
+
+    /// The connection must authenticate before running the command.
+    AuthRequired = 3,
+
+    /// The server failed to carry out the command.
+    Internal = 4,
+
+    /// A key or value is larger than the engine accepts.
+    TooLarge = 5,
+
+    /// The engine found data on disk that it can't read.
+    Corruption = 6,
+
+    /// The command's arguments are malformed.
+    Invalid = 7,
+
+    /// The server won't run the command in its current role, such as a write
+    /// sent to a follower.
+    Refused = 8,
+
+    /// The connection's user isn't allowed to run the command, or to run it on
+    /// the keys or database that it names.
+    Forbidden = 9,
+
+    /// The command's key belongs to a slot that another node of the slot
+    /// cluster owns. The message is the slot and the node's address, separated
+    /// by a space.
+    Moved = 10,
+
+    /// The write was applied, but fewer followers acknowledged it than the
+    /// connection's acknowledgement level asks for before the timeout. It
+    /// isn't undone, and the followers may still apply it later.
+    Unreplicated = 11,
+}
+
+impl Status {
+    /// Whether a message follows the status.
+    pub fn has_message(&self) -> bool {
+        *self as u8 >= Self::Internal as u8
+    }
+}
+
+/// Decode the argument of a BATCH command.
+///
+/// The argument holds each operation in turn: a 1 byte indicator that is 1
+/// for a set or 2 for a delete, then the key, then the value for a set. Keys
+/// and values are length prefixed like any other data. Returns `None` if the
+/// argument is malformed.
+pub fn parse_batch(mut data: &[u8]) -> Option<WriteBatch> {
+    fn take<'a>(data: &mut &'a [u8]) -> Option<&'a str> {
+        let (size, rest) = data.split_first_chunk::<4>()?;
+        let size = u32::from_be_bytes(*size) as usize;
+        let bytes = rest.get(..size)?;
+        *data = &rest[size..];
+        std::str::from_utf8(bytes).ok()
+    }
+
+    let mut batch = WriteBatch::new();
+    while let Some((indicator, rest)) = data.split_first() {
+        data = rest;
+        match indicator {
+            1 => {
+                let key = take(&mut data)?;
+                batch.set(key, take(&mut data)?);
+            },
+            2 => batch.delete(take(&mut data)?),
+            _ => return None,
+        }
+    }
+    Some(batch)
+}
+
+/// The largest argument that a client can send, in bytes.
+pub const MAX_DATA_SIZE: u32 = 512 * 1024 * 1024;
+
+/// The length prefix of a chunked argument, in place of its size. It is over
+/// [`MAX_DATA_SIZE`], so it can't be mistaken for one.
+///
+/// A chunked argument is sent as any number of length prefixed chunks, ending
+/// with an empty one, so that it can be written without knowing its size up
+/// front, or holding all of it at once.
+///
+/// That spares the client, but not the server: the engine only stores whole
+/// values, so the server gathers a chunked argument into one buffer before it
+/// runs the command, as it would a length prefixed one. What it holds for each
+/// argument is bounded by the limit that it reads the argument within, past
+/// which the rest of the chunks are skipped. Replies are chunked straight out
+/// of the stored value, without copying it.
+pub const CHUNKED: u32 = u32::MAX;
+
+/// The size of the chunks that data longer than it is written in.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
 pub struct Stream(pub TcpStream);
 
+/// The error for an argument of `size` bytes, over [`MAX_DATA_SIZE`].
+fn data_too_large(size: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("argument of {size} bytes is over the limit of {MAX_DATA_SIZE}"),
+    )
+}
+
 impl Stream {
-    pub async fn read_command_indicator(&mut self) -> Result<Option<Command>, io::Error> {
+    /// Read the indicator of the next command. An unknown indicator is an
+    /// [`io::ErrorKind::InvalidData`] error, since the arguments that follow
+    /// it can't be framed.
+    pub async fn read_command_indicator(&mut self) -> Result<Command, io::Error> {
         let indicator = self.0.read_u8().await?;
-        let command = Command::from_u8_opt(indicator);
+        let command = Command::from_u8_opt(indicator).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("unknown command {indicator}"))
+        })?;
         log::trace!("read command indicator: {command:?}");
         Ok(command)
     }
 
+    /// Read a length prefixed or [chunked](CHUNKED) argument. One that is
+    /// longer than [`MAX_DATA_SIZE`] is an [`io::ErrorKind::InvalidData`]
+    /// error.
     pub async fn read_data(&mut self) -> Result<Vec<u8>, io::Error> {
         let size = self.0.read_u32().await?;
+        if size == CHUNKED {
+            return self.read_chunks(MAX_DATA_SIZE as usize).await?.map_err(data_too_large);
+        }
+        if size > MAX_DATA_SIZE {
+            return Err(data_too_large(size as u64));
+        }
         let mut bytes = vec![0; size as usize];
         self.0.read_exact(&mut bytes).await?;
         log::trace!("read {size} bytes: {bytes:?}");
         Ok(bytes)
     }
 
+    /// Like [`Self::read_data`], but an argument longer than `limit` is skipped
+    /// over without being buffered, and its size returned in place of it, so
+    /// that the command can be refused while the stream stays framed.
+    pub async fn read_data_within(
+        &mut self,
+        limit: usize,
+    ) -> Result<Result<Vec<u8>, usize>, io::Error> {
+        let size = self.0.read_u32().await?;
+        if size == CHUNKED {
+            return Ok(self.read_chunks(limit).await?.map_err(|size| size as usize));
+        }
+        if size > MAX_DATA_SIZE {
+            return Err(data_too_large(size as u64));
+        }
+        if size as usize <= limit {
+            let mut bytes = vec![0; size as usize];
+            self.0.read_exact(&mut bytes).await?;
+            log::trace!("read {size} bytes: {bytes:?}");
+            return Ok(Ok(bytes));
+        }
+        self.skip(size).await?;
+        log::trace!("skipped argument of {size} bytes, over the limit of {limit}");
+        Ok(Err(size as usize))
+    }
+
+    /// Read the chunks of a chunked argument, after its prefix, into one
+    /// buffer. Once they add up to more than `limit` bytes, the buffer is
+    /// dropped and the rest are skipped, and their total size is returned
+    /// instead, so no more than `limit` bytes of it are ever kept.
+    async fn read_chunks(&mut self, limit: usize) -> Result<Result<Vec<u8>, u64>, io::Error> {
+        let mut bytes = Vec::new();
+        let mut total = 0;
+        loop {
+            let size = self.0.read_u32().await?;
+            if size == 0 {
+                break;
+            }
+            total += size as u64;
+            if total > MAX_DATA_SIZE as u64 {
+                return Err(data_too_large(total));
+            }
+            if total > limit as u64 {
+                bytes = Vec::new();
+                self.skip(size).await?;
+                continue;
+            }
+            let start = bytes.len();
+            bytes.resize(start + size as usize, 0);
+            self.0.read_exact(&mut bytes[start..]).await?;
+        }
+        if total > limit as u64 {
+            log::trace!("skipped chunked argument of {total} bytes, over the limit of {limit}");
+            return Ok(Err(total));
+        }
+        log::trace!("read {total} bytes in chunks: {bytes:?}");
+        Ok(Ok(bytes))
+    }
+
+    /// Read past the next `size` bytes, without keeping them.
+    async fn skip(&mut self, size: u32) -> Result<(), io::Error> {
+        let skipped = io::copy(&mut (&mut self.0).take(size as u64), &mut io::sink()).await?;
+        if skipped < size as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    pub async fn read_outcome(&mut self) -> Result<u8, io::Error> {
+        self.0.read_u8().await
+    }
+
+    pub async fn read_u64(&mut self) -> Result<u64, io::Error> {
+        self.0.read_u64().await
+    }
+
+    /// Send a `command` indicator, for when this server is acting as a client
+    /// of another one.
+    pub async fn write_command(&mut self, command: Command) -> Result<(), io::Error> {
+        self.0.write_u8(command as u8).await
+    }
+
+    pub async fn write_u64(&mut self, value: u64) -> Result<(), io::Error> {
+        self.0.write_u64(value).await
+    }
+
     pub async fn write_outcome(&mut self, outcome: u8) -> Result<(), io::Error> {
         self.0.write_u8(outcome).await?;
         Ok(())
     }
 
+    /// Write a `status` that isn't followed by a message.
+    pub async fn write_status(&mut self, status: Status) -> Result<(), io::Error> {
+        debug_assert!(!status.has_message(), "{status:?} needs a message");
+        self.write_outcome(status as u8).await
+    }
+
     pub async fn write_success(&mut self) -> Result<(), io::Error> {
-        self.write_outcome(1).await
+        self.write_status(Status::Ok).await
+    }
+
+    pub async fn write_not_found(&mut self) -> Result<(), io::Error> {
+        self.write_status(Status::NotFound).await
     }
 
     pub async fn write_failure(&mut self) -> Result<(), io::Error> {
-        self.write_outcome(0).await
+        self.write_status(Status::Failed).await
     }
 
+    pub async fn write_unauthenticated(&mut self) -> Result<(), io::Error> {
+        self.write_status(Status::AuthRequired).await
+    }
+
+    /// Write an error `status`, followed by a message explaining it.
+    pub async fn write_error(&mut self, status: Status, message: &str) -> Result<(), io::Error> {
+        debug_assert!(status.has_message(), "{status:?} isn't an error");
+        self.write_outcome(status as u8).await?;
+        self.write_data(message.as_bytes()).await
+    }
+
+    pub async fn write_count(&mut self, count: u32) -> Result<(), io::Error> {
+        self.0.write_u32(count).await
+    }
+
+    /// Write length prefixed data, or [chunked](CHUNKED) data if it is longer
+    /// than [`CHUNK_SIZE`].
     pub async fn write_data(&mut self, data: &[u8]) -> Result<(), io::Error> {
-        // TODO: Bounds check this.
-        let size = data.len() as u32;
-        self.0.write_u32(size).await?;
+        if data.len() > CHUNK_SIZE {
+            self.0.write_u32(CHUNKED).await?;
+            for chunk in data.chunks(CHUNK_SIZE) {
+                self.0.write_u32(chunk.len() as u32).await?;
+                self.0.write_all(chunk).await?;
+            }
+            return self.0.write_u32(0).await;
+        }
+        self.0.write_u32(data.len() as u32).await?;
         self.0.write_all(data).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_batch() {
+        let mut data = vec![1, 0, 0, 0, 1, b'a', 0, 0, 0, 2, b'1', b'2'];
+        data.extend([2, 0, 0, 0, 1, b'b']);
+        let mut expected = WriteBatch::new();
+        expected.set("a", "12");
+        expected.delete("b");
+        assert_eq!(super::parse_batch(&data), Some(expected));
+        assert_eq!(super::parse_batch(&[]), Some(WriteBatch::new()));
+        assert_eq!(super::parse_batch(&data[..data.len() - 1]), None);
+        assert_eq!(super::parse_batch(&[3]), None);
+    }
+}
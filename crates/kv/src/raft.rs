@@ -0,0 +1,984 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::path::Path;
+
+use crate::replication::Record;
+
+/// How many ticks pass between the heartbeats that a leader sends.
+const HEARTBEAT_TICKS: u32 = 1;
+
+/// A follower that hasn't heard from a leader for somewhere between this many
+/// ticks and twice as many starts an election.
+const ELECTION_TICKS: u32 = 10;
+
+/// The most entries that a single `AppendEntries` message carries.
+const MAX_ENTRIES_PER_MESSAGE: usize = 64;
+
+/// The most bytes of keys and values that a single `AppendEntries` message
+/// carries, past which no more entries are added to it. The first entry is
+/// always sent, however large it is.
+pub const MAX_BYTES_PER_MESSAGE: usize = 4 * 1024 * 1024;
+
+pub type NodeId = u64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub term: u64,
+
+    /// The write to apply, or `None` for the no-op entry that every new leader
+    /// appends to commit the entries of earlier terms.
+    pub record: Option<Record>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    AppendResponse {
+        term: u64,
+        success: bool,
+
+        /// On success, the index of the last entry the follower now holds. On
+        /// failure, the index of the last entry it holds at all, as a hint for
+        /// where the leader should retry from.
+        match_index: u64,
+    },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Self::RequestVote { term, .. }
+            | Self::Vote { term, .. }
+            | Self::AppendEntries { term, .. }
+            | Self::AppendResponse { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub message: Message,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Where a node persists the state that it must not forget across restarts.
+///
+/// A node calls into its storage before sending any message that depends on
+/// the change, so once a call returns, the change must be durable.
+pub trait Storage {
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<NodeId>) -> io::Result<()>;
+
+    /// Replace every entry from `index` onwards with `entries`.
+    fn save_entries(&mut self, index: u64, entries: &[LogEntry]) -> io::Result<()>;
+}
+
+/// The state recovered from a node's [`Storage`] when it starts.
+#[derive(Debug, Default)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub log: Vec<LogEntry>,
+}
+
+/// A single member of a Raft cluster.
+///
+/// This only implements the consensus algorithm itself, and is driven entirely
+/// from the outside: the caller feeds it clock ticks and incoming messages,
+/// sends the messages that it produces, and applies the entries that it
+/// commits. Log indexes start from 1.
+///
+/// Ticks, messages and proposals fail if the node can't write to its storage.
+/// The node may then have changed state that it couldn't persist, so it must
+/// be dropped without sending the messages that it has produced.
+pub struct RaftNode<S> {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    storage: S,
+
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    log: Vec<LogEntry>,
+    commit_index: u64,
+    applied_index: u64,
+
+    /// For each peer, the index of the next entry to send it (leaders only).
+    next_index: HashMap<NodeId, u64>,
+
+    /// For each peer, the index of the last entry known to be replicated on it
+    /// (leaders only).
+    match_index: HashMap<NodeId, u64>,
+
+    /// The peers that have voted for this node in the current term (candidates
+    /// only).
+    votes: HashSet<NodeId>,
+
+    elapsed_ticks: u32,
+    election_timeout: u32,
+
+    /// The state of a xorshift generator, used to randomize election timeouts
+    /// so that nodes don't keep splitting the vote.
+    rng: u64,
+
+    outbox: Vec<Envelope>,
+}
+
+/// A proposal was made to a node that isn't the leader. Holds the id of the
+/// leader, if the node knows it.
+#[derive(Debug)]
+pub struct NotLeader(pub Option<NodeId>);
+
+impl<S: Storage> RaftNode<S> {
+    pub fn new(id: NodeId, peers: Vec<NodeId>, storage: S, state: HardState) -> Self {
+        let mut node = Self {
+            id,
+            peers,
+            storage,
+            role: Role::Follower,
+            term: state.term,
+            voted_for: state.voted_for,
+            leader: None,
+            log: state.log,
+            commit_index: 0,
+            applied_index: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            votes: HashSet::new(),
+            elapsed_ticks: 0,
+            election_timeout: 0,
+            rng: id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            outbox: Vec::new(),
+        };
+        node.reset_election_timeout();
+        node
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    /// Advance the node's clock by one tick.
+    pub fn tick(&mut self) -> io::Result<()> {
+        self.elapsed_ticks += 1;
+        match self.role {
+            Role::Leader => {
+                if self.elapsed_ticks >= HEARTBEAT_TICKS {
+                    self.elapsed_ticks = 0;
+                    self.broadcast_append();
+                }
+            },
+            Role::Follower | Role::Candidate => {
+                if self.elapsed_ticks >= self.election_timeout {
+                    self.start_election()?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Handle a message from another node. Messages that aren't from one of
+    /// the node's peers, or aren't addressed to it, are dropped, so that they
+    /// can't count towards a vote or a commit.
+    pub fn step(&mut self, envelope: Envelope) -> io::Result<()> {
+        let Envelope { from, to, message } = envelope;
+        if to != self.id || !self.peers.contains(&from) {
+            log::warn!("node {} dropped a message from {from} to {to}", self.id);
+            return Ok(());
+        }
+        if message.term() > self.term {
+            self.become_follower(message.term(), None)?;
+        }
+        match message {
+            Message::RequestVote { term, last_log_index, last_log_term } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.term
+                    && self.voted_for.is_none_or(|voted_for| voted_for == from)
+                    && up_to_date;
+                if granted {
+                    self.voted_for = Some(from);
+                    self.save_hard_state()?;
+                    self.elapsed_ticks = 0;
+                }
+                self.send(from, Message::Vote { term: self.term, granted });
+            },
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+            },
+            Message::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.term {
+                    let match_index = self.last_index();
+                    self.send(from, Message::AppendResponse {
+                        term: self.term,
+                        success: false,
+                        match_index,
+                    });
+                    return Ok(());
+                }
+                if self.role != Role::Follower {
+                    self.become_follower(term, Some(from))?;
+                }
+                self.leader = Some(from);
+                self.elapsed_ticks = 0;
+
+                if prev_log_index > self.last_index()
+                    || self.term_at(prev_log_index) != prev_log_term
+                {
+                    let match_index = self.last_index().min(prev_log_index.saturating_sub(1));
+                    self.send(from, Message::AppendResponse {
+                        term: self.term,
+                        success: false,
+                        match_index,
+                    });
+                    return Ok(());
+                }
+
+                // Entries that are already in the log are skipped, unless they conflict with
+                // the leader's, in which case they and everything after them are replaced.
+                let mut index = prev_log_index + 1;
+                let mut new_entries = entries.as_slice();
+                while let Some(entry) = new_entries.first() {
+                    if index > self.last_index() || self.term_at(index) != entry.term {
+                        break;
+                    }
+                    index += 1;
+                    new_entries = &new_entries[1..];
+                }
+                if !new_entries.is_empty() {
+                    // The entries are persisted first, so that a failed write leaves the log
+                    // as it was.
+                    self.storage.save_entries(index, new_entries)?;
+                    self.log.truncate(index as usize - 1);
+                    self.log.extend_from_slice(new_entries);
+                }
+
+                // The commit index never moves back, even if this message is older than
+                // one that committed more of the log.
+                let match_index = prev_log_index + entries.len() as u64;
+                self.commit_index = self.commit_index.max(leader_commit.min(match_index));
+                self.send(from, Message::AppendResponse {
+                    term: self.term,
+                    success: true,
+                    match_index,
+                });
+            },
+            Message::AppendResponse { term, success, match_index } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Ok(());
+                }
+                if success {
+                    let matched = self.match_index.entry(from).or_default();
+                    *matched = (*matched).max(match_index);
+                    self.next_index.insert(from, match_index + 1);
+                    self.advance_commit();
+                } else {
+                    let next = self.next_index.entry(from).or_insert(1);
+                    *next = (*next - 1).min(match_index + 1).max(1);
+                    self.send_append(from);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Append `record` to the log, if this node is the leader, and return the
+    /// index and term of its entry. The record is applied once that entry is
+    /// committed, which only happens if no other leader overwrites it first.
+    pub fn propose(&mut self, record: Record) -> io::Result<Result<(u64, u64), NotLeader>> {
+        if self.role != Role::Leader {
+            return Ok(Err(NotLeader(self.leader)));
+        }
+        self.append(Some(record))?;
+        self.broadcast_append();
+        self.advance_commit();
+        Ok(Ok((self.last_index(), self.term)))
+    }
+
+    /// Take the entries that have been committed since the last call, along
+    /// with their indexes.
+    pub fn take_committed(&mut self) -> Vec<(u64, LogEntry)> {
+        let committed = (self.applied_index + 1..=self.commit_index)
+            .map(|index| (index, self.log[index as usize - 1].clone()))
+            .collect();
+        self.applied_index = self.commit_index;
+        committed
+    }
+
+    /// Take the messages that the node wants sent since the last call.
+    pub fn take_messages(&mut self) -> Vec<Envelope> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn start_election(&mut self) -> io::Result<()> {
+        self.role = Role::Candidate;
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.save_hard_state()?;
+        self.votes = HashSet::from([self.id]);
+        self.reset_election_timeout();
+        log::debug!("node {} starting election for term {}", self.id, self.term);
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let message = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers.clone() {
+            self.send(peer, message.clone());
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> io::Result<()> {
+        self.role = Role::Follower;
+        self.leader = leader;
+        if term != self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_hard_state()?;
+        }
+        self.reset_election_timeout();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> io::Result<()> {
+        log::info!("node {} became leader for term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed_ticks = 0;
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|peer| (*peer, next)).collect();
+        self.match_index = self.peers.iter().map(|peer| (*peer, 0)).collect();
+        // A leader can only count replicas of entries from its own term towards
+        // committing them, so this entry is what commits any that are left over from
+        // earlier terms.
+        self.append(None)?;
+        self.broadcast_append();
+        self.advance_commit();
+        Ok(())
+    }
+
+    fn append(&mut self, record: Option<Record>) -> io::Result<()> {
+        let entry = LogEntry { term: self.term, record };
+        self.storage.save_entries(self.last_index() + 1, std::slice::from_ref(&entry))?;
+        self.log.push(entry);
+        Ok(())
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1);
+        let prev_log_index = next - 1;
+        let mut bytes = 0;
+        let entries: Vec<_> = self
+            .log
+            .iter()
+            .skip(prev_log_index as usize)
+            .take(MAX_ENTRIES_PER_MESSAGE)
+            .take_while(|entry| {
+                let fits = bytes < MAX_BYTES_PER_MESSAGE;
+                bytes += match &entry.record {
+                    Some(Record::Set { key, value }) => key.len() + value.len(),
+                    Some(Record::Delete { key }) => key.len(),
+                    None => 0,
+                };
+                fits
+            })
+            .cloned()
+            .collect();
+        self.send(peer, Message::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries,
+            leader_commit: self.commit_index,
+        });
+    }
+
+    /// Commit the newest entry from this term that a quorum has replicated.
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let replicas =
+                1 + self.match_index.values().filter(|matched| **matched >= index).count();
+            if replicas >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    fn save_hard_state(&mut self) -> io::Result<()> {
+        self.storage.save_hard_state(self.term, self.voted_for)
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        self.outbox.push(Envelope { from: self.id, to, message });
+    }
+
+    fn reset_election_timeout(&mut self) {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.election_timeout = ELECTION_TICKS + (self.rng % ELECTION_TICKS as u64) as u32;
+        self.elapsed_ticks = 0;
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    /// The term of the entry at `index`, where the (nonexistent) entry at index
+    /// 0 has term 0.
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            index => self.log.get(index as usize - 1).map_or(0, |entry| entry.term),
+        }
+    }
+}
+
+/// Persists a node's state to an append-only file.
+///
+/// The file is a sequence of records, which are replayed in order on startup:
+///
+/// ```text
+/// 'H' | term (u64 BE) | voted for (u64 BE, 0 for none)
+/// 'E' | index (u64 BE) | term (u64 BE) | record
+/// ```
+///
+/// An entry record replaces whatever was at its index, along with every entry
+/// after it. Records are encoded the same way as in the replication stream,
+/// with an indicator of 0 standing in for a no-op.
+///
+/// A record that was cut off by a crash while it was appended was never
+/// synced, so nothing depends on it. It is dropped from the end of the file
+/// when the file is opened.
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    /// Open the file at `path`, creating it if needed, and return the state
+    /// recorded in it.
+    pub fn open(path: &Path) -> io::Result<(Self, HardState)> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut state = HardState::default();
+        let mut reader = BufReader::new(&mut file);
+        // The offset just past the last complete record.
+        let mut complete = 0;
+        loop {
+            match read_log_record(&mut reader, &mut state) {
+                Ok(true) => complete = reader.stream_position()?,
+                Ok(false) => break,
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                    log::warn!("discarding incomplete record at the end of {path:?}");
+                    drop(reader);
+                    file.set_len(complete)?;
+                    file.sync_data()?;
+                    break;
+                },
+                Err(error) => return Err(error),
+            }
+        }
+        Ok((Self { file }, state))
+    }
+}
+
+/// Read the next record of a [`FileStorage`] file from `reader` into `state`.
+/// Returns false at the end of the file, or an [`io::ErrorKind::UnexpectedEof`]
+/// if the file ends partway through the record.
+fn read_log_record(reader: &mut impl Read, state: &mut HardState) -> io::Result<bool> {
+    let mut tag = [0; 1];
+    if reader.read(&mut tag)? == 0 {
+        return Ok(false);
+    }
+    match tag[0] {
+        b'H' => {
+            state.term = read_u64(reader)?;
+            state.voted_for = Some(read_u64(reader)?).filter(|id| *id != 0);
+        },
+        b'E' => {
+            let index = read_u64(reader)?;
+            let term = read_u64(reader)?;
+            let record = read_record(reader)?;
+            // Entries can only replace the log from somewhere within it, or extend it.
+            if index == 0 || index > state.log.len() as u64 + 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("raft log entry has invalid index {index}"),
+                ));
+            }
+            state.log.truncate(index as usize - 1);
+            state.log.push(LogEntry { term, record });
+        },
+        tag => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid raft log record {tag}"),
+            ))
+        },
+    }
+    Ok(true)
+}
+
+impl Storage for FileStorage {
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<NodeId>) -> io::Result<()> {
+        let mut buffer = vec![b'H'];
+        buffer.extend(term.to_be_bytes());
+        buffer.extend(voted_for.unwrap_or(0).to_be_bytes());
+        self.file.write_all(&buffer)?;
+        self.file.sync_data()
+    }
+
+    fn save_entries(&mut self, index: u64, entries: &[LogEntry]) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        for (index, entry) in (index..).zip(entries) {
+            buffer.push(b'E');
+            buffer.extend(index.to_be_bytes());
+            buffer.extend(entry.term.to_be_bytes());
+            write_record(&mut buffer, entry.record.as_ref());
+        }
+        self.file.write_all(&buffer)?;
+        self.file.sync_data()
+    }
+}
+
+pub fn write_record(buffer: &mut Vec<u8>, record: Option<&Record>) {
+    fn write_data(buffer: &mut Vec<u8>, data: &str) {
+        buffer.extend((data.len() as u32).to_be_bytes());
+        buffer.extend(data.as_bytes());
+    }
+    match record {
+        None => buffer.push(0),
+        Some(Record::Set { key, value }) => {
+            buffer.push(1);
+            write_data(buffer, key);
+            write_data(buffer, value);
+        },
+        Some(Record::Delete { key }) => {
+            buffer.push(2);
+            write_data(buffer, key);
+        },
+    }
+}
+
+pub fn read_record(reader: &mut impl Read) -> io::Result<Option<Record>> {
+    let mut indicator = [0; 1];
+    reader.read_exact(&mut indicator)?;
+    match indicator[0] {
+        0 => Ok(None),
+        1 => Ok(Some(Record::Set { key: read_string(reader)?, value: read_string(reader)? })),
+        2 => Ok(Some(Record::Delete { key: read_string(reader)? })),
+        indicator => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid record indicator {indicator}"),
+        )),
+    }
+}
+
+pub fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    let mut size = [0; 4];
+    reader.read_exact(&mut size)?;
+    let mut bytes = vec![0; u32::from_be_bytes(size) as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryStorage;
+
+    impl Storage for MemoryStorage {
+        fn save_hard_state(&mut self, _term: u64, _voted_for: Option<NodeId>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn save_entries(&mut self, _index: u64, _entries: &[LogEntry]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Storage that can't hold any entries, like a full disk.
+    struct FullStorage;
+
+    impl Storage for FullStorage {
+        fn save_hard_state(&mut self, _term: u64, _voted_for: Option<NodeId>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn save_entries(&mut self, _index: u64, _entries: &[LogEntry]) -> io::Result<()> {
+            Err(io::Error::other("no space left"))
+        }
+    }
+
+    /// A cluster of nodes connected by a network that delivers every message,
+    /// except to and from nodes that are down.
+    struct Cluster {
+        nodes: BTreeMap<NodeId, RaftNode<MemoryStorage>>,
+        down: HashSet<NodeId>,
+        applied: BTreeMap<NodeId, Vec<Record>>,
+    }
+
+    impl Cluster {
+        fn new(size: u64) -> Self {
+            let ids: Vec<_> = (1..=size).collect();
+            let nodes = ids
+                .iter()
+                .map(|id| {
+                    let peers = ids.iter().copied().filter(|peer| peer != id).collect();
+                    (*id, RaftNode::new(*id, peers, MemoryStorage, HardState::default()))
+                })
+                .collect();
+            Self { nodes, down: HashSet::new(), applied: BTreeMap::new() }
+        }
+
+        /// Tick every live node once, and deliver messages until there are none
+        /// left in flight.
+        fn tick(&mut self) {
+            for (id, node) in &mut self.nodes {
+                if !self.down.contains(id) {
+                    node.tick().unwrap();
+                }
+            }
+            self.deliver();
+        }
+
+        fn deliver(&mut self) {
+            loop {
+                let mut in_flight = Vec::new();
+                for (id, node) in &mut self.nodes {
+                    let messages = node.take_messages();
+                    let committed = node.take_committed();
+                    if !self.down.contains(id) {
+                        in_flight.extend(messages);
+                        self.applied
+                            .entry(*id)
+                            .or_default()
+                            .extend(committed.into_iter().filter_map(|(_, entry)| entry.record));
+                    }
+                }
+                if in_flight.is_empty() {
+                    return;
+                }
+                for envelope in in_flight {
+                    if !self.down.contains(&envelope.to) {
+                        self.nodes.get_mut(&envelope.to).unwrap().step(envelope).unwrap();
+                    }
+                }
+            }
+        }
+
+        fn tick_until_leader(&mut self) -> NodeId {
+            for _ in 0..100 {
+                self.tick();
+                let leader = self
+                    .nodes
+                    .values()
+                    .find(|node| node.role() == Role::Leader && !self.down.contains(&node.id()));
+                if let Some(leader) = leader {
+                    return leader.id();
+                }
+            }
+            panic!("no leader was elected");
+        }
+
+        fn propose(&mut self, leader: NodeId, key: &str) {
+            let record = Record::Delete { key: key.to_owned() };
+            self.nodes.get_mut(&leader).unwrap().propose(record).unwrap().unwrap();
+            self.deliver();
+            // Followers learn that the entry was committed with the next heartbeat.
+            self.tick();
+        }
+
+        fn applied_keys(&self, id: NodeId) -> Vec<&str> {
+            self.applied
+                .get(&id)
+                .into_iter()
+                .flatten()
+                .map(|record| match record {
+                    Record::Delete { key } | Record::Set { key, .. } => key.as_str(),
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn survives_leader_failure() {
+        let mut cluster = Cluster::new(3);
+        let first_leader = cluster.tick_until_leader();
+        cluster.propose(first_leader, "a");
+        for id in 1..=3 {
+            assert_eq!(cluster.applied_keys(id), ["a"]);
+        }
+
+        cluster.down.insert(first_leader);
+        let second_leader = cluster.tick_until_leader();
+        assert_ne!(first_leader, second_leader);
+        cluster.propose(second_leader, "b");
+        for id in (1..=3).filter(|id| *id != first_leader) {
+            assert_eq!(cluster.applied_keys(id), ["a", "b"]);
+        }
+
+        // The old leader catches up once it comes back.
+        cluster.down.remove(&first_leader);
+        for _ in 0..5 {
+            cluster.tick();
+        }
+        assert_eq!(cluster.applied_keys(first_leader), ["a", "b"]);
+        assert_eq!(cluster.nodes[&first_leader].leader(), Some(second_leader));
+    }
+
+    #[test]
+    fn minority_cannot_commit() {
+        let mut cluster = Cluster::new(3);
+        let leader = cluster.tick_until_leader();
+        for id in (1..=3).filter(|id| *id != leader) {
+            cluster.down.insert(id);
+        }
+        cluster.propose(leader, "a");
+        assert!(cluster.applied_keys(leader).is_empty());
+    }
+
+    #[test]
+    fn proposals_are_rejected_by_followers() {
+        let mut cluster = Cluster::new(3);
+        let leader = cluster.tick_until_leader();
+        let follower = (1..=3).find(|id| *id != leader).unwrap();
+        let node = cluster.nodes.get_mut(&follower).unwrap();
+        let result = node.propose(Record::Delete { key: "a".to_owned() }).unwrap();
+        assert!(matches!(result, Err(NotLeader(Some(id))) if id == leader));
+    }
+
+    #[test]
+    fn file_storage_round_trip() {
+        let path = std::env::temp_dir().join(format!("crunch-raft-{}.log", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let entry = |term, key: &str| LogEntry {
+            term,
+            record: Some(Record::Set { key: key.to_owned(), value: "1".to_owned() }),
+        };
+        {
+            let (mut storage, state) = FileStorage::open(&path).unwrap();
+            assert_eq!(state.term, 0);
+            storage.save_hard_state(2, Some(3)).unwrap();
+            storage
+                .save_entries(1, &[entry(1, "a"), entry(1, "b"), LogEntry {
+                    term: 2,
+                    record: None,
+                }])
+                .unwrap();
+            // This replaces the entries at indexes 2 and 3.
+            storage.save_entries(2, &[entry(2, "c")]).unwrap();
+        }
+
+        let (_, state) = FileStorage::open(&path).unwrap();
+        assert_eq!(state.term, 2);
+        assert_eq!(state.voted_for, Some(3));
+        assert_eq!(state.log, [entry(1, "a"), entry(2, "c")]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_storage_incomplete_tail() {
+        let path =
+            std::env::temp_dir().join(format!("crunch-raft-tail-{}.log", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let entry = |key: &str| LogEntry {
+            term: 1,
+            record: Some(Record::Set { key: key.to_owned(), value: "1".to_owned() }),
+        };
+        {
+            let (mut storage, _) = FileStorage::open(&path).unwrap();
+            storage.save_entries(1, &[entry("a"), entry("b")]).unwrap();
+        }
+        // Cut the last entry off partway through, as a crash while appending it would.
+        let size = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(size - 3).unwrap();
+        {
+            let (mut storage, state) = FileStorage::open(&path).unwrap();
+            assert_eq!(state.log, [entry("a")]);
+            storage.save_entries(2, &[entry("c")]).unwrap();
+        }
+        let (_, state) = FileStorage::open(&path).unwrap();
+        assert_eq!(state.log, [entry("a"), entry("c")]);
+
+        // An entry at index 0 can't be replayed.
+        let mut buffer = vec![b'E'];
+        buffer.extend(0u64.to_be_bytes());
+        buffer.extend(1u64.to_be_bytes());
+        write_record(&mut buffer, None);
+        std::fs::write(&path, buffer).unwrap();
+        let error = FileStorage::open(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn commit_index_never_moves_back() {
+        let mut node = RaftNode::new(2, vec![1], MemoryStorage, HardState::default());
+        let entry = LogEntry { term: 1, record: None };
+        let append = |prev_log_index, entries: Vec<LogEntry>, leader_commit| Envelope {
+            from: 1,
+            to: 2,
+            message: Message::AppendEntries {
+                term: 1,
+                prev_log_index,
+                prev_log_term: if prev_log_index == 0 { 0 } else { 1 },
+                entries,
+                leader_commit,
+            },
+        };
+        node.step(append(0, vec![entry.clone(), entry.clone()], 2)).unwrap();
+        assert_eq!(node.take_committed().len(), 2);
+        // A delayed message that the follower already holds the entries of commits
+        // less.
+        node.step(append(0, vec![entry], 1)).unwrap();
+        assert_eq!(node.commit_index, 2);
+    }
+
+    #[test]
+    fn messages_from_non_members_are_dropped() {
+        let mut node = RaftNode::new(1, vec![2, 3], MemoryStorage, HardState::default());
+        node.start_election().unwrap();
+        assert_eq!(node.role(), Role::Candidate);
+        let vote =
+            |from, to| Envelope { from, to, message: Message::Vote { term: 1, granted: true } };
+        node.step(vote(4, 1)).unwrap();
+        node.step(vote(2, 5)).unwrap();
+        assert_eq!(node.role(), Role::Candidate);
+        assert_eq!(node.votes, HashSet::from([1]));
+
+        node.step(vote(2, 1)).unwrap();
+        assert_eq!(node.role(), Role::Leader);
+        node.take_committed();
+        let response = |from| Envelope {
+            from,
+            to: 1,
+            message: Message::AppendResponse { term: 1, success: true, match_index: 1 },
+        };
+        node.step(response(4)).unwrap();
+        node.step(response(5)).unwrap();
+        assert_eq!(node.commit_index, 0);
+        assert!(!node.match_index.contains_key(&4));
+        // Only a member's acknowledgement brings the no-op entry to a majority.
+        node.step(response(3)).unwrap();
+        assert_eq!(node.commit_index, 1);
+    }
+
+    #[test]
+    fn append_entries_are_bounded_in_size() {
+        let mut node = RaftNode::new(1, vec![2], MemoryStorage, HardState::default());
+        node.start_election().unwrap();
+        let message = Message::Vote { term: 1, granted: true };
+        node.step(Envelope { from: 2, to: 1, message }).unwrap();
+        let value = "x".repeat(MAX_BYTES_PER_MESSAGE / 2 + 1);
+        for key in ["a", "b", "c"] {
+            let record = Record::Set { key: key.to_owned(), value: value.clone() };
+            node.propose(record).unwrap().unwrap();
+        }
+        node.take_messages();
+        node.tick().unwrap();
+        // The no-op entry, and the first two writes, which only pass the limit with
+        // the second.
+        let messages = node.take_messages();
+        let Message::AppendEntries { entries, .. } = &messages[0].message else {
+            panic!("expected AppendEntries, got {:?}", messages[0]);
+        };
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn unpersisted_entries_are_not_kept() {
+        let mut node = RaftNode::new(2, vec![1], FullStorage, HardState::default());
+        let entries = vec![LogEntry { term: 1, record: None }];
+        let message = Message::AppendEntries {
+            term: 1,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            entries,
+            leader_commit: 1,
+        };
+        assert!(node.step(Envelope { from: 1, to: 2, message }).is_err());
+        assert!(node.log.is_empty());
+        assert!(node.take_messages().is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crunch_engine::error::Error as EngineError;
+use tokio::io;
+
+use crate::protocol::{self, Command};
+use crate::replication::{self, Record};
+use crate::Server;
+
+/// The number of leaves of a [`MerkleTree`], each covering the keys that hash
+/// into it.
+pub const LEAF_COUNT: usize = 1024;
+
+/// Hashes of every key-value pair in a database, which two replicas compare
+/// to find the keys that they disagree on without sending the keys
+/// themselves.
+///
+/// Keys are spread over [`LEAF_COUNT`] leaves by a hash of the key, and each
+/// leaf holds a hash of the pairs in it. Every node above the leaves holds a
+/// hash of its two children, up to the root, so that replicas that agree
+/// about a whole subtree can tell from a single hash.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MerkleTree {
+    /// The nodes in breadth first order, where the children of node `i` are
+    /// `2i` and `2i + 1`. The root is node 1, and node 0 is unused.
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    pub fn build(
+        entries: impl Iterator<Item = Result<(String, String), EngineError>>,
+    ) -> Result<Self, EngineError> {
+        let mut nodes = vec![0u64; 2 * LEAF_COUNT];
+        for entry in entries {
+            let (key, value) = entry?;
+            // Adding the pairs' hashes leaves each leaf's hash the same whatever
+            // order its pairs are read in.
+            let node = &mut nodes[LEAF_COUNT + leaf(&key)];
+            *node = node.wrapping_add(pair_hash(&key, &value));
+        }
+        for index in (1..LEAF_COUNT).rev() {
+            let mut children = [0; 16];
+            children[..8].copy_from_slice(&nodes[2 * index].to_be_bytes());
+            children[8..].copy_from_slice(&nodes[2 * index + 1].to_be_bytes());
+            nodes[index] = fnv1a(&children);
+        }
+        Ok(Self { nodes })
+    }
+
+    /// The leaves on which this tree and `other` differ, in order.
+    pub fn diverged_leaves(&self, other: &Self) -> Vec<usize> {
+        let mut diverged = Vec::new();
+        let mut pending = vec![1];
+        while let Some(index) = pending.pop() {
+            if self.nodes[index] == other.nodes[index] {
+                continue;
+            }
+            if index >= LEAF_COUNT {
+                diverged.push(index - LEAF_COUNT);
+            } else {
+                pending.extend([2 * index + 1, 2 * index]);
+            }
+        }
+        diverged
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.nodes.iter().flat_map(|node| node.to_be_bytes()).collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 8 * 2 * LEAF_COUNT {
+            return None;
+        }
+        let nodes = bytes.chunks(8).map(|node| u64::from_be_bytes(node.try_into().unwrap()));
+        Some(Self { nodes: nodes.collect() })
+    }
+}
+
+/// The leaf of a [`MerkleTree`] that `key` belongs to.
+pub fn leaf(key: &str) -> usize {
+    (fnv1a(key.as_bytes()) % LEAF_COUNT as u64) as usize
+}
+
+fn pair_hash(key: &str, value: &str) -> u64 {
+    // 0xFF never appears in UTF-8, so it can't be confused for part of the key.
+    let mut pair = Vec::with_capacity(key.len() + 1 + value.len());
+    pair.extend(key.as_bytes());
+    pair.push(0xFF);
+    pair.extend(value.as_bytes());
+    fnv1a(&pair)
+}
+
+/// The 64 bit FNV-1a hash of `data`, which, unlike the standard library's
+/// hasher, is the same in every build, so replicas on different versions
+/// agree on it.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter()
+        .fold(0xCBF29CE484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001B3))
+}
+
+/// Respond to a DIGEST with the Merkle tree of the database's pairs.
+pub async fn serve_digest(server: &Server, stream: &mut protocol::Stream) -> Result<(), io::Error> {
+    let engine = &server.databases[0].engine;
+    // Reading every pair would hold up the other connections on this thread.
+    let tree = tokio::task::block_in_place(|| MerkleTree::build(engine.entries()?));
+    match tree {
+        Ok(tree) => {
+            stream.write_success().await?;
+            stream.write_data(&tree.to_bytes()).await
+        },
+        Err(error) => crate::write_engine_error(stream, error).await,
+    }
+}
+
+/// Respond to a REPAIR with the sequence that the database is at, and every
+/// pair in the given leaves. Each pair is preceded by a 1, and the pairs end
+/// with a 0.
+pub async fn serve_repair(
+    server: &Server,
+    stream: &mut protocol::Stream,
+    leaves: &HashSet<usize>,
+) -> Result<(), io::Error> {
+    let engine = &server.databases[0].engine;
+    // The pairs are as of the last write in the replication log when they are
+    // read, which the follower needs to know to apply them safely.
+    let snapshot = {
+        let _writes = server.writes.lock().await;
+        engine.entries().map(|entries| (server.replication_log.last_sequence(), entries))
+    };
+    let pairs = match snapshot {
+        Ok((sequence, entries)) => tokio::task::block_in_place(|| {
+            entries
+                .filter(|entry| entry.as_ref().map_or(true, |(key, _)| leaves.contains(&leaf(key))))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map(|pairs| (sequence, pairs)),
+        Err(error) => Err(error),
+    };
+    let (sequence, pairs) = match pairs {
+        Ok(pairs) => pairs,
+        Err(error) => return crate::write_engine_error(stream, error).await,
+    };
+    stream.write_success().await?;
+    stream.write_u64(sequence).await?;
+    for (key, value) in pairs {
+        stream.write_outcome(1).await?;
+        stream.write_data(key.as_bytes()).await?;
+        stream.write_data(value.as_bytes()).await?;
+    }
+    stream.write_outcome(0).await
+}
+
+/// Parse the argument of a REPAIR, which is the index of each leaf as a u32.
+pub fn parse_leaves(data: &[u8]) -> Option<HashSet<usize>> {
+    if !data.len().is_multiple_of(4) {
+        return None;
+    }
+    data.chunks(4)
+        .map(|leaf| u32::from_be_bytes(leaf.try_into().unwrap()) as usize)
+        .map(|leaf| (leaf < LEAF_COUNT).then_some(leaf))
+        .collect()
+}
+
+/// Every `interval`, compare this follower's pairs with the leader's at
+/// `address`, and copy over the leader's pairs wherever they differ. This
+/// catches the follower up on writes that it missed, such as those made while
+/// it was restoring from a backup, without copying the whole keyspace.
+pub async fn run(server: Arc<Server>, address: String, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match repair_once(&server, &address).await {
+            Ok(Some(0)) => log::debug!("this follower matches the leader at {address}"),
+            Ok(Some(count)) => log::info!("repaired {count} keys from the leader at {address}"),
+            Ok(None) => log::debug!("skipped a repair, since replication moved past it"),
+            Err(error) => log::warn!("repair from {address} failed: {error}"),
+        }
+    }
+}
+
+/// Repair this follower from the leader at `address` once, returning the
+/// number of keys that were changed, or `None` if the repair had to be
+/// skipped.
+async fn repair_once(server: &Server, address: &str) -> Result<Option<usize>, io::Error> {
+    let database = &server.databases[0];
+    let mut stream = replication::connect(server, address).await?;
+    stream.write_command(Command::Digest).await?;
+    if stream.read_outcome().await? != 1 {
+        return Err(io::Error::other("leader refused to send its digest"));
+    }
+    let theirs = MerkleTree::from_bytes(&stream.read_data().await?)
+        .ok_or_else(|| io::Error::other("malformed digest"))?;
+    let ours = tokio::task::block_in_place(|| MerkleTree::build(database.engine.entries()?))
+        .map_err(io::Error::other)?;
+    let diverged = ours.diverged_leaves(&theirs);
+    if diverged.is_empty() {
+        return Ok(Some(0));
+    }
+    log::debug!("{} of {LEAF_COUNT} leaves differ from the leader", diverged.len());
+    let request: Vec<u8> = diverged.iter().flat_map(|leaf| (*leaf as u32).to_be_bytes()).collect();
+    stream.write_command(Command::Repair).await?;
+    stream.write_data(&request).await?;
+    if stream.read_outcome().await? != 1 {
+        return Err(io::Error::other("leader refused to send the pairs to repair"));
+    }
+    let sequence = stream.read_u64().await?;
+    let mut pairs = HashMap::new();
+    while stream.read_outcome().await? == 1 {
+        let key = String::from_utf8(stream.read_data().await?).map_err(io::Error::other)?;
+        let value = String::from_utf8(stream.read_data().await?).map_err(io::Error::other)?;
+        pairs.insert(key, value);
+    }
+    // The keys that this follower holds in the leaves, which the leader may not.
+    let diverged: HashSet<usize> = diverged.into_iter().collect();
+    let keys = tokio::task::block_in_place(|| {
+        database
+            .engine
+            .entries()?
+            .map(|entry| entry.map(|(key, _)| key))
+            .filter(|key| key.as_ref().map_or(true, |key| diverged.contains(&leaf(key))))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .map_err(io::Error::other)?;
+
+    let _writes = server.writes.lock().await;
+    // The pairs are the leader's state as of `sequence`. Once they are
+    // applied, the records after the last one that was replicated still have
+    // to be, and they leave every key as it ends up by `sequence`. If this
+    // follower has already replicated past it, though, the pairs could undo
+    // newer writes.
+    if server.replicated.load(Ordering::Relaxed) > sequence {
+        return Ok(None);
+    }
+    let mut records = Vec::new();
+    for key in keys {
+        if !pairs.contains_key(&key) && database.engine.exists(&key).map_err(io::Error::other)? {
+            records.push(Record::Delete { key });
+        }
+    }
+    for (key, value) in pairs {
+        if database.engine.get(&key).map_err(io::Error::other)?.as_deref() != Some(&value) {
+            records.push(Record::Set { key, value });
+        }
+    }
+    for record in &records {
+        match record {
+            Record::Set { key, value } => database.engine.set(key, value),
+            Record::Delete { key } => database.engine.delete(key),
+        }
+        .map_err(io::Error::other)?;
+        database.notify_watchers(record);
+    }
+    Ok(Some(records.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tree(pairs: &[(&str, &str)]) -> MerkleTree {
+        let entries = pairs.iter().map(|(key, value)| Ok((key.to_string(), value.to_string())));
+        MerkleTree::build(entries).unwrap()
+    }
+
+    #[test]
+    fn diverged_leaves() {
+        let pairs = [("a", "1"), ("b", "2"), ("c", "3")];
+        let ours = tree(&pairs);
+        assert_eq!(ours, tree(&[("c", "3"), ("a", "1"), ("b", "2")]));
+        assert!(ours.diverged_leaves(&tree(&pairs)).is_empty());
+
+        let changed = tree(&[("a", "1"), ("b", "changed"), ("c", "3")]);
+        assert_eq!(ours.diverged_leaves(&changed), [leaf("b")]);
+        let missing = tree(&[("a", "1"), ("b", "2")]);
+        assert_eq!(ours.diverged_leaves(&missing), [leaf("c")]);
+        // A key and value split in a different place are a different pair.
+        assert_ne!(tree(&[("ab", "c")]), tree(&[("a", "bc")]));
+
+        let bytes = ours.to_bytes();
+        assert_eq!(MerkleTree::from_bytes(&bytes), Some(ours));
+        assert_eq!(MerkleTree::from_bytes(&bytes[1..]), None);
+    }
+
+    #[test]
+    fn parse_leaves() {
+        let leaves = super::parse_leaves(&[0, 0, 0, 1, 0, 0, 3, 255]).unwrap();
+        assert_eq!(leaves, HashSet::from([1, 1023]));
+        assert!(super::parse_leaves(&[0, 0, 4, 0]).is_none());
+        assert!(super::parse_leaves(&[0, 0, 1]).is_none());
+    }
+}
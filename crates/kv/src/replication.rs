@@ -0,0 +1,720 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+use crate::hints::Hints;
+use crate::protocol::{self, Command};
+use crate::Server;
+
+/// How long a follower waits before reconnecting to its leader.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The file in the data directory that a follower saves how far it has
+/// replicated to.
+pub const STATE_FILENAME: &str = "replication-state";
+
+/// The most often that a follower saves how far it has replicated.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of pairs of a snapshot that a leader reads at a time.
+const SNAPSHOT_CHUNK_SIZE: usize = 1024;
+
+/// What a leader sends after accepting a REPLICATE, once it has sent its
+/// epoch: that it is streaming from the sequence that was asked for, or that
+/// it is sending a snapshot of its keyspace first.
+const RESUME: u8 = 1;
+const SNAPSHOT: u8 = 2;
+
+/// How many servers must have a write before it is acknowledged to the client
+/// that made it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AckLevel {
+    /// Once the leader has applied it, which is as soon as possible, but a
+    /// write can be lost if the leader's disk is.
+    #[default]
+    Local,
+
+    /// Once the leader and at least one follower have applied it.
+    LeaderPlusOne,
+
+    /// Once a majority of the leader and its followers have applied it, out of
+    /// the number of followers that the leader is configured with, whether or
+    /// not they are connected.
+    Majority,
+}
+
+impl AckLevel {
+    pub fn parse(level: &str) -> Result<Self, String> {
+        match level.trim().to_lowercase().as_str() {
+            "local" => Ok(Self::Local),
+            "leader+1" => Ok(Self::LeaderPlusOne),
+            "majority" => Ok(Self::Majority),
+            _ => Err(format!("unknown write acknowledgement level {level:?}")),
+        }
+    }
+
+    /// The level that an ACKS argument asks for, where 0 asks for the server's
+    /// own level, which is `None`.
+    pub fn from_indicator(indicator: u8) -> Option<Option<Self>> {
+        match indicator {
+            0 => Some(None),
+            1 => Some(Some(Self::Local)),
+            2 => Some(Some(Self::LeaderPlusOne)),
+            3 => Some(Some(Self::Majority)),
+            _ => None,
+        }
+    }
+
+    /// The number of followers that must acknowledge a write, out of
+    /// `followers`.
+    pub fn required(&self, followers: usize) -> usize {
+        match self {
+            Self::Local => 0,
+            Self::LeaderPlusOne => 1,
+            // The leader is one of the majority of `followers + 1` servers.
+            Self::Majority => followers.div_ceil(2),
+        }
+    }
+}
+
+/// A write that a leader ships to its followers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+impl Record {
+    pub fn indicator(&self) -> u8 {
+        match self {
+            Self::Set { .. } => 1,
+            Self::Delete { .. } => 2,
+        }
+    }
+}
+
+/// The most recent writes applied on a leader, numbered in the order that they
+/// were applied, starting from 1.
+///
+/// Only the last `capacity` records are retained. A follower that falls
+/// further behind than that can't catch up from the log, and is sent a
+/// snapshot of the whole keyspace instead.
+///
+/// Sequences start over whenever the leader does, so the log has a random
+/// epoch to tell them apart. A follower only resumes from a sequence of the
+/// same epoch.
+pub struct ReplicationLog {
+    epoch: u64,
+    records: Mutex<VecDeque<(u64, Record)>>,
+    capacity: usize,
+
+    /// Holds the sequence of the last record appended to the log.
+    last_sequence: watch::Sender<u64>,
+
+    /// Holds the sequence of the last record that each connected follower has
+    /// applied, by an id given to it when it connects.
+    acknowledged: watch::Sender<HashMap<u64, u64>>,
+    next_follower: AtomicU64,
+
+    /// The writes that named followers have missed while disconnected, if
+    /// they are hinted. Hints are written to with `records` held, so that
+    /// they are in step with it.
+    hints: Option<Hints>,
+}
+
+/// The requested records have already been dropped from the log, or were
+/// never in it.
+#[derive(Debug)]
+pub struct Behind;
+
+impl ReplicationLog {
+    pub fn new(capacity: usize, hints: Option<Hints>) -> Self {
+        Self {
+            // 0 is what a follower that has never replicated asks for.
+            epoch: rand::random::<u64>().max(1),
+            records: Mutex::new(VecDeque::new()),
+            capacity,
+            last_sequence: watch::Sender::new(0),
+            acknowledged: watch::Sender::new(HashMap::new()),
+            next_follower: AtomicU64::new(0),
+            hints,
+        }
+    }
+
+    /// Append a `record` that has just been applied to the engine, and return
+    /// its sequence.
+    ///
+    /// Callers must hold the server's `writes` lock, so that records are
+    /// numbered in the order that they were applied.
+    pub fn append(&self, record: Record) -> u64 {
+        let mut records = self.records.lock().expect("replication log lock is poisoned");
+        let sequence = *self.last_sequence.borrow() + 1;
+        if let Some(hints) = &self.hints {
+            hints.append(sequence, &record);
+        }
+        records.push_back((sequence, record));
+        if records.len() > self.capacity {
+            records.pop_front();
+        }
+        self.last_sequence.send_replace(sequence);
+        sequence
+    }
+
+    /// Start hinting the writes that follower `name` misses, from the record at
+    /// sequence `from`, if followers are hinted.
+    fn start_hints(&self, name: &str, from: u64) {
+        let Some(hints) = &self.hints else {
+            return;
+        };
+        let records = self.records.lock().expect("replication log lock is poisoned");
+        match self.records_from(&records, from) {
+            Ok(missed) => hints.start(name, from, &missed),
+            Err(Behind) => log::warn!("follower {name} is too far behind to be hinted"),
+        }
+    }
+
+    /// Stop hinting follower `name`, and return the writes that it missed from
+    /// `from` on, if they were hinted.
+    fn take_hints(&self, name: &str, from: u64) -> Option<Vec<(u64, Record)>> {
+        let _records = self.records.lock().expect("replication log lock is poisoned");
+        self.hints.as_ref()?.take(name, from)
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// The sequence of the last record appended to the log, or 0 if there are
+    /// none.
+    pub fn last_sequence(&self) -> u64 {
+        *self.last_sequence.borrow()
+    }
+
+    /// The number of followers that are connected.
+    pub fn follower_count(&self) -> usize {
+        self.acknowledged.borrow().len()
+    }
+
+    /// Wait until `count` followers have applied the record at `sequence`, or
+    /// `timeout` passes. On a timeout, returns how many had applied it.
+    pub async fn wait_for_acks(
+        &self,
+        sequence: u64,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<(), usize> {
+        let applied = |acknowledged: &HashMap<u64, u64>| {
+            acknowledged.values().filter(|applied| **applied >= sequence).count()
+        };
+        let mut acknowledged = self.acknowledged.subscribe();
+        let waited = tokio::time::timeout(
+            timeout,
+            acknowledged.wait_for(|acknowledged| applied(acknowledged) >= count),
+        )
+        .await;
+        match waited {
+            Ok(_) => Ok(()),
+            Err(_) => Err(applied(&self.acknowledged.borrow())),
+        }
+    }
+
+    /// Return every record with a sequence of at least `from`.
+    fn read_from(&self, from: u64) -> Result<Vec<(u64, Record)>, Behind> {
+        let records = self.records.lock().expect("replication log lock is poisoned");
+        self.records_from(&records, from)
+    }
+
+    fn records_from(
+        &self,
+        records: &VecDeque<(u64, Record)>,
+        from: u64,
+    ) -> Result<Vec<(u64, Record)>, Behind> {
+        let last_sequence = *self.last_sequence.borrow();
+        let oldest = records.front().map_or(last_sequence + 1, |(seq, _)| *seq);
+        // A sequence past the next one must be from before the leader restarted, and
+        // the records in between it and the leader's would be skipped.
+        if (from < oldest && from <= last_sequence) || from > last_sequence + 1 {
+            return Err(Behind);
+        }
+        Ok(records.iter().filter(|(sequence, _)| *sequence >= from).cloned().collect())
+    }
+}
+
+/// A follower that is connected to the leader, which is forgotten once it is
+/// dropped. A named follower is hinted from then on.
+struct Follower<'a> {
+    log: &'a ReplicationLog,
+    id: u64,
+    name: Option<String>,
+}
+
+impl<'a> Follower<'a> {
+    fn connect(log: &'a ReplicationLog, applied: u64, name: Option<String>) -> Self {
+        let id = log.next_follower.fetch_add(1, Ordering::Relaxed);
+        log.acknowledged.send_modify(|acknowledged| {
+            acknowledged.insert(id, applied);
+        });
+        Self { log, id, name }
+    }
+
+    fn acknowledge(&self, sequence: u64) {
+        self.log.acknowledged.send_modify(|acknowledged| {
+            acknowledged.insert(self.id, sequence);
+        });
+    }
+}
+
+impl Drop for Follower<'_> {
+    fn drop(&mut self) {
+        let mut applied = 0;
+        self.log.acknowledged.send_modify(|acknowledged| {
+            applied = acknowledged.remove(&self.id).unwrap_or_default();
+        });
+        if let Some(name) = &self.name {
+            self.log.start_hints(name, applied + 1);
+        }
+    }
+}
+
+/// Stream every record from `from` onwards to the follower `name` on `stream`,
+/// until the connection is closed. The records that a named follower missed
+/// while it was disconnected are sent from its hints, if it was hinted.
+///
+/// `from` is a sequence of the log with the given `epoch`. If it is of another
+/// epoch, or the records from it are no longer retained, the follower is sent
+/// a snapshot of the keyspace first, and the records from there on.
+///
+/// The follower sends back the sequence of each record once it has applied
+/// it, which writes wait for when they need more than the leader to
+/// acknowledge them.
+pub async fn serve_follower(
+    server: &Server,
+    stream: &mut protocol::Stream,
+    epoch: u64,
+    mut from: u64,
+    name: Option<String>,
+) -> Result<(), io::Error> {
+    let log = &server.replication_log;
+    let mut last_sequence = log.last_sequence.subscribe();
+    let same_epoch = epoch == log.epoch;
+    let hinted = name.as_deref().filter(|_| same_epoch).and_then(|name| log.take_hints(name, from));
+    let resume = same_epoch && (hinted.is_some() || log.read_from(from).is_ok());
+    stream.write_success().await?;
+    stream.write_u64(log.epoch).await?;
+    if resume {
+        stream.write_outcome(RESUME).await?;
+    } else {
+        log::info!("follower asked for sequence {from} of epoch {epoch}, sending a snapshot");
+        stream.write_outcome(SNAPSHOT).await?;
+        from = send_snapshot(server, stream).await? + 1;
+    }
+    log::info!("streaming to follower from sequence {from}");
+    let follower = Follower::connect(log, from.saturating_sub(1), name);
+    if let Some(hinted) = hinted {
+        log::info!("replaying {} hinted writes to the follower", hinted.len());
+        for (sequence, record) in hinted {
+            stream.write_u64(sequence).await?;
+            write_record(stream, &record).await?;
+            from = sequence + 1;
+        }
+    }
+    // Acknowledgements are read a piece at a time, since a read that is cut off
+    // by a new record mustn't lose what it has read.
+    let mut acknowledgement = [0; 8];
+    let mut filled = 0;
+    loop {
+        // Marking the current value as seen before reading means that a record
+        // appended in between will still wake the loop.
+        last_sequence.borrow_and_update();
+        let Ok(records) = log.read_from(from) else {
+            log::warn!("follower fell behind the replication log, disconnecting it");
+            return Ok(());
+        };
+        for (sequence, record) in records {
+            stream.write_u64(sequence).await?;
+            write_record(stream, &record).await?;
+            from = sequence + 1;
+        }
+        loop {
+            tokio::select! {
+                changed = last_sequence.changed() => match changed {
+                    Ok(()) => break,
+                    Err(_) => return Ok(()),
+                },
+                read = stream.0.read(&mut acknowledgement[filled..]) => match read? {
+                    0 => return Ok(()),
+                    read => filled += read,
+                },
+            }
+            if filled == acknowledgement.len() {
+                follower.acknowledge(u64::from_be_bytes(acknowledgement));
+                filled = 0;
+            }
+        }
+    }
+}
+
+/// Send the follower on `stream` every pair in the keyspace, each preceded by a
+/// 1 and ending with a 0, after the sequence of the last write that they hold.
+/// Returns that sequence.
+async fn send_snapshot(server: &Server, stream: &mut protocol::Stream) -> Result<u64, io::Error> {
+    let (sequence, mut entries) = {
+        let _writes = server.writes.lock().await;
+        let entries = server.databases[0].engine.entries().map_err(io::Error::other)?;
+        (server.replication_log.last_sequence(), entries)
+    };
+    stream.write_u64(sequence).await?;
+    let mut count = 0;
+    loop {
+        // Reading the pairs would hold up the other connections on this thread.
+        let pairs = tokio::task::block_in_place(|| {
+            entries.by_ref().take(SNAPSHOT_CHUNK_SIZE).collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(io::Error::other)?;
+        if pairs.is_empty() {
+            break;
+        }
+        count += pairs.len();
+        for (key, value) in pairs {
+            stream.write_outcome(1).await?;
+            stream.write_data(key.as_bytes()).await?;
+            stream.write_data(value.as_bytes()).await?;
+        }
+    }
+    stream.write_outcome(0).await?;
+    log::info!("sent a snapshot of {count} pairs as of sequence {sequence}");
+    Ok(sequence)
+}
+
+/// Write `record` to `stream`: its indicator, then its key, and then its value
+/// if it is a set.
+pub async fn write_record(stream: &mut protocol::Stream, record: &Record) -> Result<(), io::Error> {
+    stream.write_outcome(record.indicator()).await?;
+    match record {
+        Record::Set { key, value } => {
+            stream.write_data(key.as_bytes()).await?;
+            stream.write_data(value.as_bytes()).await
+        },
+        Record::Delete { key } => stream.write_data(key.as_bytes()).await,
+    }
+}
+
+/// How far a follower has replicated from its leader, which it keeps on disk
+/// to pick up from when it restarts.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FollowerState {
+    /// The epoch of the leader's log, or 0 before the follower has replicated
+    /// anything.
+    pub epoch: u64,
+
+    /// The sequence of the next record to apply.
+    pub next: u64,
+}
+
+impl FollowerState {
+    /// Read the state saved at `path`, or the state of a follower that has
+    /// never replicated if there is none.
+    pub fn load(path: &Path) -> Result<Self, io::Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self { epoch: 0, next: 1 });
+            },
+            Err(error) => return Err(error),
+        };
+        let invalid = || io::Error::other(format!("invalid replication state in {path:?}"));
+        let (epoch, next) = contents.trim().split_once(' ').ok_or_else(invalid)?;
+        Ok(Self {
+            epoch: epoch.parse().map_err(|_| invalid())?,
+            next: next.parse().map_err(|_| invalid())?,
+        })
+    }
+
+    /// Atomically replace the state saved at `path` with this one.
+    ///
+    /// Records are applied before the state that follows them is saved, so
+    /// after a crash the follower can only apply some records again, never
+    /// skip them. Applying them again leaves every key as it was.
+    pub fn save(&self, path: &Path) -> Result<(), io::Error> {
+        let temp_path = path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        writeln!(file, "{} {}", self.epoch, self.next)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+/// Follow the leader at `address`, applying every write it makes to this
+/// server's engine, and saving how far it got at `state_path`. This never
+/// returns; on any error, it reconnects and picks up after the last record
+/// that was applied.
+pub async fn follow(server: Arc<Server>, address: String, state_path: PathBuf) {
+    let mut state = FollowerState::load(&state_path).expect("failed to load replication state");
+    server.replicated.store(state.next - 1, Ordering::Relaxed);
+    loop {
+        match follow_once(&server, &address, &mut state, &state_path).await {
+            Ok(()) => log::warn!("leader at {address} closed the replication stream"),
+            Err(error) => log::warn!("replication from {address} failed: {error}"),
+        }
+        if let Err(error) = state.save(&state_path) {
+            log::error!("failed to save replication state to {state_path:?}: {error}");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connect to the leader at `address`, authenticating with this server's
+/// password if it has one.
+pub async fn connect(server: &Server, address: &str) -> Result<protocol::Stream, io::Error> {
+    let mut stream = protocol::Stream(TcpStream::connect(address).await?);
+    if let Some(password) = &server.password {
+        stream.write_command(Command::Auth).await?;
+        stream.write_data(password.as_bytes()).await?;
+        if stream.read_outcome().await? != 1 {
+            return Err(io::Error::other("leader rejected the password"));
+        }
+    }
+    Ok(stream)
+}
+
+async fn follow_once(
+    server: &Server,
+    address: &str,
+    state: &mut FollowerState,
+    state_path: &Path,
+) -> Result<(), io::Error> {
+    let mut stream = connect(server, address).await?;
+    stream.write_command(Command::Replicate).await?;
+    // The epoch and sequence to start from are followed by the follower's name,
+    // if it has one.
+    let mut request = state.epoch.to_be_bytes().to_vec();
+    request.extend(state.next.to_be_bytes());
+    request.extend(server.follower_name.as_deref().unwrap_or_default().as_bytes());
+    stream.write_data(&request).await?;
+    if stream.read_outcome().await? != 1 {
+        return Err(io::Error::other("leader refused to be replicated"));
+    }
+    let epoch = stream.read_u64().await?;
+    match stream.read_outcome().await? {
+        RESUME => {},
+        SNAPSHOT => {
+            log::info!("resyncing from a snapshot of the leader at {address}");
+            let sequence = apply_snapshot(server, &mut stream).await?;
+            server.replicated.store(sequence, Ordering::Relaxed);
+            *state = FollowerState { epoch, next: sequence + 1 };
+            state.save(state_path)?;
+        },
+        outcome => return Err(io::Error::other(format!("invalid replication outcome {outcome}"))),
+    }
+    log::info!("replicating from {address}, starting at sequence {}", state.next);
+    let mut saved_at = Instant::now();
+    loop {
+        let sequence = stream.read_u64().await?;
+        let indicator = stream.read_outcome().await?;
+        let key = String::from_utf8(stream.read_data().await?).map_err(io::Error::other)?;
+        let record = match indicator {
+            1 => {
+                let value =
+                    String::from_utf8(stream.read_data().await?).map_err(io::Error::other)?;
+                Record::Set { key, value }
+            },
+            2 => Record::Delete { key },
+            _ => return Err(io::Error::other(format!("invalid record indicator {indicator}"))),
+        };
+        log::trace!("replicating {record:?} @ {sequence}");
+        apply(server, &record).await?;
+        server.replicated.store(sequence, Ordering::Relaxed);
+        state.next = sequence + 1;
+        if saved_at.elapsed() >= STATE_SAVE_INTERVAL {
+            state.save(state_path)?;
+            saved_at = Instant::now();
+        }
+        stream.write_u64(sequence).await?;
+    }
+}
+
+/// Apply a write from the leader to this server's engine.
+async fn apply(server: &Server, record: &Record) -> Result<(), io::Error> {
+    let _writes = server.writes.lock().await;
+    // A follower only has the one database.
+    let database = &server.databases[0];
+    match record {
+        Record::Set { key, value } => database.engine.set(key, value),
+        Record::Delete { key } => database.engine.delete(key),
+    }
+    .map_err(io::Error::other)?;
+    database.notify_watchers(record);
+    Ok(())
+}
+
+/// Make this server's keyspace match the leader's snapshot on `stream`, from
+/// [`send_snapshot`], and return the sequence that the snapshot is as of.
+///
+/// Both sides are in key order, so they are merged, and only the keys that
+/// differ are written: those missing from the snapshot are deleted, and
+/// those whose values differ are set.
+async fn apply_snapshot(server: &Server, stream: &mut protocol::Stream) -> Result<u64, io::Error> {
+    let sequence = stream.read_u64().await?;
+    let mut ours = server.databases[0].engine.entries().map_err(io::Error::other)?;
+    let mut next_ours =
+        || tokio::task::block_in_place(|| ours.next().transpose()).map_err(io::Error::other);
+    let mut our_pair = next_ours()?;
+    let mut changed = 0;
+    loop {
+        let theirs = match stream.read_outcome().await? {
+            0 => None,
+            1 => {
+                let key = String::from_utf8(stream.read_data().await?).map_err(io::Error::other)?;
+                let value =
+                    String::from_utf8(stream.read_data().await?).map_err(io::Error::other)?;
+                Some((key, value))
+            },
+            outcome => return Err(io::Error::other(format!("invalid snapshot outcome {outcome}"))),
+        };
+        let before_theirs = |key: &String| theirs.as_ref().is_none_or(|(theirs, _)| key < theirs);
+        while let Some((key, _)) = our_pair.take_if(|(key, _)| before_theirs(key)) {
+            apply(server, &Record::Delete { key }).await?;
+            changed += 1;
+            our_pair = next_ours()?;
+        }
+        let Some((key, value)) = theirs else {
+            break;
+        };
+        if our_pair.as_ref().is_some_and(|(ours, _)| *ours == key) {
+            let (_, our_value) = our_pair.take().expect("our pair is set");
+            our_pair = next_ours()?;
+            if our_value == value {
+                continue;
+            }
+        }
+        apply(server, &Record::Set { key, value }).await?;
+        changed += 1;
+    }
+    log::info!("resynced from a snapshot as of sequence {sequence}, changing {changed} keys");
+    Ok(sequence)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{eventually, TestServer};
+
+    fn set(key: &str, value: &str) -> Record {
+        Record::Set { key: key.to_owned(), value: value.to_owned() }
+    }
+
+    fn delete(key: &str) -> Record {
+        Record::Delete { key: key.to_owned() }
+    }
+
+    #[test]
+    fn required_acks() {
+        assert_eq!(AckLevel::parse("Leader+1"), Ok(AckLevel::LeaderPlusOne));
+        assert!(AckLevel::parse("all").is_err());
+        assert_eq!(AckLevel::Local.required(2), 0);
+        assert_eq!(AckLevel::LeaderPlusOne.required(4), 1);
+        let majority = |followers| AckLevel::Majority.required(followers);
+        assert_eq!([majority(0), majority(1), majority(2), majority(4)], [0, 1, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn wait_for_acks() {
+        let log = ReplicationLog::new(10, None);
+        let timeout = Duration::from_millis(50);
+        let sequence = log.append(delete("a"));
+        assert_eq!(log.wait_for_acks(sequence, 1, timeout).await, Err(0));
+
+        let first = Follower::connect(&log, 0, None);
+        let second = Follower::connect(&log, 0, None);
+        assert_eq!(log.follower_count(), 2);
+        first.acknowledge(sequence);
+        assert_eq!(log.wait_for_acks(sequence, 1, timeout).await, Ok(()));
+        assert_eq!(log.wait_for_acks(sequence, 2, timeout).await, Err(1));
+
+        // An acknowledgement that arrives while a write waits wakes it.
+        let waiting = log.wait_for_acks(sequence, 2, Duration::from_secs(5));
+        let (waited, ()) = tokio::join!(waiting, async { second.acknowledge(sequence) });
+        assert_eq!(waited, Ok(()));
+        drop(first);
+        assert_eq!(log.wait_for_acks(sequence, 2, timeout).await, Err(1));
+    }
+
+    #[test]
+    fn read_from() {
+        let log = ReplicationLog::new(2, None);
+        assert_eq!(log.read_from(1).unwrap().len(), 0);
+        log.append(delete("a"));
+        log.append(delete("b"));
+        log.append(delete("c"));
+
+        let sequences = |records: Vec<(u64, Record)>| -> Vec<u64> {
+            records.into_iter().map(|(sequence, _)| sequence).collect()
+        };
+        assert!(log.read_from(1).is_err());
+        assert_eq!(sequences(log.read_from(2).unwrap()), [2, 3]);
+        assert_eq!(sequences(log.read_from(4).unwrap()), Vec::<u64>::new());
+        // A sequence past the next one is from an earlier run of the leader.
+        assert!(log.read_from(5).is_err());
+    }
+
+    #[test]
+    fn follower_state() {
+        let path = std::env::temp_dir().join("crunch-kv-test-replication-state");
+        _ = fs::remove_file(&path);
+        assert_eq!(FollowerState::load(&path).unwrap(), FollowerState { epoch: 0, next: 1 });
+        let state = FollowerState { epoch: 7, next: 42 };
+        state.save(&path).unwrap();
+        assert_eq!(FollowerState::load(&path).unwrap(), state);
+        fs::write(&path, "7").unwrap();
+        assert!(FollowerState::load(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resync_from_snapshot() {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let leader = TestServer::start("replication-leader", |_| {}).await;
+            leader.write(set("a", "1")).await;
+            leader.write(set("b", "2")).await;
+            // The follower has writes from an earlier leader, which the snapshot
+            // replaces.
+            let mut follower = TestServer::start("replication-follower", |server| {
+                server.follower = true;
+            })
+            .await;
+            let engine = &follower.server.databases[0].engine;
+            engine.set("b", "old").unwrap();
+            engine.set("z", "stale").unwrap();
+            FollowerState { epoch: 1, next: 3 }
+                .save(&follower.path().join(STATE_FILENAME))
+                .unwrap();
+            follower.spawn(follow(
+                follower.server.clone(),
+                leader.address.to_string(),
+                follower.path().join(STATE_FILENAME),
+            ));
+
+            // Writes made after the snapshot are streamed.
+            leader.write(set("c", "3")).await;
+            let engine = &follower.server.databases[0].engine;
+            eventually(|| engine.get("c").unwrap().as_deref() == Some("3")).await;
+            assert_eq!(engine.get("a").unwrap().as_deref(), Some("1"));
+            assert_eq!(engine.get("b").unwrap().as_deref(), Some("2"));
+            assert_eq!(engine.get("z").unwrap(), None);
+            let state = FollowerState::load(&follower.path().join(STATE_FILENAME)).unwrap();
+            assert_eq!(state.epoch, leader.server.replication_log.epoch());
+            follower.stop().await;
+            leader.stop().await;
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use std::cell::{Cell, RefCell};
+
+use crunch_engine::engine::Engine;
+use crunch_engine::error::Error as EngineError;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Value, VmState};
+
+use crate::replication::Record;
+
+/// The most Lua instructions that a script can run before it is stopped, so
+/// that one stuck in a loop can't hold up every other write for good.
+pub const MAX_SCRIPT_INSTRUCTIONS: u64 = 10_000_000;
+
+/// How often, in instructions, a running script is checked against the
+/// limit.
+const HOOK_INTERVAL: u32 = 1000;
+
+/// The functions of the base library that are taken away from scripts, since
+/// they load code from files on the server or from strings, or control the
+/// garbage collector.
+const REMOVED_GLOBALS: [&str; 5] = ["dofile", "loadfile", "load", "require", "collectgarbage"];
+
+/// Why a script failed.
+#[derive(Debug)]
+pub enum ScriptError {
+    /// The engine failed to carry out one of the script's reads or writes.
+    Engine(EngineError),
+
+    /// The script didn't compile, raised an error, returned something that
+    /// can't be sent back, or ran for too long.
+    Lua(String),
+}
+
+/// Decode the arguments of an EVAL command, which holds each argument for
+/// the script as length prefixed data. Returns `None` if it is malformed.
+pub fn parse_args(mut data: &[u8]) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    while !data.is_empty() {
+        let (size, rest) = data.split_first_chunk::<4>()?;
+        let size = u32::from_be_bytes(*size) as usize;
+        args.push(std::str::from_utf8(rest.get(..size)?).ok()?.to_owned());
+        data = &rest[size..];
+    }
+    Some(args)
+}
+
+/// Run the Lua `script` against `engine`, returning the writes that it made,
+/// in order, along with its result.
+///
+/// The script sees its arguments in the `ARGV` table, and reads and writes
+/// through the `crunch.get`, `crunch.exists`, `crunch.set` and
+/// `crunch.delete` functions. Beyond the base library, only the `string`,
+/// `table` and `math` libraries are loaded, and the base functions that load
+/// code, from files or otherwise, are removed, so it can't touch the
+/// filesystem. Its result is
+/// `nil` for no value, or a string, number or boolean, which is sent back as
+/// text. A script that fails keeps the writes that it made before failing,
+/// and one that hits an engine error fails with it, even if it catches it.
+///
+/// The script fails if it allocates more than `memory_limit` bytes.
+///
+/// Callers hold the server's `writes` lock for the whole script, so no other
+/// write comes between its reads and writes. Reads from other connections
+/// aren't held up, though, so they can see some of its writes before the
+/// rest. A script can run for a while, so callers run it on a blocking
+/// thread.
+pub fn eval(
+    engine: &Engine,
+    script: &str,
+    args: Vec<String>,
+    memory_limit: usize,
+) -> (Vec<Record>, Result<Option<String>, ScriptError>) {
+    let writes = RefCell::new(Vec::new());
+    let engine_error = RefCell::new(None);
+    let result = run(engine, script, args, memory_limit, &writes, &engine_error);
+    let result = match (result, engine_error.into_inner()) {
+        (_, Some(error)) => Err(ScriptError::Engine(error)),
+        (Ok(value), None) => Ok(value),
+        (Err(error), None) => Err(ScriptError::Lua(error.to_string())),
+    };
+    (writes.into_inner(), result)
+}
+
+fn run(
+    engine: &Engine,
+    script: &str,
+    args: Vec<String>,
+    memory_limit: usize,
+    writes: &RefCell<Vec<Record>>,
+    engine_error: &RefCell<Option<EngineError>>,
+) -> mlua::Result<Option<String>> {
+    let lua = Lua::new_with(StdLib::STRING | StdLib::TABLE | StdLib::MATH, LuaOptions::default())?;
+    lua.set_memory_limit(memory_limit)?;
+    let executed = Cell::new(0);
+    lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |_, _| {
+        executed.set(executed.get() + HOOK_INTERVAL as u64);
+        if executed.get() > MAX_SCRIPT_INSTRUCTIONS {
+            return Err(mlua::Error::runtime(format!(
+                "script ran for more than {MAX_SCRIPT_INSTRUCTIONS} instructions"
+            )));
+        }
+        Ok(VmState::Continue)
+    });
+    // The error is kept, to report as it is, and the script is given its message.
+    let fail = |error: EngineError| {
+        let message = error.to_string();
+        engine_error.borrow_mut().get_or_insert(error);
+        mlua::Error::runtime(message)
+    };
+    lua.scope(|scope| {
+        let crunch = lua.create_table()?;
+        crunch.set(
+            "get",
+            scope.create_function(|_, key: String| {
+                let value = engine.get(&key).map_err(fail)?;
+                Ok(value.map(|value| value.to_string()))
+            })?,
+        )?;
+        crunch.set(
+            "exists",
+            scope.create_function(|_, key: String| engine.exists(&key).map_err(fail))?,
+        )?;
+        crunch.set(
+            "set",
+            scope.create_function(|_, (key, value): (String, String)| {
+                engine.set(&key, &value).map_err(fail)?;
+                writes.borrow_mut().push(Record::Set { key, value });
+                Ok(())
+            })?,
+        )?;
+        crunch.set(
+            "delete",
+            scope.create_function(|_, key: String| {
+                engine.delete(&key).map_err(fail)?;
+                writes.borrow_mut().push(Record::Delete { key });
+                Ok(())
+            })?,
+        )?;
+        for name in REMOVED_GLOBALS {
+            lua.globals().set(name, Value::Nil)?;
+        }
+        lua.globals().set("crunch", crunch)?;
+        lua.globals().set("ARGV", args)?;
+        let value: Value = lua.load(script).set_name("script").eval()?;
+        match value {
+            Value::Nil => Ok(None),
+            Value::Boolean(value) => Ok(Some(value.to_string())),
+            Value::Integer(_) | Value::Number(_) | Value::String(_) => {
+                Ok(lua.coerce_string(value)?.map(|value| value.to_string_lossy()))
+            },
+            value => Err(mlua::Error::runtime(format!(
+                "scripts can only return strings, numbers, booleans or nil, not a {}",
+                value.type_name()
+            ))),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    const MEMORY_LIMIT: usize = 1024 * 1024;
+
+    fn engine(name: &str) -> (Engine, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("crunch-kv-test-scripting-{name}"));
+        _ = fs::remove_dir_all(&path);
+        (Engine::new(path.clone()).unwrap(), path)
+    }
+
+    #[test]
+    fn parse_args() {
+        let data = [0, 0, 0, 1, b'a', 0, 0, 0, 0];
+        assert_eq!(super::parse_args(&data), Some(vec!["a".to_owned(), String::new()]));
+        assert_eq!(super::parse_args(&data[..data.len() - 1]), None);
+        assert_eq!(super::parse_args(&[]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn eval() {
+        let (engine, path) = engine("eval");
+        engine.set("counter", "41").unwrap();
+        let script = r#"
+            local value = tonumber(crunch.get(ARGV[1])) + 1
+            crunch.set(ARGV[1], tostring(value))
+            crunch.delete("other")
+            return value
+        "#;
+        let (writes, result) =
+            super::eval(&engine, script, vec!["counter".to_owned()], MEMORY_LIMIT);
+        assert_eq!(result.unwrap().as_deref(), Some("42"));
+        assert_eq!(writes, [
+            Record::Set { key: "counter".to_owned(), value: "42".to_owned() },
+            Record::Delete { key: "other".to_owned() },
+        ]);
+        assert_eq!(engine.get("counter").unwrap().as_deref(), Some("42"));
+
+        let (_, result) =
+            super::eval(&engine, "return crunch.exists('missing')", Vec::new(), MEMORY_LIMIT);
+        assert_eq!(result.unwrap().as_deref(), Some("false"));
+        let (_, result) =
+            super::eval(&engine, "return crunch.get('missing')", Vec::new(), MEMORY_LIMIT);
+        assert_eq!(result.unwrap(), None);
+        engine.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn failures() {
+        let (engine, path) = engine("failures");
+        // Writes made before the error are kept.
+        let (writes, result) =
+            super::eval(&engine, "crunch.set('a', '1') error('oops')", Vec::new(), MEMORY_LIMIT);
+        assert!(matches!(result, Err(ScriptError::Lua(message)) if message.contains("oops")));
+        assert_eq!(writes.len(), 1);
+        assert_eq!(engine.get("a").unwrap().as_deref(), Some("1"));
+
+        let (_, result) = super::eval(&engine, "while true do end", Vec::new(), MEMORY_LIMIT);
+        assert!(
+            matches!(result, Err(ScriptError::Lua(message)) if message.contains("instructions"))
+        );
+        let (_, result) = super::eval(&engine, "return {}", Vec::new(), MEMORY_LIMIT);
+        assert!(matches!(result, Err(ScriptError::Lua(message)) if message.contains("table")));
+        let (_, result) = super::eval(&engine, "return os.time()", Vec::new(), MEMORY_LIMIT);
+        assert!(result.is_err());
+        let (_, result) =
+            super::eval(&engine, "return loadfile('/etc/passwd')", Vec::new(), MEMORY_LIMIT);
+        assert!(matches!(result, Err(ScriptError::Lua(message)) if message.contains("nil value")));
+        let (_, result) = super::eval(&engine, "return (", Vec::new(), MEMORY_LIMIT);
+        assert!(result.is_err());
+        let script = "return #string.rep('x', 4 * 1024 * 1024)";
+        let (_, result) = super::eval(&engine, script, Vec::new(), MEMORY_LIMIT);
+        assert!(matches!(result, Err(ScriptError::Lua(message)) if message.contains("memory")));
+        engine.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn globals_that_load_code_are_removed() {
+        let (engine, path) = engine("removed-globals");
+        for name in REMOVED_GLOBALS {
+            let script = format!("return {name} == nil");
+            let (_, result) = super::eval(&engine, &script, Vec::new(), MEMORY_LIMIT);
+            assert_eq!(result.unwrap().as_deref(), Some("true"), "{name} is still set");
+        }
+        engine.stop().unwrap();
+        fs::remove_dir_all(path).unwrap();
+    }
+}
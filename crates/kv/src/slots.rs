@@ -0,0 +1,147 @@
+use crunch_common::slot::{key_slot, SLOT_COUNT};
+
+use crate::protocol::{self, Command};
+use crate::watch;
+
+/// A run of slots, from `start` to `end` inclusive, that are owned by the node
+/// listening at `address`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SlotRange {
+    pub start: u16,
+    pub end: u16,
+    pub address: String,
+}
+
+/// The slots of a slot cluster, and which of them this server owns.
+///
+/// In a slot cluster, each key belongs to one of [`SLOT_COUNT`] slots, and
+/// each slot to one node. A node only serves the keys of its own slots, and
+/// redirects commands for any other key to the node that owns it. Scans and
+/// keyspace events only cover the node's own keys.
+///
+/// The slots are assigned when the servers start, and every node must be given
+/// the same assignment.
+#[derive(Debug)]
+pub struct SlotMap {
+    /// Every slot, in order.
+    ranges: Vec<SlotRange>,
+
+    /// This server's address, as it appears in `ranges`.
+    address: String,
+}
+
+impl SlotMap {
+    /// Parse an assignment of slots in the form
+    /// `0-8191=host:port,8192-16383=host:port`, for the server listening at
+    /// `address`. Every slot must be assigned to exactly one node, and at least
+    /// one of them to this server.
+    pub fn parse(map: &str, address: &str) -> Result<Self, String> {
+        let mut ranges = map
+            .split(',')
+            .map(|range| {
+                let (slots, node) =
+                    range.split_once('=').ok_or_else(|| format!("invalid slot range {range:?}"))?;
+                let (start, end) = slots.split_once('-').unwrap_or((slots, slots));
+                let parse = |slot: &str| {
+                    slot.trim()
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|slot| *slot < SLOT_COUNT)
+                        .ok_or_else(|| format!("invalid slot {slot:?}"))
+                };
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!("slot range {range:?} ends before it starts"));
+                }
+                Ok(SlotRange { start, end, address: node.trim().to_owned() })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        ranges.sort_by_key(|range| range.start);
+        let mut next = 0u32;
+        for range in &ranges {
+            if range.start as u32 != next {
+                return Err(format!("slot {next} is assigned to no node, or to more than one"));
+            }
+            next = range.end as u32 + 1;
+        }
+        if next != SLOT_COUNT as u32 {
+            return Err(format!("slot {next} is assigned to no node"));
+        }
+        if !ranges.iter().any(|range| range.address == address) {
+            return Err(format!("this server's address ({address}) owns no slots"));
+        }
+        Ok(Self { ranges, address: address.to_owned() })
+    }
+
+    pub fn ranges(&self) -> &[SlotRange] {
+        &self.ranges
+    }
+
+    /// The address of the node that owns `slot`.
+    pub fn owner(&self, slot: u16) -> &str {
+        let index = self.ranges.partition_point(|range| range.end < slot);
+        &self.ranges[index].address
+    }
+
+    /// The slot and owner of the first key that `command` names which another
+    /// node owns, if any, so that the command can be redirected to it. Like
+    /// [`Permissions::allow`](crate::acl::Permissions::allow), arguments that
+    /// can't be parsed are left for the command to reject.
+    pub fn misplaced_key(
+        &self,
+        command: Command,
+        text: &[&str],
+        args: &[Vec<u8>],
+    ) -> Option<(u16, &str)> {
+        let misplaced = |key: &str| {
+            let slot = key_slot(key.as_bytes());
+            let owner = self.owner(slot);
+            (owner != self.address).then_some((slot, owner))
+        };
+        match command {
+            Command::Get
+            | Command::Exists
+            | Command::Set
+            | Command::Delete
+            | Command::GetSet
+            | Command::Append
+            | Command::SetNx
+            | Command::GetDel => misplaced(text[0]),
+            Command::Batch => protocol::parse_batch(&args[0])?
+                .entries()
+                .iter()
+                .find_map(|entry| misplaced(entry.key())),
+            Command::Watch => watch::parse_keys(&args[0])?.iter().find_map(|key| misplaced(key)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slot_map() {
+        let map = SlotMap::parse("8192-16383=b:2, 0-8191=a:1", "a:1").unwrap();
+        assert_eq!(map.ranges()[0], SlotRange { start: 0, end: 8191, address: "a:1".into() });
+        assert_eq!((map.owner(0), map.owner(8191), map.owner(8192)), ("a:1", "a:1", "b:2"));
+        assert_eq!(map.owner(SLOT_COUNT - 1), "b:2");
+
+        // "foo" is in slot 12182, and "bar" in 5061.
+        let set = |key: &'static str| {
+            map.misplaced_key(Command::Set, &[key], &[key.as_bytes().to_vec(), b"1".to_vec()])
+        };
+        assert_eq!(set("foo"), Some((12182, "b:2")));
+        assert_eq!(set("bar"), None);
+        assert_eq!(map.misplaced_key(Command::Ping, &[], &[]), None);
+
+        assert!(SlotMap::parse("0-16383=a:1", "a:1").is_ok());
+        assert!(SlotMap::parse("0-16383=a:1", "b:2").is_err());
+        assert!(SlotMap::parse("0-8191=a:1", "a:1").is_err());
+        assert!(SlotMap::parse("0-8191=a:1,8000-16383=b:2", "a:1").is_err());
+        assert!(SlotMap::parse("0-16384=a:1", "a:1").is_err());
+        assert!(SlotMap::parse("5-0=a:1", "a:1").is_err());
+        assert!(SlotMap::parse("0-16383", "a:1").is_err());
+    }
+}
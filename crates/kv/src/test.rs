@@ -0,0 +1,376 @@
+use std::fs;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crunch_common::config::Config;
+use crunch_engine::metrics::Histogram;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::acl::Users;
+use crate::cluster::{Cluster, ClusterArgs};
+use crate::protocol::{self, Command, Status};
+use crate::replication::{AckLevel, Record, ReplicationLog};
+use crate::{handle_client, Database, Server, LATENCY_BOUNDS};
+
+/// A server with a fresh data directory, listening for connections on a
+/// loopback port.
+pub struct TestServer {
+    pub server: Arc<Server>,
+    pub address: SocketAddr,
+    path: PathBuf,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start a server with its data directory at `name` in the temporary
+    /// directory, once `configure` has changed whatever the test needs from
+    /// the defaults.
+    pub async fn start(name: &str, configure: impl FnOnce(&mut Server)) -> Self {
+        Self::start_with(name, Config::default(), configure).await
+    }
+
+    /// Like [`Self::start`], with its engine opened with the settings in
+    /// `config`.
+    pub async fn start_with(
+        name: &str,
+        config: Config,
+        configure: impl FnOnce(&mut Server),
+    ) -> Self {
+        let path = std::env::temp_dir().join(format!("crunch-kv-test-{name}"));
+        _ = fs::remove_dir_all(&path);
+        let mut server = Server {
+            databases: vec![Database::open(path.clone(), &config).unwrap()],
+            writes: Mutex::new(()),
+            password: None,
+            users: Users::default(),
+            replication_log: ReplicationLog::new(100, None),
+            follower: false,
+            write_acks: AckLevel::Local,
+            followers: 0,
+            write_ack_timeout: Duration::from_secs(1),
+            replicated: AtomicU64::new(0),
+            follower_name: None,
+            cluster: None,
+            started: Instant::now(),
+            slots: None,
+            command_counts: Default::default(),
+            command_latencies: (0..Command::COUNT)
+                .map(|_| Histogram::new(LATENCY_BOUNDS))
+                .collect(),
+            script_memory_limit: 64 * 1024 * 1024,
+            config,
+        };
+        configure(&mut server);
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepting = {
+            let server = server.clone();
+            tokio::task::spawn(async move {
+                let mut connections = tokio::task::JoinSet::new();
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    connections.spawn(handle_client(server.clone(), stream));
+                }
+            })
+        };
+        Self { server, address, path, tasks: vec![accepting] }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn connect(&self) -> protocol::Stream {
+        protocol::Stream(TcpStream::connect(self.address).await.unwrap())
+    }
+
+    /// Run `task` alongside the server until it stops.
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + Send + 'static) {
+        self.tasks.push(tokio::task::spawn(task));
+    }
+
+    /// Apply `record` as a client's write would be, so that followers are sent
+    /// it.
+    pub async fn write(&self, record: Record) {
+        let _writes = self.server.writes.lock().await;
+        let database = &self.server.databases[0];
+        match &record {
+            Record::Set { key, value } => database.engine.set(key, value),
+            Record::Delete { key } => database.engine.delete(key),
+        }
+        .unwrap();
+        database.notify_watchers(&record);
+        self.server.replication_log.append(record);
+    }
+
+    /// Stop the server's tasks and connections, and then its engines, and
+    /// remove its data directory.
+    pub async fn stop(self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        for task in self.tasks {
+            _ = task.await;
+        }
+        // Aborted connections let go of the server once they are dropped.
+        let mut server = self.server;
+        let server = loop {
+            match Arc::try_unwrap(server) {
+                Ok(server) => break server,
+                Err(shared) => server = shared,
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+        for database in server.databases {
+            database.engine.stop().unwrap();
+        }
+        fs::remove_dir_all(self.path).unwrap();
+    }
+}
+
+/// Wait until `condition` holds, checking it every few milliseconds.
+pub async fn eventually(mut condition: impl FnMut() -> bool) {
+    while !condition() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+/// Send `command` with `args` on `stream`, and return the status that the
+/// server answers with.
+pub async fn request(stream: &mut protocol::Stream, command: Command, args: &[&[u8]]) -> u8 {
+    stream.write_command(command).await.unwrap();
+    for arg in args {
+        stream.write_data(arg).await.unwrap();
+    }
+    stream.read_outcome().await.unwrap()
+}
+
+#[tokio::test]
+async fn authentication() {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut config = Config::default();
+        config.set_override("kv.users.reader.password=letmein").unwrap();
+        config.set_override("kv.users.reader.read=*").unwrap();
+        let server = TestServer::start("authentication", |server| {
+            server.password = Some("secret".to_owned());
+            server.users = Users::from_config(&config).unwrap();
+        })
+        .await;
+        let mut stream = server.connect().await;
+        // PING is answered before authenticating, and other commands are refused
+        // with their arguments read, so the connection stays in step.
+        assert_eq!(request(&mut stream, Command::Ping, &[]).await, Status::Ok as u8);
+        let set: &[&[u8]] = &[b"a", b"1"];
+        assert_eq!(request(&mut stream, Command::Set, set).await, Status::AuthRequired as u8);
+        assert_eq!(request(&mut stream, Command::Get, &[b"a"]).await, Status::AuthRequired as u8);
+        assert_eq!(request(&mut stream, Command::Ping, &[]).await, Status::Ok as u8);
+
+        assert_eq!(request(&mut stream, Command::Auth, &[b"secreT"]).await, Status::Failed as u8);
+        assert_eq!(request(&mut stream, Command::Auth, &[b"secrets"]).await, Status::Failed as u8);
+        let wrong: &[&[u8]] = &[b"reader", b"secret"];
+        assert_eq!(request(&mut stream, Command::AuthUser, wrong).await, Status::Failed as u8);
+        assert_eq!(request(&mut stream, Command::Set, set).await, Status::AuthRequired as u8);
+
+        assert_eq!(request(&mut stream, Command::Auth, &[b"secret"]).await, Status::Ok as u8);
+        assert_eq!(request(&mut stream, Command::Set, set).await, Status::Ok as u8);
+        assert_eq!(request(&mut stream, Command::Get, &[b"a"]).await, Status::Ok as u8);
+        assert_eq!(stream.read_data().await.unwrap(), b"1");
+
+        // A user is kept to their own permissions.
+        let mut stream = server.connect().await;
+        let user: &[&[u8]] = &[b"reader", b"letmein"];
+        assert_eq!(request(&mut stream, Command::AuthUser, user).await, Status::Ok as u8);
+        assert_eq!(request(&mut stream, Command::Get, &[b"a"]).await, Status::Ok as u8);
+        assert_eq!(stream.read_data().await.unwrap(), b"1");
+        assert_eq!(request(&mut stream, Command::Set, set).await, Status::Forbidden as u8);
+        stream.read_data().await.unwrap();
+        server.stop().await;
+    })
+    .await
+    .unwrap();
+}
+
+/// Every status that the server answers with, as the client reports it.
+#[tokio::test(flavor = "multi_thread")]
+async fn statuses() {
+    use crunch_client::{AckLevel, Client, Error};
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut config = Config::default();
+        config.set_override("engine.max_key_size=16").unwrap();
+        config.set_override("kv.users.reader.password=letmein").unwrap();
+        config.set_override("kv.users.reader.read=*").unwrap();
+        let users = Users::from_config(&config).unwrap();
+        let plain = TestServer::start_with("statuses", config, |server| {
+            server.password = Some("secret".to_owned());
+            server.users = users;
+            server.write_ack_timeout = Duration::from_millis(10);
+        })
+        .await;
+        let follower = TestServer::start("statuses-follower", |server| {
+            server.follower = true;
+        })
+        .await;
+        let slots = TestServer::start("statuses-slots", |server| {
+            let map = "0-0=127.0.0.1:1,1-16383=127.0.0.1:2";
+            server.slots = Some(crate::slots::SlotMap::parse(map, "127.0.0.1:1").unwrap());
+        })
+        .await;
+        // Every write fills the memtable, and is flushed to a segment file.
+        let mut config = Config::default();
+        config.set_override("engine.memtable.capacity=1").unwrap();
+        let storage = TestServer::start_with("statuses-storage", config, |_| {}).await;
+        let addresses = (plain.address, follower.address, slots.address, storage.address);
+        let storage_path = storage.path().to_owned();
+        tokio::task::spawn_blocking(move || {
+            let (plain, follower, slots, storage) = addresses;
+            let mut client = Client::connect(plain).unwrap();
+            assert!(matches!(client.get(b"a"), Err(Error::Unauthenticated)));
+            assert!(matches!(client.auth(b"wrong"), Err(Error::InvalidPassword)));
+            client.auth(b"secret").unwrap();
+            client.set(b"a", b"1").unwrap();
+            assert_eq!(client.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+            assert_eq!(client.get(b"missing").unwrap(), None);
+            let long_key = [b'k'; 17];
+            assert!(matches!(client.set(&long_key, b"1"), Err(Error::TooLarge(_))));
+            let result = client.eval(b"error('oops')", &[]);
+            assert!(
+                matches!(result, Err(Error::InvalidRequest(message)) if message.contains("oops"))
+            );
+            client.acks(Some(AckLevel::LeaderPlusOne)).unwrap();
+            assert!(matches!(client.set(b"b", b"2"), Err(Error::Unreplicated(_))));
+            // The write was applied all the same.
+            assert_eq!(client.get(b"b").unwrap().as_deref(), Some(&b"2"[..]));
+
+            let mut reader = Client::connect(plain).unwrap();
+            reader.auth_user(b"reader", b"letmein").unwrap();
+            assert!(matches!(reader.set(b"a", b"2"), Err(Error::Forbidden(_))));
+
+            let mut client = Client::connect(follower).unwrap();
+            assert!(matches!(client.set(b"a", b"1"), Err(Error::Refused(_))));
+
+            let mut client = Client::connect(slots).unwrap();
+            let moved = client.get(b"a");
+            assert!(matches!(moved, Err(Error::Moved { address, .. }) if address == "127.0.0.1:2"));
+
+            let mut client = Client::connect(storage).unwrap();
+            client.set(b"a", b"1").unwrap();
+            let segment = storage_path.join(crunch_engine::segment::segment_filename(1));
+            let size = fs::metadata(&segment).unwrap().len();
+            fs::write(&segment, vec![0xFF; size as usize]).unwrap();
+            assert!(matches!(client.get(b"a"), Err(Error::Corruption(_))));
+            // The next segment file can't be created, so the write fails to flush.
+            let blocker = storage_path.join(crunch_engine::segment::segment_filename(2));
+            fs::create_dir(&blocker).unwrap();
+            assert!(matches!(client.set(b"b", b"2"), Err(Error::Server(_))));
+            fs::remove_dir(&blocker).unwrap();
+            fs::remove_file(&segment).unwrap();
+        })
+        .await
+        .unwrap();
+        plain.stop().await;
+        follower.stop().await;
+        slots.stop().await;
+        storage.stop().await;
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn invalid_utf8() {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let server = TestServer::start("invalid-utf8", |_| {}).await;
+        let mut stream = server.connect().await;
+        let set: &[&[u8]] = &[b"\xff", b"1"];
+        assert_eq!(request(&mut stream, Command::Set, set).await, Status::Invalid as u8);
+        let message = stream.read_data().await.unwrap();
+        assert_eq!(message, b"arguments must be valid UTF-8");
+        // The arguments were read, so the connection carries on.
+        assert_eq!(request(&mut stream, Command::Get, &[b"\xff"]).await, Status::Invalid as u8);
+        stream.read_data().await.unwrap();
+        assert_eq!(request(&mut stream, Command::Ping, &[]).await, Status::Ok as u8);
+        server.stop().await;
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn framing_error_closes_connection() {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let server = TestServer::start("framing-error", |_| {}).await;
+        let mut stream = server.connect().await;
+        stream.write_outcome(200).await.unwrap();
+        assert_eq!(stream.read_outcome().await.unwrap(), Status::Invalid as u8);
+        let message = stream.read_data().await.unwrap();
+        assert_eq!(message, b"unknown command 200");
+        // Nothing after it can be framed, so the server closes the connection.
+        let error = stream.read_outcome().await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // Nor can an argument that claims to be larger than any allowed.
+        let mut stream = server.connect().await;
+        stream.write_command(Command::Auth).await.unwrap();
+        stream.0.write_u32(u32::MAX - 1).await.unwrap();
+        assert_eq!(stream.read_outcome().await.unwrap(), Status::Invalid as u8);
+        stream.read_data().await.unwrap();
+        let error = stream.read_outcome().await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        server.stop().await;
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clustered_write_that_fails_to_apply() {
+    tokio::time::timeout(Duration::from_secs(20), async {
+        let raft_path = std::env::temp_dir().join("crunch-kv-test-cluster-apply-raft");
+        _ = fs::remove_dir_all(&raft_path);
+        fs::create_dir_all(&raft_path).unwrap();
+        let args = ClusterArgs::parse(1, "1=127.0.0.1:0", "secret".to_owned()).unwrap();
+        let (cluster, inbound) = Cluster::start(args, &raft_path).await.unwrap();
+        // Every write fills the memtable, and is flushed to a segment file.
+        let mut config = Config::default();
+        config.set_override("engine.memtable.capacity=1").unwrap();
+        let mut server = TestServer::start_with("cluster-apply", config, |server| {
+            server.cluster = Some(cluster);
+        })
+        .await;
+        server.spawn(crate::cluster::run(server.server.clone(), inbound));
+
+        // The lone member elects itself once its election timeout passes.
+        let mut stream = server.connect().await;
+        let set: &[&[u8]] = &[b"a", b"1"];
+        loop {
+            match request(&mut stream, Command::Set, set).await {
+                status if status == Status::Refused as u8 => stream.read_data().await.unwrap(),
+                status => break assert_eq!(status, Status::Ok as u8),
+            };
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        // The next segment file can't be created, so the committed write can't be
+        // applied, and the client is told so.
+        let blocker = server.path().join(crunch_engine::segment::segment_filename(2));
+        fs::create_dir(&blocker).unwrap();
+        let set: &[&[u8]] = &[b"b", b"2"];
+        assert_eq!(request(&mut stream, Command::Set, set).await, Status::Internal as u8);
+        let message = stream.read_data().await.unwrap();
+        assert!(message.starts_with(b"the write was committed, but not applied"));
+        fs::remove_dir(&blocker).unwrap();
+        server.stop().await;
+        fs::remove_dir_all(raft_path).unwrap();
+    })
+    .await
+    .unwrap();
+}
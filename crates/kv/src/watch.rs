@@ -0,0 +1,69 @@
+use std::collections::HashSet;
+
+use tokio::io;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::protocol;
+use crate::replication::{self, Record};
+
+/// How many changes a watcher can fall behind by before it is disconnected.
+pub const WATCH_BACKLOG: usize = 1024;
+
+/// Decode the argument of a WATCH command, which holds each key to watch as
+/// length prefixed data. Returns `None` if the argument is malformed.
+pub fn parse_keys(mut data: &[u8]) -> Option<HashSet<String>> {
+    let mut keys = HashSet::new();
+    while !data.is_empty() {
+        let (size, rest) = data.split_first_chunk::<4>()?;
+        let size = u32::from_be_bytes(*size) as usize;
+        keys.insert(std::str::from_utf8(rest.get(..size)?).ok()?.to_owned());
+        data = &rest[size..];
+    }
+    Some(keys)
+}
+
+/// Push every change to one of `keys` from `changes` to a watcher on
+/// `stream`, until the connection is closed.
+///
+/// Each change is written in the same form as a replicated record. If the
+/// watcher falls too far behind, a failure outcome is written in place of the
+/// changes that were missed, and the connection is closed.
+pub async fn serve_watcher(
+    mut changes: broadcast::Receiver<Record>,
+    keys: HashSet<String>,
+    stream: &mut protocol::Stream,
+) -> Result<(), io::Error> {
+    stream.write_success().await?;
+    log::info!("watching {} keys", keys.len());
+    loop {
+        let record = match changes.recv().await {
+            Ok(record) => record,
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("watcher missed {missed} changes, disconnecting it");
+                return stream.write_failure().await;
+            },
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let key = match &record {
+            Record::Set { key, .. } | Record::Delete { key } => key,
+        };
+        if keys.contains(key) {
+            replication::write_record(stream, &record).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_keys() {
+        let data = [0, 0, 0, 1, b'a', 0, 0, 0, 2, b'b', b'c'];
+        let keys = super::parse_keys(&data).unwrap();
+        assert_eq!(keys, HashSet::from(["a".to_owned(), "bc".to_owned()]));
+        assert_eq!(super::parse_keys(&data[..data.len() - 1]), None);
+        assert_eq!(super::parse_keys(&[]), Some(HashSet::new()));
+    }
+}
@@ -0,0 +1,99 @@
+//! Reading the segment files of stores written by older versions.
+//!
+//! The first segment files held one pair per line, as `key=value`, with
+//! whitespace around either half trimmed, and had no way to record a delete.
+//! Later ones are made of binary entries, which are read as they are today.
+
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::anyhow;
+use crunch_engine::segment::{Entry, EntryIter};
+
+/// The writes in the segment file at `path`, in the order they should be
+/// applied, with `None` for a delete. Only the newest version of each key is
+/// kept.
+pub fn read_segment(path: &Path) -> anyhow::Result<Vec<(String, Option<String>)>> {
+    let mut file = File::open(path)?;
+    let entries = EntryIter::from_start(&mut file)?.with_path(path);
+    let mut writes: Vec<(String, Option<String>)> = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // A line-based file fails on its very first byte, which is never a valid
+            // entry indicator.
+            Err(_) if writes.is_empty() => return parse_lines(&fs::read_to_string(path)?),
+            Err(error) => {
+                return Err(anyhow!("{error}, so run crunch-doctor on the store first"));
+            },
+        };
+        // Versions of a key are written newest first.
+        if writes.last().is_some_and(|(key, _)| key == entry.key()) {
+            continue;
+        }
+        writes.push(match entry {
+            Entry::Assignment { key, value } => (key, Some(value)),
+            Entry::Tombstone { key } => (key, None),
+        });
+    }
+    Ok(writes)
+}
+
+/// Parse the `contents` of a line-based segment file. Blank lines are skipped.
+fn parse_lines(contents: &str) -> anyhow::Result<Vec<(String, Option<String>)>> {
+    let mut writes = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (key, value) =
+            line.split_once('=').ok_or_else(|| anyhow!("line {} isn't key=value", index + 1))?;
+        writes.push((key.trim().to_owned(), Some(value.trim().to_owned())));
+    }
+    Ok(writes)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crunch_engine::segment;
+
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        let writes = super::parse_lines("a=1\n\n b = 2 = 3 \nc=\n").unwrap();
+        let expected = [("a", Some("1")), ("b", Some("2 = 3")), ("c", Some(""))];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.map(str::to_owned)))
+            .collect();
+        assert_eq!(writes, expected);
+        assert!(super::parse_lines("a=1\nb\n").is_err());
+    }
+
+    #[test]
+    fn read_segment() {
+        let dir = Path::new("./test-migrate-read-segment");
+        _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+        let lines = dir.join("segment-1.dat");
+        fs::write(&lines, "a=1\nb=2\n").unwrap();
+        assert_eq!(super::read_segment(&lines).unwrap().len(), 2);
+
+        let binary = dir.join("segment-2.dat");
+        let mut file = File::create(&binary).unwrap();
+        segment::write_version(&mut file, 2).unwrap();
+        segment::write(&mut file, "a", "new").unwrap();
+        segment::write_version(&mut file, 1).unwrap();
+        segment::write(&mut file, "a", "old").unwrap();
+        segment::tombstone(&mut file, "b").unwrap();
+        drop(file);
+        assert_eq!(super::read_segment(&binary).unwrap(), [
+            ("a".to_owned(), Some("new".to_owned())),
+            ("b".to_owned(), None)
+        ]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+mod legacy;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use clap::Parser;
+use crunch_engine::batch::WriteBatch;
+use crunch_engine::engine::Engine;
+use crunch_engine::segment::{is_segment_filename, segment_id};
+
+/// Rewrites a store written by an older version of Crunch into a new store in
+/// the current format
+///
+/// Segment files are read oldest first, in either the original line-based
+/// `key=value` format or the binary one, and their live pairs are written to
+/// the new store, which is opened with the engine's usual settings. The old
+/// store isn't changed.
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// The directory of the store to read
+    source: PathBuf,
+
+    /// The directory to create the new store in, which must not exist yet
+    destination: PathBuf,
+}
+
+/// How many writes are applied to the new store at a time.
+const BATCH_SIZE: usize = 1000;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let cli = Cli::parse();
+    if cli.destination.exists() {
+        return Err(anyhow!("{:?} already exists", cli.destination));
+    }
+    let segments = segment_files(&cli.source)?;
+    let engine = Engine::new(cli.destination.clone())?;
+    let mut writes = 0;
+    for path in &segments {
+        let file_writes = legacy::read_segment(path)
+            .map_err(|error| anyhow!("failed to read {path:?}: {error}"))?;
+        for chunk in file_writes.chunks(BATCH_SIZE) {
+            let mut batch = WriteBatch::new();
+            for (key, value) in chunk {
+                match value {
+                    Some(value) => batch.set(key, value),
+                    None => batch.delete(key),
+                }
+            }
+            engine.apply(&batch)?;
+        }
+        log::info!("migrated {} writes from {path:?}", file_writes.len());
+        writes += file_writes.len();
+    }
+    engine.stop().map_err(|_| anyhow!("the engine failed to shut down"))?;
+    println!("Migrated {writes} writes from {} segment files", segments.len());
+
+    let wal_files: Vec<_> = fs::read_dir(&cli.source)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|filename| filename.starts_with("wal") && filename.ends_with(".dat"))
+        .collect();
+    if !wal_files.is_empty() {
+        println!(
+            "Note: {wal_files:?} weren't migrated. Writes that were never flushed out of them are \
+             only in the old store"
+        );
+    }
+    Ok(())
+}
+
+/// The segment files in `directory`, oldest first.
+fn segment_files(directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files: Vec<_> = fs::read_dir(directory)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let filename = path.file_name()?.to_str()?;
+            is_segment_filename(filename).then(|| Some((segment_id(&path)?, path)))?
+        })
+        .collect();
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
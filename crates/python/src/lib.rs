@@ -0,0 +1,249 @@
+//! The `crunchdb` Python module, which embeds the engine in a Python
+//! process.
+//!
+//! ```python
+//! import crunchdb
+//!
+//! with crunchdb.open("data") as db:
+//!     db["greeting"] = "hello"
+//!     for key, value in db.items(prefix="greet"):
+//!         print(key, value)
+//! ```
+//!
+//! A database acts like a dict of strings: keys are read, written and
+//! deleted by indexing it, and iterating over it gives its keys in order.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crunch_engine::engine::Engine;
+use crunch_engine::error::Error as EngineError;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyKeyError};
+use pyo3::prelude::*;
+
+/// How many entries an iterator reads from the engine at a time.
+const PAGE_SIZE: usize = 256;
+
+create_exception!(crunchdb, Error, PyException, "A failure in the engine.");
+
+/// The engine behind a database, and its iterators, which is taken out when
+/// the database is closed.
+type Handle = Arc<RwLock<Option<Engine>>>;
+
+/// Run `operation` against the engine, without holding the GIL, so other
+/// Python threads carry on while it waits on the disk.
+fn with_engine<T: Send>(
+    py: Python<'_>,
+    handle: &Handle,
+    operation: impl FnOnce(&Engine) -> Result<T, EngineError> + Send,
+) -> PyResult<T> {
+    py.detach(|| {
+        let engine = handle.read().map_err(|_| Error::new_err("lock was poisoned"))?;
+        let engine = engine.as_ref().ok_or_else(|| Error::new_err("the database is closed"))?;
+        operation(engine).map_err(|error| Error::new_err(error.to_string()))
+    })
+}
+
+/// Open the engine at `path`, with its settings read from the environment.
+#[pyfunction]
+fn open(py: Python<'_>, path: PathBuf) -> PyResult<Database> {
+    let engine =
+        py.detach(|| Engine::new(path)).map_err(|error| Error::new_err(error.to_string()))?;
+    Ok(Database { engine: Arc::new(RwLock::new(Some(engine))) })
+}
+
+#[pyclass(module = "crunchdb")]
+struct Database {
+    engine: Handle,
+}
+
+#[pymethods]
+impl Database {
+    fn __getitem__(&self, py: Python<'_>, key: &str) -> PyResult<String> {
+        let value = with_engine(py, &self.engine, |engine| engine.get(key))?;
+        value.map(|value| value.to_string()).ok_or_else(|| PyKeyError::new_err(key.to_owned()))
+    }
+
+    fn __setitem__(&self, py: Python<'_>, key: &str, value: &str) -> PyResult<()> {
+        with_engine(py, &self.engine, |engine| engine.set(key, value))
+    }
+
+    fn __delitem__(&self, py: Python<'_>, key: &str) -> PyResult<()> {
+        match with_engine(py, &self.engine, |engine| engine.get_delete(key))? {
+            Some(_) => Ok(()),
+            None => Err(PyKeyError::new_err(key.to_owned())),
+        }
+    }
+
+    fn __contains__(&self, py: Python<'_>, key: &str) -> PyResult<bool> {
+        with_engine(py, &self.engine, |engine| engine.exists(key))
+    }
+
+    fn __iter__(&self) -> Entries {
+        self.keys("")
+    }
+
+    /// The value of `key`, or `default` if it doesn't exist.
+    #[pyo3(signature = (key, default = None))]
+    fn get(&self, py: Python<'_>, key: &str, default: Option<String>) -> PyResult<Option<String>> {
+        let value = with_engine(py, &self.engine, |engine| engine.get(key))?;
+        Ok(value.map(|value| value.to_string()).or(default))
+    }
+
+    /// The keys that start with `prefix`, in order.
+    #[pyo3(signature = (prefix = ""))]
+    fn keys(&self, prefix: &str) -> Entries {
+        Entries::new(&self.engine, prefix, false)
+    }
+
+    /// The `(key, value)` pairs whose keys start with `prefix`, in key order.
+    #[pyo3(signature = (prefix = ""))]
+    fn items(&self, prefix: &str) -> Entries {
+        Entries::new(&self.engine, prefix, true)
+    }
+
+    /// Shut the engine down. Closing a database that is already closed does
+    /// nothing.
+    fn close(&self, py: Python<'_>) -> PyResult<()> {
+        py.detach(|| {
+            let engine =
+                self.engine.write().map_err(|_| Error::new_err("lock was poisoned"))?.take();
+            match engine.map(Engine::stop) {
+                Some(Err(_)) => Err(Error::new_err("a background thread of the engine panicked")),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: &Bound<'_, PyAny>,
+        _exc_value: &Bound<'_, PyAny>,
+        _traceback: &Bound<'_, PyAny>,
+    ) -> PyResult<bool> {
+        self.close(py)?;
+        Ok(false)
+    }
+}
+
+/// An iterator over the keys or pairs of a database that start with a
+/// prefix.
+///
+/// Entries are read a page at a time, so writes made while iterating may or
+/// may not be seen, but every key that exists throughout is.
+#[pyclass(module = "crunchdb")]
+struct Entries {
+    engine: Handle,
+    prefix: String,
+    with_values: bool,
+    page: VecDeque<(String, String)>,
+
+    /// Where the next page starts, or `None` once the last page is read.
+    next: Option<String>,
+}
+
+#[derive(IntoPyObject)]
+enum Entry {
+    Key(String),
+    Pair((String, String)),
+}
+
+impl Entries {
+    fn new(engine: &Handle, prefix: &str, with_values: bool) -> Self {
+        Self {
+            engine: engine.clone(),
+            prefix: prefix.to_owned(),
+            with_values,
+            page: VecDeque::new(),
+            next: Some(prefix.to_owned()),
+        }
+    }
+}
+
+#[pymethods]
+impl Entries {
+    fn __iter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<Entry>> {
+        if self.page.is_empty() {
+            let Some(start) = self.next.take() else {
+                return Ok(None);
+            };
+            let page = with_engine(py, &self.engine, |engine| engine.scan(&start, PAGE_SIZE))?;
+            self.page = page.entries.into();
+            self.next = page.next;
+        }
+        let Some((key, value)) = self.page.pop_front() else {
+            return Ok(None);
+        };
+        if !key.starts_with(&self.prefix) {
+            self.page.clear();
+            self.next = None;
+            return Ok(None);
+        }
+        Ok(Some(if self.with_values { Entry::Pair((key, value)) } else { Entry::Key(key) }))
+    }
+}
+
+#[pymodule]
+fn crunchdb(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(open, module)?)?;
+    module.add_class::<Database>()?;
+    module.add("Error", module.py().get_type::<Error>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use pyo3::py_run;
+
+    use super::*;
+
+    #[test]
+    fn database() {
+        let path = std::env::temp_dir().join("crunch-python-test-database");
+        _ = fs::remove_dir_all(&path);
+        Python::attach(|py| {
+            let db = Py::new(py, open(py, path.clone()).unwrap()).unwrap();
+            py_run!(
+                py,
+                db,
+                r#"
+                with db:
+                    for n in range(300):
+                        db[f"key{n:03}"] = str(n)
+                    db["other"] = "x"
+                    assert db["key007"] == "7"
+                    assert db.get("missing") is None and db.get("missing", "d") == "d"
+                    assert "other" in db and "missing" not in db
+                    del db["other"]
+                    try:
+                        del db["other"]
+                        assert False
+                    except KeyError:
+                        pass
+                    keys = list(db)
+                    assert len(keys) == 300 and keys == sorted(keys)
+                    assert list(db.items(prefix="key29")) == [(f"key29{n}", f"29{n}") for n in range(10)]
+                try:
+                    db["key000"]
+                    assert False
+                except Exception as error:
+                    assert "closed" in str(error)
+                "#
+            );
+        });
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! The text format that `dump` writes key-value pairs in, and `import` reads
+//! them from.
+//!
+//! Each line holds one pair as `key=value`, split at the first `=`. A
+//! backslash escapes a `=` in the key, or a `#` at the start of it, along with
+//! `\\`, `\n` and `\r` in either half, so that any pair fits on one line.
+//! Blank lines, and lines starting with `#`, are skipped.
+
+use anyhow::anyhow;
+
+/// Parse a `line` of the format, returning `None` if it doesn't hold a pair.
+pub fn parse_line(line: &str) -> anyhow::Result<Option<(String, String)>> {
+    if line.trim().is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let mut key = String::new();
+    let mut chars = line.chars();
+    loop {
+        match chars.next() {
+            Some('=') => break,
+            Some('\\') => key.push(unescape(chars.next())?),
+            Some(char) => key.push(char),
+            None => return Err(anyhow!("expected key=value")),
+        }
+    }
+    let mut value = String::new();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => value.push(unescape(chars.next())?),
+            char => value.push(char),
+        }
+    }
+    Ok(Some((key, value)))
+}
+
+/// Format a pair as a line, without the trailing newline.
+pub fn format_line(key: &str, value: &str) -> String {
+    let mut line = String::with_capacity(key.len() + value.len() + 1);
+    escape(&mut line, key, true);
+    line.push('=');
+    escape(&mut line, value, false);
+    line
+}
+
+fn escape(line: &mut String, text: &str, is_key: bool) {
+    // A line starting with a `#` would be skipped as a comment.
+    if is_key && text.starts_with('#') {
+        line.push('\\');
+    }
+    for char in text.chars() {
+        match char {
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '=' if is_key => line.push_str("\\="),
+            char => line.push(char),
+        }
+    }
+}
+
+fn unescape(char: Option<char>) -> anyhow::Result<char> {
+    match char {
+        Some('\\') => Ok('\\'),
+        Some('=') => Ok('='),
+        Some('#') => Ok('#'),
+        Some('n') => Ok('\n'),
+        Some('r') => Ok('\r'),
+        Some(char) => Err(anyhow!("unknown escape \\{char}")),
+        None => Err(anyhow!("nothing to escape at the end of the line")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn parse_line() {
+        let pair = |key: &str, value: &str| Some((key.to_owned(), value.to_owned()));
+        assert_eq!(super::parse_line("a=1").unwrap(), pair("a", "1"));
+        assert_eq!(super::parse_line("a=b=c d").unwrap(), pair("a", "b=c d"));
+        assert_eq!(super::parse_line(r"a\=b=\\1\n2").unwrap(), pair("a=b", "\\1\n2"));
+        assert_eq!(super::parse_line("a=").unwrap(), pair("a", ""));
+        assert_eq!(super::parse_line("# a=1").unwrap(), None);
+        assert_eq!(super::parse_line("  ").unwrap(), None);
+        assert!(super::parse_line("a").is_err());
+        assert!(super::parse_line(r"a=\x").is_err());
+    }
+
+    #[test]
+    fn format_line() {
+        assert_eq!(super::format_line("a", "b=c"), "a=b=c");
+        assert_eq!(super::format_line("a=b", "1\\\n2\r"), r"a\=b=1\\\n2\r");
+        for (key, value) in [("a=b\n", "=\\\r"), ("", ""), ("#a", "#")] {
+            let line = super::format_line(key, value);
+            assert_eq!(super::parse_line(&line).unwrap(), Some((key.to_owned(), value.to_owned())));
+        }
+    }
+}
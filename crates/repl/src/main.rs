@@ -1,43 +1,169 @@
-use std::io::{stdin, Write};
+mod format;
+
+use std::fs::File;
+use std::io::{stdin, BufRead, BufReader, BufWriter, Write};
+use std::time::Instant;
 
 use anyhow::anyhow;
+use clap::Parser;
+use crunch_client::Client;
+use crunch_engine::batch::WriteBatch;
 use crunch_engine::engine::Engine;
 
+/// Interactive prompt for a Crunch database
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Run commands against the CrunchKV server at this `host:port`, instead of
+    /// opening a local engine
+    #[arg(long)]
+    connect: Option<String>,
+
+    /// The password to authenticate with, if the server requires one
+    #[arg(long, requires = "connect")]
+    password: Option<String>,
+}
+
+/// Where commands are run.
+enum Backend {
+    /// An engine opened by the REPL itself.
+    Local(Box<Engine>),
+
+    /// A CrunchKV server, over the wire protocol.
+    Remote(Client),
+}
+
+impl Backend {
+    /// Set each of the `pairs`. Against a local engine this is atomic, while a
+    /// server is sent them all at once, but applies them one at a time.
+    fn set_all(&mut self, pairs: &[(String, String)]) -> anyhow::Result<()> {
+        match self {
+            Self::Local(engine) => {
+                let mut batch = WriteBatch::new();
+                for (key, value) in pairs {
+                    batch.set(key, value);
+                }
+                engine.apply(&batch)?;
+            },
+            Self::Remote(client) => {
+                let mut pipeline = client.pipeline();
+                for (key, value) in pairs {
+                    pipeline.set(key.as_bytes(), value.as_bytes());
+                }
+                for reply in pipeline.exec()? {
+                    reply?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// How many keys to fetch from the server at a time, when listing them.
+const LIST_PAGE_SIZE: u32 = 100;
+
+/// How many pairs `import` writes at a time.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
 enum Command {
-    Set { key: String, value: String },
-    Get { key: String },
-    Delete { key: String },
-    List,
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Delete {
+        key: String,
+    },
+    List {
+        prefix: String,
+        limit: Option<usize>,
+    },
     SegmentList,
-    SegmentInspect { segment_file: String },
+    SegmentInspect {
+        segment_file: String,
+    },
+    WalInspect,
+
+    /// Print the state of the database.
+    Stats,
+
+    /// Load the key-value pairs in a file, in the format from [`format`].
+    Import {
+        path: String,
+    },
+
+    /// Write every key-value pair to a file, in the format from [`format`].
+    Dump {
+        path: String,
+    },
+
+    /// Turn printing how long each command takes on or off.
+    Timing {
+        enabled: bool,
+    },
     Exit,
 }
 
 impl Command {
     /// Parse a [`Command`] from the given REPL input from the user.
     fn parse(input: &str) -> anyhow::Result<Self> {
-        let input = input.trim().to_lowercase();
-        let tokens: Vec<_> = input.split(" ").collect();
-        match (tokens[0], tokens.len() - 1) {
-            // TODO: It's probably best UX to have this parse from key=value, but this is just
-            // easier for now.
+        let mut tokens = tokenize(input)?;
+        if tokens.is_empty() {
+            return Err(anyhow!("invalid command"));
+        }
+        let name = tokens.remove(0).to_lowercase();
+        let mut tokens = tokens.into_iter();
+        let command = match (name.as_str(), tokens.len()) {
             ("set", 2) => {
-                Ok(Command::Set { key: tokens[1].to_owned(), value: tokens[2].to_owned() })
+                Command::Set { key: tokens.next().unwrap(), value: tokens.next().unwrap() }
             },
-            ("get", 1) => Ok(Command::Get { key: tokens[1].to_owned() }),
-            ("del", 1) => Ok(Command::Delete { key: tokens[1].to_owned() }),
-            ("list", 0) => Ok(Command::List),
-            ("segment-list", 0) => Ok(Command::SegmentList),
+            ("set", 1) => {
+                let token = tokens.next().unwrap();
+                let (key, value) =
+                    token.split_once('=').ok_or_else(|| anyhow!("expected key=value"))?;
+                Command::Set { key: key.to_owned(), value: value.to_owned() }
+            },
+            ("get", 1) => Command::Get { key: tokens.next().unwrap() },
+            ("del", 1) => Command::Delete { key: tokens.next().unwrap() },
+            ("list", 0) => Command::List { prefix: String::new(), limit: None },
+            ("list", 1) => Command::List { prefix: tokens.next().unwrap(), limit: None },
+            ("list", 2) => {
+                let prefix = tokens.next().unwrap();
+                let limit = tokens.next().unwrap().parse().map_err(|_| anyhow!("invalid limit"))?;
+                Command::List { prefix, limit: Some(limit) }
+            },
+            ("segment-list", 0) => Command::SegmentList,
             ("segment-inspect", 1) => {
-                Ok(Command::SegmentInspect { segment_file: tokens[1].to_owned() })
+                Command::SegmentInspect { segment_file: tokens.next().unwrap() }
             },
-            ("exit", 0) => Ok(Command::Exit),
-            _ => Err(anyhow!("invalid command")),
-        }
+            ("wal-inspect", 0) => Command::WalInspect,
+            ("stats", 0) => Command::Stats,
+            ("import", 1) => Command::Import { path: tokens.next().unwrap() },
+            ("dump", 1) => Command::Dump { path: tokens.next().unwrap() },
+            ("timing", 1) => match tokens.next().unwrap().to_lowercase().as_str() {
+                "on" => Command::Timing { enabled: true },
+                "off" => Command::Timing { enabled: false },
+                _ => return Err(anyhow!("expected timing on or off")),
+            },
+            ("exit", 0) => Command::Exit,
+            _ => return Err(anyhow!("invalid command")),
+        };
+        Ok(command)
     }
 
-    /// Execute this command against the database `engine`.
-    fn execute(&self, engine: &mut Engine) -> anyhow::Result<()> {
+    /// Execute this command against the database in `backend`.
+    fn execute(&self, backend: &mut Backend) -> anyhow::Result<()> {
+        match self {
+            Self::Import { path } => return import(backend, path),
+            Self::Dump { path } => return dump(backend, path),
+            _ => {},
+        }
+        let engine = match backend {
+            Backend::Local(engine) => engine,
+            Backend::Remote(client) => return self.execute_remote(client),
+        };
         match self {
             Self::Set { key, value } => engine.set(key, value)?,
             Self::Get { key } => match engine.get(key) {
@@ -46,7 +172,9 @@ impl Command {
                 Err(error) => return Err(error.into()),
             },
             Self::Delete { key } => engine.delete(key)?,
-            Self::List => engine.list()?.into_iter().for_each(|key| println!("{key}")),
+            Self::List { prefix, limit } => {
+                engine.list(prefix, *limit)?.into_iter().for_each(|key| println!("{key}"))
+            },
             Self::SegmentList => engine
                 .store()
                 .list_segments()?
@@ -55,31 +183,238 @@ impl Command {
             Self::SegmentInspect { segment_file } => {
                 engine.store().inspect_segment(segment_file)?;
             },
-            // Exit will be handled by caller due to `Engine` ownership requirement.
-            Self::Exit => {},
+            Self::WalInspect => engine.store().inspect_wal()?,
+            Self::Stats => {
+                let stats = engine.stats()?;
+                println!("Memtable: {} of {} keys", stats.memtable_len, stats.memtable_capacity);
+                println!("WAL: {} bytes", stats.store.wal_bytes);
+                println!(
+                    "Segments: {} files, {} bytes",
+                    stats.store.segment_count(),
+                    stats.store.segment_bytes()
+                );
+                for segment in &stats.store.segments {
+                    println!(
+                        "  {:?}: level {}, {} bytes, {} entries ({} tombstones, {} overwrites)",
+                        segment.path,
+                        segment.level,
+                        segment.size,
+                        segment.entry_count,
+                        segment.tombstone_count,
+                        segment.overwrite_count
+                    );
+                }
+                if let Some(ratio) = stats.store.estimated_dead_ratio() {
+                    println!("Estimated dead data: {:.1}%", ratio * 100.0);
+                }
+                if let Some(amplification) = stats.store.space_amplification() {
+                    println!("Space amplification: {amplification:.2}");
+                }
+                if let Some(amplification) = stats.store.read_amplification() {
+                    println!("Read amplification: {amplification:.2} segment files per get");
+                }
+                if let Some(amplification) = stats.store.write_amplification() {
+                    println!("Write amplification: {amplification:.2}");
+                }
+                match stats.store.last_compaction.map(|time| time.elapsed()) {
+                    Some(Ok(elapsed)) => {
+                        println!("Last compaction: {}s ago", elapsed.as_secs())
+                    },
+                    _ => println!("Last compaction: never"),
+                }
+            },
+            // Timing is tracked by the caller, and exit is handled by it due to `Engine`
+            // ownership requirement.
+            // Imports and dumps are run before this, since they go through the same
+            // code for either backend.
+            Self::Import { .. } | Self::Dump { .. } => unreachable!(),
+            Self::Timing { .. } | Self::Exit => {},
+        }
+        Ok(())
+    }
+
+    /// Execute this command against a server, through `client`.
+    fn execute_remote(&self, client: &mut Client) -> anyhow::Result<()> {
+        match self {
+            Self::Set { key, value } => client.set(key.as_bytes(), value.as_bytes())?,
+            Self::Get { key } => match client.get(key.as_bytes())? {
+                Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+                None => return Err(anyhow!("not found")),
+            },
+            Self::Delete { key } => client.delete(key.as_bytes())?,
+            Self::List { prefix, limit } => {
+                let mut cursor = prefix.as_bytes().to_vec();
+                let mut remaining = limit.unwrap_or(usize::MAX);
+                while remaining > 0 {
+                    let count = remaining.min(LIST_PAGE_SIZE as usize) as u32;
+                    let page = client.scan(&cursor, count, false)?;
+                    for (key, _) in page.entries {
+                        if !key.starts_with(prefix.as_bytes()) {
+                            return Ok(());
+                        }
+                        println!("{}", String::from_utf8_lossy(&key));
+                        remaining -= 1;
+                    }
+                    match page.next {
+                        Some(next) => cursor = next,
+                        None => break,
+                    }
+                }
+            },
+            Self::Stats => {
+                for (name, value) in client.info()? {
+                    println!("{name}: {value}");
+                }
+            },
+            Self::SegmentList | Self::SegmentInspect { .. } | Self::WalInspect => {
+                return Err(anyhow!("only available with a local engine"));
+            },
+            Self::Import { .. } | Self::Dump { .. } => unreachable!(),
+            Self::Timing { .. } | Self::Exit => {},
         }
         Ok(())
     }
 }
 
+/// Load the key-value pairs in the file at `path` into `backend`, a batch at
+/// a time.
+fn import(backend: &mut Backend, path: &str) -> anyhow::Result<()> {
+    let file = BufReader::new(File::open(path)?);
+    let mut pairs = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    let mut imported = 0;
+    for (index, line) in file.lines().enumerate() {
+        let pair = format::parse_line(&line?)
+            .map_err(|error| anyhow!("line {} of {path}: {error}", index + 1))?;
+        pairs.extend(pair);
+        if pairs.len() == IMPORT_BATCH_SIZE {
+            backend.set_all(&pairs)?;
+            imported += pairs.len();
+            pairs.clear();
+            print!("\rimported {imported} keys");
+            std::io::stdout().flush()?;
+        }
+    }
+    if !pairs.is_empty() {
+        backend.set_all(&pairs)?;
+        imported += pairs.len();
+    }
+    println!("\rimported {imported} keys from {path}");
+    Ok(())
+}
+
+/// Split `input` into whitespace separated tokens, like a shell would.
+///
+/// Double or single quotes group characters, including whitespace, into a
+/// token, and a backslash takes the character after it literally, except
+/// within single quotes.
+fn tokenize(input: &str) -> anyhow::Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    // The token being built, which is `Some` even when empty if it was quoted.
+    let mut token: Option<String> = None;
+    let mut quote = None;
+    let mut chars = input.chars();
+    while let Some(char) = chars.next() {
+        match (quote, char) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), char) => token.get_or_insert_with(String::new).push(char),
+            (_, '\\') => {
+                let escaped =
+                    chars.next().ok_or_else(|| anyhow!("nothing to escape at the end"))?;
+                token.get_or_insert_with(String::new).push(escaped);
+            },
+            (None, '"' | '\'') => {
+                quote = Some(char);
+                token.get_or_insert_with(String::new);
+            },
+            (None, char) if char.is_whitespace() => tokens.extend(token.take()),
+            (_, char) => token.get_or_insert_with(String::new).push(char),
+        }
+    }
+    if let Some(quote) = quote {
+        return Err(anyhow!("unterminated {quote} quote"));
+    }
+    tokens.extend(token);
+    Ok(tokens)
+}
+
+/// Write every key-value pair in `backend` to a file at `path`.
+///
+/// A local engine is dumped as of a single point in time. A server is read a
+/// page at a time, so writes made to it during the dump may or may not be
+/// included.
+fn dump(backend: &mut Backend, path: &str) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut dumped = 0;
+    let mut write = |key: &str, value: &str| -> anyhow::Result<()> {
+        writeln!(file, "{}", format::format_line(key, value))?;
+        dumped += 1;
+        Ok(())
+    };
+    match backend {
+        Backend::Local(engine) => {
+            for entry in engine.entries()? {
+                let (key, value) = entry?;
+                write(&key, &value)?;
+            }
+        },
+        Backend::Remote(client) => {
+            let mut cursor = Vec::new();
+            loop {
+                let page = client.scan(&cursor, LIST_PAGE_SIZE, true)?;
+                for (key, value) in page.entries {
+                    let text = |bytes: Vec<u8>| {
+                        String::from_utf8(bytes)
+                            .map_err(|_| anyhow!("server sent a non-UTF-8 pair"))
+                    };
+                    write(&text(key)?, &text(value.unwrap_or_default())?)?;
+                }
+                match page.next {
+                    Some(next) => cursor = next,
+                    None => break,
+                }
+            }
+        },
+    }
+    file.flush()?;
+    println!("dumped {dumped} keys to {path}");
+    Ok(())
+}
+
 fn main() {
     env_logger::init();
-    let mut engine = Engine::new("test-db".into()).unwrap();
+    let args = Cli::parse();
+    let mut backend = match &args.connect {
+        Some(address) => {
+            let mut client = Client::connect(address.as_str()).unwrap();
+            if let Some(password) = &args.password {
+                client.auth(password.as_bytes()).unwrap();
+            }
+            Backend::Remote(client)
+        },
+        None => Backend::Local(Box::new(Engine::new("test-db".into()).unwrap())),
+    };
 
     println!("Crunch");
     println!("The worst key-value store on the planet!");
     println!();
     println!("Here is how to use:");
-    println!("SET key value");
+    println!("SET key value (or key=value, quoting values with spaces)");
     println!("GET key");
     println!("DEL key");
-    println!("LIST");
-    println!("SEGMENT-LIST");
-    println!("SEGMENT-INSPECT segment");
+    println!("LIST [prefix] [limit]");
+    if matches!(backend, Backend::Local(_)) {
+        println!("SEGMENT-LIST");
+        println!("SEGMENT-INSPECT segment");
+        println!("WAL-INSPECT");
+    }
+    println!("IMPORT file (with a key=value pair on each line)");
+    println!("DUMP file");
+    println!("TIMING on|off");
     println!("EXIT");
     println!();
     println!("That's it - Have fun!");
 
+    let mut timing = false;
     loop {
         let mut command = String::new();
         print!("> ");
@@ -92,12 +427,51 @@ fn main() {
                 continue;
             },
         };
-        if let Err(error) = command.execute(&mut engine) {
+        if let Command::Timing { enabled } = command {
+            timing = enabled;
+            continue;
+        }
+        let start = Instant::now();
+        let result = command.execute(&mut backend);
+        let elapsed = start.elapsed();
+        if let Err(error) = result {
             println!("error: {error}");
         }
+        if timing {
+            println!("({elapsed:.2?})");
+        }
         if matches!(command, Command::Exit) {
-            engine.stop().unwrap();
+            if let Backend::Local(engine) = backend {
+                (*engine).stop().unwrap();
+            }
             break;
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokenize() {
+        assert_eq!(super::tokenize("  set a   b ").unwrap(), ["set", "a", "b"]);
+        assert_eq!(super::tokenize(r#"set a "b c""#).unwrap(), ["set", "a", "b c"]);
+        assert_eq!(super::tokenize(r#"set a='b "c"'"#).unwrap(), ["set", r#"a=b "c""#]);
+        assert_eq!(super::tokenize(r#"set a b\ \"c"#).unwrap(), ["set", "a", r#"b "c"#]);
+        assert_eq!(super::tokenize(r#"set a """#).unwrap(), ["set", "a", ""]);
+        assert!(super::tokenize(r#"set a "b"#).is_err());
+        assert!(super::tokenize(r"set a b\").is_err());
+    }
+
+    #[test]
+    fn parse_set() {
+        for input in [r#"SET Key "Some Value""#, r#"set Key="Some Value""#] {
+            let Command::Set { key, value } = Command::parse(input).unwrap() else {
+                panic!("expected a set from {input}");
+            };
+            assert_eq!((key.as_str(), value.as_str()), ("Key", "Some Value"));
+        }
+        assert!(Command::parse("set key").is_err());
+    }
+}